@@ -0,0 +1,274 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use dashmap::{DashMap, DashSet};
+use tracing::debug;
+
+use crate::{peer::PeerAddr, peer_connection::Chunk};
+
+/// Hash failures a peer may be involved in before we ban it.
+pub const MAX_STRIKES: u32 = 3;
+/// Failed pieces we keep blocks of, the oldest are forgotten past it.
+const MAX_SUSPECT_PIECES: usize = 64;
+/// Blocks we keep per failed piece, across its failures.
+const MAX_SUSPECT_BLOCKS: usize = 1024;
+
+/// A block of a failed piece, kept around until the piece passes so we can
+/// tell which peer sent bad data.
+#[derive(Debug, Clone)]
+struct SuspectBlock {
+    start: u32,
+    length: u32,
+    peer: PeerAddr,
+    hash: [u8; 20],
+}
+
+/// What we know of the failures of a piece several peers took part in.
+#[derive(Debug, Default)]
+struct Suspects {
+    blocks: Vec<SuspectBlock>,
+    /// The contributors of every failure, each got a strike for it.
+    struck: Vec<PeerAddr>,
+    /// Order of the first failure, to forget the oldest piece first.
+    order: u64,
+}
+
+/// Keeps track of the peers that sent us corrupt pieces.
+///
+/// Peers are blamed by IP, so reconnecting from another port doesn't clear the record.
+#[derive(Debug, Default)]
+pub struct PeerBans {
+    strikes: DashMap<IpAddr, u32>,
+    banned: DashSet<IpAddr>,
+    suspects: Mutex<HashMap<u32, Suspects>>,
+    failures: AtomicU64,
+}
+
+impl PeerBans {
+    pub fn is_banned(&self, peer: &PeerAddr) -> bool {
+        self.banned.contains(&peer.ip())
+    }
+
+    pub fn ban(&self, peer: &PeerAddr) {
+        if self.banned.insert(peer.ip()) {
+            debug!("banning peer {}", peer.ip());
+        }
+    }
+
+    pub fn strikes(&self, peer: &PeerAddr) -> u32 {
+        self.strikes.get(&peer.ip()).map(|s| *s).unwrap_or(0)
+    }
+
+    /// Adds a strike to `peer`, banning it once it reaches `MAX_STRIKES`.
+    /// Returns `true` if the peer got banned.
+    pub fn strike(&self, peer: &PeerAddr) -> bool {
+        let mut strikes = self.strikes.entry(peer.ip()).or_insert(0);
+        *strikes += 1;
+        if *strikes >= MAX_STRIKES {
+            drop(strikes);
+            self.ban(peer);
+            return true;
+        }
+        false
+    }
+
+    fn forgive(&self, peer: &PeerAddr) {
+        if let Some(mut strikes) = self.strikes.get_mut(&peer.ip()) {
+            *strikes = strikes.saturating_sub(1);
+        }
+    }
+
+    /// Blames the peers that contributed to a piece that failed the hash check.
+    ///
+    /// Every contributor gets a strike. When several peers took part we also
+    /// keep the hash of every block, and once the piece passes the peers that
+    /// sent a different block are banned while the others get their strike
+    /// back (see `on_piece_passed`).
+    pub fn on_piece_failed(&self, index: u32, chunks: &[Chunk]) {
+        let contributors: HashSet<PeerAddr> = chunks.iter().map(|c| c.peer).collect();
+        for peer in contributors.iter() {
+            self.strike(peer);
+        }
+        if contributors.len() < 2 {
+            return;
+        }
+
+        let order = self.failures.fetch_add(1, Ordering::Relaxed);
+        let mut all = self.suspects.lock().unwrap();
+        if !all.contains_key(&index) && all.len() >= MAX_SUSPECT_PIECES {
+            let oldest = all.iter().min_by_key(|(_, s)| s.order).map(|(i, _)| *i);
+            if let Some(oldest) = oldest {
+                all.remove(&oldest);
+            }
+        }
+        let suspects = all.entry(index).or_insert_with(|| Suspects {
+            order,
+            ..Default::default()
+        });
+        suspects.struck.extend(contributors);
+        let room = MAX_SUSPECT_BLOCKS.saturating_sub(suspects.blocks.len());
+        suspects
+            .blocks
+            .extend(chunks.iter().take(room).map(|c| SuspectBlock {
+                start: c.start,
+                length: c.length,
+                peer: c.peer,
+                hash: block_hash(&c.buf),
+            }));
+    }
+
+    /// Compares the blocks we kept from earlier failures of this piece with the
+    /// ones that just passed, and bans every peer that sent a different block.
+    pub fn on_piece_passed(&self, index: u32, chunks: &[Chunk]) {
+        self.judge(index, |start, _| {
+            chunks
+                .iter()
+                .find(|c| c.start == start)
+                .map(|c| c.buf.as_slice())
+        });
+    }
+
+    /// Like [`PeerBans::on_piece_passed`], for a piece that came whole, e.g.
+    /// from a web seed.
+    pub fn on_piece_verified(&self, index: u32, buf: &[u8]) {
+        self.judge(index, |start, length| {
+            buf.get(start as usize..start as usize + length as usize)
+        });
+    }
+
+    fn judge<'a>(&self, index: u32, good_block: impl Fn(u32, u32) -> Option<&'a [u8]>) {
+        let Some(suspects) = self.suspects.lock().unwrap().remove(&index) else {
+            return;
+        };
+
+        let mut liars = HashSet::new();
+        for suspect in suspects.blocks {
+            let good = good_block(suspect.start, suspect.length);
+            if good.is_some_and(|good| block_hash(good) != suspect.hash) {
+                debug!(
+                    "peer {} sent a bad block for piece {} at {}",
+                    suspect.peer, index, suspect.start
+                );
+                liars.insert(suspect.peer);
+            }
+        }
+        for peer in liars.iter() {
+            self.ban(peer);
+        }
+        for peer in suspects.struck.iter().filter(|p| !liars.contains(p)) {
+            self.forgive(peer);
+        }
+    }
+
+    #[cfg(test)]
+    fn suspect_pieces(&self) -> usize {
+        self.suspects.lock().unwrap().len()
+    }
+}
+
+fn block_hash(buf: &[u8]) -> [u8; 20] {
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(buf);
+    hasher.digest().bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(start: u32, peer: PeerAddr, buf: &[u8]) -> Chunk {
+        Chunk {
+            index: 0,
+            start,
+            length: buf.len() as u32,
+            buf: buf.to_vec(),
+            peer,
+        }
+    }
+
+    #[test]
+    fn single_contributor_is_banned_after_max_strikes() {
+        let bans = PeerBans::default();
+        let peer: PeerAddr = "10.0.0.1:6881".parse().unwrap();
+
+        for _ in 0..MAX_STRIKES - 1 {
+            bans.on_piece_failed(0, &[chunk(0, peer, b"bad")]);
+            assert!(!bans.is_banned(&peer));
+        }
+        bans.on_piece_failed(0, &[chunk(0, peer, b"bad")]);
+
+        assert!(bans.is_banned(&peer));
+        // Same host, different port.
+        assert!(bans.is_banned(&"10.0.0.1:51413".parse().unwrap()));
+    }
+
+    #[test]
+    fn smart_ban_finds_the_culprit() {
+        let bans = PeerBans::default();
+        let honest: PeerAddr = "10.0.0.1:6881".parse().unwrap();
+        let liar: PeerAddr = "10.0.0.2:6881".parse().unwrap();
+
+        bans.on_piece_failed(7, &[chunk(0, honest, b"good"), chunk(4, liar, b"evil")]);
+        assert_eq!(bans.strikes(&honest), 1);
+        assert_eq!(bans.strikes(&liar), 1);
+
+        bans.on_piece_passed(7, &[chunk(0, honest, b"good"), chunk(4, honest, b"data")]);
+
+        assert!(!bans.is_banned(&honest));
+        assert_eq!(bans.strikes(&honest), 0);
+        assert!(bans.is_banned(&liar));
+        assert_eq!(bans.suspect_pieces(), 0);
+    }
+
+    #[test]
+    fn contributors_are_banned_when_the_piece_never_passes() {
+        let bans = PeerBans::default();
+        let a: PeerAddr = "10.0.0.1:6881".parse().unwrap();
+        let b: PeerAddr = "10.0.0.2:6881".parse().unwrap();
+
+        for _ in 0..MAX_STRIKES {
+            bans.on_piece_failed(3, &[chunk(0, a, b"bad!"), chunk(4, b, b"bad!")]);
+        }
+        assert!(bans.is_banned(&a));
+        assert!(bans.is_banned(&b));
+    }
+
+    #[test]
+    fn whole_pieces_settle_the_suspects() {
+        let bans = PeerBans::default();
+        let honest: PeerAddr = "10.0.0.1:6881".parse().unwrap();
+        let liar: PeerAddr = "10.0.0.2:6881".parse().unwrap();
+
+        bans.on_piece_failed(2, &[chunk(0, honest, b"good"), chunk(4, liar, b"evil")]);
+        bans.on_piece_verified(2, b"gooddata");
+
+        assert!(bans.is_banned(&liar));
+        assert_eq!(bans.strikes(&honest), 0);
+        assert_eq!(bans.suspect_pieces(), 0);
+    }
+
+    #[test]
+    fn suspects_are_capped() {
+        let bans = PeerBans::default();
+        let a: PeerAddr = "10.0.0.1:6881".parse().unwrap();
+        let b: PeerAddr = "10.0.0.2:6881".parse().unwrap();
+
+        for index in 0..MAX_SUSPECT_PIECES as u32 + 10 {
+            bans.on_piece_failed(index, &[chunk(0, a, b"bad!"), chunk(4, b, b"bad!")]);
+        }
+        assert_eq!(bans.suspect_pieces(), MAX_SUSPECT_PIECES);
+        // The oldest pieces went first.
+        assert!(!bans.suspects.lock().unwrap().contains_key(&0));
+        assert!(bans
+            .suspects
+            .lock()
+            .unwrap()
+            .contains_key(&(MAX_SUSPECT_PIECES as u32 + 9)));
+    }
+}
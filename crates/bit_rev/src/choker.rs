@@ -0,0 +1,103 @@
+use std::collections::HashSet;
+
+use crate::peer::PeerAddr;
+
+/// Picks the peers we upload to. The interested peers that give us the most
+/// take the regular slots, one more slot goes to an optimistic unchoke that
+/// moves around so peers we don't know yet get a chance to prove themselves.
+#[derive(Debug)]
+pub struct Choker {
+    slots: usize,
+    optimistic: Option<PeerAddr>,
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots: slots.max(1),
+            optimistic: None,
+        }
+    }
+
+    /// The peer holding the optimistic slot.
+    pub fn optimistic(&self) -> Option<PeerAddr> {
+        self.optimistic
+    }
+
+    /// Returns the peers to unchoke, every other one gets choked.
+    ///
+    /// `ranked` are the interested peers with the rate they are judged by,
+    /// `candidates` the optimistic unchoke candidates, best first, see
+    /// `PeerStates::optimistic_unchoke_candidates`. The optimistic slot only
+    /// moves on when `rotate` is set or its peer can't keep it.
+    pub fn rechoke(
+        &mut self,
+        mut ranked: Vec<(PeerAddr, f64)>,
+        candidates: &[PeerAddr],
+        rotate: bool,
+    ) -> HashSet<PeerAddr> {
+        ranked.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        let mut unchoked: HashSet<PeerAddr> = ranked
+            .into_iter()
+            .take(self.slots - 1)
+            .map(|(peer, _)| peer)
+            .collect();
+
+        let keep = !rotate
+            && self
+                .optimistic
+                .is_some_and(|peer| candidates.contains(&peer) && !unchoked.contains(&peer));
+        if !keep {
+            self.optimistic = candidates
+                .iter()
+                .find(|peer| !unchoked.contains(peer))
+                .copied();
+        }
+        unchoked.extend(self.optimistic);
+        unchoked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(n: u8) -> PeerAddr {
+        format!("10.0.0.{}:6881", n).parse().unwrap()
+    }
+
+    #[test]
+    fn fastest_peers_and_one_optimistic() {
+        let mut choker = Choker::new(3);
+        let ranked = vec![
+            (peer(1), 10.0),
+            (peer(2), 30.0),
+            (peer(3), 20.0),
+            (peer(4), 0.0),
+        ];
+        let candidates = [peer(2), peer(4), peer(1), peer(3)];
+
+        let unchoked = choker.rechoke(ranked, &candidates, true);
+        assert_eq!(unchoked, HashSet::from([peer(2), peer(3), peer(4)]));
+        assert_eq!(choker.optimistic(), Some(peer(4)));
+    }
+
+    #[test]
+    fn optimistic_slot_rotates() {
+        let mut choker = Choker::new(1);
+        let ranked = vec![(peer(1), 0.0), (peer(2), 0.0)];
+
+        choker.rechoke(ranked.clone(), &[peer(1), peer(2)], true);
+        assert_eq!(choker.optimistic(), Some(peer(1)));
+        // Kept until it is time to rotate.
+        choker.rechoke(ranked.clone(), &[peer(2), peer(1)], false);
+        assert_eq!(choker.optimistic(), Some(peer(1)));
+        choker.rechoke(ranked.clone(), &[peer(2), peer(1)], true);
+        assert_eq!(choker.optimistic(), Some(peer(2)));
+
+        // Its peer lost interest.
+        let unchoked = choker.rechoke(vec![(peer(1), 0.0)], &[peer(1)], false);
+        assert_eq!(choker.optimistic(), Some(peer(1)));
+        assert_eq!(unchoked, HashSet::from([peer(1)]));
+    }
+}
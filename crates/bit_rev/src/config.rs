@@ -0,0 +1,463 @@
+use std::{net::IpAddr, path::Path, path::PathBuf, time::Duration};
+
+use serde::{de::Error as _, Deserialize, Serialize};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::{
+    connection_manager::ConnectionLimits,
+    network::BindTo,
+    peer_id::{self, ClientFilter},
+    pipeline,
+    proxy::ProxyConfig,
+    utils,
+};
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("can't read settings from {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid settings in {}: {source}", path.display())]
+    Invalid {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[error("invalid torrent settings: {0} must be more than 0")]
+    Torrent(&'static str),
+}
+
+/// Settings of a [`Session`](crate::session::Session).
+///
+/// Every field is optional in a settings file, missing ones keep their
+/// default. Durations are written in seconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// Port we accept peers on, also used by the DHT. `0` picks a free port.
+    pub listen_port: u16,
+    /// Directory the torrents are downloaded to.
+    pub download_dir: PathBuf,
+    /// Prefix of our peer id, Azureus-style.
+    pub peer_id_prefix: String,
+    pub connection_limits: ConnectionLimits,
+    /// Look for peers in the mainline DHT.
+    pub enable_dht: bool,
+    /// Look for peers on the local network, BEP 14.
+    pub enable_lsd: bool,
+    /// Disk reads and writes running at the same time, across every torrent.
+    pub max_disk_ops: usize,
+    /// Upload limit of the whole session, in bytes per second.
+    pub upload_limit: Option<u64>,
+    /// Download limit of the whole session, in bytes per second.
+    pub download_limit: Option<u64>,
+    /// Upload limit of every new connection, in bytes per second.
+    pub peer_upload_limit: Option<u64>,
+    /// Download limit of every new connection, in bytes per second.
+    pub peer_download_limit: Option<u64>,
+    /// Peers on the local network skip every rate limit.
+    pub exempt_lan_peers: bool,
+    /// Address trackers should give out instead of the one we announce
+    /// from, e.g. behind a NAT they can't see through.
+    pub announce_ip: Option<IpAddr>,
+    /// Proxy for trackers and peers.
+    pub proxy: Option<ProxyConfig>,
+    /// Interface or address every connection goes out from and the
+    /// listener binds to.
+    pub bind: Option<BindTo>,
+    /// Blocklist of peer addresses, in eMule `ipfilter.dat`, PeerGuardian
+    /// P2P or CIDR format.
+    pub ip_filter: Option<PathBuf>,
+    /// Settings of the torrents added without settings of their own.
+    pub torrent: TorrentConfig,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            listen_port: 6881,
+            download_dir: PathBuf::from("."),
+            peer_id_prefix: peer_id::DEFAULT_PEER_ID_PREFIX.to_string(),
+            connection_limits: ConnectionLimits::default(),
+            enable_dht: true,
+            enable_lsd: true,
+            max_disk_ops: 8,
+            upload_limit: None,
+            download_limit: None,
+            peer_upload_limit: None,
+            peer_download_limit: None,
+            exempt_lan_peers: true,
+            announce_ip: None,
+            proxy: None,
+            bind: None,
+            ip_filter: None,
+            torrent: TorrentConfig::default(),
+        }
+    }
+}
+
+impl SessionConfig {
+    /// Reads the settings from a JSON file, e.g. `util::paths::SETTINGS`.
+    /// A missing file gives the defaults.
+    pub fn load(path: impl AsRef<Path>) -> Result<SessionConfig, ConfigError> {
+        let path = path.as_ref();
+        match std::fs::read(path) {
+            Ok(bytes) => Self::from_json(&bytes).map_err(|source| ConfigError::Invalid {
+                path: path.to_path_buf(),
+                source,
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(source) => Err(ConfigError::Io {
+                path: path.to_path_buf(),
+                source,
+            }),
+        }
+    }
+
+    pub fn from_json(json: &[u8]) -> serde_json::Result<SessionConfig> {
+        let mut config: SessionConfig = serde_json::from_slice(json)?;
+        config
+            .torrent
+            .validate()
+            .map_err(serde_json::Error::custom)?;
+        Ok(config)
+    }
+}
+
+/// Timeouts and limits of a single torrent and its connections.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TorrentConfig {
+    /// How long the peer gets to answer our handshake.
+    #[serde(with = "secs")]
+    pub handshake_timeout: Duration,
+    /// How long we wait for a TCP connection to a peer.
+    #[serde(with = "secs")]
+    pub connect_timeout: Duration,
+    /// Silence after which we drop a peer. Peers only send keep-alives
+    /// every two minutes, so with the default an idle peer that has nothing
+    /// for us is dropped.
+    #[serde(with = "secs")]
+    pub read_timeout: Duration,
+    /// How long a single message may take to send.
+    #[serde(with = "secs")]
+    pub write_timeout: Duration,
+    /// We send a keep-alive after this long without sending anything else.
+    #[serde(with = "secs")]
+    pub keep_alive_interval: Duration,
+    /// Delay before announcing again to a tracker that failed or didn't
+    /// give an interval.
+    #[serde(with = "secs")]
+    pub tracker_interval: Duration,
+    /// Peers we ask trackers for in each announce.
+    pub numwant: u32,
+    /// Requests we keep in flight to a peer at most, unless it asks for fewer.
+    pub max_outstanding_requests: usize,
+    /// Size of the blocks we request, at most 16 KiB as most peers refuse
+    /// bigger requests.
+    pub block_size: u32,
+    /// How often we tell peers about the other peers we're connected to.
+    #[serde(with = "secs")]
+    pub pex_interval: Duration,
+    /// Download the pieces in order, the first ones we miss before the rest.
+    pub sequential: bool,
+    /// Pieces past the read position of a stream, or past the first piece
+    /// we miss in sequential mode, that get a deadline.
+    pub readahead: u32,
+    /// How long the first piece of a readahead window may take, every piece
+    /// after it gets as long again. Pieces past their deadline are asked from
+    /// several peers.
+    #[serde(with = "secs")]
+    pub piece_deadline: Duration,
+    /// Clients we talk to or turn away, by the name in their peer id or
+    /// extended handshake.
+    pub client_filter: ClientFilter,
+    /// Peers we upload to at once, one of them picked optimistically.
+    pub unchoke_slots: usize,
+    /// How often we pick the peers we upload to again.
+    #[serde(with = "secs")]
+    pub rechoke_interval: Duration,
+    /// How often the optimistic unchoke moves on to another peer.
+    #[serde(with = "secs")]
+    pub optimistic_unchoke_interval: Duration,
+    /// How long a single block request may stay unanswered before we give up on it.
+    #[serde(with = "secs")]
+    pub request_timeout: Duration,
+    /// How long a peer may sit on our requests without sending any data
+    /// before we consider it snubbing us.
+    #[serde(with = "secs")]
+    pub snub_timeout: Duration,
+    /// How long a peer is kept off a piece after one of its requests for it
+    /// timed out, so it goes to the other peers meanwhile.
+    #[serde(with = "secs")]
+    pub timed_out_retry: Duration,
+    /// How long a peer we can't tell from its peer id gets to name itself in
+    /// its extended handshake, when an allow list is set.
+    #[serde(with = "secs")]
+    pub identify_timeout: Duration,
+    /// How often we look for slow peers to replace when the connection
+    /// limits are reached.
+    #[serde(with = "secs")]
+    pub replace_peers_interval: Duration,
+    /// How often we look the torrent up in the DHT.
+    #[serde(with = "secs")]
+    pub dht_announce_interval: Duration,
+    /// Retry delay for DHT lookups while the routing table is still empty.
+    #[serde(with = "secs")]
+    pub dht_bootstrap_retry: Duration,
+    /// How often we announce the torrent to the local network.
+    #[serde(with = "secs")]
+    pub lsd_announce_interval: Duration,
+    /// Failures in a row after which we stop using a web seed.
+    pub max_web_seed_failures: u32,
+    /// Delay before trying a web seed again after a failure, doubled every time.
+    #[serde(with = "secs")]
+    pub web_seed_retry_delay: Duration,
+    /// How long a web seed waits when peers are on every piece left.
+    #[serde(with = "secs")]
+    pub web_seed_idle: Duration,
+}
+
+impl Default for TorrentConfig {
+    fn default() -> Self {
+        Self {
+            handshake_timeout: Duration::from_secs(3),
+            connect_timeout: Duration::from_secs(6),
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            keep_alive_interval: Duration::from_secs(120),
+            tracker_interval: Duration::from_secs(30),
+            numwant: 50,
+            max_outstanding_requests: pipeline::DEFAULT_MAX_DEPTH,
+            block_size: utils::BLOCK_SIZE,
+            pex_interval: Duration::from_secs(60),
+            sequential: false,
+            readahead: 16,
+            piece_deadline: Duration::from_secs(2),
+            client_filter: ClientFilter::default(),
+            unchoke_slots: 4,
+            rechoke_interval: Duration::from_secs(10),
+            optimistic_unchoke_interval: Duration::from_secs(30),
+            request_timeout: Duration::from_secs(20),
+            snub_timeout: Duration::from_secs(60),
+            timed_out_retry: Duration::from_secs(120),
+            identify_timeout: Duration::from_secs(10),
+            replace_peers_interval: Duration::from_secs(10),
+            dht_announce_interval: Duration::from_secs(15 * 60),
+            dht_bootstrap_retry: Duration::from_secs(30),
+            lsd_announce_interval: Duration::from_secs(5 * 60),
+            max_web_seed_failures: 5,
+            web_seed_retry_delay: Duration::from_secs(5),
+            web_seed_idle: Duration::from_secs(1),
+        }
+    }
+}
+
+impl TorrentConfig {
+    /// Refuses settings we can't download with and caps the block size.
+    pub fn validate(&mut self) -> Result<(), ConfigError> {
+        let counts = [
+            ("block_size", self.block_size as usize),
+            ("max_outstanding_requests", self.max_outstanding_requests),
+            ("unchoke_slots", self.unchoke_slots),
+            ("max_web_seed_failures", self.max_web_seed_failures as usize),
+        ];
+        if let Some((name, _)) = counts.iter().find(|(_, count)| *count == 0) {
+            return Err(ConfigError::Torrent(name));
+        }
+        // Every one of them ends up in a timeout, a sleep or an interval.
+        let durations = [
+            ("handshake_timeout", self.handshake_timeout),
+            ("connect_timeout", self.connect_timeout),
+            ("read_timeout", self.read_timeout),
+            ("write_timeout", self.write_timeout),
+            ("keep_alive_interval", self.keep_alive_interval),
+            ("tracker_interval", self.tracker_interval),
+            ("pex_interval", self.pex_interval),
+            ("piece_deadline", self.piece_deadline),
+            ("rechoke_interval", self.rechoke_interval),
+            (
+                "optimistic_unchoke_interval",
+                self.optimistic_unchoke_interval,
+            ),
+            ("request_timeout", self.request_timeout),
+            ("snub_timeout", self.snub_timeout),
+            ("identify_timeout", self.identify_timeout),
+            ("replace_peers_interval", self.replace_peers_interval),
+            ("dht_announce_interval", self.dht_announce_interval),
+            ("dht_bootstrap_retry", self.dht_bootstrap_retry),
+            ("lsd_announce_interval", self.lsd_announce_interval),
+            ("web_seed_idle", self.web_seed_idle),
+        ];
+        if let Some((name, _)) = durations.iter().find(|(_, d)| d.is_zero()) {
+            return Err(ConfigError::Torrent(name));
+        }
+        if self.block_size > utils::BLOCK_SIZE {
+            warn!(
+                "block_size {} is too big for most peers, using {}",
+                self.block_size,
+                utils::BLOCK_SIZE
+            );
+            self.block_size = utils::BLOCK_SIZE;
+        }
+        Ok(())
+    }
+}
+
+// Durations as a number of seconds, fractions allowed.
+pub(crate) mod secs {
+    use std::time::Duration;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(duration.as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let secs = f64::deserialize(deserializer)?;
+        Duration::try_from_secs_f64(secs).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_settings_keep_their_default() {
+        let config = SessionConfig::from_json(
+            br#"{
+                "listen_port": 7000,
+                "connection_limits": { "per_torrent": 10, "min_peer_age": 30 },
+                "torrent": { "read_timeout": 30, "handshake_timeout": 1.5, "snub_timeout": 90 },
+                "theme": "dark"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.listen_port, 7000);
+        assert_eq!(config.connection_limits.per_torrent, 10);
+        assert_eq!(
+            config.connection_limits.global,
+            ConnectionLimits::default().global
+        );
+        assert_eq!(
+            config.connection_limits.min_peer_age,
+            Duration::from_secs(30)
+        );
+        assert_eq!(config.torrent.read_timeout, Duration::from_secs(30));
+        assert_eq!(config.torrent.snub_timeout, Duration::from_secs(90));
+        assert_eq!(
+            config.torrent.request_timeout,
+            TorrentConfig::default().request_timeout
+        );
+        assert_eq!(
+            config.torrent.handshake_timeout,
+            Duration::from_millis(1500)
+        );
+        assert_eq!(config.torrent.block_size, utils::BLOCK_SIZE);
+        assert!(config.enable_dht);
+        assert!(config.enable_lsd);
+    }
+
+    #[test]
+    fn settings_roundtrip() {
+        let config = SessionConfig {
+            upload_limit: Some(1024),
+            ..Default::default()
+        };
+        let json = serde_json::to_vec(&config).unwrap();
+        assert_eq!(SessionConfig::from_json(&json).unwrap(), config);
+    }
+
+    #[test]
+    fn proxy_settings() {
+        let config = SessionConfig::from_json(
+            br#"{ "proxy": { "kind": "socks5", "addr": "10.0.0.1:1080", "proxy_only": true } }"#,
+        )
+        .unwrap();
+        let proxy = config.proxy.unwrap();
+        assert_eq!(proxy.kind, crate::proxy::ProxyKind::Socks5);
+        assert_eq!(proxy.username, None);
+        assert!(proxy.proxy_only);
+    }
+
+    #[test]
+    fn bind_settings() {
+        let config = SessionConfig::from_json(br#"{ "bind": { "interface": "tun0" } }"#).unwrap();
+        assert_eq!(config.bind, Some(BindTo::Interface("tun0".to_string())));
+        let config = SessionConfig::from_json(br#"{ "bind": { "address": "10.8.0.2" } }"#).unwrap();
+        assert_eq!(
+            config.bind,
+            Some(BindTo::Address("10.8.0.2".parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn client_filter_settings() {
+        let config = SessionConfig::from_json(
+            br#"{ "torrent": { "client_filter": { "deny": ["Xunlei"] } } }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.torrent.client_filter.deny,
+            vec!["Xunlei".to_string()]
+        );
+        assert!(config.torrent.client_filter.allow.is_empty());
+    }
+
+    #[test]
+    fn invalid_settings() {
+        assert!(SessionConfig::from_json(br#"{ "torrent": { "read_timeout": -1 } }"#).is_err());
+        assert!(SessionConfig::from_json(br#"{ "listen_port": "http" }"#).is_err());
+        assert!(SessionConfig::from_json(br#"{ "torrent": { "block_size": 0 } }"#).is_err());
+        assert!(
+            SessionConfig::from_json(br#"{ "torrent": { "max_outstanding_requests": 0 } }"#)
+                .is_err()
+        );
+        assert!(SessionConfig::from_json(br#"{ "torrent": { "handshake_timeout": 0 } }"#).is_err());
+    }
+
+    #[test]
+    fn invalid_torrent_config() {
+        let mut config = TorrentConfig {
+            block_size: 0,
+            ..Default::default()
+        };
+        assert!(matches!(config.validate(), Err(ConfigError::Torrent(_))));
+        let mut config = TorrentConfig {
+            max_outstanding_requests: 0,
+            ..Default::default()
+        };
+        assert!(matches!(config.validate(), Err(ConfigError::Torrent(_))));
+        let mut config = TorrentConfig {
+            keep_alive_interval: Duration::ZERO,
+            ..Default::default()
+        };
+        assert!(matches!(config.validate(), Err(ConfigError::Torrent(_))));
+        let mut config = TorrentConfig {
+            write_timeout: Duration::ZERO,
+            ..Default::default()
+        };
+        assert!(matches!(config.validate(), Err(ConfigError::Torrent(_))));
+        assert!(TorrentConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn block_size_is_capped() {
+        let config =
+            SessionConfig::from_json(br#"{ "torrent": { "block_size": 1048576 } }"#).unwrap();
+        assert_eq!(config.torrent.block_size, utils::BLOCK_SIZE);
+        let config = SessionConfig::from_json(br#"{ "torrent": { "block_size": 4096 } }"#).unwrap();
+        assert_eq!(config.torrent.block_size, 4096);
+    }
+
+    #[test]
+    fn missing_settings_file() {
+        let path = std::env::temp_dir().join("bit_rev_no_such_settings.json");
+        assert_eq!(SessionConfig::load(path).unwrap(), SessionConfig::default());
+    }
+}
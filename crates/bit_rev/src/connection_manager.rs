@@ -0,0 +1,879 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use dashmap::{DashMap, DashSet};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+use crate::{
+    ban::PeerBans, config::secs, ip_filter::IpFilter, peer::PeerAddr, peer_connection::PeerHandler,
+};
+
+/// Connection attempts to an address before we stop retrying it.
+const MAX_CONNECT_FAILURES: u32 = 6;
+/// Attempts per address family after which older outcomes count half, so
+/// we notice when a family starts or stops working.
+const FAMILY_WINDOW: u32 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConnectionLimits {
+    /// Connections across every torrent.
+    pub global: usize,
+    /// Connections for a single torrent.
+    pub per_torrent: usize,
+    /// Outgoing connections that haven't completed the handshake yet.
+    pub half_open: usize,
+    /// Delay before the first retry of a failed address, doubled on every failure.
+    #[serde(with = "secs")]
+    pub retry_base_delay: Duration,
+    #[serde(with = "secs")]
+    pub retry_max_delay: Duration,
+    /// Candidates we keep queued per torrent, the lowest priority ones are dropped first.
+    pub max_candidates: usize,
+    /// How long a peer gets to prove itself before it can be replaced.
+    #[serde(with = "secs")]
+    pub min_peer_age: Duration,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            global: 200,
+            per_torrent: 50,
+            half_open: 20,
+            retry_base_delay: Duration::from_secs(30),
+            retry_max_delay: Duration::from_secs(30 * 60),
+            max_candidates: 1000,
+            min_peer_age: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Backoff {
+    failures: u32,
+    retry_at: Instant,
+}
+
+/// How our outgoing connections to one address family went.
+#[derive(Debug, Clone, Copy, Default)]
+struct FamilyOutcomes {
+    attempts: u32,
+    connected: u32,
+}
+
+impl FamilyOutcomes {
+    fn record(&mut self, connected: bool) {
+        if self.attempts >= FAMILY_WINDOW {
+            self.attempts /= 2;
+            self.connected /= 2;
+        }
+        self.attempts += 1;
+        self.connected += connected as u32;
+    }
+
+    // Starts from an even guess, so a single failure doesn't rule a family
+    // out.
+    fn success_rate(&self) -> f64 {
+        (self.connected as f64 + 1.0) / (self.attempts as f64 + 2.0)
+    }
+}
+
+/// Connection slots and retry bookkeeping shared by every torrent.
+#[derive(Debug)]
+pub struct ConnectionManager {
+    limits: ConnectionLimits,
+    global_slots: Arc<Semaphore>,
+    half_open_slots: Arc<Semaphore>,
+    backoff: DashMap<PeerAddr, Backoff>,
+    external_ip: RwLock<Option<IpAddr>>,
+    /// IPv4, then IPv6.
+    families: Mutex<[FamilyOutcomes; 2]>,
+    ip_filter: RwLock<Arc<IpFilter>>,
+    /// Addresses the IP filter turned away.
+    blocked: DashSet<IpAddr>,
+}
+
+impl Default for ConnectionManager {
+    fn default() -> Self {
+        Self::new(ConnectionLimits::default())
+    }
+}
+
+impl ConnectionManager {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            limits,
+            global_slots: Arc::new(Semaphore::new(limits.global)),
+            half_open_slots: Arc::new(Semaphore::new(limits.half_open)),
+            backoff: DashMap::new(),
+            external_ip: RwLock::new(None),
+            families: Mutex::default(),
+            ip_filter: RwLock::default(),
+            blocked: DashSet::new(),
+        }
+    }
+
+    pub fn limits(&self) -> ConnectionLimits {
+        self.limits
+    }
+
+    /// Our address as seen by others, used to rank peers (BEP 40).
+    pub fn set_external_ip(&self, ip: IpAddr) {
+        *self.external_ip.write().unwrap() = Some(ip);
+    }
+
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        *self.external_ip.read().unwrap()
+    }
+
+    /// Replaces the address ranges we refuse peers from. Peers already
+    /// connected stay until [`TorrentConnections::disconnect_blocked`].
+    pub fn set_ip_filter(&self, filter: IpFilter) {
+        *self.ip_filter.write().unwrap() = Arc::new(filter);
+    }
+
+    pub fn ip_filter(&self) -> Arc<IpFilter> {
+        self.ip_filter.read().unwrap().clone()
+    }
+
+    /// Whether the IP filter turns `peer` away, remembering its address if so.
+    pub fn block(&self, peer: &PeerAddr) -> bool {
+        let blocked = self.ip_filter.read().unwrap().is_blocked(peer.ip());
+        if blocked {
+            self.blocked.insert(peer.ip());
+        }
+        blocked
+    }
+
+    /// Distinct IP addresses the IP filter turned away, across every torrent
+    /// and incoming connections. Seeing the same address again doesn't count.
+    pub fn blocked_peers(&self) -> u64 {
+        self.blocked.len() as u64
+    }
+
+    fn priority(&self, peer: &PeerAddr) -> u32 {
+        let ip = self
+            .external_ip()
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        canonical_peer_priority((ip, 0).into(), *peer)
+    }
+
+    /// Records a failed connection attempt, returns when the address may be
+    /// retried, or `None` if we gave up on it.
+    fn on_connect_failed(&self, peer: PeerAddr) -> Option<Instant> {
+        let mut backoff = self.backoff.entry(peer).or_insert(Backoff {
+            failures: 0,
+            retry_at: Instant::now(),
+        });
+        backoff.failures += 1;
+        if backoff.failures >= MAX_CONNECT_FAILURES {
+            return None;
+        }
+        backoff.retry_at = Instant::now() + retry_delay(&self.limits, backoff.failures);
+        Some(backoff.retry_at)
+    }
+
+    fn on_connected(&self, peer: PeerAddr) {
+        self.backoff.remove(&peer);
+    }
+
+    // Records how an outgoing connection went for its address family.
+    fn on_attempt(&self, peer: &PeerAddr, connected: bool) {
+        self.families.lock().unwrap()[peer.is_ipv6() as usize].record(connected);
+    }
+
+    /// Whether IPv6 (`true`) or IPv4 peers connect more reliably, `None`
+    /// until one does. Candidates of that family are tried first.
+    pub fn prefers_ipv6(&self) -> Option<bool> {
+        let [ipv4, ipv6] = *self.families.lock().unwrap();
+        match ipv6.success_rate().total_cmp(&ipv4.success_rate()) {
+            std::cmp::Ordering::Greater => Some(true),
+            std::cmp::Ordering::Less => Some(false),
+            std::cmp::Ordering::Equal => None,
+        }
+    }
+
+    fn retry_at(&self, peer: &PeerAddr) -> Option<Instant> {
+        self.backoff.get(peer).map(|b| b.retry_at)
+    }
+
+    fn gave_up_on(&self, peer: &PeerAddr) -> bool {
+        self.backoff
+            .get(peer)
+            .is_some_and(|b| b.failures >= MAX_CONNECT_FAILURES)
+    }
+}
+
+fn retry_delay(limits: &ConnectionLimits, failures: u32) -> Duration {
+    limits
+        .retry_base_delay
+        .saturating_mul(1 << (failures - 1).min(16))
+        .min(limits.retry_max_delay)
+}
+
+/// Where we heard of a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    /// Local Service Discovery, the peer is on our network.
+    Lan,
+    /// The peer connected to us.
+    Incoming,
+    /// Added by hand through `add_candidates`.
+    Manual,
+    /// Another peer told us about it through peer exchange.
+    Pex,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    peer: PeerAddr,
+    priority: u32,
+    retry_at: Option<Instant>,
+    source: PeerSource,
+}
+
+struct ActivePeer {
+    source: PeerSource,
+    cancel: CancellationToken,
+    handler: Option<Arc<PeerHandler>>,
+    connected_at: Option<Instant>,
+}
+
+/// The peers of one torrent: those waiting to be connected and those connected.
+pub struct TorrentConnections {
+    manager: Arc<ConnectionManager>,
+    torrent_slots: Arc<Semaphore>,
+    candidates: Mutex<Vec<Candidate>>,
+    candidate_notify: Notify,
+    active: DashMap<PeerAddr, ActivePeer>,
+    blocked: DashSet<IpAddr>,
+    private: bool,
+    peer_bans: Arc<PeerBans>,
+}
+
+impl std::fmt::Debug for TorrentConnections {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TorrentConnections")
+            .field("candidates", &self.candidates_len())
+            .field("connected", &self.connected_len())
+            .finish()
+    }
+}
+
+impl TorrentConnections {
+    pub fn new(manager: Arc<ConnectionManager>) -> Self {
+        Self {
+            torrent_slots: Arc::new(Semaphore::new(manager.limits().per_torrent)),
+            manager,
+            candidates: Mutex::new(vec![]),
+            candidate_notify: Notify::new(),
+            active: DashMap::new(),
+            blocked: DashSet::new(),
+            private: false,
+            peer_bans: Arc::default(),
+        }
+    }
+
+    /// Connections of a private torrent, which only takes peers from its
+    /// trackers, BEP 27. Peers from the DHT, LSD and PEX are ignored.
+    pub fn private(mut self) -> Self {
+        self.private = true;
+        self
+    }
+
+    /// Keeps the peers `peer_bans` banned out, wherever we hear of them.
+    pub fn with_bans(mut self, peer_bans: Arc<PeerBans>) -> Self {
+        self.peer_bans = peer_bans;
+        self
+    }
+
+    pub fn manager(&self) -> &Arc<ConnectionManager> {
+        &self.manager
+    }
+
+    /// Queues peers we heard about, skipping the ones we are already connected
+    /// to, already know about, gave up on, banned, or that the IP filter blocks.
+    pub fn add_candidates(&self, peers: impl IntoIterator<Item = PeerAddr>) {
+        self.add_candidates_from(PeerSource::Manual, peers);
+    }
+
+    /// Like `add_candidates`, tagging the peers with where they come from.
+    pub fn add_candidates_from(
+        &self,
+        source: PeerSource,
+        peers: impl IntoIterator<Item = PeerAddr>,
+    ) {
+        if self.private && matches!(source, PeerSource::Dht | PeerSource::Lan | PeerSource::Pex) {
+            debug!("private torrent, ignoring peers from {:?}", source);
+            return;
+        }
+        let mut candidates = self.candidates.lock().unwrap();
+        for peer in peers {
+            if self.manager.block(&peer) {
+                self.blocked.insert(peer.ip());
+                continue;
+            }
+            if self.peer_bans.is_banned(&peer) {
+                debug!("skipping banned peer {}", peer);
+                continue;
+            }
+            if self.active.contains_key(&peer)
+                || self.manager.gave_up_on(&peer)
+                || candidates.iter().any(|c| c.peer == peer)
+            {
+                continue;
+            }
+            candidates.push(Candidate {
+                peer,
+                priority: self.manager.priority(&peer),
+                retry_at: self.manager.retry_at(&peer),
+                source,
+            });
+        }
+
+        let max_candidates = self.manager.limits.max_candidates;
+        if candidates.len() > max_candidates {
+            candidates.sort_by_key(|c| std::cmp::Reverse(c.priority));
+            candidates.truncate(max_candidates);
+        }
+        drop(candidates);
+
+        self.candidate_notify.notify_one();
+    }
+
+    /// Distinct IP addresses of this torrent's peers the IP filter turned away.
+    pub fn blocked_peers(&self) -> u64 {
+        self.blocked.len() as u64
+    }
+
+    pub fn candidates_len(&self) -> usize {
+        self.candidates.lock().unwrap().len()
+    }
+
+    pub fn connected_len(&self) -> usize {
+        self.active.len()
+    }
+
+    pub fn is_connected(&self, peer: &PeerAddr) -> bool {
+        self.active.contains_key(peer)
+    }
+
+    /// Connected peers that we dialed, so they listen on their address.
+    pub fn outgoing_peers(&self) -> Vec<PeerAddr> {
+        self.active
+            .iter()
+            .filter(|a| a.connected_at.is_some() && a.source != PeerSource::Incoming)
+            .map(|a| *a.key())
+            .collect()
+    }
+
+    /// Handlers of the peers we finished the handshake with.
+    pub fn handlers(&self) -> Vec<Arc<PeerHandler>> {
+        self.active
+            .iter()
+            .filter_map(|a| a.handler.clone())
+            .collect()
+    }
+
+    /// Where we heard of a connected peer.
+    pub fn source(&self, peer: &PeerAddr) -> Option<PeerSource> {
+        self.active.get(peer).map(|active| active.source)
+    }
+
+    fn next_candidate_at(&self) -> Option<Instant> {
+        let now = Instant::now();
+        self.candidates
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|c| c.retry_at.unwrap_or(now))
+            .min()
+    }
+
+    fn pop_candidate(&self) -> Option<Candidate> {
+        let now = Instant::now();
+        let prefers_ipv6 = self.manager.prefers_ipv6();
+        let mut candidates = self.candidates.lock().unwrap();
+        let (i, _) = candidates
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.retry_at.is_none_or(|at| at <= now))
+            .max_by_key(|(_, c)| (prefers_ipv6 == Some(c.peer.is_ipv6()), c.priority))?;
+        Some(candidates.swap_remove(i))
+    }
+
+    /// Waits until a candidate is ready and every limit has room for it, then
+    /// hands out the peer to connect to along with its slot.
+    pub async fn next_connection(self: &Arc<Self>) -> (PeerAddr, ConnectionSlot) {
+        loop {
+            // Wait for a candidate before taking any slot, so an idle torrent
+            // doesn't hold on to global slots.
+            loop {
+                let notified = self.candidate_notify.notified();
+                match self.next_candidate_at() {
+                    Some(at) if at <= Instant::now() => break,
+                    Some(at) => {
+                        let _ =
+                            tokio::time::timeout_at(tokio::time::Instant::from_std(at), notified)
+                                .await;
+                    }
+                    None => notified.await,
+                }
+            }
+
+            let torrent = self.torrent_slots.clone().acquire_owned().await.unwrap();
+            let global = self
+                .manager
+                .global_slots
+                .clone()
+                .acquire_owned()
+                .await
+                .unwrap();
+            let half_open = self
+                .manager
+                .half_open_slots
+                .clone()
+                .acquire_owned()
+                .await
+                .unwrap();
+
+            let Some(candidate) = self.pop_candidate() else {
+                continue;
+            };
+
+            let cancel = CancellationToken::new();
+            self.active.insert(
+                candidate.peer,
+                ActivePeer {
+                    source: candidate.source,
+                    cancel: cancel.clone(),
+                    handler: None,
+                    connected_at: None,
+                },
+            );
+
+            return (
+                candidate.peer,
+                ConnectionSlot {
+                    peer: candidate.peer,
+                    source: candidate.source,
+                    connections: self.clone(),
+                    cancel,
+                    connected: false,
+                    incoming: false,
+                    _torrent: torrent,
+                    _global: global,
+                    half_open: Some(half_open),
+                },
+            );
+        }
+    }
+
+    /// Takes a slot for a peer that connected to us. Incoming connections are
+    /// already open so they skip the half-open limit, but are turned away when
+    /// the torrent or global limits are reached.
+    pub fn try_accept(self: &Arc<Self>, peer: PeerAddr) -> Option<ConnectionSlot> {
+        if self.active.contains_key(&peer) {
+            return None;
+        }
+        let torrent = self.torrent_slots.clone().try_acquire_owned().ok()?;
+        let global = self.manager.global_slots.clone().try_acquire_owned().ok()?;
+
+        let cancel = CancellationToken::new();
+        self.active.insert(
+            peer,
+            ActivePeer {
+                source: PeerSource::Incoming,
+                cancel: cancel.clone(),
+                handler: None,
+                connected_at: None,
+            },
+        );
+        self.candidates.lock().unwrap().retain(|c| c.peer != peer);
+
+        Some(ConnectionSlot {
+            peer,
+            source: PeerSource::Incoming,
+            connections: self.clone(),
+            cancel,
+            connected: false,
+            incoming: true,
+            _torrent: torrent,
+            _global: global,
+            half_open: None,
+        })
+    }
+
+    /// When we are out of slots and still have candidates, disconnects the
+    /// peer that had enough time to prove itself and exchanges the least with
+    /// us, in both directions so seeding doesn't drop whoever we upload to.
+    /// Returns the peer that was dropped.
+    pub fn replace_worst_peer(&self) -> Option<PeerAddr> {
+        let limits_reached = self.torrent_slots.available_permits() == 0
+            || self.manager.global_slots.available_permits() == 0;
+        let candidate_ready = self
+            .next_candidate_at()
+            .is_some_and(|at| at <= Instant::now());
+        if !limits_reached || !candidate_ready {
+            return None;
+        }
+
+        let min_peer_age = self.manager.limits.min_peer_age;
+        let worst = self
+            .active
+            .iter()
+            .filter(|p| {
+                p.connected_at
+                    .is_some_and(|at| at.elapsed() >= min_peer_age)
+            })
+            .filter_map(|p| {
+                let transfer = p.handler.as_ref()?.stats().snapshot();
+                Some((*p.key(), transfer.download_rate + transfer.upload_rate))
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(peer, _)| peer)?;
+
+        debug!("replacing slowest peer {}", worst);
+        self.disconnect(&worst);
+        Some(worst)
+    }
+
+    pub fn disconnect(&self, peer: &PeerAddr) {
+        if let Some(active) = self.active.get(peer) {
+            active.cancel.cancel();
+        }
+    }
+
+    /// Drops the candidates and disconnects the peers the IP filter blocks
+    /// now, e.g. after it was reloaded.
+    pub fn disconnect_blocked(&self) {
+        let filter = self.manager.ip_filter();
+        self.candidates
+            .lock()
+            .unwrap()
+            .retain(|c| !filter.is_blocked(c.peer.ip()));
+        for active in self.active.iter() {
+            if filter.is_blocked(active.key().ip()) {
+                debug!("disconnecting blocked peer {}", active.key());
+                active.cancel.cancel();
+            }
+        }
+    }
+
+    /// Disconnects every peer of the torrent.
+    pub fn disconnect_all(&self) {
+        for active in self.active.iter() {
+            active.cancel.cancel();
+        }
+    }
+}
+
+/// Holds the connection limits taken by one peer, handing them back when dropped.
+pub struct ConnectionSlot {
+    peer: PeerAddr,
+    source: PeerSource,
+    connections: Arc<TorrentConnections>,
+    cancel: CancellationToken,
+    connected: bool,
+    /// The peer connected to us, its address is not one we can connect back to.
+    incoming: bool,
+    _torrent: OwnedSemaphorePermit,
+    _global: OwnedSemaphorePermit,
+    half_open: Option<OwnedSemaphorePermit>,
+}
+
+impl ConnectionSlot {
+    /// Cancelled when the connection manager wants this peer gone.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// The handshake went through, the connection is no longer half-open.
+    pub fn on_connected(&mut self, handler: Arc<PeerHandler>) {
+        self.half_open.take();
+        self.connected = true;
+        self.connections.manager.on_connected(self.peer);
+        if !self.incoming {
+            self.connections.manager.on_attempt(&self.peer, true);
+        }
+        if let Some(mut active) = self.connections.active.get_mut(&self.peer) {
+            active.handler = Some(handler);
+            active.connected_at = Some(Instant::now());
+        }
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.connections.active.remove(&self.peer);
+        if self.connected || self.incoming {
+            return;
+        }
+
+        // Never got through the handshake, try again later.
+        self.connections.manager.on_attempt(&self.peer, false);
+        if let Some(retry_at) = self.connections.manager.on_connect_failed(self.peer) {
+            let mut candidates = self.connections.candidates.lock().unwrap();
+            candidates.push(Candidate {
+                peer: self.peer,
+                priority: self.connections.manager.priority(&self.peer),
+                retry_at: Some(retry_at),
+                source: self.source,
+            });
+            drop(candidates);
+            self.connections.candidate_notify.notify_one();
+        }
+    }
+}
+
+/// BEP 40 canonical peer priority between us and a peer. Peers with a higher
+/// priority are connected to first.
+pub fn canonical_peer_priority(us: PeerAddr, peer: PeerAddr) -> u32 {
+    if us.ip() == peer.ip() {
+        let (a, b) = ordered(us.port(), peer.port());
+        let mut buf = a.to_be_bytes().to_vec();
+        buf.extend(b.to_be_bytes());
+        return crc32c(&buf);
+    }
+
+    let (a, b) = match (us.ip(), peer.ip()) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => {
+            let (a, b) = (a.octets(), b.octets());
+            let mask: [u8; 4] = if a[..3] == b[..3] {
+                [0xff, 0xff, 0xff, 0xff]
+            } else if a[..2] == b[..2] {
+                [0xff, 0xff, 0xff, 0x55]
+            } else {
+                [0xff, 0xff, 0x55, 0x55]
+            };
+            (apply_mask(&a, &mask), apply_mask(&b, &mask))
+        }
+        (a, b) => {
+            let (a, b) = (ipv6_octets(a), ipv6_octets(b));
+            let mut mask = [0x55u8; 16];
+            let prefix = if a[..6] == b[..6] { 7 } else { 6 };
+            mask[..prefix].fill(0xff);
+            (apply_mask(&a, &mask), apply_mask(&b, &mask))
+        }
+    };
+
+    let (a, b) = ordered(a, b);
+    crc32c(&[a, b].concat())
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+fn apply_mask(ip: &[u8], mask: &[u8]) -> Vec<u8> {
+    ip.iter().zip(mask).map(|(b, m)| b & m).collect()
+}
+
+fn ordered<T: Ord>(a: T, b: T) -> (T, T) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+/// CRC-32C (Castagnoli), as required by BEP 40.
+fn crc32c(buf: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in buf {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn canonical_peer_priority_examples() {
+        // Examples from BEP 40.
+        let us: PeerAddr = "123.213.32.10:0".parse().unwrap();
+        assert_eq!(
+            canonical_peer_priority(us, "98.76.54.32:0".parse().unwrap()),
+            0xec2d_7224
+        );
+        assert_eq!(
+            canonical_peer_priority(us, "123.213.32.234:0".parse().unwrap()),
+            0x9956_8189
+        );
+    }
+
+    #[test]
+    fn canonical_peer_priority_is_symmetric() {
+        let a: PeerAddr = "10.1.2.3:6881".parse().unwrap();
+        let b: PeerAddr = "192.168.7.9:51413".parse().unwrap();
+        assert_eq!(canonical_peer_priority(a, b), canonical_peer_priority(b, a));
+    }
+
+    #[test]
+    fn retry_delay_grows_and_is_capped() {
+        let limits = ConnectionLimits::default();
+        assert_eq!(retry_delay(&limits, 1), limits.retry_base_delay);
+        assert_eq!(retry_delay(&limits, 2), limits.retry_base_delay * 2);
+        assert_eq!(retry_delay(&limits, 40), limits.retry_max_delay);
+    }
+
+    #[tokio::test]
+    async fn limits_and_backoff() {
+        let manager = Arc::new(ConnectionManager::new(ConnectionLimits {
+            global: 10,
+            per_torrent: 1,
+            half_open: 10,
+            ..Default::default()
+        }));
+        let connections = Arc::new(TorrentConnections::new(manager.clone()));
+        let a: PeerAddr = "10.0.0.1:6881".parse().unwrap();
+        let b: PeerAddr = "10.0.0.2:6881".parse().unwrap();
+        connections.add_candidates([a, b, a]);
+        assert_eq!(connections.candidates_len(), 2);
+
+        let (first, slot) = connections.next_connection().await;
+        assert!(connections.is_connected(&first));
+
+        // The torrent is at its limit, the second peer has to wait.
+        let next =
+            tokio::time::timeout(Duration::from_millis(50), connections.next_connection()).await;
+        assert!(next.is_err());
+
+        // A failed attempt frees the slot and puts the peer back with a delay.
+        drop(slot);
+        assert!(!connections.is_connected(&first));
+        assert_eq!(connections.candidates_len(), 2);
+        assert!(manager.retry_at(&first).unwrap() > Instant::now());
+
+        let (second, _slot) = connections.next_connection().await;
+        assert_ne!(first, second);
+    }
+
+    #[tokio::test]
+    async fn the_family_that_works_goes_first() {
+        let manager = Arc::new(ConnectionManager::default());
+        let connections = Arc::new(TorrentConnections::new(manager.clone()));
+        let ipv4: PeerAddr = "10.0.0.1:6881".parse().unwrap();
+        let ipv6: PeerAddr = "[2001:db8::1]:6881".parse().unwrap();
+        assert_eq!(manager.prefers_ipv6(), None);
+
+        for _ in 0..3 {
+            manager.on_attempt(&ipv4, false);
+            manager.on_attempt(&ipv6, true);
+        }
+        assert_eq!(manager.prefers_ipv6(), Some(true));
+        connections.add_candidates([ipv4, ipv6]);
+        assert_eq!(connections.pop_candidate().unwrap().peer, ipv6);
+
+        // IPv6 breaks down.
+        for _ in 0..20 {
+            manager.on_attempt(&ipv6, false);
+        }
+        assert_eq!(manager.prefers_ipv6(), Some(false));
+        connections.add_candidates([ipv6]);
+        assert_eq!(connections.pop_candidate().unwrap().peer, ipv4);
+    }
+
+    #[tokio::test]
+    async fn the_ip_filter_turns_peers_away() {
+        let manager = Arc::new(ConnectionManager::default());
+        let connections = Arc::new(TorrentConnections::new(manager.clone()));
+        let blocked: PeerAddr = "10.0.0.1:6881".parse().unwrap();
+        let allowed: PeerAddr = "10.1.0.1:6881".parse().unwrap();
+        let later: PeerAddr = "10.2.0.1:6881".parse().unwrap();
+        manager.set_ip_filter(IpFilter::parse("10.0.0.0/16\n"));
+
+        connections.add_candidates([blocked, allowed, later]);
+        assert_eq!(connections.candidates_len(), 2);
+        assert_eq!(connections.blocked_peers(), 1);
+        // The same address seen again, from another port too, counts once.
+        connections.add_candidates([blocked, "10.0.0.1:6882".parse().unwrap()]);
+        assert_eq!(connections.blocked_peers(), 1);
+
+        let slot = connections.try_accept(allowed).unwrap();
+
+        // Reloading drops what the new filter blocks.
+        manager.set_ip_filter(IpFilter::parse("10.1.0.0/16\n10.2.0.0/16\n"));
+        connections.disconnect_blocked();
+        assert!(slot.cancellation_token().is_cancelled());
+        assert_eq!(connections.candidates_len(), 0);
+        assert_eq!(manager.blocked_peers(), 1);
+    }
+
+    #[test]
+    fn private_torrents_only_take_tracker_peers() {
+        let connections = TorrentConnections::new(Arc::default()).private();
+        let peer: PeerAddr = "10.0.0.1:6881".parse().unwrap();
+
+        for source in [PeerSource::Dht, PeerSource::Lan, PeerSource::Pex] {
+            connections.add_candidates_from(source, [peer]);
+        }
+        assert_eq!(connections.candidates_len(), 0);
+
+        connections.add_candidates_from(PeerSource::Tracker, [peer]);
+        assert_eq!(connections.candidates_len(), 1);
+    }
+
+    #[test]
+    fn banned_peers_are_skipped() {
+        let peer_bans = Arc::new(PeerBans::default());
+        let connections = TorrentConnections::new(Arc::default()).with_bans(peer_bans.clone());
+        let banned: PeerAddr = "10.0.0.1:6881".parse().unwrap();
+        let other: PeerAddr = "10.0.0.2:6881".parse().unwrap();
+        peer_bans.ban(&banned);
+
+        for source in [PeerSource::Tracker, PeerSource::Dht, PeerSource::Pex] {
+            connections.add_candidates_from(source, [banned, other]);
+        }
+        // Another port of a banned IP is still out.
+        connections.add_candidates(["10.0.0.1:6882".parse().unwrap()]);
+        assert_eq!(connections.candidates_len(), 1);
+        assert_eq!(connections.pop_candidate().unwrap().peer, other);
+    }
+
+    #[tokio::test]
+    async fn peers_keep_their_source() {
+        let connections = Arc::new(TorrentConnections::new(Arc::default()));
+        let lan: PeerAddr = "192.168.1.2:6881".parse().unwrap();
+        let incoming: PeerAddr = "10.0.0.1:6881".parse().unwrap();
+        connections.add_candidates_from(PeerSource::Lan, [lan]);
+
+        let (peer, slot) = connections.next_connection().await;
+        assert_eq!(peer, lan);
+        assert_eq!(connections.source(&lan), Some(PeerSource::Lan));
+        let _incoming = connections.try_accept(incoming).unwrap();
+        assert_eq!(connections.source(&incoming), Some(PeerSource::Incoming));
+
+        // Retries are still tagged.
+        drop(slot);
+        let candidates = connections.candidates.lock().unwrap();
+        assert_eq!(candidates[0].peer, lan);
+        assert_eq!(candidates[0].source, PeerSource::Lan);
+    }
+}
@@ -0,0 +1,859 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use rand::Rng;
+use serde_bencode::value::Value;
+use tokio::{net::UdpSocket, sync::oneshot, task::JoinSet};
+use tracing::{debug, trace};
+
+use crate::peer::PeerAddr;
+
+pub const BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Bucket size and number of closest nodes a lookup converges on.
+const K: usize = 8;
+/// Queries a lookup keeps in flight.
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Peers we keep per info hash from `announce_peer` queries.
+const MAX_STORED_PEERS: usize = 100;
+/// Info hashes we keep announced peers for.
+const MAX_STORED_TORRENTS: usize = 2000;
+/// How long an announced peer is kept unless it announces again.
+const STORED_PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// Queries in a row a node may leave unanswered before we drop it.
+const MAX_NODE_FAILURES: u32 = 3;
+/// How often the secret behind our tokens changes. Tokens of the previous
+/// secret are still accepted, so a token is good for 5 to 10 minutes.
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+pub type NodeId = [u8; 20];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut d = [0u8; 20];
+    for (i, byte) in d.iter_mut().enumerate() {
+        *byte = a[i] ^ b[i];
+    }
+    d
+}
+
+/// A simplified Kademlia routing table: one bucket per shared prefix length,
+/// new nodes are dropped when their bucket is full.
+#[derive(Debug)]
+struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Node>>,
+    /// Unanswered queries in a row, per node address.
+    failures: HashMap<SocketAddr, u32>,
+}
+
+impl RoutingTable {
+    fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: vec![vec![]; 160],
+            failures: HashMap::new(),
+        }
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> usize {
+        let d = distance(&self.id, id);
+        let leading_zeros = d
+            .iter()
+            .position(|&b| b != 0)
+            .map(|i| i * 8 + d[i].leading_zeros() as usize)
+            .unwrap_or(159);
+        leading_zeros.min(159)
+    }
+
+    fn insert(&mut self, node: Node) {
+        if node.id == self.id {
+            return;
+        }
+        let index = self.bucket_index(&node.id);
+        let bucket = &mut self.buckets[index];
+        if let Some(i) = bucket.iter().position(|n| n.id == node.id) {
+            bucket.remove(i);
+            bucket.push(node);
+        } else if bucket.len() < K {
+            bucket.push(node);
+        }
+    }
+
+    fn remove(&mut self, addr: &SocketAddr) {
+        for bucket in self.buckets.iter_mut() {
+            bucket.retain(|n| n.addr != *addr);
+        }
+        self.failures.remove(addr);
+    }
+
+    /// The node answered or queried us.
+    fn heard_from(&mut self, node: Node) {
+        self.failures.remove(&node.addr);
+        self.insert(node);
+    }
+
+    /// A query to `addr` went unanswered, the node is dropped after
+    /// `MAX_NODE_FAILURES` in a row.
+    fn on_timeout(&mut self, addr: &SocketAddr) {
+        if !self.buckets.iter().flatten().any(|n| n.addr == *addr) {
+            return;
+        }
+        let failures = self.failures.entry(*addr).or_default();
+        *failures += 1;
+        if *failures >= MAX_NODE_FAILURES {
+            self.remove(addr);
+        }
+    }
+
+    fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.buckets.iter().flatten().copied().collect();
+        nodes.sort_by_key(|n| distance(&n.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+}
+
+type Dict = HashMap<Vec<u8>, Value>;
+
+/// The secrets `get_peers` tokens are made from.
+#[derive(Debug)]
+struct TokenSecrets {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated_at: Instant,
+}
+
+impl TokenSecrets {
+    fn new(now: Instant) -> Self {
+        let mut rng = rand::thread_rng();
+        Self {
+            current: rng.gen(),
+            previous: rng.gen(),
+            rotated_at: now,
+        }
+    }
+
+    fn token(&mut self, ip: &IpAddr, now: Instant) -> Vec<u8> {
+        self.rotate(now);
+        token_with(&self.current, ip)
+    }
+
+    fn is_valid(&mut self, token: &[u8], ip: &IpAddr, now: Instant) -> bool {
+        self.rotate(now);
+        token == token_with(&self.current, ip) || token == token_with(&self.previous, ip)
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        if now.duration_since(self.rotated_at) < TOKEN_ROTATION {
+            return;
+        }
+        // Quiet for more than two rotations, the previous secret is too old.
+        self.previous = if now.duration_since(self.rotated_at) < TOKEN_ROTATION * 2 {
+            self.current
+        } else {
+            rand::thread_rng().gen()
+        };
+        self.current = rand::thread_rng().gen();
+        self.rotated_at = now;
+    }
+}
+
+fn token_with(secret: &[u8; 16], ip: &IpAddr) -> Vec<u8> {
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(secret);
+    hasher.update(ip.to_string().as_bytes());
+    hasher.digest().bytes()[..8].to_vec()
+}
+
+/// Peers that announced themselves to us, per info hash.
+#[derive(Debug, Default)]
+struct PeerStore {
+    torrents: HashMap<[u8; 20], Vec<(PeerAddr, Instant)>>,
+}
+
+impl PeerStore {
+    fn insert(&mut self, info_hash: [u8; 20], peer: PeerAddr, now: Instant) {
+        if !self.torrents.contains_key(&info_hash) && self.torrents.len() >= MAX_STORED_TORRENTS {
+            self.expire(now);
+            if self.torrents.len() >= MAX_STORED_TORRENTS {
+                return;
+            }
+        }
+        let peers = self.torrents.entry(info_hash).or_default();
+        peers.retain(|(p, at)| *p != peer && now.duration_since(*at) < STORED_PEER_TTL);
+        if peers.len() >= MAX_STORED_PEERS {
+            peers.remove(0);
+        }
+        peers.push((peer, now));
+    }
+
+    fn get(&self, info_hash: &[u8; 20], now: Instant) -> Vec<PeerAddr> {
+        self.torrents
+            .get(info_hash)
+            .into_iter()
+            .flatten()
+            .filter(|(_, at)| now.duration_since(*at) < STORED_PEER_TTL)
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.torrents.retain(|_, peers| {
+            peers.retain(|(_, at)| now.duration_since(*at) < STORED_PEER_TTL);
+            !peers.is_empty()
+        });
+    }
+}
+
+/// A mainline DHT (BEP 5) node. IPv6 nodes form their own DHT, which only
+/// differs in the size of the addresses it sends around, BEP 32.
+#[derive(Debug)]
+pub struct Dht {
+    id: NodeId,
+    socket: Arc<UdpSocket>,
+    ipv6: bool,
+    table: Mutex<RoutingTable>,
+    pending: DashMap<Vec<u8>, oneshot::Sender<Dict>>,
+    next_transaction: AtomicU16,
+    token_secrets: Mutex<TokenSecrets>,
+    stored_peers: Mutex<PeerStore>,
+}
+
+impl Dht {
+    /// Binds the node's socket and starts answering queries.
+    pub async fn bind(addr: SocketAddr) -> std::io::Result<Arc<Dht>> {
+        Ok(Dht::with_socket(UdpSocket::bind(addr).await?))
+    }
+
+    /// Starts a node answering queries on `socket`.
+    pub fn with_socket(socket: UdpSocket) -> Arc<Dht> {
+        let socket = Arc::new(socket);
+        let ipv6 = socket.local_addr().is_ok_and(|addr| addr.is_ipv6());
+        let mut rng = rand::thread_rng();
+        let id: NodeId = rng.gen();
+        let dht = Arc::new(Dht {
+            id,
+            socket: socket.clone(),
+            ipv6,
+            table: Mutex::new(RoutingTable::new(id)),
+            pending: DashMap::new(),
+            next_transaction: AtomicU16::new(rng.gen()),
+            token_secrets: Mutex::new(TokenSecrets::new(Instant::now())),
+            stored_peers: Mutex::new(PeerStore::default()),
+        });
+
+        // Only hold on to the node while handling a packet, so dropping the
+        // last handle stops the task.
+        let receiver = Arc::downgrade(&dht);
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 2048];
+            loop {
+                let received = socket.recv_from(&mut buf).await;
+                let Some(dht) = receiver.upgrade() else {
+                    break;
+                };
+                match received {
+                    Ok((len, from)) => dht.on_packet(&buf[..len], from).await,
+                    Err(e) => debug!("dht socket error: {:?}", e),
+                }
+            }
+        });
+
+        dht
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn nodes_len(&self) -> usize {
+        self.table.lock().unwrap().len()
+    }
+
+    pub fn add_node(&self, node: Node) {
+        self.table.lock().unwrap().insert(node);
+    }
+
+    /// Fills the routing table by looking ourselves up through the bootstrap nodes.
+    pub async fn bootstrap(self: &Arc<Self>, nodes: &[&str]) {
+        for node in nodes {
+            let Ok(addrs) = tokio::net::lookup_host(node).await else {
+                debug!("could not resolve dht bootstrap node {}", node);
+                continue;
+            };
+            for addr in addrs.filter(|a| a.is_ipv6() == self.ipv6) {
+                let args = vec![("target", Value::Bytes(self.id.to_vec()))];
+                if let Some(r) = self.query(addr, "find_node", args).await {
+                    self.on_response_nodes(&r);
+                }
+            }
+        }
+        self.lookup(self.id, false).await;
+    }
+
+    /// Looks up peers for `info_hash`.
+    pub async fn get_peers(self: &Arc<Self>, info_hash: [u8; 20]) -> Vec<PeerAddr> {
+        self.lookup(info_hash, true).await.peers
+    }
+
+    /// Looks up peers for `info_hash` and tells the closest nodes we are
+    /// downloading it on `port`.
+    pub async fn announce(self: &Arc<Self>, info_hash: [u8; 20], port: u16) -> Vec<PeerAddr> {
+        let lookup = self.lookup(info_hash, true).await;
+
+        let mut announces = JoinSet::new();
+        for (node, token) in lookup.tokens.into_iter().take(K) {
+            let dht = self.clone();
+            announces.spawn(async move {
+                let args = vec![
+                    ("info_hash", Value::Bytes(info_hash.to_vec())),
+                    ("port", Value::Int(port as i64)),
+                    ("token", Value::Bytes(token)),
+                ];
+                dht.query(node.addr, "announce_peer", args).await
+            });
+        }
+        while announces.join_next().await.is_some() {}
+
+        lookup.peers
+    }
+
+    /// Whether this node is part of the IPv6 DHT.
+    pub fn is_ipv6(&self) -> bool {
+        self.ipv6
+    }
+
+    // Where the nodes of our address family go in replies.
+    fn nodes_key(&self) -> &'static [u8] {
+        if self.ipv6 {
+            b"nodes6"
+        } else {
+            b"nodes"
+        }
+    }
+
+    async fn lookup(self: &Arc<Self>, target: [u8; 20], want_peers: bool) -> Lookup {
+        let mut shortlist = self.table.lock().unwrap().closest(&target, K);
+        let mut queried = HashSet::new();
+        let mut result = Lookup::default();
+
+        loop {
+            let batch: Vec<Node> = shortlist
+                .iter()
+                .filter(|n| !queried.contains(&n.addr))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let mut queries = JoinSet::new();
+            for node in batch {
+                queried.insert(node.addr);
+                let dht = self.clone();
+                queries.spawn(async move {
+                    let (q, key) = if want_peers {
+                        ("get_peers", "info_hash")
+                    } else {
+                        ("find_node", "target")
+                    };
+                    let args = vec![(key, Value::Bytes(target.to_vec()))];
+                    (node, dht.query(node.addr, q, args).await)
+                });
+            }
+
+            while let Some(Ok((node, response))) = queries.join_next().await {
+                let Some(r) = response else {
+                    continue;
+                };
+                for found in self.on_response_nodes(&r) {
+                    if !shortlist.iter().any(|n| n.addr == found.addr) {
+                        shortlist.push(found);
+                    }
+                }
+                if let Some(Value::List(values)) = r.get(b"values".as_ref()) {
+                    for value in values {
+                        if let Value::Bytes(b) = value {
+                            result.peers.extend(decode_compact_peers(b, self.ipv6));
+                        }
+                    }
+                }
+                if let Some(Value::Bytes(token)) = r.get(b"token".as_ref()) {
+                    result.tokens.push((node, token.clone()));
+                }
+            }
+
+            shortlist.sort_by_key(|n| distance(&n.id, &target));
+            shortlist.truncate(K);
+        }
+
+        result.tokens.sort_by_key(|(n, _)| distance(&n.id, &target));
+        result.peers.sort();
+        result.peers.dedup();
+        result
+    }
+
+    /// Adds the nodes of a response to the routing table and returns them.
+    fn on_response_nodes(&self, r: &Dict) -> Vec<Node> {
+        let mut nodes = vec![];
+        if let Some(Value::Bytes(compact)) = r.get(self.nodes_key()) {
+            nodes.extend(decode_compact_nodes(compact, self.ipv6));
+        }
+        let mut table = self.table.lock().unwrap();
+        for node in nodes.iter() {
+            table.insert(*node);
+        }
+        nodes
+    }
+
+    async fn query(&self, addr: SocketAddr, q: &str, args: Vec<(&str, Value)>) -> Option<Dict> {
+        let transaction = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+
+        let mut a: Dict = args
+            .into_iter()
+            .map(|(k, v)| (k.as_bytes().to_vec(), v))
+            .collect();
+        a.insert(b"id".to_vec(), Value::Bytes(self.id.to_vec()));
+
+        let message = dict([
+            ("t", Value::Bytes(transaction.clone())),
+            ("y", Value::Bytes(b"q".to_vec())),
+            ("q", Value::Bytes(q.as_bytes().to_vec())),
+            ("a", Value::Dict(a)),
+        ]);
+
+        let (tx, rx) = oneshot::channel();
+        self.pending.insert(transaction.clone(), tx);
+        let sent = self.send(&message, addr).await;
+
+        let response = match sent {
+            Ok(()) => tokio::time::timeout(QUERY_TIMEOUT, rx).await.ok()?.ok(),
+            Err(_) => None,
+        };
+        self.pending.remove(&transaction);
+
+        if response.is_none() {
+            self.table.lock().unwrap().on_timeout(&addr);
+        }
+        response
+    }
+
+    async fn send(&self, message: &Value, addr: SocketAddr) -> std::io::Result<()> {
+        let buf = serde_bencode::to_bytes(message).map_err(std::io::Error::other)?;
+        self.socket.send_to(&buf, addr).await?;
+        Ok(())
+    }
+
+    async fn on_packet(&self, packet: &[u8], from: SocketAddr) {
+        let Ok(Value::Dict(message)) = serde_bencode::from_bytes::<Value>(packet) else {
+            trace!("invalid dht packet from {}", from);
+            return;
+        };
+        let Some(Value::Bytes(transaction)) = message.get(b"t".as_ref()) else {
+            return;
+        };
+
+        match message.get(b"y".as_ref()) {
+            Some(Value::Bytes(y)) if y == b"r" => {
+                let Some(Value::Dict(r)) = message.get(b"r".as_ref()) else {
+                    return;
+                };
+                if let Some(Value::Bytes(id)) = r.get(b"id".as_ref()) {
+                    if let Ok(id) = id.as_slice().try_into() {
+                        self.table
+                            .lock()
+                            .unwrap()
+                            .heard_from(Node { id, addr: from });
+                    }
+                }
+                if let Some((_, tx)) = self.pending.remove(transaction) {
+                    let _ = tx.send(r.clone());
+                }
+            }
+            Some(Value::Bytes(y)) if y == b"q" => {
+                let response = self.on_query(&message, from);
+                let reply = match response {
+                    Ok(r) => dict([
+                        ("t", Value::Bytes(transaction.clone())),
+                        ("y", Value::Bytes(b"r".to_vec())),
+                        ("r", Value::Dict(r)),
+                    ]),
+                    Err((code, reason)) => dict([
+                        ("t", Value::Bytes(transaction.clone())),
+                        ("y", Value::Bytes(b"e".to_vec())),
+                        (
+                            "e",
+                            Value::List(vec![
+                                Value::Int(code),
+                                Value::Bytes(reason.as_bytes().to_vec()),
+                            ]),
+                        ),
+                    ]),
+                };
+                if let Err(e) = self.send(&reply, from).await {
+                    debug!("error answering dht query: {:?}", e);
+                }
+            }
+            _ => {
+                if let Some((_, tx)) = self.pending.remove(transaction) {
+                    drop(tx);
+                }
+            }
+        }
+    }
+
+    fn on_query(&self, message: &Dict, from: SocketAddr) -> Result<Dict, (i64, &'static str)> {
+        let Some(Value::Bytes(q)) = message.get(b"q".as_ref()) else {
+            return Err((203, "missing query"));
+        };
+        let Some(Value::Dict(a)) = message.get(b"a".as_ref()) else {
+            return Err((203, "missing arguments"));
+        };
+        let id = match a.get(b"id".as_ref()) {
+            Some(Value::Bytes(id)) => <NodeId>::try_from(id.as_slice()).ok(),
+            _ => None,
+        };
+        let Some(id) = id else {
+            return Err((203, "invalid id"));
+        };
+        self.table
+            .lock()
+            .unwrap()
+            .heard_from(Node { id, addr: from });
+
+        let target = |key: &[u8]| match a.get(key) {
+            Some(Value::Bytes(t)) => <[u8; 20]>::try_from(t.as_slice()).ok(),
+            _ => None,
+        };
+
+        let mut r = dict_map([("id", Value::Bytes(self.id.to_vec()))]);
+        match q.as_slice() {
+            b"ping" => {}
+            b"find_node" => {
+                let target = target(b"target").ok_or((203, "invalid target"))?;
+                r.insert(
+                    self.nodes_key().to_vec(),
+                    Value::Bytes(self.compact_closest(&target)),
+                );
+            }
+            b"get_peers" => {
+                let info_hash = target(b"info_hash").ok_or((203, "invalid info_hash"))?;
+                let token = self
+                    .token_secrets
+                    .lock()
+                    .unwrap()
+                    .token(&from.ip(), Instant::now());
+                r.insert(b"token".to_vec(), Value::Bytes(token));
+                let peers = self
+                    .stored_peers
+                    .lock()
+                    .unwrap()
+                    .get(&info_hash, Instant::now());
+                match peers {
+                    peers if !peers.is_empty() => {
+                        let values = peers
+                            .iter()
+                            .filter_map(encode_compact_peer)
+                            .map(Value::Bytes)
+                            .collect();
+                        r.insert(b"values".to_vec(), Value::List(values));
+                    }
+                    _ => {
+                        r.insert(
+                            self.nodes_key().to_vec(),
+                            Value::Bytes(self.compact_closest(&info_hash)),
+                        );
+                    }
+                }
+            }
+            b"announce_peer" => {
+                let info_hash = target(b"info_hash").ok_or((203, "invalid info_hash"))?;
+                let valid = match a.get(b"token".as_ref()) {
+                    Some(Value::Bytes(token)) => self.token_secrets.lock().unwrap().is_valid(
+                        token,
+                        &from.ip(),
+                        Instant::now(),
+                    ),
+                    _ => false,
+                };
+                if !valid {
+                    return Err((203, "bad token"));
+                }
+                let implied_port = matches!(a.get(b"implied_port".as_ref()), Some(Value::Int(1)));
+                let port = match a.get(b"port".as_ref()) {
+                    _ if implied_port => from.port(),
+                    Some(Value::Int(port)) => *port as u16,
+                    _ => return Err((203, "missing port")),
+                };
+
+                self.stored_peers.lock().unwrap().insert(
+                    info_hash,
+                    SocketAddr::new(from.ip(), port),
+                    Instant::now(),
+                );
+            }
+            _ => return Err((204, "method unknown")),
+        }
+        Ok(r)
+    }
+
+    fn compact_closest(&self, target: &NodeId) -> Vec<u8> {
+        self.table
+            .lock()
+            .unwrap()
+            .closest(target, K)
+            .iter()
+            .filter_map(encode_compact_node)
+            .flatten()
+            .collect()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Lookup {
+    peers: Vec<PeerAddr>,
+    tokens: Vec<(Node, Vec<u8>)>,
+}
+
+fn dict_map<const N: usize>(entries: [(&str, Value); N]) -> Dict {
+    entries
+        .into_iter()
+        .map(|(k, v)| (k.as_bytes().to_vec(), v))
+        .collect()
+}
+
+fn dict<const N: usize>(entries: [(&str, Value); N]) -> Value {
+    Value::Dict(dict_map(entries))
+}
+
+fn decode_compact_nodes(buf: &[u8], ipv6: bool) -> Vec<Node> {
+    let len = 20 + compact_peer_len(ipv6);
+    buf.chunks_exact(len)
+        .map(|c| Node {
+            id: c[..20].try_into().unwrap(),
+            addr: decode_compact_peer(&c[20..]),
+        })
+        .collect()
+}
+
+fn encode_compact_node(node: &Node) -> Option<Vec<u8>> {
+    let mut buf = node.id.to_vec();
+    buf.extend(encode_compact_peer(&node.addr)?);
+    Some(buf)
+}
+
+fn compact_peer_len(ipv6: bool) -> usize {
+    if ipv6 {
+        18
+    } else {
+        6
+    }
+}
+
+pub(crate) fn decode_compact_peers(buf: &[u8], ipv6: bool) -> Vec<PeerAddr> {
+    buf.chunks_exact(compact_peer_len(ipv6))
+        .map(decode_compact_peer)
+        .collect()
+}
+
+// An address and port, 6 or 18 bytes.
+fn decode_compact_peer(c: &[u8]) -> PeerAddr {
+    let (ip, port) = c.split_at(c.len() - 2);
+    let ip = match <[u8; 16]>::try_from(ip) {
+        Ok(octets) => IpAddr::V6(Ipv6Addr::from(octets)),
+        Err(_) => IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])),
+    };
+    SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+}
+
+pub(crate) fn encode_compact_peer(addr: &PeerAddr) -> Option<Vec<u8>> {
+    let mut buf = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    buf.extend(addr.port().to_be_bytes());
+    Some(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_node_roundtrip() {
+        let node = Node {
+            id: [7u8; 20],
+            addr: "10.1.2.3:6881".parse().unwrap(),
+        };
+        let encoded = encode_compact_node(&node).unwrap();
+        assert_eq!(encoded.len(), 26);
+        assert_eq!(decode_compact_nodes(&encoded, false), vec![node]);
+
+        let node = Node {
+            id: [7u8; 20],
+            addr: "[2001:db8::1]:6881".parse().unwrap(),
+        };
+        let encoded = encode_compact_node(&node).unwrap();
+        assert_eq!(encoded.len(), 38);
+        assert_eq!(decode_compact_nodes(&encoded, true), vec![node]);
+    }
+
+    #[test]
+    fn routing_table_returns_closest_nodes() {
+        let mut table = RoutingTable::new([0u8; 20]);
+        for i in 1..=20u8 {
+            let mut id = [0u8; 20];
+            id[19] = i;
+            table.insert(Node {
+                id,
+                addr: SocketAddr::new(Ipv4Addr::new(10, 0, 0, i).into(), 6881),
+            });
+        }
+
+        let mut target = [0u8; 20];
+        target[19] = 3;
+        let closest = table.closest(&target, 2);
+        assert_eq!(closest[0].id[19], 3);
+        assert_eq!(closest[1].id[19], 2);
+        // Buckets are capped at K nodes.
+        assert!(table.len() <= 20);
+    }
+
+    #[test]
+    fn nodes_survive_a_lost_reply() {
+        let mut table = RoutingTable::new([0u8; 20]);
+        let node = Node {
+            id: [1u8; 20],
+            addr: "10.0.0.1:6881".parse().unwrap(),
+        };
+        table.insert(node);
+
+        table.on_timeout(&node.addr);
+        table.on_timeout(&node.addr);
+        table.heard_from(node);
+        table.on_timeout(&node.addr);
+        table.on_timeout(&node.addr);
+        assert_eq!(table.len(), 1);
+
+        table.on_timeout(&node.addr);
+        assert_eq!(table.len(), 0);
+        assert!(table.failures.is_empty());
+    }
+
+    #[test]
+    fn tokens_expire_after_two_rotations() {
+        let start = Instant::now();
+        let mut secrets = TokenSecrets::new(start);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let token = secrets.token(&ip, start);
+
+        assert!(secrets.is_valid(&token, &ip, start + Duration::from_secs(60)));
+        assert!(!secrets.is_valid(&token, &"10.0.0.2".parse().unwrap(), start));
+        assert!(secrets.is_valid(&token, &ip, start + TOKEN_ROTATION));
+        assert!(!secrets.is_valid(&token, &ip, start + TOKEN_ROTATION * 2));
+        // Nothing asked for a long time: old tokens don't come back.
+        let mut secrets = TokenSecrets::new(start);
+        let token = secrets.token(&ip, start);
+        assert!(!secrets.is_valid(&token, &ip, start + TOKEN_ROTATION * 3));
+    }
+
+    #[test]
+    fn stored_peers_expire_and_are_capped() {
+        let start = Instant::now();
+        let mut store = PeerStore::default();
+        let peer: PeerAddr = "10.0.0.1:6881".parse().unwrap();
+        store.insert([1u8; 20], peer, start);
+        store.insert([1u8; 20], peer, start);
+        assert_eq!(store.get(&[1u8; 20], start), vec![peer]);
+        assert!(store.get(&[1u8; 20], start + STORED_PEER_TTL).is_empty());
+
+        for i in 0..MAX_STORED_TORRENTS as u32 + 10 {
+            let mut info_hash = [0u8; 20];
+            info_hash[..4].copy_from_slice(&i.to_be_bytes());
+            store.insert(info_hash, peer, start);
+        }
+        assert_eq!(store.torrents.len(), MAX_STORED_TORRENTS);
+        // Expired torrents make room for new ones.
+        let later = start + STORED_PEER_TTL;
+        store.insert([2u8; 20], peer, later);
+        assert_eq!(store.get(&[2u8; 20], later), vec![peer]);
+        assert_eq!(store.torrents.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn announce_and_find_peers_between_two_nodes() {
+        let a = Dht::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let b = Dht::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        a.add_node(Node {
+            id: b.id(),
+            addr: b.local_addr().unwrap(),
+        });
+        b.add_node(Node {
+            id: a.id(),
+            addr: a.local_addr().unwrap(),
+        });
+
+        let info_hash = [42u8; 20];
+        assert!(a.announce(info_hash, 51413).await.is_empty());
+
+        let peers = b.get_peers(info_hash).await;
+        assert_eq!(peers, vec!["127.0.0.1:51413".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn ipv6_nodes_find_each_other() {
+        let nodes: Vec<Arc<Dht>> = vec![
+            Dht::bind("[::1]:0".parse().unwrap()).await.unwrap(),
+            Dht::bind("[::1]:0".parse().unwrap()).await.unwrap(),
+            Dht::bind("[::1]:0".parse().unwrap()).await.unwrap(),
+        ];
+        assert!(nodes[0].is_ipv6());
+        // The last node only hears of the first through `nodes6`.
+        let node = |i: usize| Node {
+            id: nodes[i].id(),
+            addr: nodes[i].local_addr().unwrap(),
+        };
+        nodes[0].add_node(node(1));
+        nodes[1].add_node(node(0));
+        nodes[2].add_node(node(1));
+
+        let info_hash = [42u8; 20];
+        nodes[0].announce(info_hash, 51413).await;
+        nodes[2].lookup(nodes[0].id(), false).await;
+        assert_eq!(nodes[2].nodes_len(), 2);
+        let peers = nodes[2].get_peers(info_hash).await;
+        assert_eq!(peers, vec!["[::1]:51413".parse().unwrap()]);
+    }
+}
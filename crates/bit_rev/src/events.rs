@@ -0,0 +1,153 @@
+use std::path::PathBuf;
+
+use tokio::sync::broadcast;
+
+use crate::{peer::PeerAddr, session::TorrentState};
+
+/// Events buffered per subscriber before the slowest ones start missing some.
+const EVENT_CAPACITY: usize = 1024;
+
+/// Something that happened to a torrent of a session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub info_hash: [u8; 20],
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    TorrentAdded,
+    StateChanged {
+        from: TorrentState,
+        to: TorrentState,
+    },
+    /// The piece passed the hash check and was written to disk.
+    PieceVerified {
+        index: u32,
+    },
+    /// The piece failed the hash check and will be downloaded again.
+    PieceFailed {
+        index: u32,
+    },
+    PeerConnected {
+        peer: PeerAddr,
+        incoming: bool,
+    },
+    PeerDisconnected {
+        peer: PeerAddr,
+        reason: DisconnectReason,
+    },
+    TrackerReplied {
+        url: String,
+        peers: usize,
+        /// Seeders and leechers in the swarm, if the tracker said.
+        seeders: Option<u64>,
+        leechers: Option<u64>,
+    },
+    /// The tracker accepted the announce with a warning.
+    TrackerWarning {
+        url: String,
+        message: String,
+    },
+    TrackerFailed {
+        url: String,
+        error: String,
+    },
+    /// We stopped using the web seed after too many errors.
+    WebSeedFailed {
+        url: String,
+        error: String,
+    },
+    /// Every piece of the file is on disk.
+    FileCompleted {
+        index: usize,
+        path: PathBuf,
+    },
+    /// Every piece of the torrent is on disk.
+    TorrentFinished,
+    StorageError {
+        error: String,
+    },
+    TorrentRemoved,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The connection ended without an error, e.g. the peer hung up.
+    Closed,
+    /// The connection manager made room for a better peer.
+    Replaced,
+    /// The torrent was paused, stopped or removed.
+    TorrentStopped,
+    Error(String),
+}
+
+/// Fans out events to every subscriber. Subscribers that fall more than
+/// `EVENT_CAPACITY` events behind get `RecvError::Lagged` and skip ahead.
+#[derive(Debug, Clone)]
+pub struct Events {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_CAPACITY);
+        Self { tx }
+    }
+}
+
+impl Events {
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+
+    pub fn emit(&self, event: Event) {
+        // Nobody listening is fine.
+        let _ = self.tx.send(event);
+    }
+}
+
+/// The events of one torrent.
+#[derive(Debug, Clone)]
+pub struct TorrentEvents {
+    info_hash: [u8; 20],
+    events: Events,
+}
+
+impl TorrentEvents {
+    pub fn new(info_hash: [u8; 20], events: Events) -> Self {
+        Self { info_hash, events }
+    }
+
+    pub fn emit(&self, kind: EventKind) {
+        self.events.emit(Event {
+            info_hash: self.info_hash,
+            kind,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribers_receive_torrent_events() {
+        let events = Events::default();
+        let mut rx = events.subscribe();
+        let torrent = TorrentEvents::new([1u8; 20], events.clone());
+
+        torrent.emit(EventKind::PieceVerified { index: 3 });
+        torrent.emit(EventKind::TorrentFinished);
+
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            Event {
+                info_hash: [1u8; 20],
+                kind: EventKind::PieceVerified { index: 3 },
+            }
+        );
+        assert_eq!(rx.try_recv().unwrap().kind, EventKind::TorrentFinished);
+        assert!(rx.try_recv().is_err());
+    }
+}
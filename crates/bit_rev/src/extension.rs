@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::{
+    dht::{decode_compact_peers, encode_compact_peer},
+    message::Message,
+    peer::PeerAddr,
+};
+
+/// Extended message id reserved for the BEP 10 handshake.
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// Extended message id we ask peers to use for `ut_pex` messages to us.
+pub const UT_PEX_ID: u8 = 1;
+
+/// Version string we advertise in the `v` field.
+pub const CLIENT_VERSION: &str = concat!("BitRev ", env!("CARGO_PKG_VERSION"));
+
+/// The BEP 10 extended handshake dictionary.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ExtendedHandshake {
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+}
+
+impl ExtendedHandshake {
+    pub fn new(reqq: u32) -> Self {
+        Self {
+            v: Some(CLIENT_VERSION.to_string()),
+            reqq: Some(reqq),
+            ..Default::default()
+        }
+    }
+
+    pub fn to_message(&self) -> Result<Message, serde_bencode::Error> {
+        Ok(Message::Extended(
+            HANDSHAKE_ID,
+            serde_bencode::to_bytes(self)?,
+        ))
+    }
+
+    pub fn from_bytes(payload: &[u8]) -> Result<Self, serde_bencode::Error> {
+        serde_bencode::from_bytes(payload)
+    }
+
+    /// Advertises peer exchange support.
+    pub fn with_pex(mut self) -> Self {
+        self.m.insert("ut_pex".to_string(), UT_PEX_ID as i64);
+        self
+    }
+
+    /// The id the peer wants its `ut_pex` messages sent with.
+    pub fn pex_id(&self) -> Option<u8> {
+        self.m
+            .get("ut_pex")
+            .and_then(|&id| u8::try_from(id).ok())
+            .filter(|&id| id != 0)
+    }
+}
+
+/// A BEP 11 peer exchange message, with the peers added and dropped since
+/// the last one.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct PexMessage {
+    #[serde(default)]
+    pub added: ByteBuf,
+    #[serde(default, rename = "added.f")]
+    pub added_flags: ByteBuf,
+    #[serde(default)]
+    pub added6: ByteBuf,
+    #[serde(default, rename = "added6.f")]
+    pub added6_flags: ByteBuf,
+    #[serde(default)]
+    pub dropped: ByteBuf,
+    #[serde(default)]
+    pub dropped6: ByteBuf,
+}
+
+impl PexMessage {
+    pub fn new(added: &[PeerAddr], dropped: &[PeerAddr]) -> Self {
+        let mut message = Self::default();
+        for peer in added {
+            let (buf, flags) = match peer.is_ipv6() {
+                true => (&mut message.added6, &mut message.added6_flags),
+                false => (&mut message.added, &mut message.added_flags),
+            };
+            buf.extend(encode_compact_peer(peer).unwrap_or_default());
+            flags.push(0);
+        }
+        for peer in dropped {
+            let buf = match peer.is_ipv6() {
+                true => &mut message.dropped6,
+                false => &mut message.dropped,
+            };
+            buf.extend(encode_compact_peer(peer).unwrap_or_default());
+        }
+        message
+    }
+
+    /// IPv4 and IPv6 peers that were added.
+    pub fn added(&self) -> Vec<PeerAddr> {
+        let mut peers = decode_compact_peers(&self.added, false);
+        peers.extend(decode_compact_peers(&self.added6, true));
+        peers
+    }
+
+    pub fn to_message(&self, id: u8) -> Result<Message, serde_bencode::Error> {
+        Ok(Message::Extended(id, serde_bencode::to_bytes(self)?))
+    }
+
+    pub fn from_bytes(payload: &[u8]) -> Result<Self, serde_bencode::Error> {
+        serde_bencode::from_bytes(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extended_handshake_roundtrip() {
+        let handshake = ExtendedHandshake::new(250);
+        let message = handshake.to_message().unwrap();
+        let Message::Extended(id, payload) = message else {
+            panic!("expected an extended message");
+        };
+
+        assert_eq!(id, HANDSHAKE_ID);
+        assert_eq!(ExtendedHandshake::from_bytes(&payload).unwrap(), handshake);
+    }
+
+    #[test]
+    fn extended_handshake_ignores_unknown_keys() {
+        let payload = b"d1:ei1e1:md11:ut_metadatai3ee4:reqqi500e1:v13:qBittorrent/4e";
+        let handshake = ExtendedHandshake::from_bytes(payload).unwrap();
+
+        assert_eq!(handshake.reqq, Some(500));
+        assert_eq!(handshake.v.as_deref(), Some("qBittorrent/4"));
+        assert_eq!(handshake.m.get("ut_metadata"), Some(&3));
+        assert_eq!(handshake.pex_id(), None);
+    }
+
+    #[test]
+    fn pex_is_advertised() {
+        let handshake = ExtendedHandshake::new(250).with_pex();
+        let Message::Extended(_, payload) = handshake.to_message().unwrap() else {
+            panic!("expected an extended message");
+        };
+
+        let handshake = ExtendedHandshake::from_bytes(&payload).unwrap();
+        assert_eq!(handshake.pex_id(), Some(UT_PEX_ID));
+    }
+
+    #[test]
+    fn pex_message_roundtrip() {
+        let added: Vec<PeerAddr> = vec![
+            "10.0.0.1:6881".parse().unwrap(),
+            "[2001:db8::1]:51413".parse().unwrap(),
+        ];
+        let dropped: Vec<PeerAddr> = vec!["10.0.0.2:6881".parse().unwrap()];
+        let Message::Extended(id, payload) = PexMessage::new(&added, &dropped)
+            .to_message(UT_PEX_ID)
+            .unwrap()
+        else {
+            panic!("expected an extended message");
+        };
+
+        assert_eq!(id, UT_PEX_ID);
+        let message = PexMessage::from_bytes(&payload).unwrap();
+        assert_eq!(message.added(), added);
+        assert_eq!(message.added.len(), 6);
+        assert_eq!(message.added6.len(), 18);
+        assert_eq!(message.added_flags.as_slice(), [0]);
+        assert_eq!(message.dropped.len(), 6);
+        assert!(message.dropped6.is_empty());
+    }
+
+    #[test]
+    fn pex_message_keys_are_optional() {
+        let message = PexMessage::from_bytes(b"d5:added6:\x0a\x00\x00\x01\x1a\xe1e").unwrap();
+
+        assert_eq!(message.added(), vec!["10.0.0.1:6881".parse().unwrap()]);
+        assert!(message.dropped.is_empty());
+    }
+}
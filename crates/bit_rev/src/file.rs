@@ -1,10 +1,30 @@
 use serde::Deserialize;
+use serde::Deserializer;
 use serde::Serialize;
 use serde_bencode::de;
 use serde_bencode::ser;
 use serde_bytes::ByteBuf;
 use std::fmt::Write;
-use std::{error::Error, io::Read};
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum MetainfoError {
+    #[error("can't read torrent file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid bencode: {0}")]
+    Bencode(#[from] serde_bencode::Error),
+    #[error("pieces length {0} is not a multiple of 20")]
+    InvalidPieces(usize),
+    #[error("invalid piece length {0}")]
+    InvalidPieceLength(i64),
+    #[error("torrent has neither a length nor files")]
+    MissingLength,
+    #[error("invalid length {0}")]
+    InvalidLength(i64),
+    #[error("{got} piece hashes for {expected} pieces")]
+    PieceCount { expected: u64, got: usize },
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Node(String, i64);
@@ -38,6 +58,25 @@ pub struct Info {
     pub root_hash: Option<String>,
 }
 
+impl Info {
+    /// Private torrents only get peers from their trackers, BEP 27.
+    ///
+    /// Only `.torrent` files are read for now. Creating torrents and
+    /// resolving magnet links aren't supported yet, both will have to honour
+    /// the flag once they are.
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    /// Size of the torrent's content, summing the files of multi-file torrents.
+    pub fn total_length(&self) -> i64 {
+        match &self.files {
+            Some(files) => files.iter().map(|f| f.length).sum(),
+            None => self.length.unwrap_or(0),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TorrentFile {
     pub info: Info,
@@ -47,8 +86,12 @@ pub struct TorrentFile {
     pub nodes: Option<Vec<Node>>,
     #[serde(default)]
     pub encoding: Option<String>,
+    /// BEP 17 web seeds.
     #[serde(default)]
     pub httpseeds: Option<Vec<String>>,
+    /// BEP 19 web seeds.
+    #[serde(default, rename = "url-list", deserialize_with = "one_or_many")]
+    pub url_list: Option<Vec<String>>,
     #[serde(default)]
     #[serde(rename = "announce-list")]
     pub announce_list: Option<Vec<Vec<String>>>,
@@ -62,6 +105,24 @@ pub struct TorrentFile {
     pub created_by: Option<String>,
 }
 
+// `url-list` may be a single URL instead of a list.
+fn one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match Option::<OneOrMany>::deserialize(deserializer)? {
+        Some(OneOrMany::One(url)) => Some(vec![url]),
+        Some(OneOrMany::Many(urls)) => Some(urls),
+        None => None,
+    })
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TorrentMeta {
     pub torrent_file: TorrentFile,
@@ -70,8 +131,40 @@ pub struct TorrentMeta {
 }
 
 impl TorrentMeta {
-    pub fn new(torrent_file: TorrentFile) -> Self {
-        let file_info_beaconde = &ser::to_bytes(&torrent_file.info).unwrap();
+    pub fn new(torrent_file: TorrentFile) -> Result<Self, MetainfoError> {
+        let info = &torrent_file.info;
+        if !info.pieces.len().is_multiple_of(20) {
+            return Err(MetainfoError::InvalidPieces(info.pieces.len()));
+        }
+        if info.piece_length <= 0 || info.piece_length > u32::MAX as i64 {
+            return Err(MetainfoError::InvalidPieceLength(info.piece_length));
+        }
+        if info.length.is_none() && info.files.is_none() {
+            return Err(MetainfoError::MissingLength);
+        }
+        let lengths: Vec<i64> = match &info.files {
+            Some(files) => files.iter().map(|f| f.length).collect(),
+            None => info.length.into_iter().collect(),
+        };
+        let mut total_length: i64 = 0;
+        for length in lengths {
+            if length < 0 {
+                return Err(MetainfoError::InvalidLength(length));
+            }
+            total_length = total_length
+                .checked_add(length)
+                .ok_or(MetainfoError::InvalidLength(length))?;
+        }
+        // Every piece has a hash, the last one may be shorter.
+        let expected = (total_length as u64).div_ceil(info.piece_length as u64);
+        if info.pieces.len() as u64 / 20 != expected {
+            return Err(MetainfoError::PieceCount {
+                expected,
+                got: info.pieces.len() / 20,
+            });
+        }
+
+        let file_info_beaconde = &ser::to_bytes(&torrent_file.info)?;
         let mut hasher = sha1_smol::Sha1::new();
         hasher.update(file_info_beaconde);
         let info_hash = hasher.digest().bytes();
@@ -87,52 +180,116 @@ impl TorrentMeta {
             })
             .collect();
 
-        Self {
+        Ok(Self {
             torrent_file,
             info_hash,
             piece_hashes,
-        }
+        })
+    }
+
+    /// Parses the content of a `.torrent` file.
+    pub fn from_bytes(content: &[u8]) -> Result<TorrentMeta, MetainfoError> {
+        TorrentMeta::new(de::from_bytes::<TorrentFile>(content)?)
     }
 }
 
-pub fn from_filename(filename: &str) -> Result<TorrentMeta, Box<dyn Error>> {
-    let mut file = std::fs::File::open(filename)?;
-    let mut content = Vec::new();
-    file.read_to_end(&mut content)?;
-    let torrent = de::from_bytes::<TorrentFile>(&content)?;
-    Ok(TorrentMeta::new(torrent))
+pub fn from_filename(filename: impl AsRef<Path>) -> Result<TorrentMeta, MetainfoError> {
+    TorrentMeta::from_bytes(&std::fs::read(filename)?)
 }
 
-pub fn url_encode_bytes(content: &[u8]) -> Result<String, Box<dyn Error>> {
+pub fn url_encode_bytes(content: &[u8]) -> String {
     let mut out: String = String::new();
 
     for byte in content.iter() {
         match *byte as char {
             '0'..='9' | 'a'..='z' | 'A'..='Z' | '.' | '-' | '_' | '~' => out.push(*byte as char),
-            _ => write!(&mut out, "%{:02X}", byte)?,
+            // Writing to a String can't fail.
+            _ => {
+                let _ = write!(&mut out, "%{:02X}", byte);
+            }
         };
     }
 
-    Ok(out)
+    out
 }
 
-pub fn build_tracker_url(
-    torrent_meta: &TorrentMeta,
-    peer_id: &[u8],
-    port: u16,
-    tracker_url: &str,
-) -> String {
-    // let announce_url = torrent_meta.torrent_file.announce.as_ref().unwrap();
-    let info_hash_encoded = url_encode_bytes(torrent_meta.info_hash.as_ref()).unwrap();
-    let peer_id_encoded = url_encode_bytes(peer_id).unwrap();
-
-    format!(
-        "{}?info_hash={}&peer_id={}&port={}&uploaded=0&downloaded=0&compact=1&left={}",
-        tracker_url,
-        info_hash_encoded,
-        peer_id_encoded,
-        port,
-        torrent_meta.torrent_file.info.length.as_ref().unwrap()
-    )
-    .to_string()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_metainfo() {
+        assert!(matches!(
+            TorrentMeta::from_bytes(b"not bencode"),
+            Err(MetainfoError::Bencode(_))
+        ));
+        assert!(matches!(
+            TorrentMeta::from_bytes(
+                b"d4:infod4:name1:a6:pieces3:abc12:piece lengthi4e6:lengthi4eee"
+            ),
+            Err(MetainfoError::InvalidPieces(3))
+        ));
+        assert!(matches!(
+            TorrentMeta::from_bytes(b"d4:infod4:name1:a6:pieces0:12:piece lengthi0e6:lengthi4eee"),
+            Err(MetainfoError::InvalidPieceLength(0))
+        ));
+        assert!(matches!(
+            TorrentMeta::from_bytes(b"d4:infod4:name1:a6:pieces0:12:piece lengthi4eee"),
+            Err(MetainfoError::MissingLength)
+        ));
+        // Two hashes for a single piece.
+        assert!(matches!(
+            TorrentMeta::from_bytes(
+                &[
+                    &b"d4:infod4:name1:a6:pieces40:"[..],
+                    &[0; 40],
+                    b"12:piece lengthi4e6:lengthi4eee",
+                ]
+                .concat()
+            ),
+            Err(MetainfoError::PieceCount {
+                expected: 1,
+                got: 2
+            })
+        ));
+        assert!(matches!(
+            TorrentMeta::from_bytes(b"d4:infod4:name1:a6:pieces0:12:piece lengthi4e6:lengthi-4eee"),
+            Err(MetainfoError::InvalidLength(-4))
+        ));
+        assert!(matches!(
+            TorrentMeta::from_bytes(
+                b"d4:infod4:name1:a6:pieces0:12:piece lengthi4e\
+                  5:filesld4:pathl1:xe6:lengthi4eed4:pathl1:ye6:lengthi-4eeeee"
+            ),
+            Err(MetainfoError::InvalidLength(-4))
+        ));
+        assert!(TorrentMeta::from_bytes(
+            b"d4:infod4:name1:a6:pieces20:aaaaaaaaaaaaaaaaaaaa12:piece lengthi4e6:lengthi4eee"
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn private_flag_is_part_of_the_info_hash() {
+        let public = TorrentMeta::from_bytes(
+            b"d4:infod4:name1:a6:pieces20:aaaaaaaaaaaaaaaaaaaa12:piece lengthi4e6:lengthi4eee",
+        )
+        .unwrap();
+        let private = TorrentMeta::from_bytes(
+            b"d4:infod4:name1:a6:pieces20:aaaaaaaaaaaaaaaaaaaa12:piece lengthi4e6:lengthi4e7:privatei1eee",
+        )
+        .unwrap();
+
+        assert!(!public.torrent_file.info.is_private());
+        assert!(private.torrent_file.info.is_private());
+        assert_ne!(public.info_hash, private.info_hash);
+    }
+
+    #[test]
+    fn missing_torrent_file() {
+        assert!(matches!(
+            from_filename("/no/such/file.torrent"),
+            Err(MetainfoError::Io(_))
+        ));
+    }
 }
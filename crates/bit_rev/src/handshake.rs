@@ -1,8 +1,12 @@
 use thiserror::Error;
 
+/// Bit in `reserved[5]` signalling BEP 10 support.
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Handshake {
     pub pstr: String,
+    pub reserved: [u8; 8],
     pub info_hash: [u8; 20],
     pub peer_id: [u8; 20],
 }
@@ -17,16 +21,27 @@ impl Handshake {
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
         Self {
             pstr: "BitTorrent protocol".to_string(),
+            reserved: [0u8; 8],
             info_hash,
             peer_id,
         }
     }
 
+    /// Advertises support for the BEP 10 extension protocol.
+    pub fn with_extension_protocol(mut self) -> Self {
+        self.reserved[5] |= EXTENSION_PROTOCOL_BIT;
+        self
+    }
+
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.reserved[5] & EXTENSION_PROTOCOL_BIT != 0
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
         let mut handshake = Vec::new();
         handshake.push(self.pstr.len() as u8);
         handshake.extend(self.pstr.as_bytes());
-        handshake.extend(vec![0u8; 8]);
+        handshake.extend(self.reserved);
         handshake.extend(self.info_hash);
         handshake.extend(self.peer_id);
         handshake
@@ -38,10 +53,16 @@ impl Handshake {
         if protocol_str_len == 0 {
             return Err(HandshakeError::ProtocolLengthCantBeZero);
         }
+        let reserved = handshake_buf[protocol_str_len..(protocol_str_len + 8)]
+            .try_into()
+            .unwrap();
         let i = protocol_str_len + 8;
         let info_hash_buffer = handshake_buf[i..(i + 20)].try_into().unwrap();
         let peer_id_buffer = handshake_buf[(i + 20)..].try_into().unwrap();
-        Ok(Handshake::new(info_hash_buffer, peer_id_buffer))
+        Ok(Handshake {
+            reserved,
+            ..Handshake::new(info_hash_buffer, peer_id_buffer)
+        })
     }
 }
 
@@ -97,4 +118,14 @@ mod tests {
 
         assert_eq!(result, Err(HandshakeError::ProtocolLengthCantBeZero));
     }
+
+    #[test]
+    fn extension_protocol_bit() {
+        let handshake = Handshake::new(HASH_INFO, PEER_ID).with_extension_protocol();
+        let bytes = handshake.serialize();
+
+        assert_eq!(bytes[25], 0x10);
+        let result = Handshake::read(19, bytes[1..].to_vec()).unwrap();
+        assert!(result.supports_extension_protocol());
+    }
 }
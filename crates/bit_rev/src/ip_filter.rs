@@ -0,0 +1,265 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+use tracing::warn;
+
+/// eMule access levels above this one allow the range instead of blocking it.
+const EMULE_BLOCK_LEVEL: u32 = 127;
+
+/// Address ranges we never connect to nor accept peers from.
+///
+/// Reads eMule `ipfilter.dat`, PeerGuardian P2P text and CIDR lists, one range
+/// per line, and any mix of them. Overlapping ranges are merged so a lookup is
+/// a binary search.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IpFilter {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpFilter {
+    /// Reads a blocklist file, see [`IpFilter::parse`].
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<IpFilter> {
+        Ok(Self::parse(&std::fs::read_to_string(path)?))
+    }
+
+    /// Parses a blocklist. Blank lines and `#` or `//` comments are skipped,
+    /// so are lines that aren't a range, with a warning.
+    pub fn parse(text: &str) -> IpFilter {
+        let mut ranges = Vec::new();
+        let mut invalid = 0;
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            match parse_line(line) {
+                Some(Some(range)) => ranges.push(range),
+                Some(None) => {}
+                None => invalid += 1,
+            }
+        }
+        if invalid > 0 {
+            warn!("skipped {} invalid lines of the ip filter", invalid);
+        }
+        Self::from_ranges(ranges)
+    }
+
+    /// Blocks every address from the first to the second of each pair, both
+    /// included. Pairs mixing IPv4 and IPv6 are ignored.
+    pub fn from_ranges(ranges: impl IntoIterator<Item = (IpAddr, IpAddr)>) -> IpFilter {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        for (start, end) in ranges {
+            match (start.to_canonical(), end.to_canonical()) {
+                (IpAddr::V4(start), IpAddr::V4(end)) => v4.push(ordered(start.into(), end.into())),
+                (IpAddr::V6(start), IpAddr::V6(end)) => v6.push(ordered(start.into(), end.into())),
+                _ => {}
+            }
+        }
+        IpFilter {
+            v4: merge(v4, |ip| ip.saturating_add(1)),
+            v6: merge(v6, |ip| ip.saturating_add(1)),
+        }
+    }
+
+    /// IPv4-mapped IPv6 addresses are looked up as IPv4.
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => contains(&self.v4, ip.into()),
+            IpAddr::V6(ip) => contains(&self.v6, ip.into()),
+        }
+    }
+
+    /// Number of ranges left once overlapping ones are merged.
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn ordered<T: Ord>(a: T, b: T) -> (T, T) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+// Sorts the ranges, joining the ones that overlap or touch.
+fn merge<T: Ord + Copy>(mut ranges: Vec<(T, T)>, next: impl Fn(T) -> T) -> Vec<(T, T)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= next(last.1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    // The last range starting at or before `ip`.
+    let i = ranges.partition_point(|&(start, _)| start <= ip);
+    i > 0 && ip <= ranges[i - 1].1
+}
+
+// A range, `Some(None)` for an eMule range that allows the addresses, `None`
+// if the line can't be read.
+fn parse_line(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+    // eMule: `001.002.003.000 - 001.002.003.255 , 000 , Description`
+    if let Some((range, rest)) = line.split_once(',') {
+        let level = rest
+            .split(',')
+            .next()
+            .and_then(|l| l.trim().parse::<u32>().ok());
+        if let (Some(range), Some(level)) = (parse_range(range), level) {
+            return Some((level <= EMULE_BLOCK_LEVEL).then_some(range));
+        }
+    }
+    if let Some(range) = parse_range(line) {
+        return Some(Some(range));
+    }
+    // PeerGuardian P2P: `Description:1.2.3.0-1.2.3.255`
+    let (_, range) = line.rsplit_once(':')?;
+    parse_range(range).map(Some)
+}
+
+// `start-end`, `address/prefix` or a single address.
+fn parse_range(range: &str) -> Option<(IpAddr, IpAddr)> {
+    let range = range.trim();
+    if let Some((start, end)) = range.split_once('-') {
+        return Some((parse_ip(start)?, parse_ip(end)?));
+    }
+    if let Some((ip, prefix)) = range.split_once('/') {
+        return cidr(parse_ip(ip)?, prefix.trim().parse().ok()?);
+    }
+    let ip = parse_ip(range)?;
+    Some((ip, ip))
+}
+
+// eMule lists pad IPv4 octets with zeros, which `IpAddr` rejects.
+fn parse_ip(ip: &str) -> Option<IpAddr> {
+    let ip = ip.trim();
+    if let Ok(ip) = ip.parse() {
+        return Some(ip);
+    }
+    let mut octets = [0u8; 4];
+    let mut parts = ip.split('.');
+    for octet in &mut octets {
+        let part = parts.next()?;
+        if part.is_empty() || part.len() > 3 {
+            return None;
+        }
+        *octet = part.parse().ok()?;
+    }
+    parts
+        .next()
+        .is_none()
+        .then(|| IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+fn cidr(ip: IpAddr, prefix: u32) -> Option<(IpAddr, IpAddr)> {
+    match ip {
+        IpAddr::V4(ip) if prefix <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            let start = u32::from(ip) & mask;
+            Some((
+                Ipv4Addr::from(start).into(),
+                Ipv4Addr::from(start | !mask).into(),
+            ))
+        }
+        IpAddr::V6(ip) if prefix <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            let start = u128::from(ip) & mask;
+            Some((
+                Ipv6Addr::from(start).into(),
+                Ipv6Addr::from(start | !mask).into(),
+            ))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn emule_ipfilter_dat() {
+        let filter = IpFilter::parse(
+            "# comment\n\
+             001.002.003.000 - 001.002.003.255 , 000 , Some range\n\
+             010.000.000.000 - 010.255.255.255 , 200 , Allowed range\n",
+        );
+
+        assert!(filter.is_blocked(ip("1.2.3.4")));
+        assert!(!filter.is_blocked(ip("1.2.4.0")));
+        assert!(!filter.is_blocked(ip("10.0.0.1")));
+        assert_eq!(filter.len(), 1);
+    }
+
+    #[test]
+    fn peerguardian_p2p() {
+        let filter = IpFilter::parse(
+            "Some: org, with colons:1.2.3.0-1.2.3.255\n\
+             Other org:5.6.7.8-5.6.7.8\n",
+        );
+
+        assert!(filter.is_blocked(ip("1.2.3.200")));
+        assert!(filter.is_blocked(ip("5.6.7.8")));
+        assert!(!filter.is_blocked(ip("5.6.7.9")));
+    }
+
+    #[test]
+    fn cidr_lists() {
+        let filter = IpFilter::parse("192.168.0.0/16\n2001:db8::/32\n8.8.8.8\n0.0.0.0/0x\n");
+
+        assert!(filter.is_blocked(ip("192.168.42.1")));
+        assert!(!filter.is_blocked(ip("192.169.0.0")));
+        assert!(filter.is_blocked(ip("2001:db8:ffff::1")));
+        assert!(!filter.is_blocked(ip("2001:db9::1")));
+        assert!(filter.is_blocked(ip("8.8.8.8")));
+        // IPv4-mapped addresses count as IPv4.
+        assert!(filter.is_blocked(ip("::ffff:192.168.1.1")));
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        let filter = IpFilter::parse(
+            "10.0.0.0-10.0.0.10\n10.0.0.5-10.0.0.20\n10.0.0.21-10.0.0.30\n10.0.1.0/24\n\
+             0.0.0.0/0\n",
+        );
+        assert_eq!(filter.len(), 1);
+
+        let filter = IpFilter::from_ranges([
+            (ip("10.0.0.30"), ip("10.0.0.21")),
+            (ip("10.0.0.0"), ip("10.0.0.20")),
+            (ip("10.0.0.40"), ip("10.0.0.40")),
+        ]);
+        assert_eq!(filter.len(), 2);
+        assert!(filter.is_blocked(ip("10.0.0.25")));
+        assert!(!filter.is_blocked(ip("10.0.0.35")));
+        assert!(filter.is_blocked(ip("10.0.0.40")));
+        assert!(!filter.is_blocked(ip("10.0.0.41")));
+        assert!(!filter.is_blocked(ip("9.255.255.255")));
+    }
+
+    #[test]
+    fn empty_filter_blocks_nothing() {
+        let filter = IpFilter::default();
+        assert!(filter.is_empty());
+        assert!(!filter.is_blocked(ip("1.2.3.4")));
+        assert!(!filter.is_blocked(ip("::1")));
+    }
+}
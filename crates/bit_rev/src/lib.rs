@@ -1,13 +1,32 @@
+pub mod ban;
 pub mod bitfield;
+pub mod choker;
+pub mod config;
+pub mod connection_manager;
+pub mod dht;
+pub mod events;
+pub mod extension;
 pub mod file;
 pub mod handshake;
+pub mod ip_filter;
+pub mod lsd;
 pub mod message;
+pub mod network;
 pub mod peer;
 pub mod peer_connection;
+pub mod peer_id;
 pub mod peer_state;
+pub mod pipeline;
 pub mod protocol;
 pub mod protocol_udp;
+pub mod proxy;
+pub mod rate_limit;
 pub mod session;
+pub mod stats;
+pub mod storage;
+pub mod streaming;
 pub mod torrent;
+pub mod tracker;
 pub mod tracker_peers;
 pub mod utils;
+pub mod web_seed;
@@ -0,0 +1,250 @@
+use std::{
+    fmt::Write,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+};
+
+use dashmap::DashMap;
+use socket2::{Protocol, Type};
+use tokio::net::UdpSocket;
+use tracing::{debug, trace};
+
+use crate::{
+    network::{self, BindTo, Network},
+    peer::PeerAddr,
+};
+
+/// Multicast group of Local Service Discovery, BEP 14.
+pub const LSD_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+
+/// A `BT-SEARCH` announce.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Search {
+    /// Port the announcing peer accepts connections on.
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    /// Lets a client recognize its own announces.
+    pub cookie: Option<String>,
+}
+
+impl Search {
+    pub fn to_message(&self) -> String {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            LSD_GROUP, self.port
+        );
+        // Writing to a String can't fail.
+        for info_hash in &self.info_hashes {
+            let _ = write!(message, "Infohash: {}\r\n", hex(info_hash));
+        }
+        if let Some(cookie) = &self.cookie {
+            let _ = write!(message, "cookie: {}\r\n", cookie);
+        }
+        message.push_str("\r\n\r\n");
+        message
+    }
+
+    /// Parses an announce, ignoring the info hashes we can't read.
+    pub fn parse(message: &[u8]) -> Option<Search> {
+        let message = std::str::from_utf8(message).ok()?;
+        let mut lines = message.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse().ok(),
+                "infohash" => info_hashes.extend(parse_hex(value)),
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Some(Search {
+            port: port.filter(|&port| port != 0)?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0u8; 20];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Announces our torrents to the local network and hands the peers that
+/// announce the same torrents to whoever subscribed to them.
+#[derive(Debug)]
+pub struct Lsd {
+    socket: Arc<UdpSocket>,
+    listen_port: u16,
+    cookie: String,
+    subscribers: DashMap<[u8; 20], flume::Sender<PeerAddr>>,
+}
+
+impl Lsd {
+    /// Joins the multicast group on the interface of `network` and starts
+    /// listening for announces.
+    pub async fn bind(network: &Network, listen_port: u16) -> io::Result<Arc<Lsd>> {
+        let interface = match network.bind() {
+            None => Ipv4Addr::UNSPECIFIED,
+            Some(BindTo::Address(IpAddr::V4(ip))) => *ip,
+            Some(BindTo::Address(IpAddr::V6(_))) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "local service discovery needs an IPv4 address",
+                ));
+            }
+            Some(BindTo::Interface(name)) => network::interface_addrs(name)?
+                .into_iter()
+                .find_map(|ip| match ip {
+                    IpAddr::V4(ip) => Some(ip),
+                    IpAddr::V6(_) => None,
+                })
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::AddrNotAvailable,
+                        format!("interface {} has no IPv4 address", name),
+                    )
+                })?,
+        };
+
+        // Other clients on this machine listen on the same port.
+        let socket = network.socket(false, Type::DGRAM, Protocol::UDP)?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LSD_GROUP.port())).into())?;
+        socket.join_multicast_v4(LSD_GROUP.ip(), &interface)?;
+        socket.set_multicast_if_v4(&interface)?;
+        socket.set_multicast_loop_v4(true)?;
+        let socket = Arc::new(UdpSocket::from_std(socket.into())?);
+
+        let lsd = Arc::new(Lsd {
+            socket: socket.clone(),
+            listen_port,
+            cookie: hex(&rand::random::<[u8; 8]>()),
+            subscribers: DashMap::new(),
+        });
+
+        // Only hold on to the service while handling a packet, so dropping
+        // the last handle stops the task.
+        let receiver = Arc::downgrade(&lsd);
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 1500];
+            loop {
+                let received = socket.recv_from(&mut buf).await;
+                let Some(lsd) = receiver.upgrade() else {
+                    break;
+                };
+                match received {
+                    Ok((len, from)) => lsd.on_packet(&buf[..len], from),
+                    Err(e) => debug!("lsd socket error: {:?}", e),
+                }
+            }
+        });
+
+        Ok(lsd)
+    }
+
+    /// Announces `info_hash` to the local network. BEP 14 asks for at most
+    /// one announce per torrent and minute.
+    pub async fn announce(&self, info_hash: [u8; 20]) -> io::Result<()> {
+        let search = Search {
+            port: self.listen_port,
+            info_hashes: vec![info_hash],
+            cookie: Some(self.cookie.clone()),
+        };
+        self.socket
+            .send_to(search.to_message().as_bytes(), LSD_GROUP)
+            .await?;
+        Ok(())
+    }
+
+    /// Peers announcing `info_hash` from now on, until the receiver is
+    /// dropped.
+    pub fn subscribe(&self, info_hash: [u8; 20]) -> flume::Receiver<PeerAddr> {
+        let (tx, rx) = flume::unbounded();
+        self.subscribers.insert(info_hash, tx);
+        rx
+    }
+
+    fn on_packet(&self, packet: &[u8], from: SocketAddr) {
+        let Some(search) = Search::parse(packet) else {
+            trace!("invalid lsd announce from {}", from);
+            return;
+        };
+        if search.cookie.as_deref() == Some(self.cookie.as_str()) {
+            return;
+        }
+
+        let peer = SocketAddr::new(from.ip(), search.port);
+        for info_hash in search.info_hashes {
+            let closed = match self.subscribers.get(&info_hash) {
+                Some(subscriber) => subscriber.send(peer).is_err(),
+                None => false,
+            };
+            if closed {
+                self.subscribers
+                    .remove_if(&info_hash, |_, subscriber| subscriber.is_disconnected());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_roundtrip() {
+        let search = Search {
+            port: 6881,
+            info_hashes: vec![[0xab; 20], [0x01; 20]],
+            cookie: Some("c00k1e".to_string()),
+        };
+        let message = search.to_message();
+        assert!(message.starts_with(
+            "BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\nInfohash: abab"
+        ));
+        assert_eq!(Search::parse(message.as_bytes()), Some(search));
+    }
+
+    #[test]
+    fn lenient_parsing() {
+        let search = Search::parse(
+            b"BT-SEARCH * HTTP/1.1\r\nhost: 239.192.152.143:6771\r\nport:6882\r\n\
+              infohash: ABABABABABABABABABABABABABABABABABABABAB\r\ninfohash: nothex\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(search.port, 6882);
+        assert_eq!(search.info_hashes, vec![[0xab; 20]]);
+        assert_eq!(search.cookie, None);
+
+        assert_eq!(
+            Search::parse(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n"),
+            None
+        );
+        assert_eq!(
+            Search::parse(b"BT-SEARCH * HTTP/1.1\r\nPort: 0\r\n\r\n"),
+            None
+        );
+    }
+}
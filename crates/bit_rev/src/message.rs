@@ -1,5 +1,7 @@
 use std::fmt::Display;
 
+use thiserror::Error;
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum MessageId {
     MsgChoke = 0,
@@ -12,6 +14,7 @@ pub enum MessageId {
     MsgPiece = 7,
     MsgCancel = 8,
     MsgReject = 16,
+    MsgExtended = 20,
     MsgHashRequest = 21,
     MsgHashes = 22,
     MsgHashReject = 23,
@@ -20,10 +23,19 @@ pub enum MessageId {
 #[derive(Debug)]
 pub enum WriterRequest {
     Message(Message),
-    //ReadChunkRequest(ChunkInfo),
+    /// A block the peer asked for, read from disk by the writer before sending it.
+    ReadChunkRequest(ChunkInfo),
     //Disconnect(anyhow::Result<()>),
 }
 
+/// A block of a piece, as carried by request and cancel messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkInfo {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Message {
     Choke,
@@ -36,15 +48,34 @@ pub enum Message {
     Piece(PieceChunk),
     Cancel(Vec<u8>),
     Reject,
+    Extended(u8, Vec<u8>),
     HashRequest,
     Hashes(Vec<u8>),
     HashReject,
     KeepAlive,
 }
 
-impl From<MessageInner> for Message {
-    fn from(inner: MessageInner) -> Self {
-        match inner.id {
+impl TryFrom<MessageInner> for Message {
+    type Error = MessageError;
+
+    fn try_from(inner: MessageInner) -> Result<Self, MessageError> {
+        // Shortest payload of each message, anything less comes from a broken peer.
+        let min_length = match inner.id {
+            MessageId::MsgHave => 4,
+            MessageId::MsgRequest | MessageId::MsgCancel => 12,
+            MessageId::MsgPiece => 8,
+            MessageId::MsgExtended => 1,
+            _ => 0,
+        };
+        if inner.payload.len() < min_length {
+            return Err(MessageError::InvalidPayload(format!(
+                "{} payload of {} bytes",
+                inner,
+                inner.payload.len()
+            )));
+        }
+
+        Ok(match inner.id {
             MessageId::MsgChoke => Message::Choke,
             MessageId::MsgUnchoke => Message::Unchoke,
             MessageId::MsgInterested => Message::Interested,
@@ -67,10 +98,13 @@ impl From<MessageInner> for Message {
             }
             MessageId::MsgCancel => Message::Cancel(inner.payload[0..12].to_vec()),
             MessageId::MsgReject => Message::Reject,
+            MessageId::MsgExtended => {
+                Message::Extended(inner.payload[0], inner.payload[1..].to_vec())
+            }
             MessageId::MsgHashRequest => Message::HashRequest,
             MessageId::MsgHashes => Message::Hashes(inner.payload),
             MessageId::MsgHashReject => Message::HashReject,
-        }
+        })
     }
 }
 
@@ -93,6 +127,7 @@ impl Display for MessageInner {
             MessageId::MsgPiece => "PIECE",
             MessageId::MsgCancel => "CANCEL",
             MessageId::MsgReject => "REJECT",
+            MessageId::MsgExtended => "EXTENDED",
             MessageId::MsgHashRequest => "HASH_REQUEST",
             MessageId::MsgHashes => "HASHES",
             MessageId::MsgHashReject => "HASH_REJECT",
@@ -101,9 +136,11 @@ impl Display for MessageInner {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Error, Debug, Clone, PartialEq)]
 pub enum MessageError {
+    #[error("invalid message id {0}")]
     InvalidMessageId(String),
+    #[error("invalid payload: {0}")]
     InvalidPayload(String),
 }
 
@@ -116,6 +153,27 @@ pub fn format_request(index: u32, start: u32, length: u32) -> Message {
     Message::Request(payload)
 }
 
+pub fn format_cancel(index: u32, start: u32, length: u32) -> Message {
+    let mut payload = Vec::with_capacity(12);
+    payload.extend_from_slice(&index.to_be_bytes());
+    payload.extend_from_slice(&start.to_be_bytes());
+    payload.extend_from_slice(&length.to_be_bytes());
+
+    Message::Cancel(payload)
+}
+
+/// Parses the payload of a request or cancel message.
+pub fn parse_chunk_info(payload: &[u8]) -> Option<ChunkInfo> {
+    if payload.len() < 12 {
+        return None;
+    }
+    Some(ChunkInfo {
+        index: u32::from_be_bytes(payload[0..4].try_into().unwrap()),
+        begin: u32::from_be_bytes(payload[4..8].try_into().unwrap()),
+        length: u32::from_be_bytes(payload[8..12].try_into().unwrap()),
+    })
+}
+
 pub fn format_have(index: u32) -> Message {
     let mut payload = Vec::with_capacity(4);
     payload.extend_from_slice(&index.to_be_bytes());
@@ -219,6 +277,12 @@ pub fn serialize(msg: Option<Message>) -> Vec<u8> {
                 }
                 Message::Cancel(payload) => (MessageId::MsgCancel, payload),
                 Message::Reject => (MessageId::MsgReject, vec![]),
+                Message::Extended(id, data) => {
+                    let mut payload = Vec::with_capacity(1 + data.len());
+                    payload.push(id);
+                    payload.extend_from_slice(&data);
+                    (MessageId::MsgExtended, payload)
+                }
                 Message::HashRequest => (MessageId::MsgHashRequest, vec![]),
                 Message::Hashes(payload) => (MessageId::MsgHashes, payload),
                 Message::HashReject => (MessageId::MsgHashReject, vec![]),
@@ -236,13 +300,32 @@ pub fn serialize(msg: Option<Message>) -> Vec<u8> {
 }
 
 pub fn read(length_buf: &[u8], message_buf: &[u8]) -> Option<Message> {
-    let length = u32::from_be_bytes(length_buf.try_into().unwrap());
+    try_read(length_buf, message_buf).ok().flatten()
+}
+
+/// Parses a message, `None` being a keep-alive.
+pub fn try_read(length_buf: &[u8], message_buf: &[u8]) -> Result<Option<Message>, MessageError> {
+    let length = match length_buf.try_into() {
+        Ok(length) => u32::from_be_bytes(length) as usize,
+        Err(_) => {
+            return Err(MessageError::InvalidPayload(format!(
+                "length prefix of {} bytes",
+                length_buf.len()
+            )))
+        }
+    };
     match length {
-        0 => None,
+        0 => Ok(None),
         _ => {
-            let char_code = &message_buf[0..];
-            let id = char_code[0];
-            let payload = message_buf[1..(length) as usize].into();
+            if message_buf.len() < length {
+                return Err(MessageError::InvalidPayload(format!(
+                    "message of {} bytes, expected {}",
+                    message_buf.len(),
+                    length
+                )));
+            }
+            let id = message_buf[0];
+            let payload = message_buf[1..length].into();
 
             let message_id = match id {
                 0 => MessageId::MsgChoke,
@@ -255,21 +338,21 @@ pub fn read(length_buf: &[u8], message_buf: &[u8]) -> Option<Message> {
                 7 => MessageId::MsgPiece,
                 8 => MessageId::MsgCancel,
                 16 => MessageId::MsgReject,
+                20 => MessageId::MsgExtended,
                 21 => MessageId::MsgHashRequest,
                 22 => MessageId::MsgHashes,
                 23 => MessageId::MsgHashReject,
                 _ => {
-                    return None;
+                    return Err(MessageError::InvalidMessageId(id.to_string()));
                 }
             };
 
-            Some(
-                (MessageInner {
-                    id: message_id,
-                    payload,
-                })
-                .into(),
-            )
+            MessageInner {
+                id: message_id,
+                payload,
+            }
+            .try_into()
+            .map(Some)
         }
     }
 }
@@ -293,6 +376,24 @@ mod tests {
         assert!(matches!(msg, Message::Request(payload) if payload == expected));
     }
 
+    #[test]
+    fn parse_chunk_info_test() {
+        let msg = format_request(4, 567, 4321);
+        let Message::Request(payload) = msg else {
+            panic!("expected a request");
+        };
+
+        assert_eq!(
+            parse_chunk_info(&payload),
+            Some(ChunkInfo {
+                index: 4,
+                begin: 567,
+                length: 4321,
+            })
+        );
+        assert_eq!(parse_chunk_info(&payload[..8]), None);
+    }
+
     #[test]
     fn format_have_test() {
         let index = 4;
@@ -360,4 +461,39 @@ mod tests {
         let result = read(&length_buf, &message_buf);
         assert_eq!(result, Some(expected));
     }
+
+    #[test]
+    fn read_extended_test() {
+        let length_buf = vec![0x00, 0x00, 0x00, 0x04];
+        let message_buf = vec![0x14, 0x00, b'd', b'e'];
+        let expected = Message::Extended(0, vec![b'd', b'e']);
+
+        let result = read(&length_buf, &message_buf);
+        assert_eq!(result, Some(expected));
+    }
+
+    #[test]
+    fn read_empty_extended_test() {
+        // An extended message without its extended id is refused, not a panic.
+        let result = try_read(&[0x00, 0x00, 0x00, 0x01], &[0x14]);
+        assert!(matches!(result, Err(MessageError::InvalidPayload(_))));
+        assert_eq!(read(&[0x00, 0x00, 0x00, 0x01], &[0x14]), None);
+    }
+
+    #[test]
+    fn read_invalid_test() {
+        // A have message without its index.
+        let result = try_read(&[0x00, 0x00, 0x00, 0x02], &[0x04, 0x00]);
+        assert!(matches!(result, Err(MessageError::InvalidPayload(_))));
+
+        let result = try_read(&[0x00, 0x00, 0x00, 0x01], &[0xff]);
+        assert_eq!(
+            result,
+            Err(MessageError::InvalidMessageId("255".to_string()))
+        );
+
+        // Shorter than its length prefix.
+        let result = try_read(&[0x00, 0x00, 0x00, 0x05], &[0x04]);
+        assert!(result.is_err());
+    }
 }
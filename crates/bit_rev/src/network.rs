@@ -0,0 +1,341 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+
+/// Connections waiting to be accepted.
+const LISTEN_BACKLOG: i32 = 1024;
+/// A global address we route towards to learn our own IPv6 address. Nothing
+/// is sent to it.
+const IPV6_PROBE: SocketAddr = SocketAddr::new(
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888)),
+    53,
+);
+
+/// Where our connections go out from and the listener binds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BindTo {
+    /// A network interface, e.g. `tun0`. Nothing goes through another
+    /// route while it is down.
+    Interface(String),
+    /// A local address, which also decides the address family we can
+    /// reach.
+    Address(IpAddr),
+}
+
+/// Opens the sockets of a session, all bound the same way.
+#[derive(Debug, Clone, Default)]
+pub struct Network {
+    bind: Option<BindTo>,
+}
+
+impl Network {
+    pub fn new(bind: Option<BindTo>) -> Network {
+        Network { bind }
+    }
+
+    pub fn bind(&self) -> Option<&BindTo> {
+        self.bind.as_ref()
+    }
+
+    /// Bound to an IPv6 address, so only IPv6 peers are reachable.
+    pub fn is_ipv6(&self) -> bool {
+        matches!(self.bind, Some(BindTo::Address(IpAddr::V6(_))))
+    }
+
+    /// Opens a TCP connection to `addr`.
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        if self.bind.is_none() {
+            return TcpStream::connect(addr).await;
+        }
+        let socket = self.socket(addr.is_ipv6(), Type::STREAM, Protocol::TCP)?;
+        socket.bind(&self.local_addr(addr.is_ipv6(), 0)?.into())?;
+        TcpSocket::from_std_stream(socket.into())
+            .connect(addr)
+            .await
+    }
+
+    /// Opens a TCP connection to `host:port`, trying each of its addresses.
+    pub async fn connect_host(&self, host: &str) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        for addr in tokio::net::lookup_host(host).await? {
+            match self.connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", host))
+        }))
+    }
+
+    /// Listens for peers on `port`, 0 for any free port. Unless we are bound
+    /// to an address, IPv4 and IPv6 peers share the listener, or only IPv4
+    /// ones if the machine has no IPv6.
+    pub fn listen(&self, port: u16) -> io::Result<TcpListener> {
+        if matches!(self.bind, Some(BindTo::Address(_))) {
+            return self.listen_on(self.is_ipv6(), port);
+        }
+        self.listen_on(true, port)
+            .or_else(|_| self.listen_on(false, port))
+    }
+
+    fn listen_on(&self, ipv6: bool, port: u16) -> io::Result<TcpListener> {
+        let socket = self.socket(ipv6, Type::STREAM, Protocol::TCP)?;
+        if ipv6 {
+            socket.set_only_v6(false)?;
+        }
+        // Like `TcpListener::bind`, so a restart doesn't wait for TIME_WAIT.
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        socket.bind(&self.local_addr(ipv6, port)?.into())?;
+        socket.listen(LISTEN_BACKLOG)?;
+        TcpListener::from_std(socket.into())
+    }
+
+    /// A UDP socket on `port` talking to IPv4, or IPv6 addresses. Both can
+    /// use the same port.
+    pub fn udp(&self, ipv6: bool, port: u16) -> io::Result<UdpSocket> {
+        let socket = self.socket(ipv6, Type::DGRAM, Protocol::UDP)?;
+        if ipv6 {
+            socket.set_only_v6(true)?;
+        }
+        socket.bind(&self.local_addr(ipv6, port)?.into())?;
+        UdpSocket::from_std(socket.into())
+    }
+
+    /// Whether IPv6, or IPv4, addresses are reachable from our binding.
+    pub fn can_reach(&self, ipv6: bool) -> bool {
+        match &self.bind {
+            Some(BindTo::Address(ip)) => ip.is_ipv6() == ipv6,
+            _ => true,
+        }
+    }
+
+    /// Our global IPv6 address, if we have a route to the IPv6 internet.
+    pub async fn local_ipv6(&self) -> Option<Ipv6Addr> {
+        if !self.can_reach(true) {
+            return None;
+        }
+        // Connecting a UDP socket only picks the route.
+        let socket = self.udp(true, 0).ok()?;
+        socket.connect(IPV6_PROBE).await.ok()?;
+        match socket.local_addr().ok()?.ip() {
+            IpAddr::V6(ip) if is_global_ipv6(&ip) => Some(ip),
+            _ => None,
+        }
+    }
+
+    /// The source address of the tracker HTTP client, which can't bind to
+    /// an interface. An interface is looked up again on every call, see
+    /// `tracker::HttpClient`.
+    pub fn http_address(&self) -> io::Result<Option<IpAddr>> {
+        match &self.bind {
+            None => Ok(None),
+            Some(BindTo::Address(ip)) => Ok(Some(*ip)),
+            Some(BindTo::Interface(name)) => {
+                let addrs = interface_addrs(name)?;
+                let ipv4 = addrs.iter().find(|ip| ip.is_ipv4());
+                let ipv6 = addrs.iter().find(|ip| match ip {
+                    IpAddr::V6(ip) => !is_link_local(ip),
+                    IpAddr::V4(_) => false,
+                });
+                match ipv4.or(ipv6) {
+                    Some(ip) => Ok(Some(*ip)),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::AddrNotAvailable,
+                        format!("interface {} has no address", name),
+                    )),
+                }
+            }
+        }
+    }
+
+    /// A non-blocking socket, bound to our interface if we have one.
+    pub(crate) fn socket(&self, ipv6: bool, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+        let domain = if ipv6 { Domain::IPV6 } else { Domain::IPV4 };
+        let socket = Socket::new(domain, ty, Some(protocol))?;
+        socket.set_nonblocking(true)?;
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(BindTo::Interface(name)) = &self.bind {
+            socket.bind_device(Some(name.as_bytes()))?;
+        }
+        Ok(socket)
+    }
+
+    /// The address to bind a socket of the family to.
+    pub(crate) fn local_addr(&self, ipv6: bool, port: u16) -> io::Result<SocketAddr> {
+        let unspecified = if ipv6 {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        };
+        let ip = match &self.bind {
+            None => unspecified,
+            Some(BindTo::Address(ip)) if ip.is_ipv6() == ipv6 => *ip,
+            Some(BindTo::Address(ip)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!(
+                        "{} can't reach IPv{} addresses",
+                        ip,
+                        if ipv6 { 6 } else { 4 }
+                    ),
+                ));
+            }
+            // The socket is bound to the device already.
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            Some(BindTo::Interface(_)) => unspecified,
+            // Elsewhere the best we can do is the address of the interface.
+            #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+            Some(BindTo::Interface(name)) => interface_addrs(name)?
+                .into_iter()
+                .find(|ip| ip.is_ipv6() == ipv6)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::AddrNotAvailable,
+                        format!(
+                            "interface {} has no IPv{} address",
+                            name,
+                            if ipv6 { 6 } else { 4 }
+                        ),
+                    )
+                })?,
+        };
+        Ok(SocketAddr::new(ip, port))
+    }
+}
+
+fn is_link_local(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Whether peers elsewhere can reach us at `ip`.
+fn is_global_ipv6(ip: &Ipv6Addr) -> bool {
+    !ip.is_unspecified() && !ip.is_loopback() && !is_link_local(ip) && ip.to_ipv4_mapped().is_none()
+}
+
+/// Addresses of the interface `name`, none if it doesn't exist or is down.
+#[cfg(unix)]
+pub fn interface_addrs(name: &str) -> io::Result<Vec<IpAddr>> {
+    let mut addrs = Vec::new();
+    let mut ifaddrs = std::ptr::null_mut();
+    // Safety: getifaddrs gives a list we only read until it is freed, and
+    // every address is as long as its family says.
+    unsafe {
+        if libc::getifaddrs(&mut ifaddrs) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut entry = ifaddrs;
+        while let Some(ifaddr) = entry.as_ref() {
+            entry = ifaddr.ifa_next;
+            if ifaddr.ifa_addr.is_null()
+                || ifaddr.ifa_flags & libc::IFF_UP as libc::c_uint == 0
+                || std::ffi::CStr::from_ptr(ifaddr.ifa_name).to_bytes() != name.as_bytes()
+            {
+                continue;
+            }
+            match i32::from((*ifaddr.ifa_addr).sa_family) {
+                libc::AF_INET => {
+                    let addr = &*(ifaddr.ifa_addr as *const libc::sockaddr_in);
+                    addrs.push(IpAddr::from(addr.sin_addr.s_addr.to_ne_bytes()));
+                }
+                libc::AF_INET6 => {
+                    let addr = &*(ifaddr.ifa_addr as *const libc::sockaddr_in6);
+                    addrs.push(IpAddr::from(addr.sin6_addr.s6_addr));
+                }
+                _ => {}
+            }
+        }
+        libc::freeifaddrs(ifaddrs);
+    }
+    Ok(addrs)
+}
+
+#[cfg(not(unix))]
+pub fn interface_addrs(name: &str) -> io::Result<Vec<IpAddr>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("can't look up the addresses of interface {}", name),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn dual_stack_listener() {
+        let listener = Network::default().listen(0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        for ip in [
+            IpAddr::from([127, 0, 0, 1]),
+            IpAddr::from(Ipv6Addr::LOCALHOST),
+        ] {
+            let stream = Network::default()
+                .connect(SocketAddr::new(ip, port))
+                .await
+                .unwrap();
+            let (_, peer) = listener.accept().await.unwrap();
+            // IPv4 peers show up as mapped addresses.
+            assert_eq!(peer.ip().to_canonical(), stream.local_addr().unwrap().ip());
+        }
+
+        assert!(is_global_ipv6(&"2001:db8::1".parse().unwrap()));
+        assert!(!is_global_ipv6(&"fe80::1".parse().unwrap()));
+        assert!(!is_global_ipv6(&"::ffff:10.0.0.1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn bound_to_an_address() {
+        let network = Network::new(Some(BindTo::Address("127.0.0.1".parse().unwrap())));
+        let listener = network.listen(0).unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert_eq!(addr.ip(), IpAddr::from([127, 0, 0, 1]));
+
+        let stream = network.connect(addr).await.unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), addr.ip());
+        assert_eq!(network.http_address().unwrap(), Some(addr.ip()));
+
+        // An IPv4 address can't reach IPv6 peers, rather than going around
+        // the binding.
+        let error = network.connect("[::1]:6881".parse().unwrap()).await;
+        assert_eq!(error.unwrap_err().kind(), io::ErrorKind::AddrNotAvailable);
+        assert!(network.udp(true, 0).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn bound_to_an_interface() {
+        assert!(interface_addrs("lo")
+            .unwrap()
+            .contains(&IpAddr::from([127, 0, 0, 1])));
+
+        let network = Network::new(Some(BindTo::Interface("lo".to_string())));
+        assert_eq!(
+            network.http_address().unwrap(),
+            Some(IpAddr::from([127, 0, 0, 1]))
+        );
+        let listener = network.listen(0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        network
+            .connect(SocketAddr::from(([127, 0, 0, 1], port)))
+            .await
+            .unwrap();
+
+        // A missing interface stops the traffic instead of using the
+        // default route.
+        let missing = Network::new(Some(BindTo::Interface("bitrev-none0".to_string())));
+        assert!(missing
+            .connect(SocketAddr::from(([127, 0, 0, 1], port)))
+            .await
+            .is_err());
+        assert!(missing.udp(false, 0).is_err());
+        assert!(missing.http_address().is_err());
+    }
+}
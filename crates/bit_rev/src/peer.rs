@@ -1,76 +1,276 @@
-use std::net::SocketAddr;
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
 
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 use serde_bytes::ByteBuf;
 
+use crate::tracker::TrackerError;
+
 pub type PeerAddr = SocketAddr;
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+/// The reply to an HTTP announce. Trackers that fail only send
+/// `failure reason`, everything else is optional.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
 pub struct BencodeResponse {
-    pub peers: ByteBuf,
+    #[serde(
+        default,
+        rename = "failure reason",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub failure_reason: Option<String>,
+    /// The announce went through, but the tracker has something to say.
+    #[serde(
+        default,
+        rename = "warning message",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub warning_message: Option<String>,
+    /// Seconds until the next regular announce.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+    /// Seconds we must wait at least between two announces.
+    #[serde(
+        default,
+        rename = "min interval",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub min_interval: Option<u64>,
+    /// To send back in our next announces.
+    #[serde(
+        default,
+        rename = "tracker id",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub tracker_id: Option<ByteBuf>,
+    /// Seeders in the swarm.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub complete: Option<u64>,
+    /// Leechers in the swarm.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incomplete: Option<u64>,
+    #[serde(default)]
+    pub peers: TrackerPeerList,
+    /// Compact IPv6 peers, BEP 7.
+    #[serde(default, skip_serializing_if = "is_empty")]
     pub peers6: ByteBuf,
-    pub interval: u64,
+    /// Our address as the tracker sees it, BEP 24.
+    #[serde(
+        default,
+        rename = "external ip",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub external_ip: Option<ByteBuf>,
 }
 
-impl BencodeResponse {
-    pub fn get_peers(self) -> anyhow::Result<Vec<PeerAddr>> {
-        // TODO: This is a bit of a mess
-        let peers_bin = self.peers;
-        let peer_size = 6;
-        let peers_bin_length = peers_bin.len();
-        let num_peers = peers_bin_length / peer_size;
-        if peers_bin_length % peer_size != 0 {
-            anyhow::bail!("Received empty peers");
-        }
+/// The `peers` of a response, either compact (BEP 23) or the original list of
+/// dictionaries.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrackerPeerList {
+    Compact(ByteBuf),
+    Dicts(Vec<DictPeer>),
+}
 
-        let peers_bin6 = self.peers6;
-        let peer_size6 = 18;
-        let peers_bin_length6 = peers_bin6.len();
-        let num_peers6 = peers_bin_length6 / peer_size6;
-        if peers_bin_length6 % peer_size6 != 0 {
-            anyhow::bail!("Received empty peers");
-        }
+impl Default for TrackerPeerList {
+    fn default() -> Self {
+        TrackerPeerList::Compact(ByteBuf::new())
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct DictPeer {
+    #[serde(default, rename = "peer id", skip_serializing_if = "Option::is_none")]
+    pub peer_id: Option<ByteBuf>,
+    /// An IP address, or a DNS name we don't resolve.
+    pub ip: String,
+    pub port: u16,
+}
 
-        let mut peers = Vec::new();
-        for i in 0..num_peers {
-            let ip_size = 4;
-            let offset = i * peer_size;
-            let ip_bin = &peers_bin[offset..offset + ip_size];
-            let port =
-                u16::from_be_bytes([peers_bin[offset + ip_size], peers_bin[offset + ip_size + 1]]);
-            let ip_array = [ip_bin[0], ip_bin[1], ip_bin[2], ip_bin[3]];
-            let ip = std::net::Ipv4Addr::new(ip_array[0], ip_array[1], ip_array[2], ip_array[3]);
-            let peer = SocketAddr::new(ip.into(), port);
-            peers.push(peer);
+impl Serialize for TrackerPeerList {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            TrackerPeerList::Compact(bytes) => serializer.serialize_bytes(bytes),
+            TrackerPeerList::Dicts(peers) => serializer.collect_seq(peers),
         }
+    }
+}
+
+impl<'de> Deserialize<'de> for TrackerPeerList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PeersVisitor;
+
+        impl<'de> Visitor<'de> for PeersVisitor {
+            type Value = TrackerPeerList;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a compact peer string or a list of peer dictionaries")
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+                Ok(TrackerPeerList::Compact(ByteBuf::from(v)))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                self.visit_bytes(v.as_bytes())
+            }
 
-        for i in 0..num_peers6 {
-            let ip_size = 16;
-            let offset = i * peer_size6;
-            let ip_bin = &peers_bin6[offset..offset + ip_size];
-            let port = u16::from_be_bytes([
-                peers_bin6[offset + ip_size],
-                peers_bin6[offset + ip_size + 1],
-            ]);
-            let ip_array = [
-                ip_bin[0], ip_bin[1], ip_bin[2], ip_bin[3], ip_bin[4], ip_bin[5], ip_bin[6],
-                ip_bin[7], ip_bin[8], ip_bin[9], ip_bin[10], ip_bin[11], ip_bin[12], ip_bin[13],
-                ip_bin[14], ip_bin[15],
-            ];
-            let ip = std::net::Ipv6Addr::new(
-                u16::from_be_bytes([ip_array[0], ip_array[1]]),
-                u16::from_be_bytes([ip_array[2], ip_array[3]]),
-                u16::from_be_bytes([ip_array[4], ip_array[5]]),
-                u16::from_be_bytes([ip_array[6], ip_array[7]]),
-                u16::from_be_bytes([ip_array[8], ip_array[9]]),
-                u16::from_be_bytes([ip_array[10], ip_array[11]]),
-                u16::from_be_bytes([ip_array[12], ip_array[13]]),
-                u16::from_be_bytes([ip_array[14], ip_array[15]]),
-            );
-            let peer = SocketAddr::new(ip.into(), port);
-            peers.push(peer);
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut peers = Vec::new();
+                while let Some(peer) = seq.next_element()? {
+                    peers.push(peer);
+                }
+                Ok(TrackerPeerList::Dicts(peers))
+            }
         }
 
+        deserializer.deserialize_any(PeersVisitor)
+    }
+}
+
+impl BencodeResponse {
+    pub fn get_peers(&self) -> Result<Vec<PeerAddr>, TrackerError> {
+        let mut peers = match &self.peers {
+            TrackerPeerList::Compact(bytes) => parse_compact_peers(bytes, 4)?,
+            TrackerPeerList::Dicts(dicts) => dicts
+                .iter()
+                .filter_map(|peer| {
+                    let ip = peer.ip.parse::<IpAddr>().ok()?;
+                    Some(SocketAddr::new(ip, peer.port))
+                })
+                .collect(),
+        };
+        peers.extend(parse_compact_peers(&self.peers6, 16)?);
         Ok(peers)
     }
+
+    /// Our address as the tracker sees it.
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        let bytes = self.external_ip.as_ref()?;
+        match bytes.len() {
+            4 => Some(Ipv4Addr::from(<[u8; 4]>::try_from(&bytes[..]).ok()?).into()),
+            16 => Some(Ipv6Addr::from(<[u8; 16]>::try_from(&bytes[..]).ok()?).into()),
+            _ => None,
+        }
+    }
+}
+
+fn is_empty(bytes: &ByteBuf) -> bool {
+    bytes.is_empty()
+}
+
+/// Addresses of `ip_len` bytes, each followed by a big-endian port.
+pub fn parse_compact_peers(bytes: &[u8], ip_len: usize) -> Result<Vec<PeerAddr>, TrackerError> {
+    let peer_len = ip_len + 2;
+    if !bytes.len().is_multiple_of(peer_len) {
+        return Err(TrackerError::InvalidPeers(bytes.len()));
+    }
+
+    Ok(bytes
+        .chunks_exact(peer_len)
+        .map(|peer| {
+            let (ip, port) = peer.split_at(ip_len);
+            let ip: IpAddr = match ip_len {
+                4 => Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]).into(),
+                _ => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(ip);
+                    Ipv6Addr::from(octets).into()
+                }
+            };
+            SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_response() {
+        let res: BencodeResponse = serde_bencode::from_bytes(
+            b"d8:completei5e10:incompletei3e8:intervali1800e12:min intervali60e\
+              5:peers6:\x7f\x00\x00\x01\x1a\xe16:peers618:\x20\x01\x0d\xb8\x00\x00\x00\x00\
+              \x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe210:tracker id3:abc\
+              11:external ip4:\x01\x02\x03\x04e",
+        )
+        .unwrap();
+
+        assert_eq!(res.interval, Some(1800));
+        assert_eq!(res.min_interval, Some(60));
+        assert_eq!((res.complete, res.incomplete), (Some(5), Some(3)));
+        assert_eq!(res.tracker_id, Some(ByteBuf::from(b"abc".to_vec())));
+        assert_eq!(res.external_ip(), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(
+            res.get_peers().unwrap(),
+            vec![
+                "127.0.0.1:6881".parse().unwrap(),
+                "[2001:db8::1]:6882".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn dictionary_peers_without_peers6() {
+        let res: BencodeResponse = serde_bencode::from_bytes(
+            b"d8:intervali900e5:peersld2:ip9:127.0.0.17:peer id20:-BR0001-abcdefghijkl\
+              4:porti6881eed2:ip11:example.com4:porti6882eed2:ip3:::14:porti6883eeee",
+        )
+        .unwrap();
+
+        assert_eq!(
+            res.get_peers().unwrap(),
+            vec![
+                "127.0.0.1:6881".parse().unwrap(),
+                "[::1]:6883".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn failure_and_warning() {
+        let res: BencodeResponse =
+            serde_bencode::from_bytes(b"d14:failure reason12:unregisterede").unwrap();
+        assert_eq!(res.failure_reason.as_deref(), Some("unregistered"));
+        assert_eq!(res.get_peers().unwrap(), vec![]);
+
+        let res: BencodeResponse =
+            serde_bencode::from_bytes(b"d8:intervali900e5:peers0:15:warning message4:slowe")
+                .unwrap();
+        assert_eq!(res.warning_message.as_deref(), Some("slow"));
+    }
+
+    #[test]
+    fn truncated_peers() {
+        let res = BencodeResponse {
+            peers: TrackerPeerList::Compact(ByteBuf::from(vec![0u8; 7])),
+            ..Default::default()
+        };
+        assert!(matches!(
+            res.get_peers(),
+            Err(TrackerError::InvalidPeers(7))
+        ));
+    }
+
+    #[test]
+    fn response_roundtrip() {
+        let res = BencodeResponse {
+            interval: Some(1800),
+            peers: TrackerPeerList::Dicts(vec![DictPeer {
+                peer_id: None,
+                ip: "10.0.0.1".to_string(),
+                port: 6881,
+            }]),
+            ..Default::default()
+        };
+        let bytes = serde_bencode::to_bytes(&res).unwrap();
+        assert_eq!(
+            serde_bencode::from_bytes::<BencodeResponse>(&bytes).unwrap(),
+            res
+        );
+    }
 }
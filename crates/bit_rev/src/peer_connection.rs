@@ -1,11 +1,13 @@
 use std::{
+    collections::{HashMap, HashSet},
     sync::{
-        atomic::{AtomicBool, AtomicU32},
+        atomic::{AtomicBool, AtomicU8, Ordering},
         Arc, Mutex,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use thiserror::Error;
 use tokio::{
     io::AsyncWriteExt,
     net::TcpStream,
@@ -15,18 +17,75 @@ use tokio::{
 use tracing::{debug, error, trace};
 
 use crate::{
+    ban::PeerBans,
     bitfield::Bitfield,
-    message::{self, Message, WriterRequest},
+    config::TorrentConfig,
+    connection_manager::{PeerSource, TorrentConnections},
+    events::{EventKind, TorrentEvents},
+    extension::{self, ExtendedHandshake, PexMessage},
+    handshake::Handshake,
+    message::{self, Message, PieceChunk, WriterRequest},
+    network::Network,
     peer::PeerAddr,
+    peer_id::{ClientFilter, ClientInfo},
     peer_state::{PeerState, PeerStates},
-    protocol::{Protocol, ProtocolError},
+    pipeline::RequestPipeline,
+    protocol::{self, Protocol, ProtocolError},
+    proxy::{ProxyConfig, ProxyError},
+    rate_limit::PeerRateLimiter,
     session::PieceWork,
+    stats::{CountingReader, TransferStats},
+    storage::{Storage, StorageError},
+    streaming::StreamFocus,
     utils,
 };
 
+#[derive(Error, Debug)]
+pub enum PeerError {
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("invalid extended message: {0}")]
+    Extension(#[from] serde_bencode::Error),
+    #[error("invalid request message")]
+    InvalidRequest,
+    #[error("invalid piece message: index {index}, offset {start}, {length} bytes")]
+    InvalidPiece { index: u32, start: u32, length: u32 },
+    #[error("client {0:?} is not allowed")]
+    ClientNotAllowed(Option<ClientInfo>),
+    #[error("peer is banned")]
+    Banned,
+    #[error("peer sent too many corrupted pieces")]
+    TooManyCorruptPieces,
+    #[error("peer asked for an unknown torrent")]
+    UnknownTorrent,
+    #[error("channel closed")]
+    ChannelClosed,
+    #[error(transparent)]
+    Proxy(#[from] ProxyError),
+}
+
+impl<T> From<flume::SendError<T>> for PeerError {
+    fn from(_: flume::SendError<T>) -> Self {
+        PeerError::ChannelClosed
+    }
+}
+
+/// Largest block we serve in one piece message, bigger requests are ignored.
+const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+
 pub struct TorrentDownloadedState {
     pub semaphore: Semaphore,
     pub pieces: Vec<PieceWorkState>,
+    /// Pieces wanted first, by readers and in sequential mode.
+    pub focus: Mutex<StreamFocus>,
+    /// Woken every time a piece is verified.
+    pub verified_notify: Notify,
+    /// How long a peer is kept off a piece after a request for it timed out.
+    pub timed_out_retry: Duration,
 }
 
 impl TorrentDownloadedState {
@@ -66,8 +125,26 @@ impl TorrentDownloadedState {
         //    }
         //}
 
-        for pw in self.pieces.iter() {
-            if pw.downloaded.load(std::sync::atomic::Ordering::Relaxed) {
+        for (index, overdue) in self.urgent_pieces() {
+            let pw = &self.pieces[index as usize];
+            if pw.timed_out_on(peer, self.timed_out_retry) {
+                continue;
+            }
+            if self.try_reserve(pw, peer) {
+                return Some(pw);
+            }
+            // Too late already, ask this peer as well.
+            if overdue && *pw.reserved.lock().unwrap() != Some(peer) {
+                return Some(pw);
+            }
+        }
+
+        let start = self.focus.lock().unwrap().start() as usize;
+        let (after, before) = self.pieces.split_at(start.min(self.pieces.len()));
+        for pw in after.iter().chain(before) {
+            if pw.downloaded.load(std::sync::atomic::Ordering::Relaxed)
+                || pw.timed_out_on(peer, self.timed_out_retry)
+            {
                 continue;
             }
 
@@ -87,7 +164,7 @@ impl TorrentDownloadedState {
             //    }
             //}
 
-            if reserved.is_some() {
+            if reserved.is_some() || pw.fetching.load(std::sync::atomic::Ordering::Relaxed) {
                 continue;
             }
 
@@ -101,7 +178,9 @@ impl TorrentDownloadedState {
         }
 
         for pw in self.pieces.iter() {
-            if pw.downloaded.load(std::sync::atomic::Ordering::Relaxed) {
+            if pw.downloaded.load(std::sync::atomic::Ordering::Relaxed)
+                || pw.timed_out_on(peer, self.timed_out_retry)
+            {
                 continue;
             }
 
@@ -110,6 +189,63 @@ impl TorrentDownloadedState {
 
         None
     }
+
+    fn try_reserve(&self, pw: &PieceWorkState, peer: PeerAddr) -> bool {
+        if pw.downloaded.load(std::sync::atomic::Ordering::Relaxed)
+            || pw.fetching.load(std::sync::atomic::Ordering::Relaxed)
+        {
+            return false;
+        }
+        let mut reserved = pw.reserved.lock().unwrap();
+        if reserved.is_some() {
+            return false;
+        }
+        reserved.replace(peer);
+        drop(reserved);
+        self.semaphore.add_permits(1);
+        true
+    }
+
+    // The pieces of the readahead windows we miss, see `StreamFocus`.
+    fn urgent_pieces(&self) -> Vec<(u32, bool)> {
+        self.focus
+            .lock()
+            .unwrap()
+            .urgent(self.pieces.len() as u32, Instant::now(), |index| {
+                self.pieces[index as usize]
+                    .downloaded
+                    .load(std::sync::atomic::Ordering::Relaxed)
+            })
+    }
+
+    /// Takes a piece nobody is downloading for a source that fetches whole
+    /// pieces, like a web seed. Peers leave it alone until the endgame, and
+    /// until [`TorrentDownloadedState::release_fetching`].
+    pub fn reserve_for_fetch(&self) -> Option<&PieceWorkState> {
+        let urgent = self.urgent_pieces();
+        let start = self.focus.lock().unwrap().start() as usize;
+        let (after, before) = self.pieces.split_at(start.min(self.pieces.len()));
+        let urgent = urgent
+            .iter()
+            .map(|&(index, _)| &self.pieces[index as usize]);
+        urgent.chain(after).chain(before).find(|pw| {
+            if pw.downloaded.load(std::sync::atomic::Ordering::Relaxed)
+                || pw.reserved.lock().unwrap().is_some()
+                || !pw.chuncks.lock().unwrap().is_empty()
+            {
+                return false;
+            }
+            !pw.fetching.swap(true, std::sync::atomic::Ordering::Relaxed)
+        })
+    }
+
+    pub fn release_fetching(&self, index: u32) {
+        if let Some(pw) = self.pieces.get(index as usize) {
+            pw.fetching
+                .store(false, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
     pub fn remove_downloaded(&self, index: u32) {
         for pw in self.pieces.iter() {
             if pw.piece_work.index == index {
@@ -136,38 +272,127 @@ impl TorrentDownloadedState {
         }
     }
 
-    pub fn set_chuncks(&self, index: u32, start: u32, buf: Vec<u8>) {
+    /// Gives up `peer`'s reservation on a piece after a request for it timed
+    /// out, so other peers pick up the blocks that are still missing. `peer`
+    /// isn't handed the piece again for a while.
+    pub fn release_piece(&self, index: u32, peer: PeerAddr) {
+        if let Some(pw) = self.pieces.get(index as usize) {
+            pw.timed_out.lock().unwrap().insert(peer, Instant::now());
+            let mut reserved = pw.reserved.lock().unwrap();
+            if *reserved == Some(peer) {
+                reserved.take();
+            }
+        }
+    }
+
+    /// Stores a block of a piece. Returns false if we had no use for it.
+    pub fn set_chuncks(&self, index: u32, start: u32, buf: Vec<u8>, peer: PeerAddr) -> bool {
         //let mut chuncks = self.pieces[index as usize].chuncks.lock().unwrap();
-        let mut chuncks = self
-            .pieces
-            .iter()
-            .find(|pw| pw.piece_work.index == index)
-            .unwrap()
-            .chuncks
-            .lock()
-            .unwrap();
+        let Some(pw) = self.pieces.iter().find(|pw| pw.piece_work.index == index) else {
+            return false;
+        };
+        // Late blocks of a piece we already have.
+        if pw.downloaded.load(std::sync::atomic::Ordering::Relaxed) {
+            return false;
+        }
+        let mut chuncks = pw.chuncks.lock().unwrap();
+        // A block can arrive twice when it was re-requested after a choke.
+        if chuncks.iter().any(|c| c.start == start) {
+            return false;
+        }
         chuncks.push(Chunk {
             index,
             start,
             length: buf.len() as u32,
             buf,
+            peer,
         });
+        true
+    }
+
+    /// The piece passed the hash check and is on disk, its blocks can be
+    /// dropped from memory and served to other peers.
+    pub fn set_verified(&self, index: u32) {
+        if let Some(pw) = self.pieces.get(index as usize) {
+            pw.downloaded
+                .store(true, std::sync::atomic::Ordering::Relaxed);
+            pw.verified
+                .store(true, std::sync::atomic::Ordering::Relaxed);
+            pw.chuncks.lock().unwrap().clear();
+            pw.timed_out.lock().unwrap().clear();
+            self.verified_notify.notify_waiters();
+        }
+    }
+
+    /// The piece is no longer on disk, e.g. it failed the check on resume.
+    pub fn set_missing(&self, index: u32) {
+        if let Some(pw) = self.pieces.get(index as usize) {
+            pw.verified
+                .store(false, std::sync::atomic::Ordering::Relaxed);
+            pw.downloaded
+                .store(false, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_verified(&self, index: u32) -> bool {
+        self.pieces
+            .get(index as usize)
+            .is_some_and(|pw| pw.verified.load(std::sync::atomic::Ordering::Relaxed))
+    }
+
+    /// Bytes of the pieces that aren't verified yet.
+    pub fn bytes_left(&self) -> u64 {
+        self.pieces
+            .iter()
+            .filter(|pw| !pw.verified.load(std::sync::atomic::Ordering::Relaxed))
+            .map(|pw| pw.piece_work.length as u64)
+            .sum()
+    }
+
+    /// Every piece is verified and on disk.
+    pub fn is_verified_complete(&self) -> bool {
+        self.pieces
+            .iter()
+            .all(|pw| pw.verified.load(std::sync::atomic::Ordering::Relaxed))
+    }
+
+    /// The pieces we can serve, as sent in a bitfield message.
+    pub fn bitfield(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; self.pieces.len().div_ceil(8)];
+        for (i, pw) in self.pieces.iter().enumerate() {
+            if pw.verified.load(std::sync::atomic::Ordering::Relaxed) {
+                bytes[i / 8] |= 1 << (7 - i % 8);
+            }
+        }
+        bytes
+    }
+
+    /// Whether a block a peer sent fits in one of our pieces.
+    pub fn is_valid_block(&self, index: u32, start: u32, length: u32) -> bool {
+        self.pieces.get(index as usize).is_some_and(|pw| {
+            length > 0 && start as u64 + length as u64 <= pw.piece_work.length as u64
+        })
     }
 
     pub fn set_downloaded_if_all_chunks(&self, index: u32) -> Option<&PieceWorkState> {
+        let pw = self.pieces.get(index as usize)?;
         // check if all chuncks are downloaded
-        if self.pieces[index as usize]
+        if pw
             .chuncks
             .lock()
             .unwrap()
             .iter()
             .fold(0, |acc, c| acc + c.length as usize)
-            == self.pieces[index as usize].piece_work.length as usize
+            == pw.piece_work.length as usize
         {
-            self.pieces[index as usize]
+            // Only hand out the piece once, even if a block shows up twice.
+            if pw
                 .downloaded
-                .store(true, std::sync::atomic::Ordering::Relaxed);
-            return Some(&self.pieces[index as usize]);
+                .swap(true, std::sync::atomic::Ordering::Relaxed)
+            {
+                return None;
+            }
+            return Some(pw);
         }
         None
     }
@@ -177,15 +402,50 @@ pub struct PieceWorkState {
     pub piece_work: PieceWork,
     pub chuncks: Mutex<Vec<Chunk>>,
     pub downloaded: AtomicBool,
+    /// Passed the hash check and written to disk.
+    pub verified: AtomicBool,
     pub reserved: Mutex<Option<PeerAddr>>,
+    /// A web seed is downloading the whole piece.
+    pub fetching: AtomicBool,
+    /// Peers a request for this piece timed out on, and when.
+    pub timed_out: Mutex<HashMap<PeerAddr, Instant>>,
 }
 
 impl PieceWorkState {
+    pub fn new(piece_work: PieceWork) -> Self {
+        Self {
+            piece_work,
+            chuncks: Mutex::new(vec![]),
+            downloaded: AtomicBool::new(false),
+            verified: AtomicBool::new(false),
+            reserved: Mutex::new(None),
+            fetching: AtomicBool::new(false),
+            timed_out: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A request of `peer` for this piece timed out less than `retry` ago.
+    pub fn timed_out_on(&self, peer: PeerAddr, retry: Duration) -> bool {
+        self.timed_out
+            .lock()
+            .unwrap()
+            .get(&peer)
+            .is_some_and(|at| at.elapsed() < retry)
+    }
+
+    pub fn has_chunk(&self, start: u32) -> bool {
+        self.chuncks
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|c| c.start == start)
+    }
+
     pub fn chunk_to_buf(&self) -> Vec<u8> {
         let mut chuncks = self.chuncks.lock().unwrap();
         let mut buf = vec![];
         // sort by start
-        chuncks.sort_by(|a, b| a.start.cmp(&b.start));
+        chuncks.sort_by_key(|c| c.start);
         for chunk in chuncks.iter() {
             buf.extend(chunk.buf.iter());
         }
@@ -198,6 +458,8 @@ pub struct Chunk {
     pub start: u32,
     pub length: u32,
     pub buf: Vec<u8>,
+    /// The peer that sent us this block.
+    pub peer: PeerAddr,
 }
 
 pub struct FullPiece {
@@ -219,36 +481,76 @@ pub struct PeerHandler {
     unchoke_notify: Notify,
     on_bitfield_notify: Notify,
     chocked: AtomicBool,
-    downloaded: AtomicU32,
+    /// We don't serve the requests of the peer, the choker decides.
+    am_choking: AtomicBool,
+    /// Woken when the choker should look at the peers again.
+    rechoke_notify: Option<Arc<Notify>>,
+    stats: Arc<TransferStats>,
+    config: TorrentConfig,
     peers_state: Arc<PeerStates>,
+    peer_bans: Arc<PeerBans>,
+    client_filter: Arc<ClientFilter>,
+    /// Set while we wait for the extended handshake of a peer the allow list
+    /// can't judge from its peer id. Nothing is exchanged with it meanwhile.
+    identify_deadline: Mutex<Option<Instant>>,
+    events: TorrentEvents,
     piece_tx: flume::Sender<FullPiece>,
     peer_writer_tx: flume::Sender<WriterRequest>,
-    requests_sem: Semaphore,
+    pipeline: Mutex<RequestPipeline>,
+    request_slot_notify: Notify,
     peer: PeerAddr,
     torrent_downloaded_state: Arc<TorrentDownloadedState>,
+    pex: Option<Arc<TorrentConnections>>,
+    // The id the peer wants for `ut_pex` messages, 0 until it says it
+    // supports them.
+    peer_pex_id: AtomicU8,
+    pex_notify: Notify,
 }
 
+/// Peers we announce in, or accept from, a single PEX message at most.
+const PEX_MAX_PEERS: usize = 50;
+
 impl PeerHandler {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         peer: PeerAddr,
         unchoked_notify: Notify,
         piece_tx: flume::Sender<FullPiece>,
         peer_writer_tx: flume::Sender<WriterRequest>,
         peers_state: Arc<PeerStates>,
+        peer_bans: Arc<PeerBans>,
+        client_filter: Arc<ClientFilter>,
         //pieces: Vec<PieceWork>,
         torrent_downloaded_state: Arc<TorrentDownloadedState>,
+        events: TorrentEvents,
+        torrent_stats: Arc<TransferStats>,
+        config: TorrentConfig,
     ) -> Self {
         Self {
             unchoke_notify: unchoked_notify,
             on_bitfield_notify: Notify::new(),
-            downloaded: AtomicU32::new(0),
+            stats: Arc::new(TransferStats::with_parent(torrent_stats)),
             chocked: AtomicBool::new(true),
+            am_choking: AtomicBool::new(true),
+            rechoke_notify: None,
             peers_state,
-            requests_sem: Semaphore::new(0),
+            peer_bans,
+            client_filter,
+            identify_deadline: Mutex::new(None),
+            events,
+            pipeline: Mutex::new(RequestPipeline::with_max_depth(
+                config.max_outstanding_requests,
+                config.block_size,
+            )),
+            request_slot_notify: Notify::new(),
             piece_tx,
             peer_writer_tx,
             peer,
             torrent_downloaded_state,
+            config,
+            pex: None,
+            peer_pex_id: AtomicU8::new(0),
+            pex_notify: Notify::new(),
             //torrent_downloaded_state: Arc::new(TorrentDownloadedState {
             //
             //    semaphore: Semaphore::new(1),
@@ -265,9 +567,105 @@ impl PeerHandler {
         }
     }
 
+    /// Exchanges peers with the remote side, learning new ones into
+    /// `connections` and telling it about the ones we're connected to.
+    pub fn with_pex(mut self, connections: Arc<TorrentConnections>) -> Self {
+        self.pex = Some(connections);
+        self
+    }
+
+    /// Asks the choker of the torrent to pick the peers we upload to again
+    /// when this peer's interest changes or it goes away.
+    pub fn with_choker(mut self, rechoke_notify: Arc<Notify>) -> Self {
+        self.rechoke_notify = Some(rechoke_notify);
+        self
+    }
+
+    pub fn peer(&self) -> PeerAddr {
+        self.peer
+    }
+
     pub fn on_peer_died(&self) {
         self.peers_state.states.remove(&self.peer);
         self.torrent_downloaded_state.remove_reserved(self.peer);
+        self.rechoke();
+    }
+
+    fn rechoke(&self) {
+        if let Some(notify) = &self.rechoke_notify {
+            notify.notify_one();
+        }
+    }
+
+    /// Download rate from this peer, in bytes per second.
+    pub fn download_rate(&self) -> f64 {
+        self.pipeline.lock().unwrap().rate()
+    }
+
+    /// Bytes exchanged with this peer, they also count for the torrent.
+    pub fn stats(&self) -> &Arc<TransferStats> {
+        &self.stats
+    }
+
+    /// Chokes or unchokes the peer, telling it when that changes anything.
+    pub fn set_am_choking(&self, choking: bool) -> Result<(), PeerError> {
+        if self.am_choking.swap(choking, Ordering::Relaxed) == choking {
+            return Ok(());
+        }
+        self.update_state(|state| state.am_choking = choking);
+        let msg = if choking {
+            trace!("choking");
+            Message::Choke
+        } else {
+            trace!("unchoking");
+            Message::Unchoke
+        };
+        self.peer_writer_tx.send(WriterRequest::Message(msg))?;
+        Ok(())
+    }
+
+    // Updates what we know about the peer, adding it to the peer states if needed.
+    fn update_state(&self, f: impl FnOnce(&mut PeerState)) {
+        let mut state = self
+            .peers_state
+            .states
+            .entry(self.peer)
+            .or_insert_with(|| PeerState {
+                stats: self.stats.clone(),
+                ..Default::default()
+            });
+        f(&mut state);
+    }
+
+    pub fn on_handshake(&self, handshake: &Handshake) -> Result<(), PeerError> {
+        let client = ClientInfo::from_peer_id(&handshake.peer_id);
+        debug!("peer is running {:?}", client);
+        if client.is_none()
+            && !self.client_filter.allow.is_empty()
+            && handshake.supports_extension_protocol()
+        {
+            // It may still name itself in its extended handshake.
+            *self.identify_deadline.lock().unwrap() =
+                Some(Instant::now() + self.config.identify_timeout);
+            return Ok(());
+        }
+        self.set_client(client)
+    }
+
+    fn is_identifying(&self) -> bool {
+        self.identify_deadline.lock().unwrap().is_some()
+    }
+
+    // Records the remote client and disconnects it if the client filter rejects it.
+    fn set_client(&self, client: Option<ClientInfo>) -> Result<(), PeerError> {
+        *self.identify_deadline.lock().unwrap() = None;
+        let allowed = self.client_filter.is_allowed(client.as_ref());
+        self.update_state(|state| state.client = client.clone());
+
+        if !allowed {
+            return Err(PeerError::ClientNotAllowed(client));
+        }
+        Ok(())
     }
 
     pub fn should_transmit_have(&self, id: u32) -> bool {
@@ -280,19 +678,23 @@ impl PeerHandler {
 
     // The job of this is to request chunks and also to keep peer alive.
     // The moment this ends, the peer is disconnected.
-    pub async fn task_peer_chunk_requester(&self) -> Result<(), anyhow::Error> {
+    pub async fn task_peer_chunk_requester(&self) -> Result<(), PeerError> {
         let notfied = self.on_bitfield_notify.notified();
-        let user_state = self.peers_state.states.get(&self.peer);
-        if let Some(state) = user_state {
-            if state.bitfield.is_empty() {
-                notfied.await;
-            }
+        // Don't hold on to the entry while waiting, the bitfield handler needs it.
+        let bitfield_is_empty = self
+            .peers_state
+            .states
+            .get(&self.peer)
+            .is_some_and(|state| state.bitfield.is_empty());
+        if bitfield_is_empty {
+            notfied.await;
         }
 
         let mut update_interest = {
             let mut current = false;
-            move |h: &PeerHandler, new_value: bool| -> anyhow::Result<()> {
+            move |h: &PeerHandler, new_value: bool| -> Result<(), PeerError> {
                 if new_value != current {
+                    h.update_state(|state| state.am_interested = new_value);
                     h.peer_writer_tx.send(if new_value {
                         trace!("sending interested");
                         WriterRequest::Message(Message::Interested)
@@ -311,14 +713,20 @@ impl PeerHandler {
 
             trace!("waiting for unchoke");
 
-            if self.chocked.load(std::sync::atomic::Ordering::Relaxed) {
-                self.unchoke_notify.notified().await;
-            }
+            self.wait_for_unchoke().await;
             trace!("unchoke received");
 
+            if self.is_identifying() {
+                // No pieces until we know we may talk to it.
+                let _ = timeout(Duration::from_secs(1), self.request_slot_notify.notified()).await;
+                continue;
+            }
+
             if self.torrent_downloaded_state.is_complete() {
                 trace!("TORRENT IS COMPLETE");
-                return Ok(());
+                // Stay connected so the peer can keep downloading from us.
+                update_interest(self, false)?;
+                return std::future::pending().await;
             }
 
             let piece = self
@@ -331,22 +739,36 @@ impl PeerHandler {
                 return Ok(());
             }
 
-            let piece = piece.unwrap().piece_work;
+            let piece_state = piece.unwrap();
+            let piece = piece_state.piece_work;
 
             let mut offset: u32 = 0;
+            let mut requested = false;
             while offset < piece.length {
-                loop {
-                    match (tokio::time::timeout(
-                        Duration::from_secs(5),
-                        self.requests_sem.acquire(),
-                    ))
-                    .await
-                    {
-                        Ok(acq) => break acq?.forget(),
-                        Err(_) => continue,
-                    };
+                // A snubbing peer gets a single request at a time, enough
+                // for it to prove itself again.
+                self.wait_for_request_slot().await;
+
+                if self.chocked.load(std::sync::atomic::Ordering::Relaxed) {
+                    // The peer dropped whatever we had queued, wait to be unchoked
+                    // and go over the piece again, skipping the blocks we already have.
+                    self.wait_for_unchoke().await;
+                    offset = 0;
+                    continue;
+                }
+
+                let block_size =
+                    utils::calculate_block_size(piece.length, offset, self.config.block_size);
+                if piece_state.has_chunk(offset)
+                    || self
+                        .pipeline
+                        .lock()
+                        .unwrap()
+                        .is_outstanding(piece.index, offset)
+                {
+                    offset += block_size;
+                    continue;
                 }
-                let block_size = utils::calculate_block_size(piece.length, offset);
 
                 let r = message::format_request(piece.index, offset, block_size);
 
@@ -354,34 +776,172 @@ impl PeerHandler {
                     "requesting piece index {} start {} length {}",
                     piece.index, offset, block_size
                 );
+                self.pipeline
+                    .lock()
+                    .unwrap()
+                    .on_request_sent(piece.index, offset, block_size);
                 if self.peer_writer_tx.send(WriterRequest::Message(r)).is_err() {
                     error!("error sending request to peer");
                     return Ok(());
                 }
+                requested = true;
                 offset += block_size;
             }
+
+            if !requested {
+                // Everything left is already on its way, wait for some of it
+                // to arrive before looking for more work.
+                let _ = timeout(Duration::from_secs(1), self.request_slot_notify.notified()).await;
+            }
+        }
+    }
+
+    // Gives up on requests the peer sat on for too long, handing their pieces
+    // back to the other peers, and spots peers that stopped sending data at all.
+    pub async fn task_request_timeouts(&self) -> Result<(), PeerError> {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+
+            let identify_deadline = *self.identify_deadline.lock().unwrap();
+            if identify_deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                debug!("peer didn't name its client in time");
+                return Err(PeerError::ClientNotAllowed(None));
+            }
+
+            let (timed_out, snubbed) = self
+                .pipeline
+                .lock()
+                .unwrap()
+                .check_timeouts(self.config.request_timeout, self.config.snub_timeout);
+
+            for block in timed_out.iter() {
+                debug!(
+                    "request timed out, piece index {} start {} length {}",
+                    block.index, block.begin, block.length
+                );
+                let cancel = message::format_cancel(block.index, block.begin, block.length);
+                self.peer_writer_tx.send(WriterRequest::Message(cancel))?;
+                self.torrent_downloaded_state
+                    .release_piece(block.index, self.peer);
+            }
+
+            if snubbed {
+                debug!("peer is snubbing us");
+                if let Some(mut p_state) = self.peers_state.states.get_mut(&self.peer) {
+                    p_state.snubbed = true;
+                }
+                self.torrent_downloaded_state.remove_reserved(self.peer);
+            }
+
+            if !timed_out.is_empty() || snubbed {
+                self.request_slot_notify.notify_one();
+            }
         }
     }
 
-    fn on_received_message(&self, message: crate::message::Message) -> Result<(), anyhow::Error> {
+    // Sends the peer the peers we dialed, then what changed every
+    // `pex_interval`. Never returns if either side doesn't do PEX.
+    pub async fn task_pex(&self) -> Result<(), PeerError> {
+        let Some(connections) = &self.pex else {
+            return std::future::pending().await;
+        };
+        let id = loop {
+            match self.peer_pex_id.load(Ordering::Relaxed) {
+                0 => self.pex_notify.notified().await,
+                id => break id,
+            }
+        };
+
+        let mut sent = HashSet::new();
+        loop {
+            let connected: HashSet<PeerAddr> = connections
+                .outgoing_peers()
+                .into_iter()
+                .filter(|p| *p != self.peer)
+                .collect();
+            let added: Vec<PeerAddr> = connected
+                .difference(&sent)
+                .take(PEX_MAX_PEERS)
+                .copied()
+                .collect();
+            let dropped: Vec<PeerAddr> = sent
+                .difference(&connected)
+                .take(PEX_MAX_PEERS)
+                .copied()
+                .collect();
+
+            if !added.is_empty() || !dropped.is_empty() {
+                trace!("pex: {} added, {} dropped", added.len(), dropped.len());
+                let msg = PexMessage::new(&added, &dropped).to_message(id)?;
+                self.peer_writer_tx
+                    .send_async(WriterRequest::Message(msg))
+                    .await?;
+                sent.extend(added);
+                for peer in dropped {
+                    sent.remove(&peer);
+                }
+            }
+            tokio::time::sleep(self.config.pex_interval).await;
+        }
+    }
+
+    async fn wait_for_unchoke(&self) {
+        loop {
+            let notified = self.unchoke_notify.notified();
+            if !self.chocked.load(std::sync::atomic::Ordering::Relaxed) {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Waits until the pipeline has room for another request, or we got choked.
+    async fn wait_for_request_slot(&self) {
+        loop {
+            let notified = self.request_slot_notify.notified();
+            if self.pipeline.lock().unwrap().has_capacity()
+                || self.chocked.load(std::sync::atomic::Ordering::Relaxed)
+            {
+                return;
+            }
+            // Wake up now and then in case the peer went quiet.
+            let _ = timeout(Duration::from_secs(5), notified).await;
+        }
+    }
+
+    fn on_received_message(&self, message: crate::message::Message) -> Result<(), PeerError> {
+        // We may have been banned because of a piece another peer completed.
+        if self.peer_bans.is_banned(&self.peer) {
+            return Err(PeerError::Banned);
+        }
+
         match message {
             Message::Choke => {
                 debug!("peer choked us");
+                self.update_state(|state| state.peer_choking = true);
                 self.chocked
                     .store(true, std::sync::atomic::Ordering::Relaxed);
+                self.pipeline.lock().unwrap().reset();
+                self.request_slot_notify.notify_waiters();
             }
             Message::Unchoke => {
                 debug!("peer unchoked us");
+                self.update_state(|state| state.peer_choking = false);
                 self.chocked
                     .store(false, std::sync::atomic::Ordering::Relaxed);
                 self.unchoke_notify.notify_waiters();
-                self.requests_sem.add_permits(128);
+                self.request_slot_notify.notify_waiters();
             }
             Message::Interested => {
                 debug!("peer is interested");
+                self.update_state(|state| state.peer_interested = true);
+                self.rechoke();
             }
             Message::NotInterested => {
                 debug!("peer is not interested");
+                self.update_state(|state| state.peer_interested = false);
+                self.rechoke();
             }
             Message::Have(h) => {
                 let p_state = self.peers_state.states.get_mut(&self.peer);
@@ -393,34 +953,74 @@ impl PeerHandler {
             }
             Message::Bitfield(vec) => {
                 debug!("peer sent bitfield");
-                let p_state = self.peers_state.states.get_mut(&self.peer);
-
-                if p_state.is_none() {
-                    self.peers_state.states.insert(
-                        self.peer,
-                        PeerState {
-                            bitfield: Bitfield::new(vec),
-                            peer_interested: false,
-                        },
-                    );
-                } else {
-                    p_state.unwrap().bitfield = Bitfield::new(vec);
-                }
+                self.update_state(|state| state.bitfield = Bitfield::new(vec));
 
                 self.on_bitfield_notify.notify_waiters();
             }
-            Message::Request(_) => {
-                debug!("peer requested piece, not implemented");
+            Message::Request(payload) => {
+                let Some(chunk) = message::parse_chunk_info(&payload) else {
+                    return Err(PeerError::InvalidRequest);
+                };
+                trace!("peer requested {:?}", chunk);
+                // Storage reads across piece boundaries, don't hand out
+                // the next piece or read past the end of the torrent.
+                if !self.torrent_downloaded_state.is_valid_block(
+                    chunk.index,
+                    chunk.begin,
+                    chunk.length,
+                ) {
+                    return Err(PeerError::InvalidRequest);
+                }
+                if self.is_identifying() {
+                    debug!("ignoring request of a peer we can't identify yet");
+                } else if self.am_choking.load(Ordering::Relaxed) {
+                    debug!("ignoring request of a peer we choke");
+                } else if chunk.length > MAX_REQUEST_LENGTH
+                    || !self.torrent_downloaded_state.is_verified(chunk.index)
+                {
+                    debug!("ignoring request for a block we can't serve {:?}", chunk);
+                } else {
+                    self.peer_writer_tx
+                        .send(WriterRequest::ReadChunkRequest(chunk))?;
+                }
             }
             Message::Piece(piece_chunk) => {
-                self.downloaded
-                    .fetch_add(piece_chunk.length, std::sync::atomic::Ordering::Relaxed);
-                self.requests_sem.add_permits(1);
-                self.torrent_downloaded_state.set_chuncks(
+                // The index and offset come straight off the wire.
+                if !self.torrent_downloaded_state.is_valid_block(
+                    piece_chunk.index,
+                    piece_chunk.start,
+                    piece_chunk.length,
+                ) {
+                    return Err(PeerError::InvalidPiece {
+                        index: piece_chunk.index,
+                        start: piece_chunk.start,
+                        length: piece_chunk.length,
+                    });
+                }
+                let was_snubbed = {
+                    let mut pipeline = self.pipeline.lock().unwrap();
+                    let was_snubbed = pipeline.is_snubbed();
+                    pipeline.on_block_received(
+                        piece_chunk.index,
+                        piece_chunk.start,
+                        piece_chunk.length,
+                    );
+                    was_snubbed
+                };
+                if was_snubbed {
+                    if let Some(mut p_state) = self.peers_state.states.get_mut(&self.peer) {
+                        p_state.snubbed = false;
+                    }
+                }
+                self.request_slot_notify.notify_one();
+                if !self.torrent_downloaded_state.set_chuncks(
                     piece_chunk.index,
                     piece_chunk.start,
                     piece_chunk.data,
-                );
+                    self.peer,
+                ) {
+                    self.stats.on_wasted(piece_chunk.length as u64);
+                }
                 if let Some(full_piece) = self
                     .torrent_downloaded_state
                     .set_downloaded_if_all_chunks(piece_chunk.index)
@@ -429,19 +1029,32 @@ impl PeerHandler {
 
                     if utils::check_integrity(full_piece.piece_work.hash.as_ref(), &buf) {
                         trace!("piece index {} is correct", piece_chunk.index);
+                        self.peer_bans.on_piece_passed(
+                            piece_chunk.index,
+                            &full_piece.chuncks.lock().unwrap(),
+                        );
                         let full_piece = FullPiece {
                             index: piece_chunk.index,
                             length: full_piece.piece_work.length,
                             buf,
                         };
 
-                        self.piece_tx.send(full_piece).unwrap();
+                        self.piece_tx.send(full_piece)?;
                     } else {
                         trace!("piece index {} is corrupted", piece_chunk.index);
+                        self.stats.on_failed(buf.len() as u64);
+                        self.events.emit(EventKind::PieceFailed {
+                            index: piece_chunk.index,
+                        });
+                        self.peer_bans.on_piece_failed(
+                            piece_chunk.index,
+                            &full_piece.chuncks.lock().unwrap(),
+                        );
                         self.torrent_downloaded_state
                             .remove_downloaded(piece_chunk.index);
-                        //self.torrent_downloaded_state.remove_reserved(self.peer);
-                        //return Ok(());
+                        if self.peer_bans.is_banned(&self.peer) {
+                            return Err(PeerError::TooManyCorruptPieces);
+                        }
                     }
                 }
 
@@ -453,10 +1066,42 @@ impl PeerHandler {
                     piece_chunk.length
                 );
             }
+            Message::KeepAlive => {
+                trace!("peer sent keep-alive");
+            }
             Message::Cancel(_) => {
                 debug!("peer canceled request");
                 //trace!("peer canceled request");
             }
+            Message::Extended(extension::HANDSHAKE_ID, payload) => {
+                let handshake = ExtendedHandshake::from_bytes(&payload)?;
+                debug!("peer sent extended handshake {:?}", handshake);
+                if let Some(reqq) = handshake.reqq {
+                    let depth = (reqq as usize).min(self.config.max_outstanding_requests);
+                    self.pipeline.lock().unwrap().set_max_depth(depth);
+                }
+                if let Some(id) = handshake.pex_id().filter(|_| self.pex.is_some()) {
+                    self.peer_pex_id.store(id, Ordering::Relaxed);
+                    self.pex_notify.notify_one();
+                }
+                if let Some(v) = handshake.v {
+                    self.set_client(Some(ClientInfo::from_version_string(&v)))?;
+                } else if self.is_identifying() {
+                    self.set_client(None)?;
+                }
+                self.request_slot_notify.notify_one();
+            }
+            Message::Extended(extension::UT_PEX_ID, payload) if self.pex.is_some() => {
+                let pex = PexMessage::from_bytes(&payload)?;
+                let added = pex.added();
+                debug!("peer sent {} peers", added.len());
+                if let Some(connections) = &self.pex {
+                    connections.add_candidates_from(
+                        PeerSource::Pex,
+                        added.into_iter().take(PEX_MAX_PEERS),
+                    );
+                }
+            }
             message => {
                 debug!("received unsupported message {:?}, ignoring", message);
             }
@@ -472,6 +1117,8 @@ pub struct PeerConnection {
     pub peer: PeerAddr,
     pub info_hash: [u8; 20],
     pub peer_id: [u8; 20],
+    storage: Arc<Storage>,
+    rate_limiter: PeerRateLimiter,
 }
 
 impl PeerConnection {
@@ -480,6 +1127,8 @@ impl PeerConnection {
         info_hash: [u8; 20],
         peer_id: [u8; 20],
         handler: Arc<PeerHandler>,
+        storage: Arc<Storage>,
+        rate_limiter: PeerRateLimiter,
     ) -> Self {
         Self {
             handler,
@@ -487,30 +1136,101 @@ impl PeerConnection {
             peer,
             info_hash,
             peer_id,
+            storage,
+            rate_limiter,
         }
     }
 
-    pub async fn manage_peer_incoming(
+    /// Opens the TCP connection from `network`, through `proxy` if there is
+    /// one, and goes through the handshakes.
+    pub async fn connect(
         &self,
-        peer_writer_rx: flume::Receiver<WriterRequest>,
-        mut have_broadcast: tokio::sync::broadcast::Receiver<u32>,
-    ) -> anyhow::Result<()> {
+        network: &Network,
+        proxy: Option<&ProxyConfig>,
+    ) -> Result<TcpStream, PeerError> {
         let connect = async {
-            TcpStream::connect(self.peer)
-                .await
-                .map_err(ProtocolError::Io)
+            match proxy {
+                Some(proxy) => Ok(proxy
+                    .connect(network, &self.peer.ip().to_string(), self.peer.port())
+                    .await?),
+                None => Ok(network
+                    .connect(self.peer)
+                    .await
+                    .map_err(ProtocolError::Io)?),
+            }
         };
-        let mut stream = match tokio::time::timeout(Duration::from_secs(6), connect).await {
-            Ok(Ok(b)) => Ok(b),
-            Ok(Err(e)) => Err(e),
-            Err(e) => Err(ProtocolError::Timeout(e)),
-        }?;
-
-        let protocol = Arc::new(Protocol::connect(self.peer, self.info_hash, self.peer_id).await?);
-        let _handshake = protocol.complete_handshake(&mut stream).await?;
-        protocol.send_unchoke(&mut stream).await?;
+        let mut stream =
+            match tokio::time::timeout(self.handler.config.connect_timeout, connect).await {
+                Ok(Ok(b)) => Ok(b),
+                Ok(Err(e)) => Err(e),
+                Err(e) => Err(PeerError::Protocol(ProtocolError::Timeout(e))),
+            }?;
+
+        let protocol = self.protocol().await?;
+        let handshake = protocol.complete_handshake(&mut stream).await?;
+        self.on_handshake(&mut stream, &handshake).await?;
         protocol.send_interested(&mut stream).await?;
 
+        Ok(stream)
+    }
+
+    /// Answers a peer that connected to us and already sent its `handshake`.
+    pub async fn accept(
+        &self,
+        mut stream: TcpStream,
+        handshake: &Handshake,
+    ) -> Result<TcpStream, PeerError> {
+        let protocol = self.protocol().await?;
+        protocol.send_handshake(&mut stream).await?;
+        self.on_handshake(&mut stream, handshake).await?;
+
+        Ok(stream)
+    }
+
+    async fn protocol(&self) -> Result<Protocol, ProtocolError> {
+        Ok(Protocol::connect(self.peer, self.info_hash, self.peer_id)
+            .await?
+            .with_handshake_timeout(self.handler.config.handshake_timeout)
+            .with_max_message_length(protocol::max_message_length(
+                self.handler.torrent_downloaded_state.pieces.len(),
+            )))
+    }
+
+    // The messages both sides of a connection send once the handshakes are done.
+    async fn on_handshake(
+        &self,
+        stream: &mut TcpStream,
+        handshake: &Handshake,
+    ) -> Result<(), PeerError> {
+        self.handler.on_handshake(handshake)?;
+
+        let bitfield = self.handler.torrent_downloaded_state.bitfield();
+        if bitfield.iter().any(|&b| b != 0) {
+            let msg = Message::Bitfield(bitfield);
+            stream.write_all(&message::serialize(Some(msg))).await?;
+        }
+        if handshake.supports_extension_protocol() {
+            let reqq = self.handler.config.max_outstanding_requests as u32;
+            let mut extended = ExtendedHandshake::new(reqq);
+            if self.handler.pex.is_some() {
+                extended = extended.with_pex();
+            }
+            let msg = extended.to_message()?;
+            stream.write_all(&message::serialize(Some(msg))).await?;
+        }
+        // The choker unchokes the peer once it is interested.
+        Ok(())
+    }
+
+    pub async fn manage_peer_incoming(
+        &self,
+        mut stream: TcpStream,
+        peer_writer_rx: flume::Receiver<WriterRequest>,
+        mut have_broadcast: tokio::sync::broadcast::Receiver<u32>,
+    ) -> Result<(), PeerError> {
+        let protocol = self.protocol().await?;
+        let config = &self.handler.config;
+
         // manage peer
         let (mut read, mut write) = stream.split();
 
@@ -535,13 +1255,13 @@ impl PeerConnection {
                                 },
                                 _ => continue
                             },
-                            r = timeout(Duration::from_secs(120), peer_writer_rx.recv_async()) => match r {
+                            r = timeout(config.keep_alive_interval, peer_writer_rx.recv_async()) => match r {
                                 Ok(Ok(msg)) =>{
                                     msg
                                 },
                                 Ok(Err(_)) => {
                                     error!("closing writer, channel closed");
-                                    anyhow::bail!("closing writer, channel closed");
+                                    return Err(PeerError::ChannelClosed);
                                 }
                                 Err(_) => {
                                     debug!("timeout reading, let's keep alive");
@@ -551,13 +1271,27 @@ impl PeerConnection {
                         };
                     };
 
-                    let buf = match req {
-                        WriterRequest::Message(msg) => message::serialize(Some(msg)),
+                    let (buf, payload) = match req {
+                        WriterRequest::Message(msg) => (message::serialize(Some(msg)), 0),
+                        WriterRequest::ReadChunkRequest(chunk) => {
+                            let data = self
+                                .storage
+                                .read(chunk.index, chunk.begin, chunk.length)
+                                .await?;
+                            let msg = Message::Piece(PieceChunk {
+                                index: chunk.index,
+                                start: chunk.begin,
+                                length: chunk.length,
+                                data,
+                            });
+                            (message::serialize(Some(msg)), chunk.length as u64)
+                        }
                     };
 
-                    match timeout(Duration::from_secs(10), write.write_all(&buf)).await {
+                    self.rate_limiter.upload(buf.len()).await;
+                    match timeout(config.write_timeout, write.write_all(&buf)).await {
                         Ok(Ok(_)) => {
-                            //debug!("sent message");
+                            self.handler.stats.on_sent(buf.len() as u64, payload);
                         }
                         Ok(Err(e)) => {
                             debug!("error writing to peer: {:?}", e);
@@ -569,39 +1303,52 @@ impl PeerConnection {
                         }
                     }
                 }
-                Ok::<_, anyhow::Error>(())
+                Ok::<_, PeerError>(())
             }
         };
 
         let reader = async move {
             loop {
+                let mut received = 0;
+                let counted = CountingReader::new(&mut read, &mut received);
                 let message =
-                    tokio::time::timeout(Duration::from_secs(10), protocol.read(&mut read)).await;
+                    tokio::time::timeout(config.read_timeout, protocol.read(counted)).await;
 
                 match message {
                     Ok(Ok(None)) => {
                         debug!("peer disconnected");
                         break;
                     }
-                    Ok(Ok(Some(msg))) => match self.handler.on_received_message(msg) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            debug!("error processing message: {:?}", e);
-                            break;
+                    Ok(Ok(Some(msg))) => {
+                        let payload = match &msg {
+                            Message::Piece(piece_chunk) => piece_chunk.length as u64,
+                            _ => 0,
+                        };
+                        self.handler.stats.on_received(received, payload);
+                        self.rate_limiter.download(received).await;
+                        if let Err(e) = self.handler.on_received_message(msg) {
+                            debug!("error processing message: {}", e);
+                            return Err(e);
                         }
-                    },
-                    Ok(Err(e)) => {
-                        debug!("error reading from peer: {:?}", e);
+                    }
+                    Ok(Err(ProtocolError::Io(e)))
+                        if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                    {
+                        debug!("peer closed the connection");
                         break;
                     }
+                    Ok(Err(e)) => {
+                        debug!("error reading from peer: {}", e);
+                        return Err(e.into());
+                    }
                     Err(e) => {
                         debug!("timeout reading from peer: {:?}", e);
-                        break;
+                        return Err(ProtocolError::Timeout(e).into());
                     }
                 }
             }
 
-            Ok::<_, anyhow::Error>(())
+            Ok::<_, PeerError>(())
         };
 
         tokio::select! {
@@ -616,3 +1363,68 @@ impl PeerConnection {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(pieces: u32) -> TorrentDownloadedState {
+        TorrentDownloadedState {
+            semaphore: Semaphore::new(1),
+            pieces: (0..pieces)
+                .map(|index| {
+                    PieceWorkState::new(PieceWork {
+                        index,
+                        length: 4,
+                        hash: [0; 20],
+                    })
+                })
+                .collect(),
+            focus: Mutex::new(StreamFocus::new(&TorrentConfig::default())),
+            verified_notify: Notify::new(),
+            timed_out_retry: TorrentConfig::default().timed_out_retry,
+        }
+    }
+
+    async fn pick(state: &TorrentDownloadedState, peer: PeerAddr) -> Option<u32> {
+        state
+            .get_and_reserve_piece(peer)
+            .await
+            .map(|pw| pw.piece_work.index)
+    }
+
+    #[test]
+    fn blocks_must_fit_in_a_piece() {
+        let state = state(2);
+        assert!(state.is_valid_block(1, 0, 4));
+        assert!(state.is_valid_block(1, 2, 2));
+        assert!(!state.is_valid_block(2, 0, 4));
+        assert!(!state.is_valid_block(u32::MAX, 0, 4));
+        assert!(!state.is_valid_block(1, 2, 4));
+        assert!(!state.is_valid_block(1, u32::MAX, 4));
+        assert!(!state.is_valid_block(1, 0, 0));
+        assert!(state.set_downloaded_if_all_chunks(2).is_none());
+    }
+
+    #[tokio::test]
+    async fn timed_out_pieces_go_to_other_peers() {
+        let state = state(2);
+        let stalled: PeerAddr = "10.0.0.1:6881".parse().unwrap();
+        let other: PeerAddr = "10.0.0.2:6881".parse().unwrap();
+
+        assert_eq!(pick(&state, stalled).await, Some(0));
+        // A request of the stalled peer for piece 0 times out.
+        state.release_piece(0, stalled);
+
+        assert_eq!(pick(&state, stalled).await, Some(1));
+        assert_eq!(pick(&state, other).await, Some(0));
+        // Down to the endgame, the stalled peer still isn't asked for it.
+        assert_eq!(pick(&state, stalled).await, Some(1));
+        state.pieces[1].downloaded.store(true, Ordering::Relaxed);
+        assert_eq!(pick(&state, stalled).await, None);
+        assert_eq!(pick(&state, other).await, Some(0));
+
+        state.set_verified(0);
+        assert!(state.pieces[0].timed_out.lock().unwrap().is_empty());
+    }
+}
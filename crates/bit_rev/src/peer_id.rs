@@ -0,0 +1,265 @@
+use std::fmt::Display;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Azureus-style prefix we put in front of our peer ids: client `BR`, version 0.1.0.0.
+pub const DEFAULT_PEER_ID_PREFIX: &str = "-BR0100-";
+
+/// Two letter Azureus-style client codes we know about.
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("AG", "Ares"),
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BR", "BitRev"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("FG", "FlashGet"),
+    ("KT", "KTorrent"),
+    ("LT", "libTorrent"),
+    ("lt", "libtorrent"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("SD", "Thunder"),
+    ("TR", "Transmission"),
+    ("UM", "µTorrent Mac"),
+    ("UT", "µTorrent"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+];
+
+/// Single letter Shadow-style client codes we know about.
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'M', "Mainline"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+/// Builds a peer id made of `prefix` followed by random alphanumeric characters.
+/// A prefix longer than 20 bytes is truncated.
+pub fn generate_with_prefix(prefix: &str) -> [u8; 20] {
+    let mut rng = rand::prelude::ThreadRng::default();
+    let mut peer_id = [0u8; 20];
+    let prefix = &prefix.as_bytes()[..prefix.len().min(20)];
+    peer_id[..prefix.len()].copy_from_slice(prefix);
+    for b in peer_id[prefix.len()..].iter_mut() {
+        *b = rng.sample(rand::distributions::Alphanumeric);
+    }
+    peer_id
+}
+
+/// The software a remote peer is running, as far as we can tell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl Display for ClientInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{} {}", self.name, version),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+impl ClientInfo {
+    /// Decodes the client from a peer id using the Azureus (`-XX1234-`) or
+    /// Shadow (`X123--`) conventions.
+    pub fn from_peer_id(peer_id: &[u8; 20]) -> Option<ClientInfo> {
+        Self::from_azureus_peer_id(peer_id).or_else(|| Self::from_shadow_peer_id(peer_id))
+    }
+
+    fn from_azureus_peer_id(peer_id: &[u8; 20]) -> Option<ClientInfo> {
+        if peer_id[0] != b'-' || peer_id[7] != b'-' {
+            return None;
+        }
+
+        let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+        if !code.chars().all(|c| c.is_ascii_alphabetic()) {
+            return None;
+        }
+        let name = AZUREUS_CLIENTS
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, name)| name.to_string())
+            .unwrap_or_else(|| code.to_string());
+
+        let version = peer_id[3..7]
+            .iter()
+            .map(|&b| decode_version_char(b).map(|v| v.to_string()))
+            .collect::<Option<Vec<_>>>()?
+            .join(".");
+
+        Some(ClientInfo {
+            name,
+            version: Some(version),
+        })
+    }
+
+    fn from_shadow_peer_id(peer_id: &[u8; 20]) -> Option<ClientInfo> {
+        let name = SHADOW_CLIENTS
+            .iter()
+            .find(|(c, _)| *c == peer_id[0])
+            .map(|(_, name)| name.to_string())?;
+
+        // The version is padded with dashes, random ids rarely look like that.
+        let end = peer_id[1..6].iter().position(|&b| b == b'-')?;
+        let version: Vec<String> = peer_id[1..1 + end]
+            .iter()
+            .map(|&b| decode_version_char(b).map(|v| v.to_string()))
+            .collect::<Option<_>>()?;
+        if version.is_empty() {
+            return None;
+        }
+
+        Some(ClientInfo {
+            name,
+            version: Some(version.join(".")),
+        })
+    }
+
+    /// Parses the BEP 10 `v` field, e.g. `qBittorrent/4.5.2` or `Transmission 3.00`.
+    pub fn from_version_string(v: &str) -> ClientInfo {
+        let v = v.trim();
+        let split = v
+            .rfind(['/', ' '])
+            .filter(|&i| v[i + 1..].starts_with(|c: char| c.is_ascii_digit()));
+
+        match split {
+            Some(i) => ClientInfo {
+                name: v[..i].trim().to_string(),
+                version: Some(v[i + 1..].to_string()),
+            },
+            None => ClientInfo {
+                name: v.to_string(),
+                version: None,
+            },
+        }
+    }
+}
+
+fn decode_version_char(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'A'..=b'Z' => Some(b - b'A' + 10),
+        b'a'..=b'z' => Some(b - b'a' + 36),
+        b'.' => Some(62),
+        _ => None,
+    }
+}
+
+/// Decides which clients we are willing to talk to.
+///
+/// Names are matched case-insensitively as prefixes, so `"Transmission"` also matches
+/// `"Transmission Qt"`. An empty allow list allows every client that isn't denied.
+/// Clients are named by their peer id, or by the `v` of their extended handshake
+/// when the peer id doesn't tell.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientFilter {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl ClientFilter {
+    pub fn is_allowed(&self, client: Option<&ClientInfo>) -> bool {
+        let matches = |names: &[String], client: &ClientInfo| {
+            let name = client.name.to_lowercase();
+            names.iter().any(|n| name.starts_with(&n.to_lowercase()))
+        };
+
+        match client {
+            Some(client) if matches(&self.deny, client) => false,
+            Some(client) => self.allow.is_empty() || matches(&self.allow, client),
+            None => self.allow.is_empty(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_id(s: &str) -> [u8; 20] {
+        let mut id = [b'x'; 20];
+        id[..s.len()].copy_from_slice(s.as_bytes());
+        id
+    }
+
+    #[test]
+    fn generated_peer_id_has_prefix() {
+        let id = generate_with_prefix(DEFAULT_PEER_ID_PREFIX);
+        assert_eq!(&id[..8], DEFAULT_PEER_ID_PREFIX.as_bytes());
+        assert!(id[8..].iter().all(|b| b.is_ascii_alphanumeric()));
+
+        let client = ClientInfo::from_peer_id(&id).unwrap();
+        assert_eq!(client.name, "BitRev");
+        assert_eq!(client.version.as_deref(), Some("0.1.0.0"));
+    }
+
+    #[test]
+    fn decode_azureus_peer_id() {
+        let client = ClientInfo::from_peer_id(&peer_id("-qB4520-")).unwrap();
+        assert_eq!(client.to_string(), "qBittorrent 4.5.2.0");
+
+        let client = ClientInfo::from_peer_id(&peer_id("-ZZ1000-")).unwrap();
+        assert_eq!(client.name, "ZZ");
+    }
+
+    #[test]
+    fn decode_shadow_peer_id() {
+        let client = ClientInfo::from_peer_id(&peer_id("T03I--")).unwrap();
+        assert_eq!(client.to_string(), "BitTornado 0.3.18");
+    }
+
+    #[test]
+    fn unknown_peer_id() {
+        assert_eq!(ClientInfo::from_peer_id(&[0u8; 20]), None);
+    }
+
+    #[test]
+    fn parse_version_string() {
+        let client = ClientInfo::from_version_string("qBittorrent/4.5.2");
+        assert_eq!(client.name, "qBittorrent");
+        assert_eq!(client.version.as_deref(), Some("4.5.2"));
+
+        let client = ClientInfo::from_version_string("Transmission 3.00");
+        assert_eq!(client.name, "Transmission");
+        assert_eq!(client.version.as_deref(), Some("3.00"));
+
+        let client = ClientInfo::from_version_string("BitRev");
+        assert_eq!(client.name, "BitRev");
+        assert_eq!(client.version, None);
+    }
+
+    #[test]
+    fn client_filter() {
+        let transmission = ClientInfo::from_version_string("Transmission 3.00");
+        let xunlei = ClientInfo::from_version_string("Xunlei 0.0.1");
+
+        let filter = ClientFilter {
+            allow: vec![],
+            deny: vec!["xunlei".to_string()],
+        };
+        assert!(filter.is_allowed(Some(&transmission)));
+        assert!(!filter.is_allowed(Some(&xunlei)));
+        assert!(filter.is_allowed(None));
+
+        let filter = ClientFilter {
+            allow: vec!["transmission".to_string()],
+            deny: vec![],
+        };
+        assert!(filter.is_allowed(Some(&transmission)));
+        assert!(!filter.is_allowed(Some(&xunlei)));
+        assert!(!filter.is_allowed(None));
+    }
+}
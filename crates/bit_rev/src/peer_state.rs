@@ -1,6 +1,13 @@
+use std::{sync::Arc, time::Instant};
+
 use dashmap::DashMap;
 
-use crate::{bitfield::Bitfield, peer::PeerAddr};
+use crate::{
+    bitfield::Bitfield,
+    peer::PeerAddr,
+    peer_id::ClientInfo,
+    stats::{PeerStats, TransferStats},
+};
 
 #[derive(Debug, Clone, Default)]
 pub struct PeerStates {
@@ -13,21 +20,162 @@ impl PeerStates {
             self.states.insert(peer, PeerState::default());
         }
     }
+
+    /// Peers we could optimistically unchoke, best candidates first: the
+    /// ones we never tried, then the ones we tried longest ago. Peers that
+    /// are snubbing us go to the back of the line.
+    pub fn optimistic_unchoke_candidates(&self) -> Vec<PeerAddr> {
+        let mut candidates: Vec<(PeerAddr, (bool, Option<Instant>))> = self
+            .states
+            .iter()
+            .filter(|s| s.peer_interested)
+            .map(|s| (*s.key(), (s.snubbed, s.optimistic_unchoked_at)))
+            .collect();
+        candidates.sort_by_key(|(_, order)| *order);
+        candidates.into_iter().map(|(peer, _)| peer).collect()
+    }
+
+    /// How many peers have each of the `num_pieces` pieces.
+    pub fn availability(&self, num_pieces: usize) -> Vec<u32> {
+        let mut availability = vec![0; num_pieces];
+        for state in self.states.iter() {
+            for (index, count) in availability.iter_mut().enumerate() {
+                if state.bitfield.has_piece(index) {
+                    *count += 1;
+                }
+            }
+        }
+        availability
+    }
+
+    pub fn stats(&self) -> Vec<PeerStats> {
+        self.states
+            .iter()
+            .map(|state| PeerStats {
+                addr: *state.key(),
+                source: None,
+                client: state.client.as_ref().map(|client| client.to_string()),
+                am_choking: state.am_choking,
+                am_interested: state.am_interested,
+                peer_choking: state.peer_choking,
+                peer_interested: state.peer_interested,
+                snubbed: state.snubbed,
+                transfer: state.stats.snapshot(),
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct PeerState {
     /// This is used to track if the peer is interested in us.
     pub peer_interested: bool,
+    /// The peer doesn't answer our requests.
+    pub peer_choking: bool,
+    /// We don't answer the requests of the peer.
+    pub am_choking: bool,
+    /// We told the peer we want some of its pieces.
+    pub am_interested: bool,
     /// This is used to track the pieces the peer has.
     pub bitfield: Bitfield,
+    /// This is used to track if the peer stopped sending us data.
+    pub snubbed: bool,
+    /// When the peer last got the optimistic unchoke.
+    pub optimistic_unchoked_at: Option<Instant>,
+    /// The client the peer is running, decoded from its peer id or extended handshake.
+    pub client: Option<ClientInfo>,
+    /// Bytes exchanged with the peer.
+    pub stats: Arc<TransferStats>,
 }
 
 impl Default for PeerState {
     fn default() -> Self {
         Self {
-            peer_interested: true,
+            peer_interested: false,
+            peer_choking: true,
+            am_choking: true,
+            am_interested: false,
             bitfield: Bitfield::new(vec![]),
+            snubbed: false,
+            optimistic_unchoked_at: None,
+            client: None,
+            stats: Arc::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snubbed_peers_are_unchoked_last() {
+        let peer_states = PeerStates::default();
+        let snubbed: PeerAddr = "10.0.0.1:6881".parse().unwrap();
+        let healthy: PeerAddr = "10.0.0.2:6881".parse().unwrap();
+        let not_interested: PeerAddr = "10.0.0.3:6881".parse().unwrap();
+
+        peer_states.states.insert(
+            snubbed,
+            PeerState {
+                peer_interested: true,
+                snubbed: true,
+                ..Default::default()
+            },
+        );
+        peer_states.states.insert(
+            healthy,
+            PeerState {
+                peer_interested: true,
+                ..Default::default()
+            },
+        );
+        peer_states.states.insert(
+            not_interested,
+            PeerState {
+                peer_interested: false,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            peer_states.optimistic_unchoke_candidates(),
+            vec![healthy, snubbed]
+        );
+
+        // A peer that already had its turn waits behind the new ones.
+        let newcomer: PeerAddr = "10.0.0.4:6881".parse().unwrap();
+        peer_states.states.insert(
+            newcomer,
+            PeerState {
+                peer_interested: true,
+                ..Default::default()
+            },
+        );
+        peer_states
+            .states
+            .get_mut(&healthy)
+            .unwrap()
+            .optimistic_unchoked_at = Some(Instant::now());
+        assert_eq!(
+            peer_states.optimistic_unchoke_candidates(),
+            vec![newcomer, healthy, snubbed]
+        );
+    }
+
+    #[test]
+    fn piece_availability() {
+        let peer_states = PeerStates::default();
+        for (i, bitfield) in [0b1100_0000, 0b1010_0000].into_iter().enumerate() {
+            peer_states.states.insert(
+                format!("10.0.0.{}:6881", i + 1).parse().unwrap(),
+                PeerState {
+                    bitfield: Bitfield::new(vec![bitfield]),
+                    ..Default::default()
+                },
+            );
+        }
+
+        assert_eq!(peer_states.availability(4), vec![2, 1, 1, 0]);
+    }
+}
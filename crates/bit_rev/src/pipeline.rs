@@ -0,0 +1,392 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::utils::BLOCK_SIZE;
+
+/// Depth used before we have any rate or RTT measurement.
+const INITIAL_DEPTH: usize = 4;
+/// Lower bound on the queue depth, so a slow start never stalls the peer.
+const MIN_DEPTH: usize = 2;
+/// Queue depth assumed when the peer doesn't advertise `reqq` (libtorrent's default).
+pub const DEFAULT_MAX_DEPTH: usize = 250;
+/// How long we accumulate received bytes before folding them into the rate.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+/// Weight given to the newest sample in the moving averages.
+const EWMA_ALPHA: f64 = 0.3;
+
+/// A block we asked a peer for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+/// Tracks the requests in flight to a single peer and decides how many we
+/// may keep outstanding, based on the bandwidth-delay product of the link.
+#[derive(Debug)]
+pub struct RequestPipeline {
+    outstanding: HashMap<(u32, u32), (u32, Instant)>,
+    max_depth: usize,
+    /// Size of the blocks we request, the unit of the queue depth.
+    block_size: u32,
+    snubbed: bool,
+    /// Since when we have been waiting for data with requests pending. Kept
+    /// when requests time out, so a silent peer still ends up snubbed.
+    waiting_since: Option<Instant>,
+    rtt: Option<Duration>,
+    rate: f64,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl Default for RequestPipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RequestPipeline {
+    pub fn new() -> Self {
+        Self::with_max_depth(DEFAULT_MAX_DEPTH, BLOCK_SIZE)
+    }
+
+    pub fn with_max_depth(max_depth: usize, block_size: u32) -> Self {
+        Self {
+            outstanding: HashMap::new(),
+            max_depth: max_depth.max(1),
+            block_size: block_size.max(1),
+            snubbed: false,
+            waiting_since: None,
+            rtt: None,
+            rate: 0.0,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    /// Caps the queue depth to the `reqq` the peer advertised in its extended handshake.
+    pub fn set_max_depth(&mut self, reqq: usize) {
+        self.max_depth = reqq.max(1);
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Number of requests we want in flight right now.
+    pub fn depth(&self) -> usize {
+        // A snubbing peer only gets one request at a time until it proves itself again.
+        if self.snubbed {
+            return 1;
+        }
+
+        let rate = self.rate();
+        let depth = match self.rtt {
+            Some(rtt) if rate > 0.0 => {
+                // Twice the bandwidth-delay product, in blocks, keeps the pipe full
+                // while responses are still on their way back.
+                let bdp = rate * rtt.as_secs_f64() / self.block_size as f64;
+                (bdp * 2.0).ceil() as usize + MIN_DEPTH
+            }
+            _ => INITIAL_DEPTH,
+        };
+        depth.clamp(MIN_DEPTH.min(self.max_depth), self.max_depth)
+    }
+
+    pub fn has_capacity(&self) -> bool {
+        self.outstanding.len() < self.depth()
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    pub fn is_outstanding(&self, index: u32, begin: u32) -> bool {
+        self.outstanding.contains_key(&(index, begin))
+    }
+
+    /// Download rate measured from received blocks, in bytes per second.
+    /// It keeps falling while the peer sends nothing.
+    pub fn rate(&self) -> f64 {
+        self.decayed_rate(self.idle_windows(Instant::now()))
+    }
+
+    /// Whole rate windows gone by since the last sample.
+    fn idle_windows(&self, now: Instant) -> i32 {
+        let elapsed = now.duration_since(self.window_start);
+        (elapsed.as_secs_f64() / RATE_WINDOW.as_secs_f64()).min(i32::MAX as f64) as i32
+    }
+
+    /// The rate after `windows` samples of nothing.
+    fn decayed_rate(&self, windows: i32) -> f64 {
+        self.rate * (1.0 - EWMA_ALPHA).powi(windows)
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    pub fn is_snubbed(&self) -> bool {
+        self.snubbed
+    }
+
+    pub fn on_request_sent(&mut self, index: u32, begin: u32, length: u32) {
+        self.waiting_since.get_or_insert_with(Instant::now);
+        self.outstanding
+            .insert((index, begin), (length, Instant::now()));
+    }
+
+    /// Records a received block. Returns `false` if we never asked for it
+    /// (or already gave up on it).
+    pub fn on_block_received(&mut self, index: u32, begin: u32, length: u32) -> bool {
+        let now = Instant::now();
+        self.snubbed = false;
+
+        self.window_bytes += length as u64;
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= RATE_WINDOW {
+            let sample = self.window_bytes as f64 / elapsed.as_secs_f64();
+            // The windows before this one went by without a block.
+            let previous = self.decayed_rate(self.idle_windows(now) - 1);
+            self.rate = if self.rate == 0.0 {
+                sample
+            } else {
+                EWMA_ALPHA * sample + (1.0 - EWMA_ALPHA) * previous
+            };
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+
+        let requested = self.outstanding.remove(&(index, begin));
+        self.waiting_since = (!self.outstanding.is_empty()).then_some(now);
+        match requested {
+            Some((_, sent_at)) => {
+                let sample = now.duration_since(sent_at);
+                self.rtt = Some(match self.rtt {
+                    Some(rtt) => rtt.mul_f64(1.0 - EWMA_ALPHA) + sample.mul_f64(EWMA_ALPHA),
+                    None => sample,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops every outstanding request. A peer that chokes us discards
+    /// the requests it hasn't served yet.
+    pub fn reset(&mut self) {
+        self.outstanding.clear();
+        self.waiting_since = None;
+    }
+
+    /// Gives up on the requests older than `request_timeout`, then checks
+    /// whether the peer is snubbing us, see `check_snubbed`.
+    pub fn check_timeouts(
+        &mut self,
+        request_timeout: Duration,
+        snub_timeout: Duration,
+    ) -> (Vec<Block>, bool) {
+        let timed_out = self.take_timed_out(request_timeout);
+        (timed_out, self.check_snubbed(snub_timeout))
+    }
+
+    /// Removes and returns the requests that have been unanswered for longer than `timeout`.
+    pub fn take_timed_out(&mut self, timeout: Duration) -> Vec<Block> {
+        let now = Instant::now();
+        let timed_out: Vec<Block> = self
+            .outstanding
+            .iter()
+            .filter(|(_, (_, sent_at))| now.duration_since(*sent_at) >= timeout)
+            .map(|(&(index, begin), &(length, _))| Block {
+                index,
+                begin,
+                length,
+            })
+            .collect();
+
+        for block in &timed_out {
+            self.outstanding.remove(&(block.index, block.begin));
+        }
+        timed_out
+    }
+
+    /// Flags the peer as snubbing us when it has had requests queued for `after`
+    /// without sending any data, even if those requests timed out meanwhile.
+    /// Returns `true` only when the peer becomes snubbed.
+    pub fn check_snubbed(&mut self, after: Duration) -> bool {
+        if self.snubbed {
+            return false;
+        }
+
+        let Some(waiting_since) = self.waiting_since else {
+            return false;
+        };
+        if waiting_since.elapsed() >= after {
+            self.snubbed = true;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn initial_depth_before_measurements() {
+        let pipeline = RequestPipeline::new();
+        assert_eq!(pipeline.depth(), INITIAL_DEPTH);
+        assert!(pipeline.has_capacity());
+    }
+
+    #[test]
+    fn depth_is_capped_by_reqq() {
+        let mut pipeline = RequestPipeline::new();
+        pipeline.rtt = Some(Duration::from_millis(500));
+        pipeline.rate = 100.0 * 1024.0 * 1024.0;
+        assert_eq!(pipeline.depth(), DEFAULT_MAX_DEPTH);
+
+        pipeline.set_max_depth(16);
+        assert_eq!(pipeline.depth(), 16);
+    }
+
+    #[test]
+    fn depth_follows_bandwidth_delay_product() {
+        let mut pipeline = RequestPipeline::new();
+        pipeline.rtt = Some(Duration::from_millis(100));
+        // 10 blocks per second over 100ms is one block in flight.
+        pipeline.rate = 10.0 * BLOCK_SIZE as f64;
+        assert_eq!(pipeline.depth(), 2 + MIN_DEPTH);
+    }
+
+    #[test]
+    fn depth_counts_configured_blocks() {
+        let mut pipeline = RequestPipeline::with_max_depth(DEFAULT_MAX_DEPTH, BLOCK_SIZE / 4);
+        pipeline.rtt = Some(Duration::from_millis(100));
+        // Four small blocks per full one.
+        pipeline.rate = 10.0 * BLOCK_SIZE as f64;
+        assert_eq!(pipeline.depth(), 8 + MIN_DEPTH);
+    }
+
+    #[test]
+    fn rate_decays_while_the_peer_is_silent() {
+        let mut pipeline = RequestPipeline::new();
+        pipeline.rate = 1000.0;
+        assert_eq!(pipeline.rate(), 1000.0);
+
+        pipeline.window_start = Instant::now() - RATE_WINDOW * 2;
+        assert!((pipeline.rate() - 490.0).abs() < 1e-6);
+
+        // A stalled peer loses its old rate, and the depth that came with it.
+        pipeline.rtt = Some(Duration::from_millis(100));
+        pipeline.rate = 100.0 * BLOCK_SIZE as f64;
+        pipeline.window_start = Instant::now();
+        assert_eq!(pipeline.depth(), 20 + MIN_DEPTH);
+        pipeline.window_start = Instant::now() - RATE_WINDOW * 60;
+        assert!(pipeline.rate() < 1.0);
+        assert_eq!(pipeline.depth(), 1 + MIN_DEPTH);
+    }
+
+    #[test]
+    fn reset_clears_outstanding() {
+        let mut pipeline = RequestPipeline::new();
+        pipeline.on_request_sent(0, 0, BLOCK_SIZE);
+        pipeline.on_request_sent(0, BLOCK_SIZE, BLOCK_SIZE);
+        assert_eq!(pipeline.outstanding(), 2);
+
+        assert!(pipeline.on_block_received(0, 0, BLOCK_SIZE));
+        assert!(pipeline.rtt().is_some());
+        assert_eq!(pipeline.outstanding(), 1);
+
+        pipeline.reset();
+        assert_eq!(pipeline.outstanding(), 0);
+        assert!(!pipeline.on_block_received(0, BLOCK_SIZE, BLOCK_SIZE));
+    }
+
+    #[test]
+    fn timed_out_requests_are_removed() {
+        let mut pipeline = RequestPipeline::new();
+        pipeline.on_request_sent(3, 0, BLOCK_SIZE);
+        pipeline.on_request_sent(3, BLOCK_SIZE, 100);
+
+        assert!(pipeline.take_timed_out(Duration::from_secs(60)).is_empty());
+
+        let mut timed_out = pipeline.take_timed_out(Duration::ZERO);
+        timed_out.sort_by_key(|b| b.begin);
+        assert_eq!(
+            timed_out,
+            vec![
+                Block {
+                    index: 3,
+                    begin: 0,
+                    length: BLOCK_SIZE
+                },
+                Block {
+                    index: 3,
+                    begin: BLOCK_SIZE,
+                    length: 100
+                },
+            ]
+        );
+        assert_eq!(pipeline.outstanding(), 0);
+    }
+
+    #[test]
+    fn snubbed_until_data_arrives() {
+        let mut pipeline = RequestPipeline::new();
+        assert!(!pipeline.check_snubbed(Duration::ZERO));
+
+        pipeline.on_request_sent(0, 0, BLOCK_SIZE);
+        assert!(pipeline.check_snubbed(Duration::ZERO));
+        assert!(!pipeline.check_snubbed(Duration::ZERO));
+        assert!(pipeline.is_snubbed());
+        assert_eq!(pipeline.depth(), 1);
+
+        pipeline.on_block_received(0, 0, BLOCK_SIZE);
+        assert!(!pipeline.is_snubbed());
+        assert!(!pipeline.check_snubbed(Duration::ZERO));
+    }
+
+    #[test]
+    fn snubbed_peer_recovers_its_depth() {
+        let mut pipeline = RequestPipeline::new();
+        pipeline.on_request_sent(0, 0, BLOCK_SIZE);
+        assert!(pipeline.check_snubbed(Duration::ZERO));
+
+        // Its pending request timed out, it may have a single one in flight.
+        assert_eq!(pipeline.take_timed_out(Duration::ZERO).len(), 1);
+        assert!(pipeline.has_capacity());
+        pipeline.on_request_sent(1, 0, BLOCK_SIZE);
+        assert!(!pipeline.has_capacity());
+
+        assert!(pipeline.on_block_received(1, 0, BLOCK_SIZE));
+        assert!(!pipeline.is_snubbed());
+        assert_eq!(pipeline.depth(), INITIAL_DEPTH);
+        assert!(pipeline.has_capacity());
+    }
+
+    #[test]
+    fn snubbed_although_requests_time_out_first() {
+        let request_timeout = Duration::from_millis(20);
+        let snub_timeout = Duration::from_millis(50);
+        let mut pipeline = RequestPipeline::new();
+
+        pipeline.on_request_sent(0, 0, BLOCK_SIZE);
+        std::thread::sleep(Duration::from_millis(30));
+        let (timed_out, snubbed) = pipeline.check_timeouts(request_timeout, snub_timeout);
+        assert_eq!(timed_out.len(), 1);
+        assert!(!snubbed);
+
+        // The block goes out again and the peer still sends nothing.
+        pipeline.on_request_sent(0, 0, BLOCK_SIZE);
+        std::thread::sleep(Duration::from_millis(30));
+        let (timed_out, snubbed) = pipeline.check_timeouts(request_timeout, snub_timeout);
+        assert_eq!(timed_out.len(), 1);
+        assert!(snubbed);
+        assert!(pipeline.is_snubbed());
+    }
+}
@@ -1,7 +1,9 @@
+use crate::config::TorrentConfig;
 use crate::handshake::{Handshake, HandshakeError};
 use crate::message;
-use crate::message::Message;
+use crate::message::{Message, MessageError};
 use crate::peer::PeerAddr;
+use crate::utils;
 use byteorder::{BigEndian, ByteOrder};
 use std::time::Duration;
 use thiserror::Error;
@@ -9,8 +11,6 @@ use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::time::error::Elapsed;
 
-const HANDSHAKE_TIMEOUT: u64 = 3;
-
 #[derive(Error, Debug)]
 pub enum ProtocolError {
     #[error("Handshake error: {0}")]
@@ -25,6 +25,23 @@ pub enum ProtocolError {
     ExpectedBitfieldId,
     #[error("Message is none")]
     MessageIsNone,
+    #[error("Invalid message: {0}")]
+    Message(MessageError),
+    #[error("Message of {0} bytes is too long")]
+    MessageTooLong(usize),
+}
+
+/// Room on top of the biggest piece or bitfield message for extended
+/// messages, e.g. metadata pieces with their header.
+const EXTENDED_HEADROOM: usize = 16 * 1024;
+
+/// Longest message we accept from a peer of a torrent with `num_pieces`
+/// pieces: a piece message with a full block or the bitfield, whichever
+/// is bigger.
+pub fn max_message_length(num_pieces: usize) -> usize {
+    let piece = 13 + utils::BLOCK_SIZE as usize;
+    let bitfield = 1 + num_pieces.div_ceil(8);
+    piece.max(bitfield) + EXTENDED_HEADROOM
 }
 
 #[derive(Debug, Clone)]
@@ -32,6 +49,10 @@ pub struct Protocol {
     pub peer: PeerAddr,
     pub info_hash: [u8; 20],
     pub peer_id: [u8; 20],
+    /// How long the handshake and the bitfield may take.
+    pub handshake_timeout: Duration,
+    /// Longer messages are refused before reading them, see `max_message_length`.
+    pub max_message_length: usize,
 }
 
 impl Protocol {
@@ -44,9 +65,21 @@ impl Protocol {
             peer,
             info_hash,
             peer_id,
+            handshake_timeout: TorrentConfig::default().handshake_timeout,
+            max_message_length: max_message_length(0),
         })
     }
 
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    pub fn with_max_message_length(mut self, length: usize) -> Self {
+        self.max_message_length = length;
+        self
+    }
+
     pub async fn read(
         &self,
         mut stream: impl AsyncReadExt + Unpin,
@@ -60,11 +93,13 @@ impl Protocol {
 
         let length = BigEndian::read_u32(&length_buf) as usize;
 
-        // Check if length is zero (keep-alive in BT),
-        // or is otherwise "invalid" (too large, etc.)
+        // A zero length is a keep-alive, not the end of the stream.
         if length == 0 {
-            // Possibly treat as keep-alive or return Ok(None):
-            return Ok(None);
+            return Ok(Some(Message::KeepAlive));
+        }
+        // The length comes straight off the wire, don't let it size our buffer.
+        if length > self.max_message_length {
+            return Err(ProtocolError::MessageTooLong(length));
         }
 
         // Read exactly `length` bytes for the payload
@@ -74,8 +109,7 @@ impl Protocol {
             .await
             .map_err(ProtocolError::Io)?;
 
-        // Delegate to your parser
-        Ok(message::read(&length_buf, &msg_bytes))
+        message::try_read(&length_buf, &msg_bytes).map_err(ProtocolError::Message)
     }
 
     pub async fn send_request(
@@ -139,28 +173,9 @@ impl Protocol {
         &self,
         stream: &mut TcpStream,
     ) -> Result<Handshake, ProtocolError> {
-        let timeout = tokio::time::timeout(Duration::from_secs(HANDSHAKE_TIMEOUT), async {
-            let handshake = Handshake::new(self.info_hash, self.peer_id);
-            let handshake_bytes = handshake.serialize();
-            stream
-                .write_all(&handshake_bytes)
-                .await
-                .map_err(ProtocolError::Io)?;
-
-            let protocol_str_len_buf = &mut [0u8; 1];
-            stream
-                .read_exact(protocol_str_len_buf)
-                .await
-                .map_err(ProtocolError::Io)?;
-            let protocol_str_len = protocol_str_len_buf[0] as usize;
-            let handshake_bytes = &mut vec![0u8; protocol_str_len + 48];
-            stream
-                .read_exact(handshake_bytes)
-                .await
-                .map_err(ProtocolError::Io)?;
-
-            Handshake::read(protocol_str_len, handshake_bytes.to_vec())
-                .map_err(ProtocolError::Handshake)
+        let timeout = tokio::time::timeout(self.handshake_timeout, async {
+            self.write_handshake(stream).await?;
+            read_handshake(stream).await
         })
         .await;
 
@@ -176,6 +191,34 @@ impl Protocol {
         }
     }
 
+    /// Reads the handshake of a peer that connected to us. We only know which
+    /// torrent it wants once this returns.
+    pub async fn receive_handshake(
+        stream: &mut TcpStream,
+        timeout: Duration,
+    ) -> Result<Handshake, ProtocolError> {
+        match tokio::time::timeout(timeout, read_handshake(stream)).await {
+            Ok(r) => r,
+            Err(e) => Err(ProtocolError::Timeout(e)),
+        }
+    }
+
+    /// Answers the handshake of a peer that connected to us.
+    pub async fn send_handshake(&self, stream: &mut TcpStream) -> Result<(), ProtocolError> {
+        match tokio::time::timeout(self.handshake_timeout, self.write_handshake(stream)).await {
+            Ok(r) => r,
+            Err(e) => Err(ProtocolError::Timeout(e)),
+        }
+    }
+
+    async fn write_handshake(&self, stream: &mut TcpStream) -> Result<(), ProtocolError> {
+        let handshake = Handshake::new(self.info_hash, self.peer_id).with_extension_protocol();
+        stream
+            .write_all(&handshake.serialize())
+            .await
+            .map_err(ProtocolError::Io)
+    }
+
     pub async fn recv_bitfield(&self, stream: &mut TcpStream) -> Result<Vec<u8>, ProtocolError> {
         let func = async {
             match self.read(stream).await? {
@@ -186,10 +229,64 @@ impl Protocol {
                 },
             }
         };
-        match tokio::time::timeout(Duration::from_secs(6), func).await {
+        match tokio::time::timeout(self.handshake_timeout, func).await {
             Ok(Ok(b)) => Ok(b),
             Ok(Err(e)) => Err(e),
             Err(e) => Err(ProtocolError::Timeout(e)),
         }
     }
 }
+
+async fn read_handshake(stream: &mut TcpStream) -> Result<Handshake, ProtocolError> {
+    let protocol_str_len_buf = &mut [0u8; 1];
+    stream
+        .read_exact(protocol_str_len_buf)
+        .await
+        .map_err(ProtocolError::Io)?;
+    let protocol_str_len = protocol_str_len_buf[0] as usize;
+    let handshake_bytes = &mut vec![0u8; protocol_str_len + 48];
+    stream
+        .read_exact(handshake_bytes)
+        .await
+        .map_err(ProtocolError::Io)?;
+
+    Handshake::read(protocol_str_len, handshake_bytes.to_vec()).map_err(ProtocolError::Handshake)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn protocol() -> Protocol {
+        let peer: PeerAddr = "127.0.0.1:6881".parse().unwrap();
+        Protocol::connect(peer, [0; 20], [0; 20]).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn refuses_too_long_messages() {
+        let protocol = protocol()
+            .await
+            .with_max_message_length(max_message_length(8));
+        let mut stream: &[u8] = &u32::MAX.to_be_bytes();
+        assert!(matches!(
+            protocol.read(&mut stream).await,
+            Err(ProtocolError::MessageTooLong(len)) if len == u32::MAX as usize
+        ));
+
+        let mut stream: &[u8] = &[0, 0, 0, 5, 4, 0, 0, 0, 1];
+        assert!(matches!(
+            protocol.read(&mut stream).await,
+            Ok(Some(Message::Have(1)))
+        ));
+    }
+
+    #[test]
+    fn max_message_length_fits_the_bitfield() {
+        let piece = 13 + utils::BLOCK_SIZE as usize + EXTENDED_HEADROOM;
+        assert_eq!(max_message_length(8), piece);
+        assert_eq!(
+            max_message_length(1_000_001),
+            1 + 125_001 + EXTENDED_HEADROOM
+        );
+    }
+}
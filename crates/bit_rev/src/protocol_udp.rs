@@ -1 +1,443 @@
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
 
+use byteorder::{BigEndian, ByteOrder};
+use tokio::{net::UdpSocket, time::Instant};
+
+use serde_bytes::ByteBuf;
+
+use crate::{
+    network::Network,
+    peer::{BencodeResponse, TrackerPeerList},
+    proxy::{self, ProxyConfig, Socks5Udp},
+    tracker::{Announce, AnnounceEvent, ScrapeStats, TrackerError},
+};
+
+/// Connection id of the connect request, BEP 15.
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+/// Info hashes that fit in a single scrape request.
+pub const MAX_SCRAPE_HASHES: usize = 74;
+/// Timeout of the first attempt, doubled on every retry.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_ATTEMPTS: u32 = 3;
+
+/// A UDP tracker we got a connection id from.
+#[derive(Debug)]
+pub struct UdpTracker {
+    socket: UdpSocket,
+    route: Route,
+    connection_id: u64,
+    /// Announce replies hold IPv6 peers when we reach the tracker over IPv6.
+    ipv6: bool,
+}
+
+#[derive(Debug)]
+enum Route {
+    /// The socket is connected to the tracker.
+    Direct,
+    /// Through the UDP relay of a SOCKS5 proxy, which resolves `host`.
+    Socks {
+        relay: Socks5Udp,
+        host: String,
+        port: u16,
+    },
+}
+
+impl UdpTracker {
+    pub async fn connect(network: &Network, addr: SocketAddr) -> Result<UdpTracker, TrackerError> {
+        let socket = network.udp(addr.is_ipv6(), 0)?;
+        socket.connect(addr).await?;
+        UdpTracker::handshake(socket, Route::Direct, addr.is_ipv6()).await
+    }
+
+    /// Reaches the tracker at `host:port` through a SOCKS5 proxy.
+    pub async fn connect_through(
+        network: &Network,
+        proxy: &ProxyConfig,
+        host: &str,
+        port: u16,
+    ) -> Result<UdpTracker, TrackerError> {
+        let relay = proxy.udp_associate(network).await?;
+        let socket = network.udp(relay.relay().is_ipv6(), 0)?;
+        let route = Route::Socks {
+            relay,
+            host: host.to_string(),
+            port,
+        };
+        // The proxy resolves names, we can only tell for IPv6 literals.
+        let ipv6 = host.parse::<Ipv6Addr>().is_ok();
+        UdpTracker::handshake(socket, route, ipv6).await
+    }
+
+    async fn handshake(
+        socket: UdpSocket,
+        route: Route,
+        ipv6: bool,
+    ) -> Result<UdpTracker, TrackerError> {
+        let mut tracker = UdpTracker {
+            socket,
+            route,
+            connection_id: PROTOCOL_ID,
+            ipv6,
+        };
+        let res = tracker.request(ACTION_CONNECT, &[]).await?;
+        if res.len() < 8 {
+            return Err(TrackerError::InvalidResponse("connect reply too short"));
+        }
+        tracker.connection_id = BigEndian::read_u64(&res[..8]);
+        Ok(tracker)
+    }
+
+    /// Announces to the tracker. The reply is turned into the one of an HTTP
+    /// tracker, with the peers in `peers` or `peers6`.
+    pub async fn announce(&self, announce: &Announce) -> Result<BencodeResponse, TrackerError> {
+        let event: u32 = match announce.event {
+            AnnounceEvent::None => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        };
+        // Only an IPv4 address fits, 0 lets the tracker use the sender's.
+        let ip = match announce.ip {
+            Some(IpAddr::V4(ip)) => u32::from(ip),
+            _ => 0,
+        };
+        let numwant = announce.numwant.map(|n| n as i32).unwrap_or(-1);
+
+        let mut body = Vec::with_capacity(82);
+        body.extend_from_slice(&announce.info_hash);
+        body.extend_from_slice(&announce.peer_id);
+        body.extend_from_slice(&announce.downloaded.to_be_bytes());
+        body.extend_from_slice(&announce.left.to_be_bytes());
+        body.extend_from_slice(&announce.uploaded.to_be_bytes());
+        body.extend_from_slice(&event.to_be_bytes());
+        body.extend_from_slice(&ip.to_be_bytes());
+        body.extend_from_slice(&announce.key.to_be_bytes());
+        body.extend_from_slice(&numwant.to_be_bytes());
+        body.extend_from_slice(&announce.port.to_be_bytes());
+
+        let res = self.request(ACTION_ANNOUNCE, &body).await?;
+        if res.len() < 12 {
+            return Err(TrackerError::InvalidResponse("announce reply too short"));
+        }
+        let peers = ByteBuf::from(&res[12..]);
+        let (peers, peers6) = match self.ipv6 {
+            true => (TrackerPeerList::default(), peers),
+            false => (TrackerPeerList::Compact(peers), ByteBuf::new()),
+        };
+        Ok(BencodeResponse {
+            interval: Some(BigEndian::read_u32(&res[0..4]) as u64),
+            incomplete: Some(BigEndian::read_u32(&res[4..8]) as u64),
+            complete: Some(BigEndian::read_u32(&res[8..12]) as u64),
+            peers,
+            peers6,
+            ..Default::default()
+        })
+    }
+
+    /// Swarm counts of each of `info_hashes`, in the same order.
+    pub async fn scrape(&self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerError> {
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let res = self.request(ACTION_SCRAPE, &chunk.concat()).await?;
+            if res.len() < chunk.len() * 12 {
+                return Err(TrackerError::InvalidResponse("scrape reply too short"));
+            }
+            stats.extend(
+                res.chunks_exact(12)
+                    .take(chunk.len())
+                    .map(|counts| ScrapeStats {
+                        seeders: BigEndian::read_u32(&counts[0..4]) as u64,
+                        completed: BigEndian::read_u32(&counts[4..8]) as u64,
+                        leechers: BigEndian::read_u32(&counts[8..12]) as u64,
+                    }),
+            );
+        }
+        Ok(stats)
+    }
+
+    // Sends a request until the tracker answers it, and returns what follows
+    // the action and transaction id of the reply.
+    async fn request(&self, action: u32, body: &[u8]) -> Result<Vec<u8>, TrackerError> {
+        let transaction_id: u32 = rand::random();
+        let mut packet = Vec::with_capacity(16 + body.len());
+        packet.extend_from_slice(&self.connection_id.to_be_bytes());
+        packet.extend_from_slice(&action.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        packet.extend_from_slice(body);
+
+        let mut buf = vec![0u8; 2048];
+        let mut timeout = REQUEST_TIMEOUT;
+        for _ in 0..REQUEST_ATTEMPTS {
+            self.send(&packet).await?;
+            let deadline = Instant::now() + timeout;
+            while let Ok(received) = tokio::time::timeout_at(deadline, self.recv(&mut buf)).await {
+                let Some(res) = received? else {
+                    continue;
+                };
+                // A late reply to an earlier attempt, or garbage.
+                if res.len() < 8 || BigEndian::read_u32(&res[4..8]) != transaction_id {
+                    continue;
+                }
+                let reply = &res[8..];
+                return match BigEndian::read_u32(&res[..4]) {
+                    ACTION_ERROR => Err(TrackerError::Failure(
+                        String::from_utf8_lossy(reply).into_owned(),
+                    )),
+                    a if a == action => Ok(reply.to_vec()),
+                    _ => Err(TrackerError::InvalidResponse("reply to another action")),
+                };
+            }
+            timeout *= 2;
+        }
+        Err(TrackerError::Timeout)
+    }
+
+    async fn send(&self, packet: &[u8]) -> Result<(), TrackerError> {
+        match &self.route {
+            Route::Direct => self.socket.send(packet).await?,
+            Route::Socks { relay, host, port } => {
+                self.socket
+                    .send_to(&proxy::udp_packet(host, *port, packet), relay.relay())
+                    .await?
+            }
+        };
+        Ok(())
+    }
+
+    // The payload of the next datagram, None if it isn't from the tracker.
+    async fn recv<'a>(&self, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>, TrackerError> {
+        match &self.route {
+            Route::Direct => {
+                let n = self.socket.recv(buf).await?;
+                Ok(Some(&buf[..n]))
+            }
+            Route::Socks { relay, .. } => {
+                let (n, from) = self.socket.recv_from(buf).await?;
+                if from != relay.relay() {
+                    return Ok(None);
+                }
+                Ok(proxy::udp_payload(&buf[..n]))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answers a connect and then a scrape of two torrents, or an error if
+    // `error` is set.
+    async fn fake_tracker(error: Option<&'static str>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            let connection_id: u64 = 0x1234;
+
+            let (n, peer) = socket.recv_from(&mut buf).await.unwrap();
+            assert_eq!(n, 16);
+            assert_eq!(BigEndian::read_u64(&buf[..8]), PROTOCOL_ID);
+            assert_eq!(BigEndian::read_u32(&buf[8..12]), ACTION_CONNECT);
+            let mut reply = vec![0, 0, 0, 0];
+            reply.extend_from_slice(&buf[12..16]);
+            reply.extend_from_slice(&connection_id.to_be_bytes());
+            socket.send_to(&reply, peer).await.unwrap();
+
+            let (n, peer) = socket.recv_from(&mut buf).await.unwrap();
+            assert_eq!(n, 16 + 2 * 20);
+            assert_eq!(BigEndian::read_u64(&buf[..8]), connection_id);
+            assert_eq!(BigEndian::read_u32(&buf[8..12]), ACTION_SCRAPE);
+            // A stray packet first, it must be skipped.
+            socket.send_to(&[0xff; 8], peer).await.unwrap();
+            let mut reply = match error {
+                Some(_) => ACTION_ERROR.to_be_bytes().to_vec(),
+                None => ACTION_SCRAPE.to_be_bytes().to_vec(),
+            };
+            reply.extend_from_slice(&buf[12..16]);
+            match error {
+                Some(message) => reply.extend_from_slice(message.as_bytes()),
+                None => {
+                    for count in [5u32, 10, 3, 1, 2, 0] {
+                        reply.extend_from_slice(&count.to_be_bytes());
+                    }
+                }
+            }
+            socket.send_to(&reply, peer).await.unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn scrape() {
+        let tracker = UdpTracker::connect(&Network::default(), fake_tracker(None).await)
+            .await
+            .unwrap();
+        assert_eq!(tracker.connection_id, 0x1234);
+
+        let stats = tracker.scrape(&[[1; 20], [2; 20]]).await.unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats {
+                    seeders: 5,
+                    completed: 10,
+                    leechers: 3
+                },
+                ScrapeStats {
+                    seeders: 1,
+                    completed: 2,
+                    leechers: 0
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn announce() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            let (_, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let mut reply = ACTION_CONNECT.to_be_bytes().to_vec();
+            reply.extend_from_slice(&buf[12..16]);
+            reply.extend_from_slice(&7u64.to_be_bytes());
+            socket.send_to(&reply, peer).await.unwrap();
+
+            let (n, peer) = socket.recv_from(&mut buf).await.unwrap();
+            assert_eq!(n, 98);
+            assert_eq!(BigEndian::read_u64(&buf[..8]), 7);
+            assert_eq!(BigEndian::read_u32(&buf[8..12]), ACTION_ANNOUNCE);
+            assert_eq!(&buf[16..36], &[0xab; 20]);
+            assert_eq!(&buf[36..56], b"-BR0001-abcdefghijkl");
+            // downloaded, left, uploaded
+            assert_eq!(BigEndian::read_u64(&buf[56..64]), 20);
+            assert_eq!(BigEndian::read_u64(&buf[64..72]), 30);
+            assert_eq!(BigEndian::read_u64(&buf[72..80]), 10);
+            // started, no ip, key, numwant, port
+            assert_eq!(BigEndian::read_u32(&buf[80..84]), 2);
+            assert_eq!(BigEndian::read_u32(&buf[84..88]), 0);
+            assert_eq!(BigEndian::read_u32(&buf[88..92]), 0xdead);
+            assert_eq!(BigEndian::read_i32(&buf[92..96]), -1);
+            assert_eq!(BigEndian::read_u16(&buf[96..98]), 6881);
+
+            let mut reply = ACTION_ANNOUNCE.to_be_bytes().to_vec();
+            reply.extend_from_slice(&buf[12..16]);
+            for value in [1800u32, 3, 5] {
+                reply.extend_from_slice(&value.to_be_bytes());
+            }
+            reply.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]);
+            socket.send_to(&reply, peer).await.unwrap();
+        });
+
+        let tracker = UdpTracker::connect(&Network::default(), addr)
+            .await
+            .unwrap();
+        let announce = Announce {
+            info_hash: [0xab; 20],
+            peer_id: *b"-BR0001-abcdefghijkl",
+            port: 6881,
+            uploaded: 10,
+            downloaded: 20,
+            left: 30,
+            event: AnnounceEvent::Started,
+            numwant: None,
+            key: 0xdead,
+            tracker_id: None,
+            ip: None,
+            ipv6: None,
+        };
+        let res = tracker.announce(&announce).await.unwrap();
+        assert_eq!(res.interval, Some(1800));
+        assert_eq!((res.complete, res.incomplete), (Some(5), Some(3)));
+        assert_eq!(
+            res.get_peers().unwrap(),
+            vec![
+                "127.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:6882".parse().unwrap()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn scrape_error() {
+        let tracker = UdpTracker::connect(
+            &Network::default(),
+            fake_tracker(Some("unknown torrent")).await,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            tracker.scrape(&[[1; 20], [2; 20]]).await,
+            Err(TrackerError::Failure(message)) if message == "unknown torrent"
+        ));
+    }
+
+    // A SOCKS5 proxy relaying the datagrams of one client to `tracker`.
+    async fn fake_udp_proxy(tracker: SocketAddr) -> SocketAddr {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut control, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            control.read_exact(&mut greeting).await.unwrap();
+            control.write_all(&[5, 0]).await.unwrap();
+            let mut request = [0u8; 10];
+            control.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request[..2], &[5, 3]);
+
+            let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let port = relay.local_addr().unwrap().port().to_be_bytes();
+            // An unspecified relay address means the proxy's own.
+            control
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, port[0], port[1]])
+                .await
+                .unwrap();
+
+            let mut buf = [0u8; 2048];
+            let mut client = None;
+            loop {
+                let (n, from) = relay.recv_from(&mut buf).await.unwrap();
+                if from == tracker {
+                    let reply = proxy::udp_packet("10.0.0.1", 80, &buf[..n]);
+                    relay.send_to(&reply, client.unwrap()).await.unwrap();
+                } else {
+                    client = Some(from);
+                    // The proxy resolves the name of the tracker.
+                    assert_eq!(&buf[3..16], b"\x03\x0btracker.lan");
+                    let payload = proxy::udp_payload(&buf[..n]).unwrap();
+                    relay.send_to(payload, tracker).await.unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn scrape_through_socks5() {
+        let proxy_addr = fake_udp_proxy(fake_tracker(None).await).await;
+        let proxy = ProxyConfig {
+            kind: proxy::ProxyKind::Socks5,
+            addr: proxy_addr.to_string(),
+            username: None,
+            password: None,
+            proxy_only: true,
+        };
+        let tracker = UdpTracker::connect_through(&Network::default(), &proxy, "tracker.lan", 80)
+            .await
+            .unwrap();
+        let stats = tracker.scrape(&[[1; 20], [2; 20]]).await.unwrap();
+        assert_eq!(stats[0].seeders, 5);
+        assert_eq!(stats[1].completed, 2);
+    }
+}
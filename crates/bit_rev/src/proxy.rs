@@ -0,0 +1,461 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::network::Network;
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_PASSWORD_AUTH: u8 = 2;
+const SOCKS_NO_ACCEPTABLE_AUTH: u8 = 0xff;
+const SOCKS_CONNECT: u8 = 1;
+const SOCKS_UDP_ASSOCIATE: u8 = 3;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+/// Longest response head we accept from an HTTP proxy.
+const MAX_HTTP_RESPONSE: usize = 8 * 1024;
+
+#[derive(Error, Debug)]
+pub enum ProxyError {
+    #[error("proxy: {0}")]
+    Io(#[from] io::Error),
+    #[error("proxy refused the connection: {0}")]
+    Refused(String),
+    #[error("proxy authentication failed")]
+    AuthFailed,
+    #[error("invalid reply from the proxy")]
+    InvalidReply,
+    /// Only SOCKS5 proxies relay UDP.
+    #[error("{0:?} proxies can't relay UDP")]
+    UdpUnsupported(ProxyKind),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyKind {
+    Socks5,
+    /// An HTTP proxy tunneling connections with `CONNECT`.
+    Http,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxyConfig {
+    pub kind: ProxyKind,
+    /// `host:port` of the proxy.
+    pub addr: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Refuse whatever can't go through the proxy: incoming peers, the DHT,
+    /// local service discovery, and UDP trackers behind an HTTP proxy.
+    #[serde(default)]
+    pub proxy_only: bool,
+}
+
+impl ProxyConfig {
+    /// The same proxy for tracker HTTP requests.
+    pub fn reqwest_proxy(&self) -> Result<reqwest::Proxy, reqwest::Error> {
+        match self.kind {
+            ProxyKind::Http => {
+                let proxy = reqwest::Proxy::all(format!("http://{}", self.addr))?;
+                Ok(match &self.username {
+                    Some(username) => {
+                        proxy.basic_auth(username, self.password.as_deref().unwrap_or(""))
+                    }
+                    None => proxy,
+                })
+            }
+            // socks5h resolves tracker names on the proxy too.
+            ProxyKind::Socks5 => match &self.username {
+                Some(username) => reqwest::Proxy::all(format!(
+                    "socks5h://{}:{}@{}",
+                    percent_encode(username),
+                    percent_encode(self.password.as_deref().unwrap_or("")),
+                    self.addr
+                )),
+                None => reqwest::Proxy::all(format!("socks5h://{}", self.addr)),
+            },
+        }
+    }
+
+    /// Opens a TCP connection to `host:port` through the proxy, which we
+    /// reach through `network`. `host` can be a name, which the proxy
+    /// resolves.
+    pub async fn connect(
+        &self,
+        network: &Network,
+        host: &str,
+        port: u16,
+    ) -> Result<TcpStream, ProxyError> {
+        let mut stream = network.connect_host(&self.addr).await?;
+        match self.kind {
+            ProxyKind::Socks5 => {
+                self.socks_request(&mut stream, SOCKS_CONNECT, host, port)
+                    .await?;
+            }
+            ProxyKind::Http => self.http_connect(&mut stream, host, port).await?,
+        }
+        Ok(stream)
+    }
+
+    /// Asks a SOCKS5 proxy to relay UDP for us.
+    pub async fn udp_associate(&self, network: &Network) -> Result<Socks5Udp, ProxyError> {
+        if self.kind != ProxyKind::Socks5 {
+            return Err(ProxyError::UdpUnsupported(self.kind));
+        }
+        let mut control = network.connect_host(&self.addr).await?;
+        let mut relay = self
+            .socks_request(&mut control, SOCKS_UDP_ASSOCIATE, "0.0.0.0", 0)
+            .await?;
+        // The relay is on the proxy when it doesn't say where.
+        if relay.ip().is_unspecified() {
+            relay.set_ip(control.peer_addr()?.ip());
+        }
+        Ok(Socks5Udp {
+            _control: control,
+            relay,
+        })
+    }
+
+    // Authenticates and sends a SOCKS5 request, returns the bound address of
+    // the reply.
+    async fn socks_request(
+        &self,
+        stream: &mut TcpStream,
+        command: u8,
+        host: &str,
+        port: u16,
+    ) -> Result<SocketAddr, ProxyError> {
+        let method = if self.username.is_some() {
+            SOCKS_PASSWORD_AUTH
+        } else {
+            SOCKS_NO_AUTH
+        };
+        stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        match reply {
+            [SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_AUTH] => return Err(ProxyError::AuthFailed),
+            [SOCKS_VERSION, m] if m == method => {}
+            _ => return Err(ProxyError::InvalidReply),
+        }
+
+        if let Some(username) = &self.username {
+            // RFC 1929.
+            let password = self.password.as_deref().unwrap_or("");
+            let mut auth = vec![1, username.len() as u8];
+            auth.extend_from_slice(username.as_bytes());
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(password.as_bytes());
+            stream.write_all(&auth).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(ProxyError::AuthFailed);
+            }
+        }
+
+        let mut request = vec![SOCKS_VERSION, command, 0];
+        write_socks_addr(&mut request, host, port);
+        stream.write_all(&request).await?;
+
+        let mut head = [0u8; 4];
+        stream.read_exact(&mut head).await?;
+        if head[0] != SOCKS_VERSION {
+            return Err(ProxyError::InvalidReply);
+        }
+        if head[1] != 0 {
+            return Err(ProxyError::Refused(
+                socks_reply_message(head[1]).to_string(),
+            ));
+        }
+        let ip: IpAddr = match head[3] {
+            ATYP_IPV4 => {
+                let mut octets = [0u8; 4];
+                stream.read_exact(&mut octets).await?;
+                Ipv4Addr::from(octets).into()
+            }
+            ATYP_IPV6 => {
+                let mut octets = [0u8; 16];
+                stream.read_exact(&mut octets).await?;
+                Ipv6Addr::from(octets).into()
+            }
+            ATYP_DOMAIN => {
+                // We never use a bound name, skip it.
+                let len = stream.read_u8().await?;
+                let mut name = vec![0u8; len as usize];
+                stream.read_exact(&mut name).await?;
+                Ipv4Addr::UNSPECIFIED.into()
+            }
+            _ => return Err(ProxyError::InvalidReply),
+        };
+        let port = stream.read_u16().await?;
+        Ok(SocketAddr::new(ip, port))
+    }
+
+    async fn http_connect(
+        &self,
+        stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+    ) -> Result<(), ProxyError> {
+        let authority = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+            _ => format!("{}:{}", host, port),
+        };
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+        if let Some(username) = &self.username {
+            let credentials = format!("{}:{}", username, self.password.as_deref().unwrap_or(""));
+            request.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                STANDARD.encode(credentials)
+            ));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // One byte at a time, so we don't read into the tunnel.
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() > MAX_HTTP_RESPONSE {
+                return Err(ProxyError::InvalidReply);
+            }
+            response.push(stream.read_u8().await?);
+        }
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            Some("407") => Err(ProxyError::AuthFailed),
+            Some(_) => Err(ProxyError::Refused(status_line.to_string())),
+            None => Err(ProxyError::InvalidReply),
+        }
+    }
+}
+
+/// A UDP relay of a SOCKS5 proxy. The relay lasts as long as the control
+/// connection.
+#[derive(Debug)]
+pub struct Socks5Udp {
+    _control: TcpStream,
+    relay: SocketAddr,
+}
+
+impl Socks5Udp {
+    /// Where the datagrams go, wrapped with `udp_packet`.
+    pub fn relay(&self) -> SocketAddr {
+        self.relay
+    }
+}
+
+/// A datagram for `host:port`, to send to a SOCKS5 UDP relay.
+pub fn udp_packet(host: &str, port: u16, data: &[u8]) -> Vec<u8> {
+    // Reserved, then no fragmentation.
+    let mut packet = vec![0, 0, 0];
+    write_socks_addr(&mut packet, host, port);
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// The payload of a datagram from a SOCKS5 UDP relay.
+pub fn udp_payload(packet: &[u8]) -> Option<&[u8]> {
+    if packet.len() < 4 || packet[2] != 0 {
+        return None;
+    }
+    let header = match packet[3] {
+        ATYP_IPV4 => 4 + 4 + 2,
+        ATYP_IPV6 => 4 + 16 + 2,
+        ATYP_DOMAIN => 4 + 1 + *packet.get(4)? as usize + 2,
+        _ => return None,
+    };
+    packet.get(header..)
+}
+
+fn write_socks_addr(buf: &mut Vec<u8>, host: &str, port: u16) {
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            buf.push(ATYP_DOMAIN);
+            buf.push(host.len() as u8);
+            buf.extend_from_slice(host.as_bytes());
+        }
+    }
+    buf.extend_from_slice(&port.to_be_bytes());
+}
+
+fn socks_reply_message(reply: u8) -> &'static str {
+    match reply {
+        1 => "general failure",
+        2 => "not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn proxy(kind: ProxyKind, addr: SocketAddr, username: Option<&str>) -> ProxyConfig {
+        ProxyConfig {
+            kind,
+            addr: addr.to_string(),
+            username: username.map(str::to_string),
+            password: username.map(|_| "secret".to_string()),
+            proxy_only: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn socks5_connect_with_password() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 3];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [5, 1, SOCKS_PASSWORD_AUTH]);
+            socket.write_all(&[5, SOCKS_PASSWORD_AUTH]).await.unwrap();
+
+            let mut auth = [0u8; 3 + 5 + 6];
+            socket.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x05alice\x06secret");
+            socket.write_all(&[1, 0]).await.unwrap();
+
+            let mut request = [0u8; 4 + 4 + 2];
+            socket.read_exact(&mut request).await.unwrap();
+            assert_eq!(
+                request,
+                [5, SOCKS_CONNECT, 0, ATYP_IPV4, 10, 0, 0, 1, 0x1a, 0xe1]
+            );
+            socket
+                .write_all(&[5, 0, 0, ATYP_IPV4, 127, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+            socket.write_all(b"tunnel").await.unwrap();
+        });
+
+        let mut stream = proxy(ProxyKind::Socks5, addr, Some("alice"))
+            .connect(&Network::default(), "10.0.0.1", 6881)
+            .await
+            .unwrap();
+        let mut data = [0u8; 6];
+        stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"tunnel");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn socks5_refusal() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 3];
+            socket.read_exact(&mut buf).await.unwrap();
+            socket.write_all(&[5, SOCKS_NO_AUTH]).await.unwrap();
+            let mut request = [0u8; 4 + 1 + 11 + 2];
+            socket.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request[3..16], b"\x03\x0btracker.lan");
+            socket
+                .write_all(&[5, 5, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+
+        let result = proxy(ProxyKind::Socks5, addr, None)
+            .connect(&Network::default(), "tracker.lan", 80)
+            .await;
+        assert!(matches!(result, Err(ProxyError::Refused(m)) if m == "connection refused"));
+    }
+
+    #[tokio::test]
+    async fn http_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for status in [
+                "200 Connection established",
+                "407 Proxy Authentication Required",
+            ] {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    request.push(socket.read_u8().await.unwrap());
+                }
+                let request = String::from_utf8(request).unwrap();
+                assert!(request.starts_with("CONNECT [2001:db8::1]:6881 HTTP/1.1\r\n"));
+                assert!(request.contains("Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n"));
+                socket
+                    .write_all(format!("HTTP/1.1 {}\r\n\r\ntunnel", status).as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let proxy = proxy(ProxyKind::Http, addr, Some("alice"));
+        let mut stream = proxy
+            .connect(&Network::default(), "2001:db8::1", 6881)
+            .await
+            .unwrap();
+        let mut data = [0u8; 6];
+        stream.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"tunnel");
+
+        assert!(matches!(
+            proxy
+                .connect(&Network::default(), "2001:db8::1", 6881)
+                .await,
+            Err(ProxyError::AuthFailed)
+        ));
+    }
+
+    #[test]
+    fn udp_headers() {
+        let packet = udp_packet("10.0.0.1", 6969, b"data");
+        assert_eq!(
+            &packet[..10],
+            &[0, 0, 0, ATYP_IPV4, 10, 0, 0, 1, 0x1b, 0x39]
+        );
+        assert_eq!(udp_payload(&packet), Some(&b"data"[..]));
+
+        let packet = udp_packet("tracker.lan", 6969, b"data");
+        assert_eq!(udp_payload(&packet), Some(&b"data"[..]));
+        // Fragments are dropped.
+        assert_eq!(udp_payload(&[0, 0, 1, ATYP_IPV4]), None);
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::peer::PeerAddr;
+
+/// Token bucket holding up to one second worth of bytes. Callers may dig it
+/// into debt and wait for the tokens to come back, so a message bigger than
+/// the bucket still goes through.
+#[derive(Debug)]
+struct TokenBucket {
+    /// Bytes per second, `0` means unlimited. Shared by every bucket that
+    /// follows the same limit.
+    limit: Arc<AtomicU64>,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: Option<u64>) -> Self {
+        Self::with_limit(Arc::new(AtomicU64::new(limit.unwrap_or(0))))
+    }
+
+    fn with_limit(limit: Arc<AtomicU64>) -> Self {
+        Self {
+            limit,
+            state: Mutex::new(BucketState {
+                // Capped to a full bucket on the first refill.
+                tokens: f64::INFINITY,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    fn limit(&self) -> Option<u64> {
+        Some(self.limit.load(Ordering::Relaxed)).filter(|&limit| limit > 0)
+    }
+
+    fn set_limit(&self, limit: Option<u64>) {
+        self.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Takes `bytes` out of the bucket and returns how long to wait before
+    /// using them.
+    fn reserve(&self, bytes: u64) -> Duration {
+        let Some(limit) = self.limit() else {
+            return Duration::ZERO;
+        };
+        let limit = limit as f64;
+
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last).as_secs_f64();
+        state.last = now;
+        state.tokens = (state.tokens + elapsed * limit).min(limit) - bytes as f64;
+
+        if state.tokens < 0.0 {
+            Duration::from_secs_f64(-state.tokens / limit)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// Upload and download limits, in bytes per second. They can be changed at
+/// any time, connections pick up the new values right away.
+#[derive(Debug)]
+pub struct RateLimiter {
+    upload: TokenBucket,
+    download: TokenBucket,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(None, None)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(upload_limit: Option<u64>, download_limit: Option<u64>) -> Self {
+        Self {
+            upload: TokenBucket::new(upload_limit),
+            download: TokenBucket::new(download_limit),
+        }
+    }
+
+    pub fn upload_limit(&self) -> Option<u64> {
+        self.upload.limit()
+    }
+
+    pub fn download_limit(&self) -> Option<u64> {
+        self.download.limit()
+    }
+
+    /// `None` lifts the limit.
+    pub fn set_upload_limit(&self, limit: Option<u64>) {
+        self.upload.set_limit(limit);
+    }
+
+    /// `None` lifts the limit.
+    pub fn set_download_limit(&self, limit: Option<u64>) {
+        self.download.set_limit(limit);
+    }
+
+    /// A limiter with buckets of its own that follows the limits of this one.
+    /// Used to give every peer of a torrent the same per-peer limits.
+    pub fn follow(&self) -> RateLimiter {
+        Self {
+            upload: TokenBucket::with_limit(self.upload.limit.clone()),
+            download: TokenBucket::with_limit(self.download.limit.clone()),
+        }
+    }
+}
+
+/// The limits a single connection is subject to: its own, its torrent's and
+/// the session's.
+#[derive(Debug)]
+pub struct PeerRateLimiter {
+    peer: RateLimiter,
+    torrent: Arc<RateLimiter>,
+    global: Arc<RateLimiter>,
+    /// Whether peers on the local network skip the limits. Shared with the
+    /// session so it can be changed at runtime.
+    exempt_lan_peers: Arc<AtomicBool>,
+    is_local: bool,
+}
+
+impl PeerRateLimiter {
+    pub fn new(
+        peer: PeerAddr,
+        peer_limits: &RateLimiter,
+        torrent: Arc<RateLimiter>,
+        global: Arc<RateLimiter>,
+        exempt_lan_peers: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            peer: peer_limits.follow(),
+            torrent,
+            global,
+            exempt_lan_peers,
+            is_local: is_local(peer.ip()),
+        }
+    }
+
+    /// Waits until we may send `bytes` to the peer.
+    pub async fn upload(&self, bytes: usize) {
+        if self.is_exempt() {
+            return;
+        }
+        let wait = [&self.peer, &*self.torrent, &*self.global]
+            .iter()
+            .map(|limiter| limiter.upload.reserve(bytes as u64))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Waits until we may read more after receiving `bytes` from the peer.
+    pub async fn download(&self, bytes: u64) {
+        if self.is_exempt() {
+            return;
+        }
+        let wait = [&self.peer, &*self.torrent, &*self.global]
+            .iter()
+            .map(|limiter| limiter.download.reserve(bytes))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn is_exempt(&self) -> bool {
+        self.is_local && self.exempt_lan_peers.load(Ordering::Relaxed)
+    }
+}
+
+/// Waits until we may read more after receiving `bytes` from outside of a
+/// peer connection, e.g. a web seed, under every one of `limiters`.
+pub async fn download(limiters: &[&RateLimiter], bytes: u64) {
+    let wait = limiters
+        .iter()
+        .map(|limiter| limiter.download.reserve(bytes))
+        .max()
+        .unwrap_or_default();
+    if !wait.is_zero() {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Loopback, private and link-local addresses.
+pub fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_local(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            // Unique local fc00::/7 and link-local fe80::/10.
+            ip.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_refills_at_the_limit() {
+        let bucket = TokenBucket::new(Some(1000));
+
+        // A full bucket lets the first second worth of bytes through.
+        assert_eq!(bucket.reserve(1000), Duration::ZERO);
+        assert_eq!(bucket.reserve(500), Duration::from_millis(500));
+
+        tokio::time::advance(Duration::from_millis(1500)).await;
+        assert_eq!(bucket.reserve(1000), Duration::ZERO);
+
+        bucket.set_limit(None);
+        assert_eq!(bucket.reserve(1_000_000), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peers_wait_for_the_strictest_limit() {
+        let global = Arc::new(RateLimiter::new(Some(1000), None));
+        let torrent = Arc::new(RateLimiter::default());
+        let peer_limits = RateLimiter::new(None, Some(100));
+        let limiter = PeerRateLimiter::new(
+            "8.8.8.8:6881".parse().unwrap(),
+            &peer_limits,
+            torrent,
+            global.clone(),
+            Arc::new(AtomicBool::new(true)),
+        );
+
+        let start = Instant::now();
+        limiter.upload(3000).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+
+        let start = Instant::now();
+        limiter.download(300).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+
+        // Limits apply to connections that are already open.
+        global.set_upload_limit(None);
+        let start = Instant::now();
+        limiter.upload(1_000_000).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lan_peers_can_be_exempt() {
+        let global = Arc::new(RateLimiter::new(Some(10), Some(10)));
+        let exempt = Arc::new(AtomicBool::new(true));
+        let limiter = PeerRateLimiter::new(
+            "192.168.1.10:6881".parse().unwrap(),
+            &RateLimiter::default(),
+            Arc::default(),
+            global,
+            exempt.clone(),
+        );
+
+        let start = Instant::now();
+        limiter.upload(1000).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        exempt.store(false, Ordering::Relaxed);
+        limiter.upload(20).await;
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn local_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.0.1",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+        ] {
+            assert!(is_local(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "2001:db8::1", "::ffff:8.8.8.8"] {
+            assert!(!is_local(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
@@ -1,9 +1,68 @@
-use std::sync::Arc;
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
 
-use crate::torrent::Torrent;
-use crate::tracker_peers::TrackerPeers;
-use crate::utils;
-use flume::Receiver;
+use dashmap::{mapref::entry::Entry, DashMap};
+use thiserror::Error;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, watch},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, trace, warn};
+
+pub use crate::config::{ConfigError, SessionConfig, TorrentConfig};
+use crate::{
+    connection_manager::ConnectionManager,
+    dht::{self, Dht},
+    events::{Event, EventKind, Events},
+    file::TorrentMeta,
+    ip_filter::IpFilter,
+    lsd::Lsd,
+    network::Network,
+    peer::PeerAddr,
+    peer_connection::PeerError,
+    peer_id,
+    protocol::Protocol,
+    proxy::ProxyConfig,
+    rate_limit::RateLimiter,
+    stats::TorrentStats,
+    storage::{DiskIo, Storage, StorageError},
+    streaming::{FileReader, StreamFocus},
+    tracker::{HttpClient, TrackerInfo},
+    tracker_peers::TrackerPeers,
+};
+
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error("can't listen on port {port}: {source}")]
+    Listen { port: u16, source: std::io::Error },
+    #[error("can't start the DHT: {0}")]
+    Dht(std::io::Error),
+    #[error("can't bind to the network: {0}")]
+    Bind(std::io::Error),
+    #[error("can't create the HTTP client: {0}")]
+    Http(reqwest::Error),
+    #[error("can't read the ip filter {}: {source}", path.display())]
+    IpFilter {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("torrent failed: {0}")]
+    TorrentFailed(String),
+    #[error("torrent was removed")]
+    TorrentRemoved,
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct PieceWork {
@@ -26,55 +85,786 @@ pub struct State {
     pub buf: Vec<u8>,
 }
 
+/// Wait after a failed accept, e.g. out of file descriptors, doubled while
+/// it keeps failing.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(50);
+const MAX_ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// What every torrent of a session shares.
+#[derive(Debug)]
+pub struct SessionContext {
+    pub peer_id: [u8; 20],
+    /// The port we actually listen on.
+    pub listen_port: u16,
+    pub download_dir: PathBuf,
+    pub connection_manager: Arc<ConnectionManager>,
+    pub dht: Option<Arc<Dht>>,
+    /// The IPv6 DHT, BEP 32.
+    pub dht6: Option<Arc<Dht>>,
+    pub lsd: Option<Arc<Lsd>>,
+    pub disk: Arc<DiskIo>,
+    pub events: Events,
+    /// Limits of the whole session.
+    pub rate_limiter: Arc<RateLimiter>,
+    /// Limits new torrents give each of their peers.
+    pub peer_upload_limit: Option<u64>,
+    pub peer_download_limit: Option<u64>,
+    pub exempt_lan_peers: Arc<AtomicBool>,
+    /// Sent to trackers as `key`, same for every torrent of the session.
+    pub announce_key: u32,
+    pub announce_ip: Option<IpAddr>,
+    /// Client for tracker announces and web seeds.
+    pub http: HttpClient,
+    /// Opens our sockets from the interface or address of the settings.
+    pub network: Network,
+    /// Proxy of tracker and peer connections.
+    pub proxy: Option<ProxyConfig>,
+    /// Blocklist the IP filter is read from.
+    pub ip_filter_path: Option<PathBuf>,
+    /// Settings of the torrents added without settings of their own.
+    pub torrent_config: TorrentConfig,
+}
+
+impl SessionContext {
+    /// The DHT nodes we run, one per address family.
+    pub fn dhts(&self) -> impl Iterator<Item = &Arc<Dht>> {
+        self.dht.iter().chain(self.dht6.iter())
+    }
+}
+
+/// Downloads many torrents at once, sharing one peer id, listen socket, DHT
+/// node, connection limits and disk I/O between them.
+///
+/// Dropping the session stops every torrent.
 pub struct Session {
-    pub tracker_stream: TrackerPeers,
-    pub pr_rx: Receiver<PieceResult>,
+    context: Arc<SessionContext>,
+    torrents: Arc<DashMap<[u8; 20], TorrentHandle>>,
+    cancel: CancellationToken,
 }
 
 impl Session {
-    pub async fn download_torrent(
-        torrent: Torrent,
-        tracker_stream: TrackerPeers,
-        have_broadcast: Arc<tokio::sync::broadcast::Sender<u32>>,
-    ) -> Self {
-        let piece_rx = tracker_stream.piece_rx.clone();
-        let (pr_tx, pr_rx) = flume::bounded::<PieceResult>(torrent.piece_hashes.len());
-        //let (pr_tx, pr_rx) = flume::unbounded::<PieceResult>();
-
-        let pieces_of_work = (0..(torrent.piece_hashes.len()) as u64)
-            .map(|index| {
-                let length = utils::calculate_piece_size(&torrent, index as usize);
-                PieceWork {
-                    index: index as u32,
-                    length: length as u32,
-                    hash: torrent.piece_hashes[index as usize],
+    pub async fn new(mut config: SessionConfig) -> Result<Session, SessionError> {
+        config.torrent.validate()?;
+        let listen_error = |source| SessionError::Listen {
+            port: config.listen_port,
+            source,
+        };
+        let network = Network::new(config.bind.clone());
+        // Incoming peers, the DHT and LSD can't go through a proxy.
+        let proxy_only = config.proxy.as_ref().is_some_and(|proxy| proxy.proxy_only);
+        let listener = if proxy_only {
+            None
+        } else {
+            Some(network.listen(config.listen_port).map_err(listen_error)?)
+        };
+        let listen_port = match &listener {
+            Some(listener) => listener.local_addr().map_err(listen_error)?.port(),
+            None => config.listen_port,
+        };
+
+        let enable_dht = config.enable_dht && !proxy_only;
+        let dht = if enable_dht && network.can_reach(false) {
+            Some(start_dht(&network, false, listen_port).map_err(SessionError::Dht)?)
+        } else {
+            None
+        };
+        // Plenty of machines have no IPv6, they only get the IPv4 DHT.
+        let dht6 = if enable_dht && network.can_reach(true) {
+            match start_dht(&network, true, listen_port) {
+                Ok(dht6) => Some(dht6),
+                Err(e) if dht.is_some() => {
+                    warn!("ipv6 dht unavailable: {}", e);
+                    None
                 }
-            })
-            .collect::<Vec<PieceWork>>();
+                Err(e) => return Err(SessionError::Dht(e)),
+            }
+        } else {
+            None
+        };
 
-        tracker_stream.connect(pieces_of_work).await;
+        // Machines without multicast still work, just without LSD.
+        let lsd = if config.enable_lsd && !proxy_only {
+            Lsd::bind(&network, listen_port)
+                .await
+                .inspect_err(|e| warn!("local service discovery unavailable: {}", e))
+                .ok()
+        } else {
+            None
+        };
 
-        let have_broadcast = have_broadcast.clone();
+        let connection_manager = ConnectionManager::new(config.connection_limits);
+        if let Some(path) = &config.ip_filter {
+            connection_manager.set_ip_filter(load_ip_filter(path)?);
+        }
 
-        tokio::spawn(async move {
-            loop {
-                let pr_tx = pr_tx.clone();
-                let piece_rx = piece_rx.clone();
-                let piece = piece_rx.recv_async().await.unwrap();
-                have_broadcast.send(piece.index).unwrap();
-
-                let pr = PieceResult {
-                    index: piece.index,
-                    length: piece.length,
-                    buf: piece.buf,
+        let local_address = network.http_address().map_err(SessionError::Bind)?;
+        let http = HttpClient::new(network.clone(), config.proxy.clone(), local_address)
+            .map_err(SessionError::Http)?;
+
+        let context = Arc::new(SessionContext {
+            peer_id: peer_id::generate_with_prefix(&config.peer_id_prefix),
+            listen_port,
+            download_dir: config.download_dir,
+            connection_manager: Arc::new(connection_manager),
+            dht,
+            dht6,
+            lsd,
+            disk: Arc::new(DiskIo::new(config.max_disk_ops)),
+            events: Events::default(),
+            rate_limiter: Arc::new(RateLimiter::new(config.upload_limit, config.download_limit)),
+            peer_upload_limit: config.peer_upload_limit,
+            peer_download_limit: config.peer_download_limit,
+            exempt_lan_peers: Arc::new(AtomicBool::new(config.exempt_lan_peers)),
+            announce_key: rand::random(),
+            announce_ip: config.announce_ip,
+            http,
+            network,
+            proxy: config.proxy,
+            ip_filter_path: config.ip_filter,
+            torrent_config: config.torrent,
+        });
+
+        let session = Session {
+            context,
+            torrents: Arc::new(DashMap::new()),
+            cancel: CancellationToken::new(),
+        };
+
+        if let Some(listener) = listener {
+            let torrents = session.torrents.clone();
+            tokio::spawn(
+                session
+                    .cancel
+                    .clone()
+                    .run_until_cancelled_owned(accept_peers(
+                        listener,
+                        torrents,
+                        session.context.clone(),
+                    )),
+            );
+        }
+
+        Ok(session)
+    }
+
+    pub fn context(&self) -> &Arc<SessionContext> {
+        &self.context
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.context.peer_id
+    }
+
+    pub fn listen_port(&self) -> u16 {
+        self.context.listen_port
+    }
+
+    /// Upload and download limits shared by every torrent of the session.
+    pub fn rate_limiter(&self) -> &Arc<RateLimiter> {
+        &self.context.rate_limiter
+    }
+
+    /// Whether peers on the local network skip the rate limits.
+    pub fn set_exempt_lan_peers(&self, exempt: bool) {
+        self.context
+            .exempt_lan_peers
+            .store(exempt, Ordering::Relaxed);
+    }
+
+    /// Replaces the IP filter, disconnecting the peers it blocks.
+    pub fn set_ip_filter(&self, filter: IpFilter) {
+        self.context.connection_manager.set_ip_filter(filter);
+        for torrent in self.torrents.iter() {
+            torrent.inner.peers.connections.disconnect_blocked();
+        }
+    }
+
+    /// Reads the blocklist of the settings again, e.g. after it was updated.
+    /// Does nothing without one.
+    pub fn reload_ip_filter(&self) -> Result<(), SessionError> {
+        if let Some(path) = &self.context.ip_filter_path {
+            self.set_ip_filter(load_ip_filter(path)?);
+        }
+        Ok(())
+    }
+
+    /// Distinct IP addresses the IP filter turned away since the session started.
+    pub fn blocked_peers(&self) -> u64 {
+        self.context.connection_manager.blocked_peers()
+    }
+
+    /// Subscribes to the events of every torrent of the session, starting
+    /// from now.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.context.events.subscribe()
+    }
+
+    /// Adds a torrent, checks the data already on disk and starts downloading
+    /// what is missing. Adding a torrent twice returns the handle of the one
+    /// already there.
+    pub async fn add_torrent(
+        &self,
+        torrent_meta: TorrentMeta,
+    ) -> Result<TorrentHandle, SessionError> {
+        let config = self.context.torrent_config.clone();
+        self.add_torrent_with_config(torrent_meta, config).await
+    }
+
+    /// Like [`Session::add_torrent`], with settings of its own for the torrent.
+    pub async fn add_torrent_with_config(
+        &self,
+        torrent_meta: TorrentMeta,
+        config: TorrentConfig,
+    ) -> Result<TorrentHandle, SessionError> {
+        self.insert_torrent(torrent_meta, config, None).await
+    }
+
+    /// Like [`Session::add_torrent`], saving the torrent's file, or the
+    /// directory of its files, at `path` instead of under `download_dir`.
+    pub async fn add_torrent_at(
+        &self,
+        torrent_meta: TorrentMeta,
+        path: impl Into<PathBuf>,
+    ) -> Result<TorrentHandle, SessionError> {
+        let config = self.context.torrent_config.clone();
+        self.insert_torrent(torrent_meta, config, Some(path.into()))
+            .await
+    }
+
+    async fn insert_torrent(
+        &self,
+        torrent_meta: TorrentMeta,
+        mut config: TorrentConfig,
+        root: Option<PathBuf>,
+    ) -> Result<TorrentHandle, SessionError> {
+        config.validate()?;
+        // Checked and inserted under the map's lock, so adding the same torrent
+        // twice at once can't start it twice.
+        let handle = match self.torrents.entry(torrent_meta.info_hash) {
+            Entry::Occupied(entry) => return Ok(entry.get().clone()),
+            Entry::Vacant(entry) => {
+                let disk = self.context.disk.clone();
+                let storage = Arc::new(match root {
+                    Some(root) => Storage::with_root(&torrent_meta, root, disk),
+                    None => Storage::new(&torrent_meta, &self.context.download_dir, disk),
+                });
+                let peers = TrackerPeers::new(
+                    torrent_meta.clone(),
+                    self.context.clone(),
+                    storage.clone(),
+                    config,
+                );
+                let not_running = CancellationToken::new();
+                not_running.cancel();
+
+                let handle = TorrentHandle {
+                    inner: Arc::new(TorrentInner {
+                        torrent_meta,
+                        storage,
+                        peers,
+                        downloaded: AtomicU64::new(0),
+                        state: watch::Sender::new(TorrentState::Checking),
+                        checked: AtomicBool::new(false),
+                        cancel: self.cancel.child_token(),
+                        run: Mutex::new(not_running),
+                        torrents: Arc::downgrade(&self.torrents),
+                    }),
                 };
-                pr_tx.send_async(pr).await.unwrap();
+                entry.insert(handle.clone());
+                handle
             }
+        };
+        let events = &handle.inner.peers.events;
+        events.emit(EventKind::TorrentAdded);
+        handle.start();
+
+        Ok(handle)
+    }
+
+    /// Stops a torrent and forgets about it, see [`TorrentHandle::remove`].
+    pub async fn remove_torrent(
+        &self,
+        info_hash: &[u8; 20],
+        delete_data: bool,
+    ) -> Result<Option<TorrentHandle>, StorageError> {
+        let Some(handle) = self.torrent(info_hash) else {
+            return Ok(None);
+        };
+        handle.remove(delete_data).await?;
+        Ok(Some(handle))
+    }
+
+    pub fn torrent(&self, info_hash: &[u8; 20]) -> Option<TorrentHandle> {
+        self.torrents.get(info_hash).map(|t| t.clone())
+    }
+
+    pub fn torrents(&self) -> Vec<TorrentHandle> {
+        self.torrents.iter().map(|t| t.clone()).collect()
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Hashing the data already on disk.
+    Checking,
+    Downloading,
+    /// Every piece is on disk, we only upload.
+    Seeding,
+    /// Peers are disconnected, resuming picks up where we left off.
+    Paused,
+    /// Like paused, but the data is checked again on resume since it may have
+    /// changed in the meantime.
+    Stopped,
+    /// Something went wrong with the data on disk, the torrent was stopped.
+    Error(String),
+}
+
+/// A torrent running in a [`Session`].
+#[derive(Clone)]
+pub struct TorrentHandle {
+    inner: Arc<TorrentInner>,
+}
+
+struct TorrentInner {
+    torrent_meta: TorrentMeta,
+    storage: Arc<Storage>,
+    peers: TrackerPeers,
+    /// Bytes of verified pieces written to disk.
+    downloaded: AtomicU64,
+    state: watch::Sender<TorrentState>,
+    /// The data on disk was checked since the torrent was last started.
+    checked: AtomicBool,
+    /// Child of the session token, cancelled when the torrent is removed.
+    cancel: CancellationToken,
+    /// Token of the current run, cancelled when pausing or stopping. State
+    /// changes happen with this lock held so they can't race with the run.
+    run: Mutex<CancellationToken>,
+    torrents: Weak<DashMap<[u8; 20], TorrentHandle>>,
+}
+
+impl TorrentHandle {
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.inner.torrent_meta.info_hash
+    }
+
+    pub fn name(&self) -> &str {
+        &self.inner.torrent_meta.torrent_file.info.name
+    }
+
+    pub fn torrent_meta(&self) -> &TorrentMeta {
+        &self.inner.torrent_meta
+    }
+
+    pub fn storage(&self) -> &Arc<Storage> {
+        &self.inner.storage
+    }
+
+    pub fn peers(&self) -> &TrackerPeers {
+        &self.inner.peers
+    }
+
+    pub fn state(&self) -> TorrentState {
+        self.inner.state.borrow().clone()
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.inner.storage.total_length()
+    }
+
+    /// Bytes of verified pieces written to disk.
+    pub fn downloaded(&self) -> u64 {
+        self.inner.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.inner
+            .peers
+            .torrent_downloaded_state
+            .is_verified_complete()
+    }
+
+    /// Streams file `index` of the torrent while it downloads. `None` if
+    /// there is no such file.
+    pub fn open_file(&self, index: usize) -> Option<FileReader> {
+        if index >= self.inner.storage.files().len() {
+            return None;
+        }
+        Some(FileReader::new(
+            self.inner.storage.clone(),
+            self.inner.peers.torrent_downloaded_state.clone(),
+            self.inner.cancel.clone(),
+            index,
+        ))
+    }
+
+    /// Downloads the pieces in order, see [`TorrentConfig::sequential`].
+    pub fn set_sequential(&self, sequential: bool) {
+        self.focus().set_sequential(sequential);
+    }
+
+    pub fn is_sequential(&self) -> bool {
+        self.focus().is_sequential()
+    }
+
+    fn focus(&self) -> std::sync::MutexGuard<'_, StreamFocus> {
+        self.inner
+            .peers
+            .torrent_downloaded_state
+            .focus
+            .lock()
+            .unwrap()
+    }
+
+    pub fn config(&self) -> &TorrentConfig {
+        &self.inner.peers.config
+    }
+
+    /// Upload and download limits of the torrent as a whole.
+    pub fn rate_limiter(&self) -> &Arc<RateLimiter> {
+        &self.inner.peers.rate_limiter
+    }
+
+    /// Upload and download limits of each peer of the torrent.
+    pub fn peer_rate_limiter(&self) -> &RateLimiter {
+        &self.inner.peers.peer_rate_limiter
+    }
+
+    /// The trackers of the torrent and how our last announces went.
+    pub fn trackers(&self) -> Vec<TrackerInfo> {
+        self.inner.peers.trackers()
+    }
+
+    /// Transfer statistics of the torrent and its connected peers.
+    pub fn stats(&self) -> TorrentStats {
+        let peers = &self.inner.peers;
+        let transfer = peers.stats.snapshot();
+        let downloaded = self.downloaded();
+        let left = self.total_length().saturating_sub(downloaded);
+        let eta = if left == 0 {
+            Some(Duration::ZERO)
+        } else if transfer.download_rate > 0.0 {
+            Some(Duration::from_secs_f64(
+                left as f64 / transfer.download_rate,
+            ))
+        } else {
+            None
+        };
+
+        let mut peer_stats = peers.peer_states.stats();
+        for stats in &mut peer_stats {
+            stats.source = peers.connections.source(&stats.addr);
+        }
+
+        TorrentStats {
+            state: self.state(),
+            total_length: self.total_length(),
+            downloaded,
+            transfer,
+            eta,
+            piece_availability: peers
+                .peer_states
+                .availability(peers.torrent_downloaded_state.pieces.len()),
+            peers: peer_stats,
+            blocked_peers: peers.connections.blocked_peers(),
+        }
+    }
+
+    /// Waits until every piece is verified and on disk. Fails if the torrent
+    /// runs into an error or is removed first.
+    pub async fn wait_for_completion(&self) -> Result<(), SessionError> {
+        let mut state = self.inner.state.subscribe();
+        loop {
+            if self.is_complete() {
+                return Ok(());
+            }
+            if self.inner.cancel.is_cancelled() {
+                return Err(SessionError::TorrentRemoved);
+            }
+            if let TorrentState::Error(e) = &*state.borrow_and_update() {
+                return Err(SessionError::TorrentFailed(e.clone()));
+            }
+            // The sender lives as long as this handle.
+            if state.changed().await.is_err() {
+                return Err(SessionError::TorrentRemoved);
+            }
+        }
+    }
+
+    /// Disconnects every peer and stops talking to trackers, keeping what was
+    /// downloaded so far.
+    pub fn pause(&self) {
+        self.halt(TorrentState::Paused);
+    }
+
+    /// Like [`TorrentHandle::pause`], but the data is checked again on resume.
+    pub fn stop(&self) {
+        self.halt(TorrentState::Stopped);
+    }
+
+    /// Restarts a paused, stopped or failed torrent.
+    pub fn resume(&self) {
+        match self.state() {
+            TorrentState::Paused => {}
+            TorrentState::Stopped | TorrentState::Error(_) => {
+                self.inner.checked.store(false, Ordering::Relaxed);
+            }
+            _ => return,
+        }
+        self.start();
+    }
+
+    /// Stops the torrent for good and removes it from its session, deleting
+    /// the downloaded files if `delete_data` is set.
+    pub async fn remove(&self, delete_data: bool) -> Result<(), StorageError> {
+        if let Some(torrents) = self.inner.torrents.upgrade() {
+            torrents.remove(&self.info_hash());
+        }
+        self.inner.cancel.cancel();
+        self.halt(TorrentState::Stopped);
+
+        if delete_data {
+            self.inner.storage.remove_files().await?;
+        }
+        self.inner.peers.events.emit(EventKind::TorrentRemoved);
+        Ok(())
+    }
+
+    fn halt(&self, state: TorrentState) {
+        let run = self.inner.run.lock().unwrap();
+        if run.is_cancelled() && *self.inner.state.borrow() == state {
+            return;
+        }
+        run.cancel();
+        self.inner.peers.connections.disconnect_all();
+        self.set_state(state);
+    }
+
+    // Starts a new run: checks the data if needed, then looks for peers.
+    fn start(&self) {
+        if self.inner.cancel.is_cancelled() {
+            return;
+        }
+        let run = {
+            let mut run = self.inner.run.lock().unwrap();
+            if !run.is_cancelled() {
+                return;
+            }
+            *run = self.inner.cancel.child_token();
+            if !self.inner.checked.load(Ordering::Relaxed) {
+                self.set_state(TorrentState::Checking);
+            }
+            run.clone()
+        };
+
+        let handle = self.clone();
+        tokio::spawn(run.clone().run_until_cancelled_owned(async move {
+            if !handle.inner.checked.load(Ordering::Relaxed) {
+                if let Err(e) = handle.check().await {
+                    error!("error checking {}: {}", handle.name(), e);
+                    handle.fail(&run, e.to_string());
+                    return;
+                }
+                handle.inner.checked.store(true, Ordering::Relaxed);
+            }
+
+            handle.set_state_if_running(&run, handle.active_state());
+            handle.inner.peers.connect(run.clone()).await;
+            handle.save_pieces(&run).await;
+        }));
+    }
+
+    fn active_state(&self) -> TorrentState {
+        if self.is_complete() {
+            TorrentState::Seeding
+        } else {
+            TorrentState::Downloading
+        }
+    }
+
+    fn set_state_if_running(&self, run: &CancellationToken, state: TorrentState) {
+        let _run = self.inner.run.lock().unwrap();
+        if !run.is_cancelled() {
+            self.set_state(state);
+        }
+    }
+
+    // Callers hold the run lock, so state changes are emitted in order.
+    fn set_state(&self, to: TorrentState) {
+        let from = self.inner.state.send_replace(to.clone());
+        if from != to {
+            self.inner
+                .peers
+                .events
+                .emit(EventKind::StateChanged { from, to });
+        }
+    }
+
+    fn fail(&self, run: &CancellationToken, error: String) {
+        let _lock = self.inner.run.lock().unwrap();
+        if run.is_cancelled() {
+            return;
+        }
+        run.cancel();
+        self.inner.peers.connections.disconnect_all();
+        self.inner.peers.events.emit(EventKind::StorageError {
+            error: error.clone(),
         });
+        self.set_state(TorrentState::Error(error));
+    }
+
+    // Hashes the pieces already on disk, so we only download what is missing.
+    async fn check(&self) -> Result<(), StorageError> {
+        let state = &self.inner.peers.torrent_downloaded_state;
+        let mut downloaded = 0;
+
+        for piece in state.pieces.iter() {
+            let pw = piece.piece_work;
+            let valid = match self
+                .inner
+                .storage
+                .check_piece(pw.index, pw.length, pw.hash)
+                .await
+            {
+                Ok(valid) => valid,
+                Err(e)
+                    if matches!(
+                        e.io_kind(),
+                        Some(std::io::ErrorKind::NotFound | std::io::ErrorKind::UnexpectedEof)
+                    ) =>
+                {
+                    false
+                }
+                Err(e) => return Err(e),
+            };
 
-        Self {
-            tracker_stream,
-            pr_rx,
+            if valid {
+                state.set_verified(pw.index);
+                downloaded += pw.length as u64;
+            } else if state.is_verified(pw.index) {
+                state.set_missing(pw.index);
+            }
         }
+
+        trace!("{} has {} bytes on disk", self.name(), downloaded);
+        self.inner.downloaded.store(downloaded, Ordering::Relaxed);
+        Ok(())
     }
+
+    // Writes the pieces that passed the hash check to disk and tells the peers
+    // about them.
+    async fn save_pieces(&self, run: &CancellationToken) {
+        let inner = &self.inner;
+        let state = &inner.peers.torrent_downloaded_state;
+
+        while let Ok(piece) = inner.peers.piece_rx.recv_async().await {
+            if let Err(e) = inner.storage.write_piece(piece.index, piece.buf).await {
+                error!("error writing piece {}: {}", piece.index, e);
+                state.remove_downloaded(piece.index);
+                self.fail(run, e.to_string());
+                return;
+            }
+            state.set_verified(piece.index);
+            inner
+                .downloaded
+                .fetch_add(piece.length as u64, Ordering::Relaxed);
+            // Nobody listening just means we have no peers right now.
+            let _ = inner.peers.have_broadcast.send(piece.index);
+
+            let events = &inner.peers.events;
+            events.emit(EventKind::PieceVerified { index: piece.index });
+            for file in inner.storage.files_in_piece(piece.index) {
+                if inner
+                    .storage
+                    .file_pieces(file)
+                    .all(|index| state.is_verified(index))
+                {
+                    events.emit(EventKind::FileCompleted {
+                        index: file,
+                        path: inner.storage.files()[file].path.clone(),
+                    });
+                }
+            }
+
+            if self.is_complete() {
+                debug!("torrent {} is complete", self.name());
+                events.emit(EventKind::TorrentFinished);
+                self.set_state_if_running(run, TorrentState::Seeding);
+            }
+        }
+    }
+}
+
+fn start_dht(network: &Network, ipv6: bool, port: u16) -> std::io::Result<Arc<Dht>> {
+    let dht = Dht::with_socket(network.udp(ipv6, port)?);
+    let bootstrap = dht.clone();
+    tokio::spawn(async move { bootstrap.bootstrap(dht::BOOTSTRAP_NODES).await });
+    Ok(dht)
+}
+
+fn load_ip_filter(path: &std::path::Path) -> Result<IpFilter, SessionError> {
+    let filter = IpFilter::load(path).map_err(|source| SessionError::IpFilter {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    debug!("ip filter blocks {} ranges", filter.len());
+    Ok(filter)
+}
+
+// Hands incoming connections to the torrent they ask for.
+async fn accept_peers(
+    listener: TcpListener,
+    torrents: Arc<DashMap<[u8; 20], TorrentHandle>>,
+    context: Arc<SessionContext>,
+) {
+    let handshake_timeout = context.torrent_config.handshake_timeout;
+    let mut retry_delay = ACCEPT_RETRY_DELAY;
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            // The dual-stack listener maps IPv4 peers into IPv6.
+            Ok((stream, peer)) => {
+                retry_delay = ACCEPT_RETRY_DELAY;
+                (
+                    stream,
+                    SocketAddr::new(peer.ip().to_canonical(), peer.port()),
+                )
+            }
+            Err(e) => {
+                error!("error accepting peer: {:?}", e);
+                tokio::time::sleep(retry_delay).await;
+                retry_delay = (retry_delay * 2).min(MAX_ACCEPT_RETRY_DELAY);
+                continue;
+            }
+        };
+        if context.connection_manager.block(&peer) {
+            trace!("ip filter blocks incoming peer {}", peer);
+            continue;
+        }
+        let torrents = torrents.clone();
+        tokio::spawn(async move {
+            if let Err(e) = accept_peer(stream, peer, torrents, handshake_timeout).await {
+                debug!("error accepting peer {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn accept_peer(
+    mut stream: TcpStream,
+    peer: PeerAddr,
+    torrents: Arc<DashMap<[u8; 20], TorrentHandle>>,
+    handshake_timeout: Duration,
+) -> Result<(), PeerError> {
+    let handshake = Protocol::receive_handshake(&mut stream, handshake_timeout).await?;
+    let Some(handle) = torrents.get(&handshake.info_hash).map(|t| t.clone()) else {
+        return Err(PeerError::UnknownTorrent);
+    };
+    trace!("incoming peer {} for {}", peer, handle.name());
+    handle.inner.peers.accept(peer, stream, handshake);
+    Ok(())
 }
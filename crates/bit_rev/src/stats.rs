@@ -0,0 +1,296 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::{connection_manager::PeerSource, peer::PeerAddr, session::TorrentState};
+
+/// Number of one second buckets the rolling rates are computed over.
+const RATE_BUCKETS: usize = 5;
+
+/// Bytes per second over the last few seconds.
+#[derive(Debug)]
+struct RateMeter {
+    start: Instant,
+    buckets: [u64; RATE_BUCKETS],
+    /// Second, counted from `start`, the newest bucket belongs to.
+    current: u64,
+}
+
+impl RateMeter {
+    fn new(start: Instant) -> Self {
+        Self {
+            start,
+            buckets: [0; RATE_BUCKETS],
+            current: 0,
+        }
+    }
+
+    fn record(&mut self, now: Instant, bytes: u64) {
+        let second = self.advance(now);
+        self.buckets[second as usize % RATE_BUCKETS] += bytes;
+    }
+
+    fn rate(&mut self, now: Instant) -> f64 {
+        self.advance(now);
+        let elapsed = now.saturating_duration_since(self.start).as_secs_f64();
+        let partial = elapsed.fract();
+        let window = elapsed.min((RATE_BUCKETS - 1) as f64 + partial);
+        // Don't report a huge rate for the first block of a connection.
+        self.buckets.iter().sum::<u64>() as f64 / window.max(1.0)
+    }
+
+    // Clears the buckets of the seconds that went by without any data.
+    fn advance(&mut self, now: Instant) -> u64 {
+        let second = now.saturating_duration_since(self.start).as_secs();
+        if second > self.current {
+            let stale = (second - self.current).min(RATE_BUCKETS as u64);
+            for s in 1..=stale {
+                self.buckets[(self.current + s) as usize % RATE_BUCKETS] = 0;
+            }
+            self.current = second;
+        }
+        self.current
+    }
+}
+
+/// Live transfer counters of a peer or a torrent. The counters of a peer also
+/// add up into the ones of its torrent.
+#[derive(Debug)]
+pub struct TransferStats {
+    downloaded: AtomicU64,
+    payload_downloaded: AtomicU64,
+    uploaded: AtomicU64,
+    payload_uploaded: AtomicU64,
+    wasted: AtomicU64,
+    failed: AtomicU64,
+    download_rate: Mutex<RateMeter>,
+    upload_rate: Mutex<RateMeter>,
+    parent: Option<Arc<TransferStats>>,
+}
+
+impl Default for TransferStats {
+    fn default() -> Self {
+        let now = Instant::now();
+        Self {
+            downloaded: AtomicU64::new(0),
+            payload_downloaded: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
+            payload_uploaded: AtomicU64::new(0),
+            wasted: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            download_rate: Mutex::new(RateMeter::new(now)),
+            upload_rate: Mutex::new(RateMeter::new(now)),
+            parent: None,
+        }
+    }
+}
+
+impl TransferStats {
+    /// Counters that also add up into `parent`.
+    pub fn with_parent(parent: Arc<TransferStats>) -> Self {
+        Self {
+            parent: Some(parent),
+            ..Default::default()
+        }
+    }
+
+    /// We read `total` bytes off the wire, `payload` of them piece data.
+    pub fn on_received(&self, total: u64, payload: u64) {
+        self.downloaded.fetch_add(total, Ordering::Relaxed);
+        self.payload_downloaded
+            .fetch_add(payload, Ordering::Relaxed);
+        if payload > 0 {
+            self.download_rate
+                .lock()
+                .unwrap()
+                .record(Instant::now(), payload);
+        }
+        if let Some(parent) = &self.parent {
+            parent.on_received(total, payload);
+        }
+    }
+
+    /// We wrote `total` bytes to the wire, `payload` of them piece data.
+    pub fn on_sent(&self, total: u64, payload: u64) {
+        self.uploaded.fetch_add(total, Ordering::Relaxed);
+        self.payload_uploaded.fetch_add(payload, Ordering::Relaxed);
+        if payload > 0 {
+            self.upload_rate
+                .lock()
+                .unwrap()
+                .record(Instant::now(), payload);
+        }
+        if let Some(parent) = &self.parent {
+            parent.on_sent(total, payload);
+        }
+    }
+
+    /// Piece data we received but had no use for, e.g. a block we already had.
+    pub fn on_wasted(&self, bytes: u64) {
+        self.wasted.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(parent) = &self.parent {
+            parent.on_wasted(bytes);
+        }
+    }
+
+    /// Piece data thrown away because the piece failed the hash check.
+    pub fn on_failed(&self, bytes: u64) {
+        self.failed.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(parent) = &self.parent {
+            parent.on_failed(bytes);
+        }
+    }
+
+    pub fn snapshot(&self) -> TransferSnapshot {
+        let now = Instant::now();
+        let downloaded = self.downloaded.load(Ordering::Relaxed);
+        let payload_downloaded = self.payload_downloaded.load(Ordering::Relaxed);
+        let uploaded = self.uploaded.load(Ordering::Relaxed);
+        let payload_uploaded = self.payload_uploaded.load(Ordering::Relaxed);
+        TransferSnapshot {
+            payload_downloaded,
+            payload_uploaded,
+            protocol_downloaded: downloaded.saturating_sub(payload_downloaded),
+            protocol_uploaded: uploaded.saturating_sub(payload_uploaded),
+            download_rate: self.download_rate.lock().unwrap().rate(now),
+            upload_rate: self.upload_rate.lock().unwrap().rate(now),
+            wasted: self.wasted.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Transfer counters at one point in time. Byte counts are totals since the
+/// connection or torrent started, rates are payload bytes per second.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TransferSnapshot {
+    pub payload_downloaded: u64,
+    pub payload_uploaded: u64,
+    /// Message framing and every non-piece message.
+    pub protocol_downloaded: u64,
+    pub protocol_uploaded: u64,
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub wasted: u64,
+    pub failed: u64,
+}
+
+/// A connected peer at one point in time.
+#[derive(Debug, Clone)]
+pub struct PeerStats {
+    pub addr: PeerAddr,
+    /// Where we heard of the peer, `None` once it disconnected.
+    pub source: Option<PeerSource>,
+    /// The client the peer runs, if we could tell.
+    pub client: Option<String>,
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    pub snubbed: bool,
+    pub transfer: TransferSnapshot,
+}
+
+/// A torrent at one point in time.
+#[derive(Debug, Clone)]
+pub struct TorrentStats {
+    pub state: TorrentState,
+    pub total_length: u64,
+    /// Bytes of verified pieces on disk.
+    pub downloaded: u64,
+    pub transfer: TransferSnapshot,
+    /// Time left at the current download rate. `None` while nothing comes in.
+    pub eta: Option<Duration>,
+    /// How many connected peers have each piece.
+    pub piece_availability: Vec<u32>,
+    pub peers: Vec<PeerStats>,
+    /// Distinct peer IP addresses the IP filter turned away.
+    pub blocked_peers: u64,
+}
+
+/// Counts the bytes read through it.
+pub(crate) struct CountingReader<'a, R> {
+    inner: R,
+    count: &'a mut u64,
+}
+
+impl<'a, R> CountingReader<'a, R> {
+    pub(crate) fn new(inner: R, count: &'a mut u64) -> Self {
+        Self { inner, count }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        *self.count += (buf.filled().len() - before) as u64;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_meter_rolls_over() {
+        let start = Instant::now();
+        let mut meter = RateMeter::new(start);
+
+        meter.record(start, 1000);
+        meter.record(start + Duration::from_millis(1500), 1000);
+        assert_eq!(meter.rate(start + Duration::from_secs(2)), 1000.0);
+
+        // The first two seconds fell out of the window.
+        let later = start + Duration::from_secs(2 + RATE_BUCKETS as u64);
+        assert_eq!(meter.rate(later), 0.0);
+        meter.record(later, 500);
+        assert_eq!(meter.rate(later), 500.0 / (RATE_BUCKETS - 1) as f64);
+    }
+
+    #[test]
+    fn peer_counters_add_up_into_the_torrent() {
+        let torrent = Arc::new(TransferStats::default());
+        let peer = TransferStats::with_parent(torrent.clone());
+
+        peer.on_received(16397, 16384);
+        peer.on_sent(17, 0);
+        peer.on_wasted(16384);
+        peer.on_failed(32768);
+
+        let snapshot = torrent.snapshot();
+        assert_eq!(snapshot.payload_downloaded, 16384);
+        assert_eq!(snapshot.protocol_downloaded, 13);
+        assert_eq!(snapshot.payload_uploaded, 0);
+        assert_eq!(snapshot.protocol_uploaded, 17);
+        assert_eq!(snapshot.wasted, 16384);
+        assert_eq!(snapshot.failed, 32768);
+        assert!(snapshot.download_rate > 0.0);
+        assert_eq!(peer.snapshot(), snapshot);
+    }
+
+    #[tokio::test]
+    async fn counting_reader_counts_bytes() {
+        let mut count = 0;
+        let mut buf = [0u8; 4];
+        let data: &[u8] = b"hello";
+        let mut reader = CountingReader::new(data, &mut count);
+        tokio::io::AsyncReadExt::read_exact(&mut reader, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(count, 4);
+    }
+}
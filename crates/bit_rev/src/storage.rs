@@ -0,0 +1,450 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use thiserror::Error;
+use tokio::sync::Semaphore;
+
+use crate::{file::TorrentMeta, utils};
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("{}: {source}", path.display())]
+    File { path: PathBuf, source: io::Error },
+    #[error("{length} bytes at offset {offset} are past the end of the torrent")]
+    OutOfRange { offset: u64, length: u64 },
+    #[error("disk task failed: {0}")]
+    Task(io::Error),
+}
+
+impl StorageError {
+    /// Kind of the underlying I/O error, if any.
+    pub fn io_kind(&self) -> Option<io::ErrorKind> {
+        match self {
+            StorageError::File { source, .. } | StorageError::Task(source) => Some(source.kind()),
+            StorageError::OutOfRange { .. } => None,
+        }
+    }
+}
+
+// Tags an I/O error with the file it happened on.
+fn file_error(path: &Path) -> impl FnOnce(io::Error) -> StorageError + '_ {
+    move |source| StorageError::File {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+// Reads the spans of a range `length` bytes long, blocking.
+fn read_spans(spans: Vec<Span>, length: u32) -> Result<Vec<u8>, StorageError> {
+    let mut buf = vec![0u8; length as usize];
+    for span in spans {
+        let mut file = std::fs::File::open(&span.path).map_err(file_error(&span.path))?;
+        file.seek(SeekFrom::Start(span.file_offset))
+            .and_then(|_| file.read_exact(&mut buf[span.buf_offset..span.buf_offset + span.length]))
+            .map_err(file_error(&span.path))?;
+    }
+    Ok(buf)
+}
+
+/// Runs blocking disk operations on tokio's blocking pool, limiting how
+/// many run at once. One instance is shared by every torrent of a session.
+#[derive(Debug)]
+pub struct DiskIo {
+    ops: Semaphore,
+}
+
+impl Default for DiskIo {
+    fn default() -> Self {
+        Self::new(8)
+    }
+}
+
+impl DiskIo {
+    pub fn new(max_concurrent_ops: usize) -> Self {
+        Self {
+            ops: Semaphore::new(max_concurrent_ops),
+        }
+    }
+
+    pub async fn run<T, F>(&self, f: F) -> Result<T, StorageError>
+    where
+        F: FnOnce() -> Result<T, StorageError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let _permit = self
+            .ops
+            .acquire()
+            .await
+            .map_err(|e| StorageError::Task(io::Error::other(e)))?;
+        tokio::task::spawn_blocking(f)
+            .await
+            .map_err(|e| StorageError::Task(io::Error::other(e)))?
+    }
+}
+
+/// A file of the torrent and where it sits in the torrent's byte stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    pub length: u64,
+    pub offset: u64,
+}
+
+/// Maps pieces to the files they belong to and reads/writes them on disk.
+#[derive(Debug)]
+pub struct Storage {
+    /// The torrent's file, or the directory holding its files.
+    root: PathBuf,
+    files: Vec<FileEntry>,
+    piece_length: u64,
+    total_length: u64,
+    disk: Arc<DiskIo>,
+}
+
+impl Storage {
+    pub fn new(torrent_meta: &TorrentMeta, download_dir: &Path, disk: Arc<DiskIo>) -> Self {
+        let name = &torrent_meta.torrent_file.info.name;
+        let root = download_dir.join(sanitize(std::slice::from_ref(name)));
+        Self::with_root(torrent_meta, root, disk)
+    }
+
+    /// Saves the torrent's file, or the directory of its files, at `root`
+    /// instead of under its name.
+    pub fn with_root(torrent_meta: &TorrentMeta, root: PathBuf, disk: Arc<DiskIo>) -> Self {
+        let info = &torrent_meta.torrent_file.info;
+
+        let mut offset = 0;
+        let files = match &info.files {
+            Some(files) => files
+                .iter()
+                .map(|f| {
+                    let entry = FileEntry {
+                        path: root.join(sanitize(&f.path)),
+                        length: f.length as u64,
+                        offset,
+                    };
+                    offset += entry.length;
+                    entry
+                })
+                .collect(),
+            None => {
+                offset = info.length.unwrap_or(0) as u64;
+                vec![FileEntry {
+                    path: root.clone(),
+                    length: offset,
+                    offset: 0,
+                }]
+            }
+        };
+
+        Self {
+            root,
+            files,
+            piece_length: info.piece_length as u64,
+            total_length: offset,
+            disk,
+        }
+    }
+
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.total_length
+    }
+
+    pub fn piece_length(&self) -> u64 {
+        self.piece_length
+    }
+
+    /// Indices of the files piece `index` holds data of.
+    pub fn files_in_piece(&self, index: u32) -> Vec<usize> {
+        let start = index as u64 * self.piece_length;
+        let end = (start + self.piece_length).min(self.total_length);
+        self.files
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.length > 0 && f.offset < end && start < f.offset + f.length)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// The pieces holding data of file `index`.
+    pub fn file_pieces(&self, index: usize) -> Range<u32> {
+        let file = &self.files[index];
+        if file.length == 0 {
+            return 0..0;
+        }
+        let first = file.offset / self.piece_length;
+        let last = (file.offset + file.length - 1) / self.piece_length;
+        first as u32..last as u32 + 1
+    }
+
+    pub async fn write_piece(&self, index: u32, buf: Vec<u8>) -> Result<(), StorageError> {
+        let spans = self.spans(index as u64 * self.piece_length, buf.len() as u64)?;
+        self.disk
+            .run(move || {
+                for span in spans {
+                    if let Some(parent) = span.path.parent() {
+                        std::fs::create_dir_all(parent).map_err(file_error(parent))?;
+                    }
+                    let mut file = OpenOptions::new()
+                        .create(true)
+                        .truncate(false)
+                        .write(true)
+                        .open(&span.path)
+                        .map_err(file_error(&span.path))?;
+                    file.seek(SeekFrom::Start(span.file_offset))
+                        .and_then(|_| {
+                            file.write_all(&buf[span.buf_offset..span.buf_offset + span.length])
+                        })
+                        .map_err(file_error(&span.path))?;
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    /// Reads `length` bytes of piece `index` starting at `begin`.
+    pub async fn read(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>, StorageError> {
+        let spans = self.spans(
+            index as u64 * self.piece_length + begin as u64,
+            length as u64,
+        )?;
+        self.disk.run(move || read_spans(spans, length)).await
+    }
+
+    /// Reads piece `index` and tells whether it matches `hash`, hashing on
+    /// the disk thread too.
+    pub async fn check_piece(
+        &self,
+        index: u32,
+        length: u32,
+        hash: [u8; 20],
+    ) -> Result<bool, StorageError> {
+        let spans = self.spans(index as u64 * self.piece_length, length as u64)?;
+        self.disk
+            .run(move || {
+                let buf = read_spans(spans, length)?;
+                Ok(utils::check_integrity(&hash, &buf))
+            })
+            .await
+    }
+
+    /// Deletes the torrent's files, along with the directories they leave empty.
+    pub async fn remove_files(&self) -> Result<(), StorageError> {
+        let root = self.root.clone();
+        let paths: Vec<PathBuf> = self.files.iter().map(|f| f.path.clone()).collect();
+        self.disk
+            .run(move || {
+                for path in paths.iter() {
+                    match std::fs::remove_file(path) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                            return Err(file_error(path)(e))
+                        }
+                        _ => {}
+                    }
+                }
+                for path in paths.iter() {
+                    let mut dir = path.parent();
+                    while let Some(d) = dir.filter(|d| d.starts_with(&root)) {
+                        // Fails on directories that still hold something, which is fine.
+                        let _ = std::fs::remove_dir(d);
+                        dir = d.parent();
+                    }
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    /// Splits a range of the torrent into the pieces of each file it covers.
+    fn spans(&self, offset: u64, length: u64) -> Result<Vec<Span>, StorageError> {
+        let end = offset + length;
+        if end > self.total_length {
+            return Err(StorageError::OutOfRange { offset, length });
+        }
+        Ok(self
+            .files
+            .iter()
+            .filter(|f| f.length > 0 && f.offset < end && offset < f.offset + f.length)
+            .map(|f| {
+                let start = offset.max(f.offset);
+                let stop = end.min(f.offset + f.length);
+                Span {
+                    path: f.path.clone(),
+                    file_offset: start - f.offset,
+                    buf_offset: (start - offset) as usize,
+                    length: (stop - start) as usize,
+                }
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Span {
+    path: PathBuf,
+    file_offset: u64,
+    buf_offset: usize,
+    length: usize,
+}
+
+/// Joins path components from the metainfo, dropping the ones that could
+/// escape the download directory.
+fn sanitize(components: &[String]) -> PathBuf {
+    components
+        .iter()
+        .flat_map(|c| Path::new(c).components())
+        .filter_map(|c| match c {
+            Component::Normal(c) => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::{File, Info, TorrentFile};
+    use serde_bytes::ByteBuf;
+
+    fn multi_file_meta() -> TorrentMeta {
+        TorrentMeta::new(TorrentFile {
+            info: Info {
+                name: "dir".to_string(),
+                pieces: ByteBuf::from(vec![0u8; 40]),
+                piece_length: 4,
+                md5sum: None,
+                length: None,
+                files: Some(vec![
+                    File {
+                        path: vec!["a.txt".to_string()],
+                        length: 3,
+                        md5sum: None,
+                    },
+                    File {
+                        path: vec!["..".to_string(), "sub".to_string(), "b.txt".to_string()],
+                        length: 5,
+                        md5sum: None,
+                    },
+                ]),
+                private: None,
+                path: None,
+                root_hash: None,
+            },
+            announce: None,
+            nodes: None,
+            encoding: None,
+            httpseeds: None,
+            url_list: None,
+            announce_list: None,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn files_are_laid_out_in_order() {
+        let storage = Storage::new(
+            &multi_file_meta(),
+            Path::new("/downloads"),
+            Arc::new(DiskIo::default()),
+        );
+
+        assert_eq!(storage.total_length(), 8);
+        assert_eq!(
+            storage.files(),
+            &[
+                FileEntry {
+                    path: PathBuf::from("/downloads/dir/a.txt"),
+                    length: 3,
+                    offset: 0,
+                },
+                FileEntry {
+                    path: PathBuf::from("/downloads/dir/sub/b.txt"),
+                    length: 5,
+                    offset: 3,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn pieces_and_files() {
+        let storage = Storage::new(
+            &multi_file_meta(),
+            Path::new("/downloads"),
+            Arc::new(DiskIo::default()),
+        );
+
+        assert_eq!(storage.files_in_piece(0), vec![0, 1]);
+        assert_eq!(storage.files_in_piece(1), vec![1]);
+        assert_eq!(storage.file_pieces(0), 0..1);
+        assert_eq!(storage.file_pieces(1), 0..2);
+    }
+
+    #[test]
+    fn spans_cross_file_boundaries() {
+        let storage = Storage::new(
+            &multi_file_meta(),
+            Path::new("/downloads"),
+            Arc::new(DiskIo::default()),
+        );
+
+        let spans = storage.spans(2, 4).unwrap();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(
+            (spans[0].file_offset, spans[0].buf_offset, spans[0].length),
+            (2, 0, 1)
+        );
+        assert_eq!(
+            (spans[1].file_offset, spans[1].buf_offset, spans[1].length),
+            (0, 1, 3)
+        );
+
+        assert!(matches!(
+            storage.spans(6, 4),
+            Err(StorageError::OutOfRange {
+                offset: 6,
+                length: 4
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn write_and_read_back() {
+        let dir = std::env::temp_dir().join(format!("bit_rev_storage_{}", std::process::id()));
+        let storage = Storage::new(&multi_file_meta(), &dir, Arc::new(DiskIo::default()));
+
+        storage.write_piece(0, b"abcd".to_vec()).await.unwrap();
+        storage.write_piece(1, b"efgh".to_vec()).await.unwrap();
+
+        assert_eq!(storage.read(0, 2, 4).await.unwrap(), b"cdef");
+        let hash = sha1_smol::Sha1::from(b"abcd").digest().bytes();
+        assert!(storage.check_piece(0, 4, hash).await.unwrap());
+        assert!(!storage.check_piece(1, 4, hash).await.unwrap());
+        assert!(matches!(
+            storage.read(1, 2, 4).await,
+            Err(StorageError::OutOfRange { .. })
+        ));
+        assert_eq!(
+            std::fs::read(dir.join("dir").join("a.txt")).unwrap(),
+            b"abc"
+        );
+
+        storage.remove_files().await.unwrap();
+        assert!(!dir.join("dir").exists());
+        assert!(dir.exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
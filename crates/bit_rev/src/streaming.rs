@@ -0,0 +1,417 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    io,
+    ops::Range,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio_util::sync::CancellationToken;
+
+use crate::{config::TorrentConfig, peer_connection::TorrentDownloadedState, storage::Storage};
+
+/// The window sequential mode keeps on the first pieces we miss, readers get
+/// the ids after it.
+const SEQUENTIAL_WINDOW: u64 = 0;
+
+/// The pieces someone is waiting for: readers streaming a file, and in
+/// sequential mode the first pieces we miss.
+///
+/// Each of them has a readahead window. Every piece in a window gets a
+/// deadline, one `piece_deadline` later than the piece before it, and the
+/// picker hands them out soonest deadline first. A piece past its deadline
+/// goes to every peer that asks, not only the one it was reserved for.
+#[derive(Debug)]
+pub struct StreamFocus {
+    sequential: bool,
+    readahead: u32,
+    piece_deadline: Duration,
+    windows: HashMap<u64, Range<u32>>,
+    deadlines: BTreeMap<u32, Instant>,
+    /// The reader that moved last and the end of its window, where the
+    /// picker goes on in sequential mode.
+    latest: Option<(u64, u32)>,
+    next_id: u64,
+}
+
+impl StreamFocus {
+    pub fn new(config: &TorrentConfig) -> Self {
+        Self {
+            sequential: config.sequential,
+            readahead: config.readahead.max(1),
+            piece_deadline: config.piece_deadline,
+            windows: HashMap::new(),
+            deadlines: BTreeMap::new(),
+            latest: None,
+            next_id: SEQUENTIAL_WINDOW + 1,
+        }
+    }
+
+    pub fn is_sequential(&self) -> bool {
+        self.sequential
+    }
+
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+        if !sequential {
+            self.unfocus(SEQUENTIAL_WINDOW);
+        }
+    }
+
+    /// An id for a new reader to move its window with.
+    pub fn add_reader(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    /// Moves the window of `id` to start at piece `first`, ending at `end`
+    /// at most. Pieces that were already in a window keep their deadline.
+    pub fn focus(&mut self, id: u64, first: u32, end: u32, now: Instant) {
+        let window = first..end.min(first.saturating_add(self.readahead)).max(first);
+        if id != SEQUENTIAL_WINDOW {
+            self.latest = Some((id, window.end));
+        }
+        if self.windows.get(&id) == Some(&window) {
+            return;
+        }
+        for (k, index) in window.clone().enumerate() {
+            self.deadlines
+                .entry(index)
+                .or_insert(now + self.piece_deadline * (k as u32 + 1));
+        }
+        self.windows.insert(id, window);
+        self.forget_deadlines();
+    }
+
+    /// Drops the window of `id`, e.g. when its reader is gone.
+    pub fn unfocus(&mut self, id: u64) {
+        if self.latest.is_some_and(|(latest, _)| latest == id) {
+            self.latest = None;
+        }
+        if self.windows.remove(&id).is_some() {
+            self.forget_deadlines();
+        }
+    }
+
+    /// The pieces of every window that `done` says we still miss, soonest
+    /// deadline first, with whether the deadline passed.
+    pub fn urgent(
+        &mut self,
+        pieces: u32,
+        now: Instant,
+        done: impl Fn(u32) -> bool,
+    ) -> Vec<(u32, bool)> {
+        if self.sequential {
+            match (0..pieces).find(|&index| !done(index)) {
+                Some(first) => self.focus(SEQUENTIAL_WINDOW, first, pieces, now),
+                None => self.unfocus(SEQUENTIAL_WINDOW),
+            }
+        }
+        let mut urgent: Vec<(Instant, u32)> = self
+            .deadlines
+            .iter()
+            .filter(|(&index, _)| !done(index))
+            .map(|(&index, &deadline)| (deadline, index))
+            .collect();
+        urgent.sort_unstable();
+        urgent
+            .into_iter()
+            .map(|(deadline, index)| (index, deadline <= now))
+            .collect()
+    }
+
+    /// The piece the picker starts from once the urgent ones are taken: right
+    /// after the window of the last reader that moved in sequential mode, the
+    /// first piece otherwise.
+    pub fn start(&self) -> u32 {
+        match (self.sequential, self.latest) {
+            (true, Some((_, end))) => end,
+            _ => 0,
+        }
+    }
+
+    fn forget_deadlines(&mut self) {
+        let windows = &self.windows;
+        self.deadlines
+            .retain(|index, _| windows.values().any(|window| window.contains(index)));
+    }
+}
+
+type PendingRead = Pin<Box<dyn Future<Output = io::Result<Vec<u8>>> + Send>>;
+
+/// Reads a file of a torrent while it downloads, see
+/// [`TorrentHandle::open_file`](crate::session::TorrentHandle::open_file).
+///
+/// A read waits until the piece it needs is verified. Reading or seeking
+/// moves the reader's readahead window, so the pieces right after the
+/// position are downloaded first.
+pub struct FileReader {
+    id: u64,
+    storage: Arc<Storage>,
+    state: Arc<TorrentDownloadedState>,
+    cancel: CancellationToken,
+    /// Where the file sits in the torrent's byte stream.
+    offset: u64,
+    length: u64,
+    /// The pieces holding the file.
+    pieces: Range<u32>,
+    pos: u64,
+    /// Data of the last piece read and the file position it starts at.
+    buffer: Vec<u8>,
+    buffer_pos: u64,
+    pending: Option<PendingRead>,
+}
+
+impl FileReader {
+    pub(crate) fn new(
+        storage: Arc<Storage>,
+        state: Arc<TorrentDownloadedState>,
+        cancel: CancellationToken,
+        file: usize,
+    ) -> FileReader {
+        let entry = &storage.files()[file];
+        let (offset, length) = (entry.offset, entry.length);
+        let pieces = storage.file_pieces(file);
+        let id = state.focus.lock().unwrap().add_reader();
+        let reader = FileReader {
+            id,
+            storage,
+            state,
+            cancel,
+            offset,
+            length,
+            pieces,
+            pos: 0,
+            buffer: Vec::new(),
+            buffer_pos: 0,
+            pending: None,
+        };
+        reader.focus();
+        reader
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// The position in the file the next read starts at.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    fn piece_length(&self) -> u64 {
+        self.storage.piece_length()
+    }
+
+    // Moves our window to the piece at the read position.
+    fn focus(&self) {
+        if self.pos >= self.length {
+            self.state.focus.lock().unwrap().unfocus(self.id);
+            return;
+        }
+        let index = ((self.offset + self.pos) / self.piece_length()) as u32;
+        self.state
+            .focus
+            .lock()
+            .unwrap()
+            .focus(self.id, index, self.pieces.end, Instant::now());
+    }
+
+    // Reads the rest of the piece at the read position that belongs to the
+    // file, once the piece is verified.
+    fn read_piece(&self) -> PendingRead {
+        let piece_length = self.piece_length();
+        let at = self.offset + self.pos;
+        let index = (at / piece_length) as u32;
+        let begin = at % piece_length;
+        let piece_end = (index as u64 + 1) * piece_length;
+        let length = (piece_end.min(self.offset + self.length) - at) as u32;
+
+        let storage = self.storage.clone();
+        let state = self.state.clone();
+        let cancel = self.cancel.clone();
+        Box::pin(async move {
+            loop {
+                let verified = state.verified_notify.notified();
+                tokio::pin!(verified);
+                verified.as_mut().enable();
+                if state.is_verified(index) {
+                    break;
+                }
+                tokio::select! {
+                    _ = verified => {}
+                    _ = cancel.cancelled() => {
+                        return Err(io::Error::other("the torrent was removed"));
+                    }
+                }
+            }
+            storage
+                .read(index, begin as u32, length)
+                .await
+                .map_err(io::Error::other)
+        })
+    }
+}
+
+impl AsyncRead for FileReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let buffered = this.buffer_pos..this.buffer_pos + this.buffer.len() as u64;
+        if !buffered.contains(&this.pos) {
+            if this.pos >= this.length {
+                return Poll::Ready(Ok(()));
+            }
+            let pending = match &mut this.pending {
+                Some(pending) => pending,
+                None => {
+                    this.focus();
+                    this.pending.insert(this.read_piece())
+                }
+            };
+            let result = ready!(pending.as_mut().poll(cx));
+            this.pending = None;
+            this.buffer = result?;
+            this.buffer_pos = this.pos;
+        }
+
+        let start = (this.pos - this.buffer_pos) as usize;
+        let n = buf.remaining().min(this.buffer.len() - start);
+        buf.put_slice(&this.buffer[start..start + n]);
+        this.pos += n as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for FileReader {
+    fn start_seek(self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let pos = match position {
+            io::SeekFrom::Start(pos) => Some(pos),
+            io::SeekFrom::End(delta) => this.length.checked_add_signed(delta),
+            io::SeekFrom::Current(delta) => this.pos.checked_add_signed(delta),
+        };
+        let Some(pos) = pos else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            ));
+        };
+        if pos != this.pos {
+            this.pos = pos;
+            this.pending = None;
+            this.focus();
+        }
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+impl Drop for FileReader {
+    fn drop(&mut self) {
+        self.state.focus.lock().unwrap().unfocus(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn focus(sequential: bool) -> StreamFocus {
+        StreamFocus::new(&TorrentConfig {
+            sequential,
+            readahead: 4,
+            piece_deadline: Duration::from_secs(1),
+            ..Default::default()
+        })
+    }
+
+    fn indices(urgent: &[(u32, bool)]) -> Vec<u32> {
+        urgent.iter().map(|&(index, _)| index).collect()
+    }
+
+    #[test]
+    fn window_pieces_get_staggered_deadlines() {
+        let mut focus = focus(false);
+        let now = Instant::now();
+        let reader = focus.add_reader();
+        focus.focus(reader, 10, 100, now);
+
+        let urgent = focus.urgent(100, now, |_| false);
+        assert_eq!(
+            urgent,
+            vec![(10, false), (11, false), (12, false), (13, false)]
+        );
+
+        let later = now + Duration::from_millis(2500);
+        let urgent = focus.urgent(100, later, |index| index == 10);
+        assert_eq!(urgent, vec![(11, true), (12, false), (13, false)]);
+        // Outside sequential mode the rest goes in the usual order.
+        assert_eq!(focus.start(), 0);
+    }
+
+    #[test]
+    fn seeking_moves_the_window() {
+        let mut focus = focus(true);
+        let now = Instant::now();
+        let reader = focus.add_reader();
+        focus.focus(reader, 0, 100, now);
+        focus.focus(reader, 50, 100, now + Duration::from_secs(10));
+
+        // The sequential window sits on the first pieces we miss, its
+        // deadlines interleave with the reader's.
+        let urgent = focus.urgent(100, now + Duration::from_secs(10), |index| index < 2);
+        assert_eq!(indices(&urgent), vec![2, 50, 3, 51, 4, 52, 5, 53]);
+        assert_eq!(focus.start(), 54);
+
+        // Moving on keeps the deadline of the pieces still in the window.
+        focus.focus(reader, 51, 100, now + Duration::from_secs(20));
+        let urgent = focus.urgent(100, now + Duration::from_secs(20), |index| index < 51);
+        assert_eq!(
+            urgent,
+            vec![(51, true), (52, true), (53, true), (54, false)]
+        );
+    }
+
+    #[test]
+    fn windows_stop_at_the_end_of_the_file() {
+        let mut focus = focus(false);
+        let now = Instant::now();
+        let reader = focus.add_reader();
+        focus.focus(reader, 8, 10, now);
+        assert_eq!(indices(&focus.urgent(100, now, |_| false)), vec![8, 9]);
+
+        focus.unfocus(reader);
+        assert!(focus.urgent(100, now, |_| false).is_empty());
+    }
+
+    #[test]
+    fn sequential_mode_can_be_turned_off() {
+        let mut focus = focus(true);
+        let now = Instant::now();
+        assert_eq!(indices(&focus.urgent(3, now, |_| false)), vec![0, 1, 2]);
+        assert!(focus.urgent(3, now, |_| true).is_empty());
+
+        focus.set_sequential(false);
+        assert!(focus.urgent(3, now, |_| false).is_empty());
+    }
+}
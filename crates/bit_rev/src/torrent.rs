@@ -14,7 +14,7 @@ impl Torrent {
             info_hash: torrent_meta.info_hash,
             piece_hashes: torrent_meta.piece_hashes.clone(),
             piece_length: torrent_meta.torrent_file.info.piece_length,
-            length: torrent_meta.torrent_file.info.length.unwrap(),
+            length: torrent_meta.torrent_file.info.total_length(),
         }
     }
 }
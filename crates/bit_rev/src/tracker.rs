@@ -0,0 +1,682 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    io,
+    net::{IpAddr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+use serde_bencode::de;
+use serde_bytes::ByteBuf;
+use thiserror::Error;
+
+use crate::{
+    file::{url_encode_bytes, TorrentFile},
+    network::{BindTo, Network},
+    peer::BencodeResponse,
+    protocol_udp::UdpTracker,
+    proxy::{ProxyConfig, ProxyError, ProxyKind},
+};
+
+/// How long we wait for a tracker to answer an announce.
+pub const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum TrackerError {
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("invalid response: {0}")]
+    Bencode(#[from] serde_bencode::Error),
+    /// The tracker turned the announce down, e.g. the torrent isn't registered.
+    #[error("tracker failure: {0}")]
+    Failure(String),
+    #[error("peer list of {0} bytes is not a list of addresses")]
+    InvalidPeers(usize),
+    #[error("udp: {0}")]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Proxy(#[from] ProxyError),
+    #[error("tracker didn't answer")]
+    Timeout,
+    #[error("invalid response: {0}")]
+    InvalidResponse(&'static str),
+    #[error("invalid tracker url {0}")]
+    InvalidUrl(String),
+    #[error("can't bind to the network: {0}")]
+    Bind(io::Error),
+    /// BEP 48 only defines scrape URLs for announce URLs ending in `announce`.
+    #[error("{0} doesn't support scrape")]
+    ScrapeNotSupported(String),
+}
+
+/// The `event` of an announce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    /// A regular announce.
+    None,
+    /// First announce of a run.
+    Started,
+    /// The download just finished. Not sent for torrents that were already
+    /// complete when they started.
+    Completed,
+    /// The torrent was paused, stopped or removed.
+    Stopped,
+}
+
+impl AnnounceEvent {
+    pub fn as_str(&self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
+}
+
+/// The parameters of an HTTP announce.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Announce {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    /// Payload bytes sent since the `started` announce.
+    pub uploaded: u64,
+    /// Payload bytes received since the `started` announce.
+    pub downloaded: u64,
+    /// Bytes we still need to download.
+    pub left: u64,
+    pub event: AnnounceEvent,
+    /// Peers we would like, `None` leaves it to the tracker.
+    pub numwant: Option<u32>,
+    /// Lets the tracker recognize us if our IP changes.
+    pub key: u32,
+    /// The `tracker id` of the tracker's last reply.
+    pub tracker_id: Option<Vec<u8>>,
+    /// Address to report instead of the one we connect from.
+    pub ip: Option<IpAddr>,
+    /// Our IPv6 address, for trackers we reach over IPv4, BEP 7.
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+impl Announce {
+    /// The announce URL for `tracker`, keeping the query it may already have.
+    pub fn url(&self, tracker: &str) -> String {
+        let mut url = format!(
+            "{}{}info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1&key={:08x}",
+            tracker,
+            if tracker.contains('?') { '&' } else { '?' },
+            url_encode_bytes(&self.info_hash),
+            url_encode_bytes(&self.peer_id),
+            self.port,
+            self.uploaded,
+            self.downloaded,
+            self.left,
+            self.key,
+        );
+        // Writing to a String can't fail.
+        if let Some(event) = self.event.as_str() {
+            let _ = write!(url, "&event={}", event);
+        }
+        if let Some(numwant) = self.numwant {
+            let _ = write!(url, "&numwant={}", numwant);
+        }
+        if let Some(tracker_id) = &self.tracker_id {
+            let _ = write!(url, "&trackerid={}", url_encode_bytes(tracker_id));
+        }
+        if let Some(ip) = self.ip {
+            let _ = write!(url, "&ip={}", url_encode_bytes(ip.to_string().as_bytes()));
+        }
+        if let Some(ipv6) = self.ipv6 {
+            let _ = write!(
+                url,
+                "&ipv6={}",
+                url_encode_bytes(ipv6.to_string().as_bytes())
+            );
+        }
+        url
+    }
+}
+
+/// What we know about one of the trackers of a torrent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackerInfo {
+    pub url: String,
+    /// BEP 12 tier, lower tiers are tried first.
+    pub tier: usize,
+    pub last_announce: Option<Instant>,
+    /// When we plan to announce to it again, if it is the one we use.
+    pub next_announce: Option<Instant>,
+    /// Announces that failed in a row.
+    pub fails: u32,
+    pub last_error: Option<String>,
+    pub last_warning: Option<String>,
+    /// Seeders and leechers of its last reply.
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+struct TrackerEntry {
+    info: TrackerInfo,
+    /// It got our `started` announce this run.
+    started: bool,
+    tracker_id: Option<Vec<u8>>,
+}
+
+/// The trackers of a torrent in BEP 12 tiers. Trackers are shuffled within
+/// their tier, tried in order, and the one that answers moves to the front of
+/// its tier.
+#[derive(Debug, Clone, Default)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<TrackerEntry>>,
+}
+
+impl TrackerTiers {
+    pub fn new(tiers: Vec<Vec<String>>) -> Self {
+        let mut rng = rand::thread_rng();
+        Self {
+            tiers: tiers
+                .into_iter()
+                .filter(|urls| !urls.is_empty())
+                .enumerate()
+                .map(|(tier, mut urls)| {
+                    urls.shuffle(&mut rng);
+                    urls.into_iter()
+                        .map(|url| TrackerEntry {
+                            info: TrackerInfo {
+                                url,
+                                tier,
+                                last_announce: None,
+                                next_announce: None,
+                                fails: 0,
+                                last_error: None,
+                                last_warning: None,
+                                seeders: None,
+                                leechers: None,
+                            },
+                            started: false,
+                            tracker_id: None,
+                        })
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    /// The `announce-list` of the torrent, or its `announce` if it has none.
+    pub fn from_torrent(torrent_file: &TorrentFile) -> Self {
+        let tiers = match &torrent_file.announce_list {
+            Some(list) if list.iter().any(|tier| !tier.is_empty()) => list.clone(),
+            _ => torrent_file
+                .announce
+                .iter()
+                .map(|url| vec![url.clone()])
+                .collect(),
+        };
+        Self::new(tiers)
+    }
+
+    /// Keeps only the trackers `f` accepts.
+    pub fn retain(&mut self, f: impl Fn(&str) -> bool) {
+        for tier in self.tiers.iter_mut() {
+            tier.retain(|entry| f(&entry.info.url));
+        }
+        self.tiers.retain(|tier| !tier.is_empty());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// Every tracker, in the order we try them.
+    pub fn urls(&self) -> Vec<String> {
+        self.entries().map(|entry| entry.info.url.clone()).collect()
+    }
+
+    pub fn trackers(&self) -> Vec<TrackerInfo> {
+        self.entries().map(|entry| entry.info.clone()).collect()
+    }
+
+    /// Whether `url` got our `started` announce this run.
+    pub fn is_started(&self, url: &str) -> bool {
+        self.entry(url).is_some_and(|entry| entry.started)
+    }
+
+    pub fn tracker_id(&self, url: &str) -> Option<Vec<u8>> {
+        self.entry(url).and_then(|entry| entry.tracker_id.clone())
+    }
+
+    /// Forgets which trackers got `started`, for a new run.
+    pub fn reset(&mut self) {
+        for entry in self.entries_mut() {
+            entry.started = false;
+            entry.info.next_announce = None;
+        }
+    }
+
+    /// `url` answered, it goes first in its tier.
+    pub fn on_success(
+        &mut self,
+        url: &str,
+        event: AnnounceEvent,
+        res: &BencodeResponse,
+        next: Option<Instant>,
+    ) {
+        for entry in self.entries_mut() {
+            entry.info.next_announce = None;
+        }
+        for tier in self.tiers.iter_mut() {
+            let Some(index) = tier.iter().position(|entry| entry.info.url == url) else {
+                continue;
+            };
+            let mut entry = tier.remove(index);
+            entry.started = event != AnnounceEvent::Stopped;
+            if let Some(id) = &res.tracker_id {
+                entry.tracker_id = Some(id.to_vec());
+            }
+            let info = &mut entry.info;
+            info.last_announce = Some(Instant::now());
+            info.next_announce = next;
+            info.fails = 0;
+            info.last_error = None;
+            info.last_warning = res.warning_message.clone();
+            info.seeders = res.complete;
+            info.leechers = res.incomplete;
+            tier.insert(0, entry);
+            return;
+        }
+    }
+
+    pub fn on_failure(&mut self, url: &str, error: String) {
+        if let Some(entry) = self.entry_mut(url) {
+            entry.info.last_announce = Some(Instant::now());
+            entry.info.fails += 1;
+            entry.info.last_error = Some(error);
+        }
+    }
+
+    /// Marks when we retry after every tracker failed.
+    pub fn set_next_announce(&mut self, next: Instant) {
+        if let Some(entry) = self.tiers.first_mut().and_then(|tier| tier.first_mut()) {
+            entry.info.next_announce = Some(next);
+        }
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &TrackerEntry> {
+        self.tiers.iter().flatten()
+    }
+
+    fn entries_mut(&mut self) -> impl Iterator<Item = &mut TrackerEntry> {
+        self.tiers.iter_mut().flatten()
+    }
+
+    fn entry(&self, url: &str) -> Option<&TrackerEntry> {
+        self.entries().find(|entry| entry.info.url == url)
+    }
+
+    fn entry_mut(&mut self, url: &str) -> Option<&mut TrackerEntry> {
+        self.entries_mut().find(|entry| entry.info.url == url)
+    }
+}
+
+/// Client for tracker requests, going through `proxy` if there is one and
+/// from `local_address`, see `Network::http_address`.
+pub fn http_client(
+    proxy: Option<&ProxyConfig>,
+    local_address: Option<IpAddr>,
+) -> Result<reqwest::Client, reqwest::Error> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(ANNOUNCE_TIMEOUT)
+        .local_address(local_address);
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(proxy.reqwest_proxy()?);
+    }
+    builder.build()
+}
+
+/// The HTTP client of a session, for trackers and web seeds. reqwest can't
+/// bind to an interface, so with `BindTo::Interface` we look the interface up
+/// before every request and follow its address. Requests fail while it is
+/// down or has no address, rather than going through another route.
+#[derive(Debug)]
+pub struct HttpClient {
+    network: Network,
+    proxy: Option<ProxyConfig>,
+    /// The client and the source address it was built with.
+    current: Mutex<(Option<IpAddr>, reqwest::Client)>,
+}
+
+impl HttpClient {
+    /// `local_address` is the current `Network::http_address`.
+    pub fn new(
+        network: Network,
+        proxy: Option<ProxyConfig>,
+        local_address: Option<IpAddr>,
+    ) -> Result<HttpClient, reqwest::Error> {
+        let client = http_client(proxy.as_ref(), local_address)?;
+        Ok(HttpClient {
+            network,
+            proxy,
+            current: Mutex::new((local_address, client)),
+        })
+    }
+
+    /// The client to use for the next request.
+    pub fn client(&self) -> io::Result<reqwest::Client> {
+        let mut current = self.current.lock().unwrap();
+        if matches!(self.network.bind(), Some(BindTo::Interface(_))) {
+            let local_address = self.network.http_address()?;
+            if local_address != current.0 {
+                let client =
+                    http_client(self.proxy.as_ref(), local_address).map_err(io::Error::other)?;
+                *current = (local_address, client);
+            }
+        }
+        Ok(current.1.clone())
+    }
+}
+
+/// Announces to an HTTP or UDP tracker. UDP trackers are reached through
+/// `network` and `proxy`, like in `scrape`.
+pub async fn announce(
+    client: &reqwest::Client,
+    network: &Network,
+    proxy: Option<&ProxyConfig>,
+    tracker: &str,
+    announce: &Announce,
+) -> Result<BencodeResponse, TrackerError> {
+    if tracker.starts_with("udp://") {
+        let udp_announce = async {
+            let udp_tracker = connect_udp(network, proxy, tracker).await?;
+            udp_tracker.announce(announce).await
+        };
+        return tokio::time::timeout(ANNOUNCE_TIMEOUT, udp_announce)
+            .await
+            .map_err(|_| TrackerError::Timeout)?;
+    }
+    request_peers(client, &announce.url(tracker)).await
+}
+
+/// Swarm counts of a torrent, as reported by a scrape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub struct ScrapeStats {
+    #[serde(default, rename = "complete")]
+    pub seeders: u64,
+    #[serde(default, rename = "incomplete")]
+    pub leechers: u64,
+    /// Times the torrent was downloaded to the end.
+    #[serde(default, rename = "downloaded")]
+    pub completed: u64,
+}
+
+#[derive(Deserialize)]
+struct ScrapeResponse {
+    #[serde(default, rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(default)]
+    files: HashMap<ByteBuf, ScrapeStats>,
+}
+
+/// The scrape URL of an HTTP announce URL, BEP 48: the last path segment must
+/// start with `announce`, which is replaced by `scrape`.
+pub fn scrape_url(tracker: &str) -> Option<String> {
+    let (path, query) = match tracker.find('?') {
+        Some(i) => tracker.split_at(i),
+        None => (tracker, ""),
+    };
+    let segment = path.rfind('/')? + 1;
+    path[segment..]
+        .strip_prefix("announce")
+        .map(|rest| format!("{}scrape{}{}", &path[..segment], rest, query))
+}
+
+/// Scrapes `info_hashes` from an HTTP or UDP tracker. Torrents the tracker
+/// doesn't know are missing from the result. UDP trackers are reached
+/// through `network` and `proxy`, which `client` should use already, see
+/// `http_client`.
+pub async fn scrape(
+    client: &reqwest::Client,
+    network: &Network,
+    proxy: Option<&ProxyConfig>,
+    tracker: &str,
+    info_hashes: &[[u8; 20]],
+) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+    if tracker.starts_with("udp://") {
+        return scrape_udp(network, proxy, tracker, info_hashes).await;
+    }
+    if !tracker.starts_with("http://") && !tracker.starts_with("https://") {
+        return Err(TrackerError::InvalidUrl(tracker.to_string()));
+    }
+
+    let mut url =
+        scrape_url(tracker).ok_or_else(|| TrackerError::ScrapeNotSupported(tracker.to_string()))?;
+    for (i, info_hash) in info_hashes.iter().enumerate() {
+        let separator = if i == 0 && !url.contains('?') {
+            '?'
+        } else {
+            '&'
+        };
+        let _ = write!(
+            url,
+            "{}info_hash={}",
+            separator,
+            url_encode_bytes(info_hash)
+        );
+    }
+
+    let body_bytes = client.get(url).send().await?.bytes().await?;
+    let res = de::from_bytes::<ScrapeResponse>(&body_bytes)?;
+    if let Some(reason) = res.failure_reason {
+        return Err(TrackerError::Failure(reason));
+    }
+    Ok(res
+        .files
+        .into_iter()
+        .filter_map(|(info_hash, stats)| Some((<[u8; 20]>::try_from(&info_hash[..]).ok()?, stats)))
+        .collect())
+}
+
+async fn scrape_udp(
+    network: &Network,
+    proxy: Option<&ProxyConfig>,
+    tracker: &str,
+    info_hashes: &[[u8; 20]],
+) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+    let udp_tracker = connect_udp(network, proxy, tracker).await?;
+    let stats = udp_tracker.scrape(info_hashes).await?;
+    Ok(info_hashes.iter().copied().zip(stats).collect())
+}
+
+async fn connect_udp(
+    network: &Network,
+    proxy: Option<&ProxyConfig>,
+    tracker: &str,
+) -> Result<UdpTracker, TrackerError> {
+    let invalid_url = || TrackerError::InvalidUrl(tracker.to_string());
+    let url = reqwest::Url::parse(tracker).map_err(|_| invalid_url())?;
+    let host = url.host_str().ok_or_else(invalid_url)?;
+    let port = url.port().ok_or_else(invalid_url)?;
+    // Brackets of IPv6 literals aren't part of the address.
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    // HTTP proxies can't relay UDP, we go around them unless told not to.
+    match proxy {
+        Some(proxy) if proxy.kind == ProxyKind::Socks5 || proxy.proxy_only => {
+            UdpTracker::connect_through(network, proxy, host, port).await
+        }
+        _ => {
+            let addr = tokio::net::lookup_host((host, port))
+                .await?
+                .next()
+                .ok_or_else(invalid_url)?;
+            UdpTracker::connect(network, addr).await
+        }
+    }
+}
+
+pub async fn request_peers(
+    client: &reqwest::Client,
+    uri: &str,
+) -> Result<BencodeResponse, TrackerError> {
+    let response = client.get(uri).send().await?;
+    let body_bytes = response.bytes().await?;
+
+    let res = de::from_bytes::<BencodeResponse>(&body_bytes)?;
+    match res.failure_reason {
+        Some(reason) => Err(TrackerError::Failure(reason)),
+        None => Ok(res),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announce() -> Announce {
+        Announce {
+            info_hash: [0xab; 20],
+            peer_id: *b"-BR0001-abcdefghijkl",
+            port: 6881,
+            uploaded: 10,
+            downloaded: 20,
+            left: 30,
+            event: AnnounceEvent::None,
+            numwant: None,
+            key: 0xdead,
+            tracker_id: None,
+            ip: None,
+            ipv6: None,
+        }
+    }
+
+    #[test]
+    fn scrape_urls() {
+        assert_eq!(
+            scrape_url("http://example.com/announce").as_deref(),
+            Some("http://example.com/scrape")
+        );
+        assert_eq!(
+            scrape_url("http://example.com/x/announce.php?passkey=a/b").as_deref(),
+            Some("http://example.com/x/scrape.php?passkey=a/b")
+        );
+        assert_eq!(scrape_url("http://example.com/a"), None);
+        assert_eq!(scrape_url("http://example.com/announce/x"), None);
+        assert_eq!(scrape_url("http://example.com/x%064announce"), None);
+    }
+
+    #[test]
+    fn regular_announce_url() {
+        assert_eq!(
+            announce().url("http://tracker.example/announce"),
+            format!(
+                "http://tracker.example/announce?info_hash={}&peer_id=-BR0001-abcdefghijkl\
+                 &port=6881&uploaded=10&downloaded=20&left=30&compact=1&key=0000dead",
+                "%AB".repeat(20)
+            )
+        );
+    }
+
+    #[test]
+    fn announce_url_with_every_parameter() {
+        let url = Announce {
+            event: AnnounceEvent::Started,
+            numwant: Some(50),
+            tracker_id: Some(b"id 1".to_vec()),
+            ip: Some("10.0.0.1".parse().unwrap()),
+            ipv6: Some("2001:db8::1".parse().unwrap()),
+            ..announce()
+        }
+        .url("http://tracker.example/announce?passkey=secret");
+
+        assert!(url.starts_with("http://tracker.example/announce?passkey=secret&info_hash="));
+        assert!(url.ends_with(
+            "&event=started&numwant=50&trackerid=id%201&ip=10.0.0.1&ipv6=2001%3Adb8%3A%3A1"
+        ));
+    }
+
+    fn urls(urls: &[&str]) -> Vec<String> {
+        urls.iter().map(|url| url.to_string()).collect()
+    }
+
+    #[test]
+    fn announce_list_replaces_announce() {
+        let mut meta = crate::file::TorrentMeta::from_bytes(
+            b"d4:infod4:name1:a6:pieces20:aaaaaaaaaaaaaaaaaaaa12:piece lengthi4e6:lengthi4eee",
+        )
+        .unwrap()
+        .torrent_file;
+        meta.announce = Some("http://a/announce".to_string());
+        meta.announce_list = None;
+        assert_eq!(
+            TrackerTiers::from_torrent(&meta).urls(),
+            urls(&["http://a/announce"])
+        );
+
+        meta.announce_list = Some(vec![
+            urls(&["http://b/announce"]),
+            vec![],
+            urls(&["udp://c:80", "http://d/announce"]),
+        ]);
+        let mut tiers = TrackerTiers::from_torrent(&meta);
+        tiers.retain(|url| !url.starts_with("udp://"));
+        assert_eq!(
+            tiers.urls(),
+            urls(&["http://b/announce", "http://d/announce"])
+        );
+        assert_eq!(
+            tiers.trackers().iter().map(|t| t.tier).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn http_client_follows_the_interface() {
+        let lo = Network::new(Some(BindTo::Interface("lo".to_string())));
+        let http = HttpClient::new(lo, None, None).unwrap();
+        http.client().unwrap();
+        assert_eq!(
+            http.current.lock().unwrap().0,
+            Some(IpAddr::from([127, 0, 0, 1]))
+        );
+
+        // The interface went away, requests must not go out another way.
+        let missing = Network::new(Some(BindTo::Interface("bitrev-none0".to_string())));
+        let http = HttpClient::new(missing, None, Some(IpAddr::from([127, 0, 0, 1]))).unwrap();
+        assert!(http.client().is_err());
+    }
+
+    #[test]
+    fn trackers_that_answer_move_to_the_front() {
+        let mut tiers = TrackerTiers::new(vec![urls(&["http://a", "http://b", "http://c"])]);
+        let res = BencodeResponse {
+            tracker_id: Some(serde_bytes::ByteBuf::from(b"id".to_vec())),
+            complete: Some(4),
+            ..Default::default()
+        };
+
+        tiers.on_failure("http://a", "refused".to_string());
+        tiers.on_success("http://b", AnnounceEvent::Started, &res, None);
+        assert_eq!(tiers.urls()[0], "http://b");
+        assert!(tiers.is_started("http://b"));
+        assert_eq!(tiers.tracker_id("http://b"), Some(b"id".to_vec()));
+
+        let info = tiers.trackers();
+        let a = info.iter().find(|t| t.url == "http://a").unwrap();
+        assert_eq!((a.fails, a.last_error.as_deref()), (1, Some("refused")));
+        assert_eq!(info[0].seeders, Some(4));
+
+        tiers.on_failure("http://b", "timeout".to_string());
+        tiers.on_success("http://a", AnnounceEvent::None, &res, None);
+        assert_eq!(tiers.urls()[0], "http://a");
+        let a = &tiers.trackers()[0];
+        assert_eq!((a.fails, a.last_error.as_deref()), (0, None));
+
+        tiers.reset();
+        assert!(!tiers.is_started("http://b"));
+    }
+}
@@ -1,190 +1,758 @@
-use serde_bencode::de;
-use std::sync::{atomic::AtomicBool, Arc, Mutex};
-use tokio::{select, sync::Semaphore};
-use tracing::debug;
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::TcpStream,
+    select,
+    sync::{broadcast, Notify, Semaphore},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace, warn};
 
 use crate::{
-    file::{self, TorrentMeta},
-    peer::BencodeResponse,
+    ban::PeerBans,
+    choker::Choker,
+    config::TorrentConfig,
+    connection_manager::{ConnectionSlot, PeerSource, TorrentConnections},
+    events::{DisconnectReason, Event, EventKind, TorrentEvents},
+    file::TorrentMeta,
+    handshake::Handshake,
+    peer::{BencodeResponse, PeerAddr},
     peer_connection::{
         FullPiece, PeerConnection, PeerHandler, PieceWorkState, TorrentDownloadedState,
     },
+    peer_id::ClientFilter,
     peer_state::PeerStates,
-    session::PieceWork,
+    rate_limit::{PeerRateLimiter, RateLimiter},
+    session::{PieceWork, SessionContext},
+    stats::{TransferSnapshot, TransferStats},
+    storage::Storage,
+    streaming::StreamFocus,
+    torrent::Torrent,
+    tracker::{self, Announce, AnnounceEvent, TrackerError, TrackerInfo, TrackerTiers},
+    utils,
+    web_seed::{WebSeed, WebSeedError},
 };
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct TrackerPeers {
     torrent_meta: TorrentMeta,
-    peer_id: [u8; 20],
+    context: Arc<SessionContext>,
+    storage: Arc<Storage>,
+    /// Token of the current run, cancelled while the torrent is paused or stopped.
+    running: Arc<Mutex<CancellationToken>>,
     pub peer_states: Arc<PeerStates>,
+    pub peer_bans: Arc<PeerBans>,
+    pub client_filter: Arc<ClientFilter>,
     pub piece_tx: flume::Sender<FullPiece>,
     pub piece_rx: flume::Receiver<FullPiece>,
     pub have_broadcast: Arc<tokio::sync::broadcast::Sender<u32>>,
+    pub connections: Arc<TorrentConnections>,
+    pub torrent_downloaded_state: Arc<TorrentDownloadedState>,
+    pub events: TorrentEvents,
+    /// Bytes exchanged with every peer of the torrent.
+    pub stats: Arc<TransferStats>,
+    pub rate_limiter: Arc<RateLimiter>,
+    /// Limits every peer of the torrent follows on its own.
+    pub peer_rate_limiter: Arc<RateLimiter>,
+    pub config: TorrentConfig,
+    trackers: Arc<Mutex<TrackerTiers>>,
+    /// Woken when the peers we upload to should be picked again.
+    rechoke_notify: Arc<Notify>,
 }
 
 impl TrackerPeers {
     pub fn new(
         torrent_meta: TorrentMeta,
-        _max_size: usize,
-        peer_id: [u8; 20],
-        peer_states: Arc<PeerStates>,
-        have_broadcast: Arc<tokio::sync::broadcast::Sender<u32>>,
+        context: Arc<SessionContext>,
+        storage: Arc<Storage>,
+        config: TorrentConfig,
     ) -> TrackerPeers {
         let (sender, receiver) = flume::unbounded();
+        let (have_broadcast, _) = tokio::sync::broadcast::channel(128);
+
+        let torrent = Torrent::new(&torrent_meta);
+        let not_running = CancellationToken::new();
+        not_running.cancel();
+        let torrent_downloaded_state = Arc::new(TorrentDownloadedState {
+            semaphore: Semaphore::new(1),
+            pieces: (0..torrent.piece_hashes.len())
+                .map(|index| {
+                    PieceWorkState::new(PieceWork {
+                        index: index as u32,
+                        length: utils::calculate_piece_size(&torrent, index) as u32,
+                        hash: torrent.piece_hashes[index],
+                    })
+                })
+                .collect(),
+            focus: Mutex::new(StreamFocus::new(&config)),
+            verified_notify: Notify::new(),
+            timed_out_retry: config.timed_out_retry,
+        });
+
+        let trackers = TrackerTiers::from_torrent(&torrent_meta.torrent_file);
+
+        let peer_rate_limiter = Arc::new(RateLimiter::new(
+            context.peer_upload_limit,
+            context.peer_download_limit,
+        ));
+
+        let peer_bans = Arc::new(PeerBans::default());
+        let mut connections = TorrentConnections::new(context.connection_manager.clone())
+            .with_bans(peer_bans.clone());
+        if torrent_meta.torrent_file.info.is_private() {
+            connections = connections.private();
+        }
+
         TrackerPeers {
+            events: TorrentEvents::new(torrent_meta.info_hash, context.events.clone()),
             torrent_meta,
-            peer_id,
+            connections: Arc::new(connections),
+            context,
+            storage,
+            running: Arc::new(Mutex::new(not_running)),
             piece_tx: sender,
             piece_rx: receiver,
-            peer_states,
-            have_broadcast,
+            peer_states: Arc::new(PeerStates::default()),
+            peer_bans,
+            client_filter: Arc::new(config.client_filter.clone()),
+            have_broadcast: Arc::new(have_broadcast),
+            torrent_downloaded_state,
+            stats: Arc::new(TransferStats::default()),
+            rate_limiter: Arc::new(RateLimiter::default()),
+            peer_rate_limiter,
+            config,
+            trackers: Arc::new(Mutex::new(trackers)),
+            rechoke_notify: Arc::new(Notify::new()),
+        }
+    }
+
+    /// The trackers of the torrent, in the order we try them.
+    pub fn trackers(&self) -> Vec<TrackerInfo> {
+        self.trackers.lock().unwrap().trackers()
+    }
+
+    /// Starts looking for peers and downloading. Everything started here
+    /// stops once `cancel` is cancelled.
+    pub async fn connect(&self, cancel: CancellationToken) {
+        *self.running.lock().unwrap() = cancel.clone();
+        let listen_port = self.context.listen_port;
+
+        if !self.trackers.lock().unwrap().is_empty() {
+            let this = self.clone();
+            let cancel = cancel.clone();
+            tokio::spawn(async move { this.announce_loop(cancel).await });
+        }
+
+        let connections = self.connections.clone();
+        // Private torrents keep out of the DHT and LSD, BEP 27.
+        let private = self.torrent_meta.torrent_file.info.is_private();
+
+        for dht in self.context.dhts().filter(|_| !private) {
+            let dht = dht.clone();
+            let info_hash = self.torrent_meta.info_hash;
+            let connections = connections.clone();
+            let config = self.config.clone();
+            spawn(&cancel, async move {
+                loop {
+                    let peers = dht.announce(info_hash, listen_port).await;
+                    debug!(ipv6 = dht.is_ipv6(), "dht found {} peers", peers.len());
+                    connections.add_candidates_from(PeerSource::Dht, peers);
+
+                    let delay = if dht.nodes_len() == 0 {
+                        config.dht_bootstrap_retry
+                    } else {
+                        config.dht_announce_interval
+                    };
+                    tokio::time::sleep(delay).await;
+                }
+            });
+        }
+
+        if let Some(lsd) = self.context.lsd.clone() {
+            if !private {
+                let info_hash = self.torrent_meta.info_hash;
+                let connections = connections.clone();
+                let found = lsd.subscribe(info_hash);
+                let announce_interval = self.config.lsd_announce_interval;
+                spawn(&cancel, async move {
+                    let mut interval = tokio::time::interval(announce_interval);
+                    loop {
+                        select! {
+                            _ = interval.tick() => {
+                                if let Err(e) = lsd.announce(info_hash).await {
+                                    debug!("lsd announce failed: {}", e);
+                                }
+                            }
+                            Ok(peer) = found.recv_async() => {
+                                debug!("lsd found {}", peer);
+                                connections.add_candidates_from(PeerSource::Lan, [peer]);
+                            }
+                        }
+                    }
+                });
+            }
+        }
+
+        for seed in WebSeed::from_torrent(&self.torrent_meta.torrent_file) {
+            spawn(&cancel, self.clone().run_web_seed(seed));
         }
+
+        // Make room for new candidates by dropping the slowest peers once we hit the limits.
+        {
+            let connections = connections.clone();
+            let replace_interval = self.config.replace_peers_interval;
+            spawn(&cancel, async move {
+                let mut interval = tokio::time::interval(replace_interval);
+                loop {
+                    interval.tick().await;
+                    connections.replace_worst_peer();
+                }
+            });
+        }
+
+        // Picks the peers we upload to now and then, and whenever a peer's
+        // interest changes. The optimistic unchoke moves on less often.
+        {
+            let this = self.clone();
+            spawn(&cancel, async move {
+                let mut choker = Choker::new(this.config.unchoke_slots);
+                let mut interval = tokio::time::interval(this.config.rechoke_interval);
+                let mut rotated_at: Option<Instant> = None;
+                loop {
+                    select! {
+                        _ = interval.tick() => {}
+                        _ = this.rechoke_notify.notified() => {}
+                    }
+                    let rotate = rotated_at
+                        .is_none_or(|at| at.elapsed() >= this.config.optimistic_unchoke_interval);
+                    if rotate {
+                        rotated_at = Some(Instant::now());
+                    }
+                    this.rechoke(&mut choker, rotate);
+                }
+            });
+        }
+
+        let this = self.clone();
+        spawn(&cancel.clone(), async move {
+            loop {
+                let (peer, slot) = connections.next_connection().await;
+                let this = this.clone();
+                let cancel = cancel.clone();
+                tokio::spawn(async move { this.run_peer(peer, slot, None, cancel).await });
+            }
+        });
     }
 
-    pub async fn connect(&self, pieces_of_work: Vec<PieceWork>) {
-        let info_hash = self.torrent_meta.info_hash;
-        let peer_id = self.peer_id;
+    // Unchokes the interested peers the choker picks and chokes the others.
+    // Peers are judged by what they give us, or by what they take while we
+    // seed.
+    fn rechoke(&self, choker: &mut Choker, rotate: bool) {
+        let seeding = self.torrent_downloaded_state.is_verified_complete();
+        let handlers = self.connections.handlers();
+        let connected = |peer: &PeerAddr| handlers.iter().any(|h| h.peer() == *peer);
 
-        let tcp_trackers = all_trackers(&self.torrent_meta.clone())
+        let ranked = handlers
+            .iter()
+            .filter(|h| {
+                self.peer_states
+                    .states
+                    .get(&h.peer())
+                    .is_some_and(|state| state.peer_interested)
+            })
+            .map(|h| {
+                let transfer = h.stats().snapshot();
+                let rate = if seeding {
+                    transfer.upload_rate
+                } else {
+                    transfer.download_rate
+                };
+                (h.peer(), rate)
+            })
+            .collect();
+        let candidates: Vec<PeerAddr> = self
+            .peer_states
+            .optimistic_unchoke_candidates()
             .into_iter()
-            .filter(|t| !t.starts_with("udp://"));
-
-        //TODO: support udp trackers
-        let tcp_trackers = tcp_trackers.clone();
-        let torrent_meta = self.torrent_meta.clone();
-        let peer_states = self.peer_states.clone();
-        let piece_tx = self.piece_tx.clone();
-        let have_broadcast = self.have_broadcast.clone();
-        let torrent_downloaded_state = Arc::new(TorrentDownloadedState {
-            semaphore: Semaphore::new(1),
-            pieces: pieces_of_work
+            .filter(connected)
+            .collect();
+
+        let previous = choker.optimistic();
+        let unchoked = choker.rechoke(ranked, &candidates, rotate);
+        if let Some(peer) = choker.optimistic().filter(|peer| Some(*peer) != previous) {
+            trace!("optimistically unchoking {}", peer);
+            if let Some(mut state) = self.peer_states.states.get_mut(&peer) {
+                state.optimistic_unchoked_at = Some(Instant::now());
+            }
+        }
+
+        for handler in handlers {
+            if let Err(e) = handler.set_am_choking(!unchoked.contains(&handler.peer())) {
+                debug!("can't choke or unchoke {}: {}", handler.peer(), e);
+            }
+        }
+    }
+
+    // Announces for as long as the run lasts: `started` first, then whenever
+    // the tracker asks us to, `completed` once the download finishes and
+    // `stopped` when the run is cancelled.
+    async fn announce_loop(self, cancel: CancellationToken) {
+        let mut events = self.context.events.subscribe();
+        // Trackers want the bytes transferred since `started`.
+        let base = self.stats.snapshot();
+        let mut event = AnnounceEvent::None;
+        let mut started = false;
+        // Whether the tracker already knows we have everything.
+        let mut complete = false;
+        self.trackers.lock().unwrap().reset();
+
+        loop {
+            let left = self.torrent_downloaded_state.bytes_left();
+            let reply = select! {
+                _ = cancel.cancelled() => break,
+                reply = self.announce_tiers(event, left, &base) => reply,
+            };
+            let delay = match reply {
+                Some(res) => {
+                    started = true;
+                    complete |= left == 0;
+                    event = AnnounceEvent::None;
+                    self.announce_interval(&res)
+                }
+                // Try again later with the same event.
+                None => {
+                    let delay = self.config.tracker_interval;
+                    self.trackers
+                        .lock()
+                        .unwrap()
+                        .set_next_announce(Instant::now() + delay);
+                    delay
+                }
+            };
+
+            select! {
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep(delay) => {}
+                _ = self.finished(&mut events), if started && !complete => {
+                    event = AnnounceEvent::Completed;
+                }
+            }
+        }
+
+        let started: Vec<String> = {
+            let trackers = self.trackers.lock().unwrap();
+            trackers
+                .urls()
                 .into_iter()
-                .map(|pw| PieceWorkState {
-                    piece_work: pw,
-                    chuncks: Mutex::new(vec![]),
-                    downloaded: AtomicBool::new(false),
-                    reserved: Mutex::new(None),
-                })
-                .collect(),
+                .filter(|url| trackers.is_started(url))
+                .collect()
+        };
+        let left = self.torrent_downloaded_state.bytes_left();
+        for url in started {
+            let _ = self
+                .announce_to(&url, AnnounceEvent::Stopped, left, &base)
+                .await;
+        }
+    }
+
+    // Tries the trackers tier by tier until one answers, BEP 12.
+    async fn announce_tiers(
+        &self,
+        event: AnnounceEvent,
+        left: u64,
+        base: &TransferSnapshot,
+    ) -> Option<BencodeResponse> {
+        let urls = self.trackers.lock().unwrap().urls();
+        for url in urls {
+            // A tracker we fell back to hasn't heard from us yet.
+            let event = if self.trackers.lock().unwrap().is_started(&url) {
+                event
+            } else {
+                AnnounceEvent::Started
+            };
+            if let Ok(res) = self.announce_to(&url, event, left, base).await {
+                return Some(res);
+            }
+        }
+        None
+    }
+
+    // Announces to a single tracker and records how it went.
+    async fn announce_to(
+        &self,
+        url: &str,
+        event: AnnounceEvent,
+        left: u64,
+        base: &TransferSnapshot,
+    ) -> Result<BencodeResponse, TrackerError> {
+        let tracker_id = self.trackers.lock().unwrap().tracker_id(url);
+        let reply = self.announce(url, event, left, base, &tracker_id).await;
+
+        let mut trackers = self.trackers.lock().unwrap();
+        match &reply {
+            Ok(res) => {
+                let next = Instant::now() + self.announce_interval(res);
+                trackers.on_success(url, event, res, Some(next));
+            }
+            Err(e) => trackers.on_failure(url, e.to_string()),
+        }
+        reply
+    }
+
+    fn announce_interval(&self, res: &BencodeResponse) -> Duration {
+        match res.interval.unwrap_or(0).max(res.min_interval.unwrap_or(0)) {
+            0 => self.config.tracker_interval,
+            secs => Duration::from_secs(secs),
+        }
+    }
+
+    async fn announce(
+        &self,
+        tracker: &str,
+        event: AnnounceEvent,
+        left: u64,
+        base: &TransferSnapshot,
+        tracker_id: &Option<Vec<u8>>,
+    ) -> Result<BencodeResponse, TrackerError> {
+        let transfer = self.stats.snapshot();
+        let announce = Announce {
+            info_hash: self.torrent_meta.info_hash,
+            peer_id: self.context.peer_id,
+            port: self.context.listen_port,
+            uploaded: transfer
+                .payload_uploaded
+                .saturating_sub(base.payload_uploaded),
+            downloaded: transfer
+                .payload_downloaded
+                .saturating_sub(base.payload_downloaded),
+            left,
+            event,
+            numwant: Some(match event {
+                AnnounceEvent::Stopped => 0,
+                _ => self.config.numwant,
+            }),
+            key: self.context.announce_key,
+            tracker_id: tracker_id.clone(),
+            ip: self.context.announce_ip,
+            // Trackers only see the address we announce from. A proxy
+            // hides our addresses.
+            ipv6: match self.context.proxy {
+                Some(_) => None,
+                None => self.context.network.local_ipv6().await,
+            },
+        };
+        debug!("announcing {:?} to {}", event, tracker);
+
+        let reply = async {
+            let client = self.context.http.client().map_err(TrackerError::Bind)?;
+            let res = tracker::announce(
+                &client,
+                &self.context.network,
+                self.context.proxy.as_ref(),
+                tracker,
+                &announce,
+            )
+            .await?;
+            Ok::<_, TrackerError>((res.get_peers()?, res))
+        }
+        .await;
+        let (new_peers, res) = match reply {
+            Ok(reply) => reply,
+            Err(e) => {
+                debug!("error announcing to {}: {}", tracker, e);
+                self.events.emit(EventKind::TrackerFailed {
+                    url: tracker.to_string(),
+                    error: e.to_string(),
+                });
+                return Err(e);
+            }
+        };
+        if let Some(message) = &res.warning_message {
+            debug!("warning from {}: {}", tracker, message);
+            self.events.emit(EventKind::TrackerWarning {
+                url: tracker.to_string(),
+                message: message.clone(),
+            });
+        }
+        self.events.emit(EventKind::TrackerReplied {
+            url: tracker.to_string(),
+            peers: new_peers.len(),
+            seeders: res.complete,
+            leechers: res.incomplete,
         });
+        if let Some(ip) = res.external_ip() {
+            self.context.connection_manager.set_external_ip(ip);
+        }
+
+        if event != AnnounceEvent::Stopped {
+            self.connections
+                .add_candidates_from(PeerSource::Tracker, new_peers);
+        }
+        Ok(res)
+    }
+
+    // Resolves once the torrent finished downloading.
+    async fn finished(&self, events: &mut broadcast::Receiver<Event>) {
+        loop {
+            match events.recv().await {
+                Ok(Event {
+                    info_hash,
+                    kind: EventKind::TorrentFinished,
+                }) if info_hash == self.torrent_meta.info_hash => return,
+                Err(broadcast::error::RecvError::Lagged(_))
+                    if self.torrent_downloaded_state.is_verified_complete() =>
+                {
+                    return
+                }
+                // The session holds the sender.
+                Err(broadcast::error::RecvError::Closed) => return std::future::pending().await,
+                _ => {}
+            }
+        }
+    }
+
+    /// Takes over a peer that connected to us and asked for this torrent.
+    pub fn accept(&self, peer: PeerAddr, stream: TcpStream, handshake: Handshake) {
+        let cancel = self.running.lock().unwrap().clone();
+        if cancel.is_cancelled() {
+            return;
+        }
+        if self.peer_bans.is_banned(&peer) {
+            debug!("rejecting banned peer {}", peer);
+            return;
+        }
+        let Some(slot) = self.connections.try_accept(peer) else {
+            debug!("no connection slot left for incoming peer {}", peer);
+            return;
+        };
+
+        let this = self.clone();
         tokio::spawn(async move {
-            loop {
-                for tracker in tcp_trackers.clone() {
-                    let torrent_meta = torrent_meta.clone();
-                    let peer_states = peer_states.clone();
-                    let piece_tx = piece_tx.clone();
-                    let have_broadcast = have_broadcast.clone();
-                    let torrent_downloaded_state = torrent_downloaded_state.clone();
-                    //let pieces_of_work = pieces_of_work.clone();
-                    tokio::spawn(async move {
-                        let url = file::build_tracker_url(&torrent_meta, &peer_id, 6881, &tracker);
-
-                        let request_peers_res = request_peers(&url).await.unwrap();
-                        let new_peers = request_peers_res.clone().get_peers().unwrap();
-                        let peer_states = peer_states.clone();
-
-                        for peer in new_peers {
-                            let peer_states = peer_states.clone();
-                            //let pieces_of_work = pieces_of_work.clone();
-
-                            if peer_states.clone().states.contains_key(&peer) {
-                                continue;
-                            }
+            this.run_peer(peer, slot, Some((stream, handshake)), cancel)
+                .await
+        });
+    }
 
-                            //let peers = peers.clone();
-                            let piece_tx = piece_tx.clone();
-                            let have_broadcast = have_broadcast.clone();
-                            let torrent_downloaded_state = torrent_downloaded_state.clone();
-
-                            tokio::spawn(async move {
-                                let unchoke_notify = tokio::sync::Notify::new();
-                                let (peer_writer_tx, peer_writer_rx) = flume::unbounded();
-
-                                let peer_handler = Arc::new(PeerHandler::new(
-                                    peer,
-                                    unchoke_notify,
-                                    piece_tx.clone(),
-                                    peer_writer_tx.clone(),
-                                    peer_states.clone(),
-                                    //pieces_of_work.clone(),
-                                    torrent_downloaded_state.clone(),
-                                ));
-
-                                let peer_connection = PeerConnection::new(
-                                    peer,
-                                    info_hash,
-                                    peer_id,
-                                    peer_handler.clone(),
-                                );
-
-                                let task_peer_chunk_req_fut =
-                                    peer_handler.task_peer_chunk_requester();
-                                let connect_peer_fut = peer_connection.manage_peer_incoming(
-                                    peer_writer_rx,
-                                    have_broadcast.subscribe(),
-                                );
-
-                                let req = select! {
-                                    r = connect_peer_fut => {
-                                        debug!("connect_peer_fut: {:#?}", r);
-                                        r
-                                    }
-                                    r = task_peer_chunk_req_fut => {
-                                        debug!("task_peer_chunk_req_fut: {:#?}", r);
-                                        r
-                                    }
-                                };
-
-                                match req {
-                                    Ok(_) => {
-                                        // We disconnected the peer ourselves as we don't need it
-                                        peer_handler.on_peer_died();
-                                    }
-                                    Err(e) => {
-                                        debug!("error managing peer: {:#}", e);
-                                        peer_handler.on_peer_died();
-                                    }
-                                }
-                            });
-                        }
-                        //sleep interval
-                        tokio::time::sleep(std::time::Duration::from_millis(
-                            request_peers_res.interval,
-                        ))
+    // Downloads whole pieces from a web seed, next to the peers, until the
+    // torrent is complete or the seed failed too many times in a row.
+    async fn run_web_seed(self, seed: WebSeed) {
+        let state = &self.torrent_downloaded_state;
+        let mut failures = 0;
+        while !state.is_complete() {
+            let Some(piece) = state.reserve_for_fetch() else {
+                tokio::time::sleep(self.config.web_seed_idle).await;
+                continue;
+            };
+            let index = piece.piece_work.index;
+            trace!("fetching piece {} from web seed {}", index, seed.url());
+            let limiters = [&*self.rate_limiter, &*self.context.rate_limiter];
+            let result = match self.context.http.client() {
+                Ok(client) => {
+                    seed.fetch_piece(&client, &self.torrent_meta, &piece.piece_work, &limiters)
                         .await
-                    });
                 }
+                Err(e) => Err(WebSeedError::Bind(e)),
+            };
 
-                tokio::time::sleep(std::time::Duration::from_secs(30)).await;
+            if let Ok(buf) = &result {
+                let length = buf.len() as u64;
+                self.stats.on_received(length, length);
             }
-        });
+
+            let error = match result {
+                Ok(buf) if utils::check_integrity(&piece.piece_work.hash, &buf) => {
+                    state.release_fetching(index);
+                    self.peer_bans.on_piece_verified(index, &buf);
+                    failures = 0;
+                    if piece
+                        .downloaded
+                        .swap(true, std::sync::atomic::Ordering::Relaxed)
+                    {
+                        // Peers got it first.
+                        self.stats.on_wasted(buf.len() as u64);
+                    } else {
+                        let length = piece.piece_work.length;
+                        if self
+                            .piece_tx
+                            .send(FullPiece { index, length, buf })
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                    continue;
+                }
+                Ok(buf) => {
+                    self.stats.on_failed(buf.len() as u64);
+                    self.events.emit(EventKind::PieceFailed { index });
+                    format!("piece {} failed the hash check", index)
+                }
+                Err(WebSeedError::Busy(delay)) => {
+                    debug!("web seed {} is busy", seed.url());
+                    state.release_fetching(index);
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+                // Every piece would cost the whole file, don't retry.
+                Err(WebSeedError::NoRangeSupport) => {
+                    failures = self.config.max_web_seed_failures - 1;
+                    WebSeedError::NoRangeSupport.to_string()
+                }
+                Err(e) => e.to_string(),
+            };
+
+            state.release_fetching(index);
+            failures += 1;
+            debug!("web seed {} failed: {}", seed.url(), error);
+            if failures >= self.config.max_web_seed_failures {
+                warn!("giving up on web seed {}: {}", seed.url(), error);
+                self.events.emit(EventKind::WebSeedFailed {
+                    url: seed.url().to_string(),
+                    error,
+                });
+                return;
+            }
+            let delay = self
+                .config
+                .web_seed_retry_delay
+                .saturating_mul(1 << (failures - 1).min(16));
+            tokio::time::sleep(delay).await;
+        }
     }
-}
 
-fn all_trackers(torrent_meta: &TorrentMeta) -> Vec<String> {
-    match (
-        &torrent_meta.torrent_file.announce,
-        &torrent_meta.torrent_file.announce_list,
+    // Runs a connection until either side hangs up. `incoming` holds the
+    // stream and handshake of a peer that connected to us.
+    async fn run_peer(
+        self,
+        peer: PeerAddr,
+        mut slot: ConnectionSlot,
+        incoming: Option<(TcpStream, Handshake)>,
+        torrent_cancel: CancellationToken,
     ) {
-        (Some(announce), None) => vec![announce.clone()],
-        (Some(announce), Some(announce_list)) => {
-            let mut h = Vec::<String>::from_iter(announce_list.iter().flatten().cloned());
-            if !h.contains(announce) {
-                h.push(announce.clone());
-            }
-            h.into_iter().collect()
+        let unchoke_notify = tokio::sync::Notify::new();
+        let (peer_writer_tx, peer_writer_rx) = flume::unbounded();
+
+        let mut peer_handler = PeerHandler::new(
+            peer,
+            unchoke_notify,
+            self.piece_tx.clone(),
+            peer_writer_tx.clone(),
+            self.peer_states.clone(),
+            self.peer_bans.clone(),
+            self.client_filter.clone(),
+            self.torrent_downloaded_state.clone(),
+            self.events.clone(),
+            self.stats.clone(),
+            self.config.clone(),
+        );
+        peer_handler = peer_handler.with_choker(self.rechoke_notify.clone());
+        // Private torrents only get their peers from the trackers.
+        if !self.torrent_meta.torrent_file.info.is_private() {
+            peer_handler = peer_handler.with_pex(self.connections.clone());
         }
-        (None, Some(announce_list)) => announce_list.clone().into_iter().flatten().collect(),
-        (None, None) => vec![],
+        let peer_handler = Arc::new(peer_handler);
+
+        let peer_connection = PeerConnection::new(
+            peer,
+            self.torrent_meta.info_hash,
+            self.context.peer_id,
+            peer_handler.clone(),
+            self.storage.clone(),
+            PeerRateLimiter::new(
+                peer,
+                &self.peer_rate_limiter,
+                self.rate_limiter.clone(),
+                self.context.rate_limiter.clone(),
+                self.context.exempt_lan_peers.clone(),
+            ),
+        );
+
+        let is_incoming = incoming.is_some();
+        let stream = match incoming {
+            Some((stream, handshake)) => peer_connection.accept(stream, &handshake).await,
+            None => {
+                peer_connection
+                    .connect(&self.context.network, self.context.proxy.as_ref())
+                    .await
+            }
+        };
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("error connecting to peer: {:#}", e);
+                peer_handler.on_peer_died();
+                return;
+            }
+        };
+        slot.on_connected(peer_handler.clone());
+        let cancel = slot.cancellation_token();
+        self.events.emit(EventKind::PeerConnected {
+            peer,
+            incoming: is_incoming,
+        });
+
+        let task_peer_chunk_req_fut = peer_handler.task_peer_chunk_requester();
+        let connect_peer_fut = peer_connection.manage_peer_incoming(
+            stream,
+            peer_writer_rx,
+            self.have_broadcast.subscribe(),
+        );
+
+        let task_request_timeouts_fut = peer_handler.task_request_timeouts();
+        let task_pex_fut = peer_handler.task_pex();
+
+        let req = select! {
+            // Stopping the torrent also disconnects every peer, report it as such.
+            biased;
+            _ = torrent_cancel.cancelled() => {
+                debug!("torrent stopped, disconnecting peer");
+                Ok(DisconnectReason::TorrentStopped)
+            }
+            _ = cancel.cancelled() => {
+                debug!("connection manager disconnected peer");
+                Ok(DisconnectReason::Replaced)
+            }
+            r = connect_peer_fut => {
+                debug!("connect_peer_fut: {:#?}", r);
+                r.map(|_| DisconnectReason::Closed)
+            }
+            r = task_peer_chunk_req_fut => {
+                debug!("task_peer_chunk_req_fut: {:#?}", r);
+                r.map(|_| DisconnectReason::Closed)
+            }
+            r = task_request_timeouts_fut => {
+                debug!("task_request_timeouts_fut: {:#?}", r);
+                r.map(|_| DisconnectReason::Closed)
+            }
+            r = task_pex_fut => {
+                debug!("task_pex_fut: {:#?}", r);
+                r.map(|_| DisconnectReason::Closed)
+            }
+        };
+
+        let reason = match req {
+            Ok(reason) => {
+                // We disconnected the peer ourselves as we don't need it
+                peer_handler.on_peer_died();
+                reason
+            }
+            Err(e) => {
+                debug!("error managing peer: {:#}", e);
+                peer_handler.on_peer_died();
+                DisconnectReason::Error(format!("{:#}", e))
+            }
+        };
+        self.events
+            .emit(EventKind::PeerDisconnected { peer, reason });
     }
 }
 
-pub async fn request_peers(uri: &str) -> anyhow::Result<BencodeResponse> {
-    let client = reqwest::Client::new();
-    let response = client.get(uri).send().await?;
-    let body_bytes = response.bytes().await?;
-
-    let tracker_bencode_decode = de::from_bytes::<BencodeResponse>(&body_bytes)?;
-    Ok(tracker_bencode_decode)
+fn spawn<F>(cancel: &CancellationToken, future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(cancel.clone().run_until_cancelled_owned(future));
 }
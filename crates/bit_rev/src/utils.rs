@@ -1,7 +1,6 @@
-use crate::torrent::Torrent;
-use rand::Rng;
+use crate::{peer_id, torrent::Torrent};
 
-const BLOCK_SIZE: u32 = 16384;
+pub const BLOCK_SIZE: u32 = 16384;
 
 pub fn calculate_bounds_for_piece(torrent: &Torrent, index: usize) -> (usize, usize) {
     let start = index * torrent.piece_length as usize;
@@ -20,11 +19,11 @@ pub fn calculate_piece_size(torrent: &Torrent, index: usize) -> usize {
     end - start
 }
 
-pub fn calculate_block_size(piece_length: u32, requested: u32) -> u32 {
-    if piece_length - requested < BLOCK_SIZE {
+pub fn calculate_block_size(piece_length: u32, requested: u32, block_size: u32) -> u32 {
+    if piece_length - requested < block_size {
         return piece_length - requested;
     };
-    BLOCK_SIZE
+    block_size
 }
 
 pub fn check_integrity(hash: &[u8], buf: &[u8]) -> bool {
@@ -34,11 +33,7 @@ pub fn check_integrity(hash: &[u8], buf: &[u8]) -> bool {
     result == hash
 }
 
+/// Generates an Azureus-style peer id, `-BR0100-` followed by random characters.
 pub fn generate_peer_id() -> [u8; 20] {
-    let mut rng = rand::prelude::ThreadRng::default();
-    (0..20)
-        .map(|_| rng.gen())
-        .collect::<Vec<u8>>()
-        .try_into()
-        .unwrap()
+    peer_id::generate_with_prefix(peer_id::DEFAULT_PEER_ID_PREFIX)
 }
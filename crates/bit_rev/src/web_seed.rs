@@ -0,0 +1,375 @@
+use std::time::Duration;
+
+use reqwest::{header, StatusCode};
+use thiserror::Error;
+
+use crate::{
+    file::{url_encode_bytes, TorrentFile, TorrentMeta},
+    rate_limit::{self, RateLimiter},
+    session::PieceWork,
+};
+
+/// How long a web seed may take to answer, or go without sending anything
+/// while the body comes in. The body as a whole may take longer when it is
+/// throttled.
+const WEB_SEED_TIMEOUT: Duration = Duration::from_secs(60);
+/// Wait before asking a BEP 17 seed that is busy but didn't say for how long.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum WebSeedError {
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("web seed answered {0}")]
+    Status(StatusCode),
+    #[error("web seed is busy, retry in {0:?}")]
+    Busy(Duration),
+    #[error("expected {expected} bytes, got {got}")]
+    Length { expected: u64, got: u64 },
+    #[error("web seed ignores range requests")]
+    NoRangeSupport,
+    #[error("web seed timed out")]
+    Timeout,
+    #[error("can't bind to the network: {0}")]
+    Bind(std::io::Error),
+}
+
+/// An HTTP server that has the torrent's data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebSeed {
+    /// BEP 19 `url-list`: the files themselves, fetched by byte range.
+    Url(String),
+    /// BEP 17 `httpseeds`: a script that serves whole pieces.
+    Http(String),
+}
+
+/// The part of a piece that lives in one file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileSpan {
+    file: usize,
+    offset: u64,
+    length: u64,
+}
+
+impl WebSeed {
+    /// Every HTTP or HTTPS web seed of the torrent, `url-list` ones first.
+    pub fn from_torrent(torrent: &TorrentFile) -> Vec<WebSeed> {
+        let url_list = torrent.url_list.iter().flatten().cloned().map(WebSeed::Url);
+        let httpseeds = torrent
+            .httpseeds
+            .iter()
+            .flatten()
+            .cloned()
+            .map(WebSeed::Http);
+        url_list
+            .chain(httpseeds)
+            .filter(|seed| seed.url().starts_with("http://") || seed.url().starts_with("https://"))
+            .collect()
+    }
+
+    pub fn url(&self) -> &str {
+        match self {
+            WebSeed::Url(url) | WebSeed::Http(url) => url,
+        }
+    }
+
+    /// Downloads a piece, throttled by `limiters` as the data comes in.
+    /// The caller checks its hash.
+    pub async fn fetch_piece(
+        &self,
+        client: &reqwest::Client,
+        meta: &TorrentMeta,
+        piece: &PieceWork,
+        limiters: &[&RateLimiter],
+    ) -> Result<Vec<u8>, WebSeedError> {
+        let buf = match self {
+            WebSeed::Url(url) => fetch_ranges(client, url, meta, piece, limiters).await?,
+            WebSeed::Http(url) => fetch_http_seed(client, url, meta, piece, limiters).await?,
+        };
+        if buf.len() != piece.length as usize {
+            return Err(WebSeedError::Length {
+                expected: piece.length as u64,
+                got: buf.len() as u64,
+            });
+        }
+        Ok(buf)
+    }
+}
+
+// BEP 19: one range request per file the piece spans.
+async fn fetch_ranges(
+    client: &reqwest::Client,
+    url: &str,
+    meta: &TorrentMeta,
+    piece: &PieceWork,
+    limiters: &[&RateLimiter],
+) -> Result<Vec<u8>, WebSeedError> {
+    let files = file_urls(url, meta);
+    let lengths: Vec<u64> = files.iter().map(|(_, length)| *length).collect();
+    let piece_length = meta.torrent_file.info.piece_length as u64;
+    let spans = piece_spans(
+        &lengths,
+        piece.index as u64 * piece_length,
+        piece.length as u64,
+    );
+
+    let mut buf = Vec::with_capacity(piece.length as usize);
+    for span in spans {
+        let (url, file_length) = &files[span.file];
+        let end = span.offset + span.length - 1;
+        let request = client
+            .get(url)
+            .header(header::RANGE, format!("bytes={}-{}", span.offset, end));
+        let response = send(request).await?;
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT => {
+                read_body(response, &mut buf, piece.length as usize, limiters).await?
+            }
+            // Without range support the server sends the whole file, which is
+            // only what we asked for when the span is the whole file.
+            StatusCode::OK if span.offset == 0 && span.length == *file_length => {
+                read_body(response, &mut buf, piece.length as usize, limiters).await?
+            }
+            StatusCode::OK => return Err(WebSeedError::NoRangeSupport),
+            status => return Err(WebSeedError::Status(status)),
+        }
+    }
+    Ok(buf)
+}
+
+// BEP 17: `?info_hash=...&piece=N` answers with the whole piece, or 503 and
+// the seconds to wait.
+async fn fetch_http_seed(
+    client: &reqwest::Client,
+    url: &str,
+    meta: &TorrentMeta,
+    piece: &PieceWork,
+    limiters: &[&RateLimiter],
+) -> Result<Vec<u8>, WebSeedError> {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let url = format!(
+        "{}{}info_hash={}&piece={}",
+        url,
+        separator,
+        url_encode_bytes(&meta.info_hash),
+        piece.index
+    );
+    let response = send(client.get(url)).await?;
+    match response.status() {
+        StatusCode::OK => {
+            let mut buf = Vec::with_capacity(piece.length as usize);
+            read_body(response, &mut buf, piece.length as usize, limiters).await?;
+            Ok(buf)
+        }
+        StatusCode::SERVICE_UNAVAILABLE => {
+            let body = response.text().await.unwrap_or_default();
+            let retry_after = body
+                .trim()
+                .parse()
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_RETRY_AFTER);
+            Err(WebSeedError::Busy(retry_after))
+        }
+        status => Err(WebSeedError::Status(status)),
+    }
+}
+
+async fn send(request: reqwest::RequestBuilder) -> Result<reqwest::Response, WebSeedError> {
+    tokio::time::timeout(WEB_SEED_TIMEOUT, request.send())
+        .await
+        .map_err(|_| WebSeedError::Timeout)?
+        .map_err(WebSeedError::from)
+}
+
+// Appends the body to `buf` as it streams in, waiting on `limiters` after
+// every chunk like the peer connections do. Stops past `max` bytes, the
+// piece can't be that long.
+async fn read_body(
+    mut response: reqwest::Response,
+    buf: &mut Vec<u8>,
+    max: usize,
+    limiters: &[&RateLimiter],
+) -> Result<(), WebSeedError> {
+    loop {
+        let chunk = tokio::time::timeout(WEB_SEED_TIMEOUT, response.chunk())
+            .await
+            .map_err(|_| WebSeedError::Timeout)??;
+        let Some(chunk) = chunk else {
+            return Ok(());
+        };
+        if buf.len() + chunk.len() > max {
+            return Err(WebSeedError::Length {
+                expected: max as u64,
+                got: (buf.len() + chunk.len()) as u64,
+            });
+        }
+        buf.extend_from_slice(&chunk);
+        rate_limit::download(limiters, chunk.len() as u64).await;
+    }
+}
+
+// The URL and length of every file. A URL ending with `/` is the directory
+// the torrent sits in, otherwise it is the file of a single-file torrent.
+fn file_urls(url: &str, meta: &TorrentMeta) -> Vec<(String, u64)> {
+    let info = &meta.torrent_file.info;
+    let name = url_encode_bytes(info.name.as_bytes());
+    match &info.files {
+        Some(files) => {
+            let base = match url.ends_with('/') {
+                true => format!("{}{}", url, name),
+                false => format!("{}/{}", url, name),
+            };
+            files
+                .iter()
+                .map(|f| {
+                    let path: Vec<String> = f
+                        .path
+                        .iter()
+                        .map(|p| url_encode_bytes(p.as_bytes()))
+                        .collect();
+                    (format!("{}/{}", base, path.join("/")), f.length as u64)
+                })
+                .collect()
+        }
+        None => {
+            let length = info.length.unwrap_or(0) as u64;
+            match url.ends_with('/') {
+                true => vec![(format!("{}{}", url, name), length)],
+                false => vec![(url.to_string(), length)],
+            }
+        }
+    }
+}
+
+// Where the `length` bytes at `offset` of the torrent's byte stream are,
+// given the length of each file.
+fn piece_spans(files: &[u64], offset: u64, length: u64) -> Vec<FileSpan> {
+    let end = offset + length;
+    let mut spans = Vec::new();
+    let mut file_start = 0;
+    for (file, &file_length) in files.iter().enumerate() {
+        let file_end = file_start + file_length;
+        let start = offset.max(file_start);
+        let stop = end.min(file_end);
+        if start < stop {
+            spans.push(FileSpan {
+                file,
+                offset: start - file_start,
+                length: stop - start,
+            });
+        }
+        file_start = file_end;
+    }
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(torrent: &[u8]) -> TorrentMeta {
+        TorrentMeta::from_bytes(torrent).unwrap()
+    }
+
+    #[test]
+    fn seeds_from_the_torrent() {
+        let meta = meta(
+            b"d4:infod4:name1:a6:pieces20:aaaaaaaaaaaaaaaaaaaa12:piece lengthi4e6:lengthi4ee\
+              8:url-listl17:http://a.example/16:ftp://b.example/e\
+              9:httpseedsl24:http://c.example/seed.pyee",
+        );
+
+        assert_eq!(
+            WebSeed::from_torrent(&meta.torrent_file),
+            vec![
+                WebSeed::Url("http://a.example/".to_string()),
+                WebSeed::Http("http://c.example/seed.py".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn url_list_can_be_a_single_url() {
+        let meta = meta(
+            b"d4:infod4:name1:a6:pieces20:aaaaaaaaaaaaaaaaaaaa12:piece lengthi4e6:lengthi4ee\
+              8:url-list20:http://a.example/a.ie",
+        );
+
+        assert_eq!(
+            WebSeed::from_torrent(&meta.torrent_file),
+            vec![WebSeed::Url("http://a.example/a.i".to_string())]
+        );
+    }
+
+    #[test]
+    fn file_urls_follow_bep_19() {
+        let single = meta(
+            b"d4:infod4:name5:a b.c6:pieces20:aaaaaaaaaaaaaaaaaaaa12:piece lengthi4e6:lengthi4eee",
+        );
+        assert_eq!(
+            file_urls("http://a.example/files/", &single),
+            vec![("http://a.example/files/a%20b.c".to_string(), 4)]
+        );
+        assert_eq!(
+            file_urls("http://a.example/other.c", &single),
+            vec![("http://a.example/other.c".to_string(), 4)]
+        );
+
+        let multi = meta(
+            b"d4:infod4:name3:dir6:pieces40:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa12:piece lengthi4e\
+              5:filesld4:pathl1:xe6:lengthi3eed4:pathl3:sub1:ye6:lengthi5eeeee",
+        );
+        assert_eq!(
+            file_urls("http://a.example/files", &multi),
+            vec![
+                ("http://a.example/files/dir/x".to_string(), 3),
+                ("http://a.example/files/dir/sub/y".to_string(), 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn pieces_span_file_boundaries() {
+        let files = [3, 0, 5, 10];
+
+        assert_eq!(
+            piece_spans(&files, 0, 4),
+            vec![
+                FileSpan {
+                    file: 0,
+                    offset: 0,
+                    length: 3
+                },
+                FileSpan {
+                    file: 2,
+                    offset: 0,
+                    length: 1
+                },
+            ]
+        );
+        assert_eq!(
+            piece_spans(&files, 4, 8),
+            vec![
+                FileSpan {
+                    file: 2,
+                    offset: 1,
+                    length: 4
+                },
+                FileSpan {
+                    file: 3,
+                    offset: 0,
+                    length: 4
+                },
+            ]
+        );
+        // The last piece is shorter.
+        assert_eq!(
+            piece_spans(&files, 16, 2),
+            vec![FileSpan {
+                file: 3,
+                offset: 8,
+                length: 2
+            }]
+        );
+    }
+}
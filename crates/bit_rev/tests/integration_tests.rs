@@ -1,6 +1,7 @@
 use std::io::Error;
 use std::net::SocketAddr;
 use std::time::Duration;
+use bit_rev::events::EventKind;
 use bit_rev::protocol::Protocol;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
@@ -64,7 +65,7 @@ async fn successful_handshake_test() {
     // Wait for the server to start
     tokio::time::sleep(Duration::from_millis(200)).await;
     let peer_addr = SocketAddr::new(std::net::Ipv4Addr::new(127, 0, 0, 1).into(), port);
-    let protocol = Protocol::connect(peer_addr.clone(), info_hash, client_peer_id)
+    let protocol = Protocol::connect(peer_addr, info_hash, client_peer_id)
         .await
         .expect("Failed to connect");
 
@@ -73,3 +74,1340 @@ async fn successful_handshake_test() {
     assert_eq!(protocol.info_hash, info_hash);
     assert_eq!(protocol.peer, peer_addr);
 }
+
+fn tracker_less_torrent() -> bit_rev::file::TorrentMeta {
+    use bit_rev::file::{Info, TorrentFile, TorrentMeta};
+
+    let data = b"hello session".to_vec();
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(&data);
+    TorrentMeta::new(TorrentFile {
+        info: Info {
+            name: "hello.txt".to_string(),
+            pieces: serde_bytes::ByteBuf::from(hasher.digest().bytes().to_vec()),
+            piece_length: 16384,
+            md5sum: None,
+            length: Some(data.len() as i64),
+            files: None,
+            private: None,
+            path: None,
+            root_hash: None,
+        },
+        announce: None,
+        nodes: None,
+        encoding: None,
+        httpseeds: None,
+        url_list: None,
+        announce_list: None,
+        creation_date: None,
+        comment: None,
+        created_by: None,
+    })
+    .unwrap()
+}
+
+fn test_session_config(name: &str) -> bit_rev::session::SessionConfig {
+    bit_rev::session::SessionConfig {
+        listen_port: 0,
+        download_dir: std::env::temp_dir().join(format!(
+            "bit_rev_{}_{}",
+            name,
+            std::process::id()
+        )),
+        enable_dht: false,
+        enable_lsd: false,
+        ..Default::default()
+    }
+}
+
+async fn wait_for_state(
+    handle: &bit_rev::session::TorrentHandle,
+    state: bit_rev::session::TorrentState,
+) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.state() != state {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("torrent is {:?}, expected {:?}", handle.state(), state));
+}
+
+#[tokio::test]
+async fn session_add_and_remove_torrents() {
+    let session = bit_rev::session::Session::new(test_session_config("add_remove"))
+        .await
+        .unwrap();
+    let meta = tracker_less_torrent();
+
+    let handle = session.add_torrent(meta.clone()).await.unwrap();
+    let again = session.add_torrent(meta.clone()).await.unwrap();
+    assert_eq!(handle.info_hash(), again.info_hash());
+    assert_eq!(session.torrents().len(), 1);
+    assert_eq!(handle.total_length(), 13);
+    assert!(!handle.is_complete());
+
+    let removed = session.remove_torrent(&meta.info_hash, false).await.unwrap();
+    assert!(removed.is_some());
+    assert!(session.torrent(&meta.info_hash).is_none());
+    assert!(session
+        .remove_torrent(&meta.info_hash, false)
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn session_saves_a_torrent_at_a_given_path() {
+    let config = test_session_config("add_at");
+    let path = config.download_dir.join("renamed.txt");
+    let session = bit_rev::session::Session::new(config).await.unwrap();
+
+    let handle = session
+        .add_torrent_at(tracker_less_torrent(), &path)
+        .await
+        .unwrap();
+    let files = handle.storage().files();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].path, path);
+    handle.remove(true).await.unwrap();
+}
+
+#[tokio::test]
+async fn session_refuses_invalid_torrent_config() {
+    use bit_rev::session::{Session, SessionError, TorrentConfig};
+
+    let mut config = test_session_config("invalid_config");
+    config.torrent.block_size = 0;
+    assert!(matches!(
+        Session::new(config).await,
+        Err(SessionError::Config(_))
+    ));
+
+    let session = Session::new(test_session_config("invalid_config"))
+        .await
+        .unwrap();
+    let config = TorrentConfig {
+        max_outstanding_requests: 0,
+        ..Default::default()
+    };
+    assert!(matches!(
+        session
+            .add_torrent_with_config(tracker_less_torrent(), config)
+            .await,
+        Err(SessionError::Config(_))
+    ));
+    assert!(session.torrents().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn adding_a_torrent_twice_at_once_starts_it_once() {
+    let session = std::sync::Arc::new(
+        bit_rev::session::Session::new(test_session_config("add_twice")).await.unwrap(),
+    );
+    let meta = tracker_less_torrent();
+
+    let adds: Vec<_> = (0..8)
+        .map(|_| {
+            let session = session.clone();
+            let meta = meta.clone();
+            tokio::spawn(async move { session.add_torrent(meta).await.unwrap() })
+        })
+        .collect();
+    let mut handles = Vec::new();
+    for add in adds {
+        handles.push(add.await.unwrap());
+    }
+
+    assert_eq!(session.torrents().len(), 1);
+    let kept = session.torrent(&meta.info_hash).unwrap();
+    for handle in &handles {
+        assert!(std::sync::Arc::ptr_eq(handle.storage(), kept.storage()));
+    }
+    kept.remove(true).await.unwrap();
+}
+
+#[tokio::test]
+async fn session_answers_incoming_handshakes() {
+    let session = bit_rev::session::Session::new(test_session_config("incoming"))
+        .await
+        .unwrap();
+    let meta = tracker_less_torrent();
+    let handle = session.add_torrent(meta.clone()).await.unwrap();
+    // Peers are only accepted once the data has been checked.
+    wait_for_state(&handle, bit_rev::session::TorrentState::Downloading).await;
+
+    let addr: SocketAddr = ([127, 0, 0, 1], session.listen_port()).into();
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let handshake = bit_rev::handshake::Handshake::new(meta.info_hash, *b"-XX0000-abcdefghijkl");
+    stream.write_all(&handshake.serialize()).await.unwrap();
+
+    let mut reply = vec![0u8; 68];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut reply))
+        .await
+        .unwrap()
+        .unwrap();
+    let reply = bit_rev::handshake::Handshake::read(19, reply[1..].to_vec()).unwrap();
+    assert_eq!(reply.info_hash, meta.info_hash);
+    assert_eq!(reply.peer_id, session.peer_id());
+}
+
+#[tokio::test]
+async fn out_of_range_pieces_disconnect_the_peer() {
+    use bit_rev::events::DisconnectReason;
+
+    let session = bit_rev::session::Session::new(test_session_config("out_of_range_piece"))
+        .await
+        .unwrap();
+    let mut events = session.subscribe();
+    let meta = tracker_less_torrent();
+    let handle = session.add_torrent(meta.clone()).await.unwrap();
+    wait_for_state(&handle, bit_rev::session::TorrentState::Downloading).await;
+
+    let addr: SocketAddr = ([127, 0, 0, 1], session.listen_port()).into();
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let handshake = bit_rev::handshake::Handshake::new(meta.info_hash, *b"-XX0000-abcdefghijkl");
+    stream.write_all(&handshake.serialize()).await.unwrap();
+    let mut reply = vec![0u8; 68];
+    stream.read_exact(&mut reply).await.unwrap();
+
+    // A piece message for piece 1000 of a one piece torrent.
+    let mut piece = vec![0, 0, 0, 13, 7];
+    piece.extend_from_slice(&1000u32.to_be_bytes());
+    piece.extend_from_slice(&0u32.to_be_bytes());
+    piece.extend_from_slice(b"evil");
+    stream.write_all(&piece).await.unwrap();
+
+    let reason = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            if let EventKind::PeerDisconnected { reason, .. } = events.recv().await.unwrap().kind {
+                break reason;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert!(matches!(reason, DisconnectReason::Error(e) if e.contains("invalid piece message")));
+
+    handle.remove(true).await.unwrap();
+}
+
+#[tokio::test]
+async fn requests_past_the_piece_disconnect_the_peer() {
+    use bit_rev::events::DisconnectReason;
+
+    let config = test_session_config("out_of_range_request");
+    std::fs::create_dir_all(&config.download_dir).unwrap();
+    std::fs::write(config.download_dir.join("hello.txt"), b"hello session").unwrap();
+    let session = bit_rev::session::Session::new(config).await.unwrap();
+    let mut events = session.subscribe();
+    let meta = tracker_less_torrent();
+    let handle = session.add_torrent(meta.clone()).await.unwrap();
+    wait_for_state(&handle, bit_rev::session::TorrentState::Seeding).await;
+
+    let addr: SocketAddr = ([127, 0, 0, 1], session.listen_port()).into();
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let handshake = bit_rev::handshake::Handshake::new(meta.info_hash, *b"-XX0000-abcdefghijkl");
+    stream.write_all(&handshake.serialize()).await.unwrap();
+    let mut reply = vec![0u8; 68];
+    stream.read_exact(&mut reply).await.unwrap();
+
+    // 16 bytes from offset 8 of the only piece, which is 13 bytes long.
+    stream
+        .write_all(&bit_rev::message::serialize(Some(
+            bit_rev::message::format_request(0, 8, 16),
+        )))
+        .await
+        .unwrap();
+
+    let reason = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            if let EventKind::PeerDisconnected { reason, .. } = events.recv().await.unwrap().kind {
+                break reason;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert!(matches!(reason, DisconnectReason::Error(e) if e.contains("invalid request")));
+
+    handle.remove(true).await.unwrap();
+}
+
+#[tokio::test]
+async fn torrent_lifecycle() {
+    use bit_rev::session::TorrentState;
+
+    let config = test_session_config("lifecycle");
+    std::fs::create_dir_all(&config.download_dir).unwrap();
+    let data_path = config.download_dir.join("hello.txt");
+    std::fs::write(&data_path, b"hello session").unwrap();
+
+    let session = bit_rev::session::Session::new(config).await.unwrap();
+    let handle = session.add_torrent(tracker_less_torrent()).await.unwrap();
+
+    // The data is already there, checking finds it and we go straight to seeding.
+    wait_for_state(&handle, TorrentState::Seeding).await;
+    assert!(handle.is_complete());
+    assert_eq!(handle.downloaded(), 13);
+
+    handle.pause();
+    assert_eq!(handle.state(), TorrentState::Paused);
+    handle.resume();
+    wait_for_state(&handle, TorrentState::Seeding).await;
+
+    // Stopped torrents check their data again on resume.
+    handle.stop();
+    assert_eq!(handle.state(), TorrentState::Stopped);
+    std::fs::write(&data_path, b"hello changed").unwrap();
+    handle.resume();
+    wait_for_state(&handle, TorrentState::Downloading).await;
+    assert_eq!(handle.downloaded(), 0);
+
+    handle.remove(true).await.unwrap();
+    assert!(session.torrents().is_empty());
+    assert!(!data_path.exists());
+}
+
+#[tokio::test]
+async fn download_between_sessions() {
+    let seeder_config = test_session_config("seeder");
+    std::fs::create_dir_all(&seeder_config.download_dir).unwrap();
+    std::fs::write(
+        seeder_config.download_dir.join("hello.txt"),
+        b"hello session",
+    )
+    .unwrap();
+    let leecher_config = test_session_config("leecher");
+    let leecher_dir = leecher_config.download_dir.clone();
+
+    let seeder = bit_rev::session::Session::new(seeder_config).await.unwrap();
+    let leecher = bit_rev::session::Session::new(leecher_config).await.unwrap();
+    let meta = tracker_less_torrent();
+
+    let seeding = seeder.add_torrent(meta.clone()).await.unwrap();
+    wait_for_state(&seeding, bit_rev::session::TorrentState::Seeding).await;
+
+    let mut events = leecher.subscribe();
+    let downloading = leecher.add_torrent(meta).await.unwrap();
+    let seeder_addr: SocketAddr = ([127, 0, 0, 1], seeder.listen_port()).into();
+    downloading
+        .peers()
+        .connections
+        .add_candidates([seeder_addr]);
+
+    tokio::time::timeout(Duration::from_secs(10), downloading.wait_for_completion())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        std::fs::read(leecher_dir.join("hello.txt")).unwrap(),
+        b"hello session"
+    );
+
+    let stats = downloading.stats();
+    assert_eq!(stats.downloaded, 13);
+    assert_eq!(stats.eta, Some(Duration::ZERO));
+    assert_eq!(stats.transfer.payload_downloaded, 13);
+    assert!(stats.transfer.protocol_downloaded > 0);
+    assert_eq!(stats.transfer.failed, 0);
+    assert_eq!(stats.piece_availability, vec![1]);
+    let peer = &stats.peers[0];
+    assert_eq!(peer.addr, seeder_addr);
+    assert!(peer.client.is_some());
+    assert_eq!(peer.transfer.payload_downloaded, 13);
+    let seeder_stats = seeding.stats();
+    assert_eq!(seeder_stats.transfer.payload_uploaded, 13);
+    assert_eq!(seeder_stats.peers[0].transfer.payload_uploaded, 13);
+
+    let mut kinds = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        assert_eq!(event.info_hash, downloading.info_hash());
+        kinds.push(event.kind);
+    }
+    assert_eq!(kinds[0], EventKind::TorrentAdded);
+    assert!(kinds.contains(&EventKind::PieceVerified { index: 0 }));
+    assert!(kinds.contains(&EventKind::FileCompleted {
+        index: 0,
+        path: leecher_dir.join("hello.txt"),
+    }));
+    assert!(kinds.contains(&EventKind::TorrentFinished));
+    assert!(kinds.iter().any(|kind| matches!(
+        kind,
+        EventKind::PeerConnected { peer, incoming: false } if *peer == seeder_addr
+    )));
+
+    downloading.remove(true).await.unwrap();
+    seeding.remove(true).await.unwrap();
+}
+
+#[tokio::test]
+async fn rate_limited_upload() {
+    let mut seeder_config = test_session_config("limited_seeder");
+    seeder_config.exempt_lan_peers = false;
+    std::fs::create_dir_all(&seeder_config.download_dir).unwrap();
+    std::fs::write(
+        seeder_config.download_dir.join("hello.txt"),
+        b"hello session",
+    )
+    .unwrap();
+    let leecher_config = test_session_config("limited_leecher");
+
+    let seeder = bit_rev::session::Session::new(seeder_config).await.unwrap();
+    let leecher = bit_rev::session::Session::new(leecher_config).await.unwrap();
+    let meta = tracker_less_torrent();
+
+    let seeding = seeder.add_torrent(meta.clone()).await.unwrap();
+    wait_for_state(&seeding, bit_rev::session::TorrentState::Seeding).await;
+    // Less than the piece message, so it has to wait for tokens.
+    seeding.rate_limiter().set_upload_limit(Some(10));
+
+    let downloading = leecher.add_torrent(meta).await.unwrap();
+    let start = std::time::Instant::now();
+    downloading
+        .peers()
+        .connections
+        .add_candidates([([127, 0, 0, 1], seeder.listen_port()).into()]);
+
+    tokio::time::timeout(Duration::from_secs(10), downloading.wait_for_completion())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(start.elapsed() >= Duration::from_secs(2));
+
+    downloading.remove(true).await.unwrap();
+    seeding.remove(true).await.unwrap();
+}
+
+// A reply handing out `peer`.
+fn tracker_reply(peer: SocketAddr) -> Vec<u8> {
+    let SocketAddr::V4(peer) = peer else {
+        panic!("expected an IPv4 peer");
+    };
+    let mut body = b"d8:intervali1800e12:min intervali60e5:peers6:".to_vec();
+    body.extend_from_slice(&peer.ip().octets());
+    body.extend_from_slice(&peer.port().to_be_bytes());
+    body.extend_from_slice(b"6:peers60:10:tracker id3:abce");
+    body
+}
+
+// A tracker that answers every announce with `body` and reports their query.
+async fn fake_tracker(body: Vec<u8>) -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/announce", listener.local_addr().unwrap());
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = socket.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            let request = String::from_utf8_lossy(&request);
+            let query = request
+                .split_whitespace()
+                .nth(1)
+                .and_then(|path| path.split_once('?'))
+                .map(|(_, query)| query.to_string())
+                .unwrap_or_default();
+            let _ = tx.send(query);
+
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .into_bytes();
+            response.extend_from_slice(&body);
+            socket.write_all(&response).await.unwrap();
+        }
+    });
+
+    (url, rx)
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+#[tokio::test]
+async fn announces_follow_the_torrent_lifecycle() {
+    let seeder_config = test_session_config("announce_seeder");
+    std::fs::create_dir_all(&seeder_config.download_dir).unwrap();
+    std::fs::write(
+        seeder_config.download_dir.join("hello.txt"),
+        b"hello session",
+    )
+    .unwrap();
+    let seeder = bit_rev::session::Session::new(seeder_config).await.unwrap();
+    let seeding = seeder.add_torrent(tracker_less_torrent()).await.unwrap();
+    wait_for_state(&seeding, bit_rev::session::TorrentState::Seeding).await;
+
+    let (url, mut announces) =
+        fake_tracker(tracker_reply(([127, 0, 0, 1], seeder.listen_port()).into())).await;
+    let mut meta = tracker_less_torrent();
+    meta.torrent_file.announce = Some(url);
+    let leecher = bit_rev::session::Session::new(test_session_config("announce_leecher"))
+        .await
+        .unwrap();
+    let downloading = leecher.add_torrent(meta).await.unwrap();
+
+    let mut next_announce = async || {
+        tokio::time::timeout(Duration::from_secs(10), announces.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    };
+
+    let started = next_announce().await;
+    assert_eq!(query_param(&started, "event"), Some("started"));
+    assert_eq!(query_param(&started, "left"), Some("13"));
+    assert_eq!(query_param(&started, "uploaded"), Some("0"));
+    assert_eq!(query_param(&started, "numwant"), Some("50"));
+    assert_eq!(query_param(&started, "trackerid"), None);
+    assert!(query_param(&started, "key").is_some());
+
+    // The tracker gave us the seeder.
+    tokio::time::timeout(Duration::from_secs(10), downloading.wait_for_completion())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let completed = next_announce().await;
+    assert_eq!(query_param(&completed, "event"), Some("completed"));
+    assert_eq!(query_param(&completed, "left"), Some("0"));
+    assert_eq!(query_param(&completed, "downloaded"), Some("13"));
+    assert_eq!(query_param(&completed, "trackerid"), Some("abc"));
+    assert_eq!(
+        query_param(&completed, "key"),
+        query_param(&started, "key")
+    );
+
+    downloading.pause();
+    let stopped = next_announce().await;
+    assert_eq!(query_param(&stopped, "event"), Some("stopped"));
+    assert_eq!(query_param(&stopped, "numwant"), Some("0"));
+
+    downloading.remove(true).await.unwrap();
+    seeding.remove(true).await.unwrap();
+}
+
+#[tokio::test]
+async fn tracker_failures_are_reported() {
+    let (url, _announces) = fake_tracker(b"d14:failure reason12:unregisterede".to_vec()).await;
+    let mut meta = tracker_less_torrent();
+    meta.torrent_file.announce = Some(url.clone());
+    let session = bit_rev::session::Session::new(test_session_config("tracker_failure"))
+        .await
+        .unwrap();
+    let mut events = session.subscribe();
+    let handle = session.add_torrent(meta).await.unwrap();
+
+    let error = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            if let EventKind::TrackerFailed { url: failed, error } = events.recv().await.unwrap().kind
+            {
+                assert_eq!(failed, url);
+                return error;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(error, "tracker failure: unregistered");
+
+    handle.remove(true).await.unwrap();
+}
+
+#[tokio::test]
+async fn trackers_fall_back_to_the_next_tier() {
+    // Nothing listens there anymore.
+    let dead = format!("http://127.0.0.1:{}/announce", find_available_port().await);
+    let (url, mut announces) = fake_tracker(b"d8:intervali1800e5:peers0:e".to_vec()).await;
+    let mut meta = tracker_less_torrent();
+    meta.torrent_file.announce = Some(dead.clone());
+    meta.torrent_file.announce_list = Some(vec![vec![dead.clone()], vec![url.clone()]]);
+    let session = bit_rev::session::Session::new(test_session_config("tracker_tiers"))
+        .await
+        .unwrap();
+    let handle = session.add_torrent(meta).await.unwrap();
+
+    let started = tokio::time::timeout(Duration::from_secs(10), announces.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(query_param(&started, "event"), Some("started"));
+
+    let trackers = handle.trackers();
+    assert_eq!(trackers.len(), 2);
+    assert_eq!((trackers[0].url.as_str(), trackers[0].tier), (dead.as_str(), 0));
+    assert_eq!(trackers[0].fails, 1);
+    assert!(trackers[0].last_error.is_some());
+    assert_eq!((trackers[1].url.as_str(), trackers[1].tier), (url.as_str(), 1));
+    // The reply may still be on its way.
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while handle.trackers()[1].next_announce.is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    let tracker = &handle.trackers()[1];
+    assert_eq!(tracker.fails, 0);
+    assert!(tracker.last_announce.is_some());
+    assert!(tracker.next_announce.unwrap() > std::time::Instant::now() + Duration::from_secs(1700));
+
+    handle.remove(true).await.unwrap();
+}
+
+// A BEP 15 tracker that hands out `peer` to every announce and reports their event.
+async fn fake_udp_tracker(peer: SocketAddr) -> (String, tokio::sync::mpsc::UnboundedReceiver<u32>) {
+    let SocketAddr::V4(peer) = peer else {
+        panic!("expected an IPv4 peer");
+    };
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let mut buf = [0u8; 2048];
+        loop {
+            let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+            let action = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+            let mut reply = buf[8..16].to_vec();
+            match action {
+                0 => reply.extend_from_slice(&42u64.to_be_bytes()),
+                1 if n >= 98 => {
+                    let _ = tx.send(u32::from_be_bytes(buf[80..84].try_into().unwrap()));
+                    for value in [1800u32, 0, 1] {
+                        reply.extend_from_slice(&value.to_be_bytes());
+                    }
+                    reply.extend_from_slice(&peer.ip().octets());
+                    reply.extend_from_slice(&peer.port().to_be_bytes());
+                }
+                _ => continue,
+            }
+            socket.send_to(&reply, from).await.unwrap();
+        }
+    });
+
+    (url, rx)
+}
+
+#[tokio::test]
+async fn download_with_peers_from_a_udp_tracker() {
+    let seeder_config = test_session_config("udp_tracker_seeder");
+    std::fs::create_dir_all(&seeder_config.download_dir).unwrap();
+    std::fs::write(seeder_config.download_dir.join("hello.txt"), b"hello session").unwrap();
+    let seeder = bit_rev::session::Session::new(seeder_config).await.unwrap();
+    let seeding = seeder.add_torrent(tracker_less_torrent()).await.unwrap();
+    wait_for_state(&seeding, bit_rev::session::TorrentState::Seeding).await;
+
+    let (url, mut announces) = fake_udp_tracker(([127, 0, 0, 1], seeder.listen_port()).into()).await;
+    let mut meta = tracker_less_torrent();
+    meta.torrent_file.announce = Some(url);
+    let leecher = bit_rev::session::Session::new(test_session_config("udp_tracker_leecher"))
+        .await
+        .unwrap();
+    let downloading = leecher.add_torrent(meta).await.unwrap();
+
+    // BEP 15 `started`.
+    let event = tokio::time::timeout(Duration::from_secs(10), announces.recv()).await.unwrap();
+    assert_eq!(event, Some(2));
+    tokio::time::timeout(Duration::from_secs(10), downloading.wait_for_completion())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(downloading.trackers()[0].seeders, Some(1));
+
+    downloading.remove(true).await.unwrap();
+    seeding.remove(true).await.unwrap();
+}
+
+#[tokio::test]
+async fn scrape_an_http_tracker() {
+    let known = [0x11u8; 20];
+    let mut body = b"d5:filesd20:".to_vec();
+    body.extend_from_slice(&known);
+    body.extend_from_slice(b"d8:completei5e10:downloadedi10e10:incompletei3eeee");
+    let (url, mut queries) = fake_tracker(body).await;
+
+    let client = reqwest::Client::new();
+    let files = bit_rev::tracker::scrape(&client, &Default::default(), None, &url, &[known, [0x22; 20]])
+        .await
+        .unwrap();
+
+    let query = queries.recv().await.unwrap();
+    assert_eq!(query, format!("info_hash={}&info_hash={}", "%11".repeat(20), "%22".repeat(20)));
+    assert_eq!(files.len(), 1);
+    assert_eq!(
+        files[&known],
+        bit_rev::tracker::ScrapeStats { seeders: 5, leechers: 3, completed: 10 }
+    );
+}
+
+#[tokio::test]
+async fn sessions_find_each_other_on_the_local_network() {
+    let seeder_config = bit_rev::session::SessionConfig {
+        enable_lsd: true,
+        ..test_session_config("lsd_seeder")
+    };
+    std::fs::create_dir_all(&seeder_config.download_dir).unwrap();
+    std::fs::write(seeder_config.download_dir.join("lsd.txt"), b"hello session").unwrap();
+    let leecher_config = bit_rev::session::SessionConfig {
+        enable_lsd: true,
+        ..test_session_config("lsd_leecher")
+    };
+
+    let seeder = bit_rev::session::Session::new(seeder_config).await.unwrap();
+    let leecher = bit_rev::session::Session::new(leecher_config).await.unwrap();
+    if seeder.context().lsd.is_none() || leecher.context().lsd.is_none() {
+        // No multicast on this machine.
+        return;
+    }
+    // Another name than the other tests so only these two sessions match.
+    let mut meta = tracker_less_torrent();
+    meta.torrent_file.info.name = "lsd.txt".to_string();
+    let meta = bit_rev::file::TorrentMeta::new(meta.torrent_file).unwrap();
+
+    let seeding = seeder.add_torrent(meta.clone()).await.unwrap();
+    wait_for_state(&seeding, bit_rev::session::TorrentState::Seeding).await;
+    let downloading = leecher.add_torrent(meta).await.unwrap();
+
+    tokio::time::timeout(Duration::from_secs(10), downloading.wait_for_completion())
+        .await
+        .unwrap()
+        .unwrap();
+
+    downloading.remove(true).await.unwrap();
+    seeding.remove(true).await.unwrap();
+}
+
+// A SOCKS5 proxy without authentication that only connects to IPv4
+// addresses, and counts the connections it tunneled.
+async fn socks5_proxy() -> (SocketAddr, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let tunnels = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = tunnels.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let counter = counter.clone();
+            tokio::spawn(async move {
+                let mut greeting = [0u8; 3];
+                client.read_exact(&mut greeting).await.unwrap();
+                client.write_all(&[5, 0]).await.unwrap();
+                let mut request = [0u8; 10];
+                client.read_exact(&mut request).await.unwrap();
+                assert_eq!(&request[..4], &[5, 1, 0, 1]);
+                let target = SocketAddr::from((
+                    [request[4], request[5], request[6], request[7]],
+                    u16::from_be_bytes([request[8], request[9]]),
+                ));
+                let mut upstream = tokio::net::TcpStream::connect(target).await.unwrap();
+                client
+                    .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+            });
+        }
+    });
+    (addr, tunnels)
+}
+
+#[tokio::test]
+async fn download_through_a_socks5_proxy() {
+    let seeder_config = test_session_config("proxy_seeder");
+    std::fs::create_dir_all(&seeder_config.download_dir).unwrap();
+    std::fs::write(seeder_config.download_dir.join("hello.txt"), b"hello session").unwrap();
+    let (proxy_addr, tunnels) = socks5_proxy().await;
+    let leecher_config = bit_rev::session::SessionConfig {
+        proxy: Some(bit_rev::proxy::ProxyConfig {
+            kind: bit_rev::proxy::ProxyKind::Socks5,
+            addr: proxy_addr.to_string(),
+            username: None,
+            password: None,
+            proxy_only: true,
+        }),
+        ..test_session_config("proxy_leecher")
+    };
+    let leecher_dir = leecher_config.download_dir.clone();
+
+    let seeder = bit_rev::session::Session::new(seeder_config).await.unwrap();
+    let leecher = bit_rev::session::Session::new(leecher_config).await.unwrap();
+    let meta = tracker_less_torrent();
+    let seeding = seeder.add_torrent(meta.clone()).await.unwrap();
+    wait_for_state(&seeding, bit_rev::session::TorrentState::Seeding).await;
+
+    let downloading = leecher.add_torrent(meta).await.unwrap();
+    downloading
+        .peers()
+        .connections
+        .add_candidates([SocketAddr::from(([127, 0, 0, 1], seeder.listen_port()))]);
+    tokio::time::timeout(Duration::from_secs(10), downloading.wait_for_completion())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(std::fs::read(leecher_dir.join("hello.txt")).unwrap(), b"hello session");
+    assert_eq!(tunnels.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    downloading.remove(true).await.unwrap();
+    seeding.remove(true).await.unwrap();
+}
+
+// Other loopback addresses than 127.0.0.1 only work out of the box on Linux.
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn connections_go_out_from_the_bound_address() {
+    let seeder_config = test_session_config("bound_seeder");
+    std::fs::create_dir_all(&seeder_config.download_dir).unwrap();
+    std::fs::write(seeder_config.download_dir.join("hello.txt"), b"hello session").unwrap();
+    let bound: std::net::IpAddr = "127.0.0.2".parse().unwrap();
+    let leecher_config = bit_rev::session::SessionConfig {
+        bind: Some(bit_rev::network::BindTo::Address(bound)),
+        ..test_session_config("bound_leecher")
+    };
+
+    let seeder = bit_rev::session::Session::new(seeder_config).await.unwrap();
+    let leecher = bit_rev::session::Session::new(leecher_config).await.unwrap();
+    let meta = tracker_less_torrent();
+    let seeding = seeder.add_torrent(meta.clone()).await.unwrap();
+    wait_for_state(&seeding, bit_rev::session::TorrentState::Seeding).await;
+
+    let mut events = seeder.subscribe();
+    let downloading = leecher.add_torrent(meta).await.unwrap();
+    downloading
+        .peers()
+        .connections
+        .add_candidates([SocketAddr::from(([127, 0, 0, 1], seeder.listen_port()))]);
+    tokio::time::timeout(Duration::from_secs(10), downloading.wait_for_completion())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut incoming = None;
+    while let Ok(event) = events.try_recv() {
+        if let EventKind::PeerConnected { peer, incoming: true } = event.kind {
+            incoming = Some(peer);
+        }
+    }
+    assert_eq!(incoming.map(|peer| peer.ip()), Some(bound));
+
+    downloading.remove(true).await.unwrap();
+    seeding.remove(true).await.unwrap();
+}
+
+#[tokio::test]
+async fn peers_learn_ipv6_peers_through_pex() {
+    let seeder_config = test_session_config("pex_seeder");
+    std::fs::create_dir_all(&seeder_config.download_dir).unwrap();
+    std::fs::write(seeder_config.download_dir.join("hello.txt"), b"hello session").unwrap();
+    let mut middle_config = test_session_config("pex_middle");
+    middle_config.torrent.pex_interval = Duration::from_millis(100);
+
+    let seeder = bit_rev::session::Session::new(seeder_config).await.unwrap();
+    let middle = bit_rev::session::Session::new(middle_config).await.unwrap();
+    let leecher = bit_rev::session::Session::new(test_session_config("pex_leecher")).await.unwrap();
+    let meta = tracker_less_torrent();
+    let seeding = seeder.add_torrent(meta.clone()).await.unwrap();
+    wait_for_state(&seeding, bit_rev::session::TorrentState::Seeding).await;
+
+    // The middle session only reaches the seeder over IPv6.
+    let seeder_addr: SocketAddr = format!("[::1]:{}", seeder.listen_port()).parse().unwrap();
+    let middle_torrent = middle.add_torrent(meta.clone()).await.unwrap();
+    middle_torrent.peers().connections.add_candidates([seeder_addr]);
+    tokio::time::timeout(Duration::from_secs(10), middle_torrent.wait_for_completion())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let downloading = leecher.add_torrent(meta).await.unwrap();
+    downloading
+        .peers()
+        .connections
+        .add_candidates([SocketAddr::from(([127, 0, 0, 1], middle.listen_port()))]);
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while downloading.peers().connections.source(&seeder_addr)
+            != Some(bit_rev::connection_manager::PeerSource::Pex)
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the seeder should be learned through PEX");
+
+    downloading.remove(true).await.unwrap();
+    middle_torrent.remove(true).await.unwrap();
+    seeding.remove(true).await.unwrap();
+}
+
+#[tokio::test]
+async fn the_ip_filter_blocks_peers_until_reloaded() {
+    let mut seeder_config = test_session_config("filter_seeder");
+    std::fs::create_dir_all(&seeder_config.download_dir).unwrap();
+    std::fs::write(seeder_config.download_dir.join("hello.txt"), b"hello session").unwrap();
+    let filter_path = seeder_config.download_dir.join("ipfilter.p2p");
+    std::fs::write(&filter_path, "Loopback:127.0.0.1-127.0.0.1\n").unwrap();
+    seeder_config.ip_filter = Some(filter_path.clone());
+
+    let seeder = bit_rev::session::Session::new(seeder_config).await.unwrap();
+    let leecher = bit_rev::session::Session::new(test_session_config("filter_leecher")).await.unwrap();
+    let meta = tracker_less_torrent();
+    let seeding = seeder.add_torrent(meta.clone()).await.unwrap();
+    wait_for_state(&seeding, bit_rev::session::TorrentState::Seeding).await;
+
+    let downloading = leecher.add_torrent(meta).await.unwrap();
+    downloading
+        .peers()
+        .connections
+        .add_candidates([SocketAddr::from(([127, 0, 0, 1], seeder.listen_port()))]);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while seeder.blocked_peers() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the seeder should turn the leecher away");
+    assert!(!downloading.is_complete());
+
+    std::fs::write(&filter_path, "").unwrap();
+    seeder.reload_ip_filter().unwrap();
+    let seeder_addr: SocketAddr = format!("[::1]:{}", seeder.listen_port()).parse().unwrap();
+    downloading.peers().connections.add_candidates([seeder_addr]);
+    tokio::time::timeout(Duration::from_secs(10), downloading.wait_for_completion())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // Outgoing connections are filtered too.
+    leecher.set_ip_filter(bit_rev::ip_filter::IpFilter::parse("10.0.0.0/8\n"));
+    downloading.peers().connections.add_candidates(["10.1.2.3:6881".parse().unwrap()]);
+    assert_eq!(downloading.stats().blocked_peers, 1);
+    assert_eq!(leecher.blocked_peers(), 1);
+
+    downloading.remove(true).await.unwrap();
+    seeding.remove(true).await.unwrap();
+}
+
+#[tokio::test]
+async fn private_torrents_do_not_exchange_peers() {
+    let mut torrent_file = tracker_less_torrent().torrent_file;
+    torrent_file.info.private = Some(1);
+    let meta = bit_rev::file::TorrentMeta::new(torrent_file).unwrap();
+    let seeder_config = test_session_config("private_seeder");
+    std::fs::create_dir_all(&seeder_config.download_dir).unwrap();
+    std::fs::write(seeder_config.download_dir.join("hello.txt"), b"hello session").unwrap();
+    let mut middle_config = test_session_config("private_middle");
+    middle_config.torrent.pex_interval = Duration::from_millis(100);
+
+    let seeder = bit_rev::session::Session::new(seeder_config).await.unwrap();
+    let middle = bit_rev::session::Session::new(middle_config).await.unwrap();
+    let leecher = bit_rev::session::Session::new(test_session_config("private_leecher")).await.unwrap();
+    let seeding = seeder.add_torrent(meta.clone()).await.unwrap();
+    wait_for_state(&seeding, bit_rev::session::TorrentState::Seeding).await;
+
+    let seeder_addr = SocketAddr::from(([127, 0, 0, 1], seeder.listen_port()));
+    let middle_torrent = middle.add_torrent(meta.clone()).await.unwrap();
+    middle_torrent.peers().connections.add_candidates([seeder_addr]);
+    tokio::time::timeout(Duration::from_secs(10), middle_torrent.wait_for_completion())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let downloading = leecher.add_torrent(meta).await.unwrap();
+    let middle_addr = SocketAddr::from(([127, 0, 0, 1], middle.listen_port()));
+    downloading.peers().connections.add_candidates([middle_addr]);
+    tokio::time::timeout(Duration::from_secs(10), downloading.wait_for_completion())
+        .await
+        .unwrap()
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    assert!(downloading.peers().connections.is_connected(&middle_addr));
+    assert!(!downloading.peers().connections.is_connected(&seeder_addr));
+    assert_eq!(downloading.peers().connections.candidates_len(), 0);
+
+    downloading.remove(true).await.unwrap();
+    middle_torrent.remove(true).await.unwrap();
+    seeding.remove(true).await.unwrap();
+}
+
+// Connects to `addr` with a peer id no client table knows, names itself `v` in
+// its extended handshake, then asks for the first block of the torrent.
+async fn anonymous_peer(addr: SocketAddr, info_hash: [u8; 20], v: &str) -> tokio::net::TcpStream {
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let handshake = bit_rev::handshake::Handshake::new(info_hash, [0; 20]).with_extension_protocol();
+    stream.write_all(&handshake.serialize()).await.unwrap();
+    let mut reply = vec![0u8; 68];
+    stream.read_exact(&mut reply).await.unwrap();
+
+    let dict = format!("d1:v{}:{}e", v.len(), v);
+    let mut extended = ((dict.len() + 2) as u32).to_be_bytes().to_vec();
+    extended.extend_from_slice(&[20, 0]);
+    extended.extend_from_slice(dict.as_bytes());
+    stream.write_all(&extended).await.unwrap();
+    let interested = bit_rev::message::serialize(Some(bit_rev::message::Message::Interested));
+    stream.write_all(&interested).await.unwrap();
+    stream
+}
+
+// The id of every message until `id` comes, None if the connection ends first.
+async fn wait_for_message(stream: &mut tokio::net::TcpStream, id: u8) -> Option<u8> {
+    loop {
+        let mut length = [0u8; 4];
+        stream.read_exact(&mut length).await.ok()?;
+        let mut message = vec![0u8; u32::from_be_bytes(length) as usize];
+        stream.read_exact(&mut message).await.ok()?;
+        if message.first() == Some(&id) {
+            return Some(id);
+        }
+    }
+}
+
+#[tokio::test]
+async fn allow_lists_match_the_extended_handshake() {
+    let mut seeder_config = test_session_config("allow_v_seeder");
+    std::fs::create_dir_all(&seeder_config.download_dir).unwrap();
+    std::fs::write(seeder_config.download_dir.join("hello.txt"), b"hello session").unwrap();
+    seeder_config.torrent.client_filter.allow = vec!["FakeClient".to_string()];
+    let seeder = bit_rev::session::Session::new(seeder_config).await.unwrap();
+    let meta = tracker_less_torrent();
+    let seeding = seeder.add_torrent(meta.clone()).await.unwrap();
+    wait_for_state(&seeding, bit_rev::session::TorrentState::Seeding).await;
+    let addr = SocketAddr::from(([127, 0, 0, 1], seeder.listen_port()));
+
+    // Its peer id doesn't tell, its extended handshake names an allowed client.
+    let mut allowed = anonymous_peer(addr, meta.info_hash, "FakeClient/1.0").await;
+    let unchoke = tokio::time::timeout(Duration::from_secs(10), wait_for_message(&mut allowed, 1)).await;
+    assert_eq!(unchoke.unwrap(), Some(1));
+    let request = bit_rev::message::serialize(Some(bit_rev::message::format_request(0, 0, 13)));
+    allowed.write_all(&request).await.unwrap();
+    let piece = tokio::time::timeout(Duration::from_secs(10), wait_for_message(&mut allowed, 7)).await;
+    assert_eq!(piece.unwrap(), Some(7));
+
+    let mut other = anonymous_peer(addr, meta.info_hash, "Other/1.0").await;
+    let piece = tokio::time::timeout(Duration::from_secs(10), wait_for_message(&mut other, 7)).await;
+    assert_eq!(piece.unwrap(), None);
+
+    seeding.remove(true).await.unwrap();
+}
+
+#[tokio::test]
+async fn denied_clients_are_disconnected() {
+    let seeder_config = test_session_config("denied_seeder");
+    std::fs::create_dir_all(&seeder_config.download_dir).unwrap();
+    std::fs::write(seeder_config.download_dir.join("hello.txt"), b"hello session").unwrap();
+    let mut leecher_config = test_session_config("denied_leecher");
+    // Both sides run BitRev.
+    leecher_config.torrent.client_filter.deny = vec!["bitrev".to_string()];
+
+    let seeder = bit_rev::session::Session::new(seeder_config).await.unwrap();
+    let leecher = bit_rev::session::Session::new(leecher_config).await.unwrap();
+    let meta = tracker_less_torrent();
+    let seeding = seeder.add_torrent(meta.clone()).await.unwrap();
+    wait_for_state(&seeding, bit_rev::session::TorrentState::Seeding).await;
+
+    // The leecher turns the seeder away right after the handshake, before
+    // it counts as connected, so watch the seeder's side.
+    let mut events = seeder.subscribe();
+    let downloading = leecher.add_torrent(meta).await.unwrap();
+    let seeder_addr = SocketAddr::from(([127, 0, 0, 1], seeder.listen_port()));
+    downloading.peers().connections.add_candidates([seeder_addr]);
+
+    tokio::time::timeout(Duration::from_secs(10), async {
+        let mut connected = false;
+        loop {
+            match events.recv().await.unwrap().kind {
+                EventKind::PeerConnected { incoming: true, .. } => connected = true,
+                EventKind::PeerDisconnected { .. } if connected => break,
+                _ => {}
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert!(!downloading.peers().connections.is_connected(&seeder_addr));
+    assert!(!downloading.is_complete());
+    assert_eq!(downloading.stats().transfer.payload_downloaded, 0);
+
+    downloading.remove(true).await.unwrap();
+    seeding.remove(true).await.unwrap();
+}
+
+// An HTTP server answering every GET with `respond(path, range)`, as a status and a body.
+async fn web_server<F>(respond: F) -> SocketAddr
+where
+    F: Fn(&str, Option<(usize, usize)>) -> (u16, Vec<u8>) + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let respond = std::sync::Arc::new(respond);
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let respond = respond.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        return;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request).to_string();
+                let path = request.split_whitespace().nth(1).unwrap_or_default();
+                let range = request
+                    .lines()
+                    .find_map(|line| line.to_ascii_lowercase().strip_prefix("range: bytes=").map(str::to_string))
+                    .and_then(|range| {
+                        let (start, end) = range.trim().split_once('-')?;
+                        Some((start.parse().ok()?, end.parse().ok()?))
+                    });
+                let (status, body) = respond(path, range);
+                let mut response = format!(
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                )
+                .into_bytes();
+                response.extend_from_slice(&body);
+                let _ = socket.write_all(&response).await;
+            });
+        }
+    });
+    addr
+}
+
+fn piece_hashes(data: &[u8], piece_length: usize) -> serde_bytes::ByteBuf {
+    let mut pieces = Vec::new();
+    for piece in data.chunks(piece_length) {
+        let mut hasher = sha1_smol::Sha1::new();
+        hasher.update(piece);
+        pieces.extend_from_slice(&hasher.digest().bytes());
+    }
+    serde_bytes::ByteBuf::from(pieces)
+}
+
+#[tokio::test]
+async fn download_from_a_url_list_web_seed() {
+    use bit_rev::file::{File, Info, TorrentMeta};
+
+    let files: Vec<(&str, &[u8])> = vec![("multi/a.txt", b"hello "), ("multi/sub/b.txt", b"web seeds!")];
+    let served = files.clone();
+    let server = web_server(move |path, range| {
+        let Some((_, data)) = served.iter().find(|(name, _)| path == format!("/files/{}", name)) else {
+            return (404, vec![]);
+        };
+        match range {
+            Some((start, end)) => (206, data[start..=end].to_vec()),
+            None => (200, data.to_vec()),
+        }
+    })
+    .await;
+
+    let data: Vec<u8> = files.iter().flat_map(|(_, data)| data.to_vec()).collect();
+    let mut torrent_file = tracker_less_torrent().torrent_file;
+    torrent_file.info = Info {
+        name: "multi".to_string(),
+        pieces: piece_hashes(&data, 4),
+        piece_length: 4,
+        md5sum: None,
+        length: None,
+        files: Some(vec![
+            File { path: vec!["a.txt".to_string()], length: 6, md5sum: None },
+            File { path: vec!["sub".to_string(), "b.txt".to_string()], length: 10, md5sum: None },
+        ]),
+        private: None,
+        path: None,
+        root_hash: None,
+    };
+    torrent_file.url_list = Some(vec![format!("http://{}/files/", server)]);
+    let meta = TorrentMeta::new(torrent_file).unwrap();
+
+    let config = test_session_config("url_list_leecher");
+    let download_dir = config.download_dir.clone();
+    let leecher = bit_rev::session::Session::new(config).await.unwrap();
+    let downloading = leecher.add_torrent(meta).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(10), downloading.wait_for_completion())
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(std::fs::read(download_dir.join("multi/a.txt")).unwrap(), b"hello ");
+    assert_eq!(std::fs::read(download_dir.join("multi/sub/b.txt")).unwrap(), b"web seeds!");
+    assert_eq!(downloading.stats().transfer.payload_downloaded, data.len() as u64);
+    downloading.remove(true).await.unwrap();
+}
+
+#[tokio::test]
+async fn web_seeds_without_range_support_are_dropped() {
+    let data: Vec<u8> = (0..16u8).collect();
+    let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counted = requests.clone();
+    let served = data.clone();
+    let server = web_server(move |_, _| {
+        counted.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        (200, served.clone())
+    })
+    .await;
+
+    let mut torrent_file = tracker_less_torrent().torrent_file;
+    torrent_file.info.name = "ranges.bin".to_string();
+    torrent_file.info.pieces = piece_hashes(&data, 4);
+    torrent_file.info.piece_length = 4;
+    torrent_file.info.length = Some(data.len() as i64);
+    torrent_file.url_list = Some(vec![format!("http://{}/ranges.bin", server)]);
+    let meta = bit_rev::file::TorrentMeta::new(torrent_file).unwrap();
+
+    let leecher = bit_rev::session::Session::new(test_session_config("no_range_leecher")).await.unwrap();
+    let mut events = leecher.subscribe();
+    let downloading = leecher.add_torrent(meta).await.unwrap();
+    let error = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            if let EventKind::WebSeedFailed { error, .. } = events.recv().await.unwrap().kind {
+                break error;
+            }
+        }
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(error, "web seed ignores range requests");
+    assert_eq!(requests.load(std::sync::atomic::Ordering::Relaxed), 1);
+    assert!(!downloading.is_complete());
+    downloading.remove(true).await.unwrap();
+}
+
+#[tokio::test]
+async fn download_from_an_http_seed() {
+    let data = b"hello session".to_vec();
+    let busy = std::sync::atomic::AtomicBool::new(true);
+    let served = data.clone();
+    let server = web_server(move |path, _| {
+        // Busy on the first request, try again right away.
+        if busy.swap(false, std::sync::atomic::Ordering::Relaxed) {
+            return (503, b"0".to_vec());
+        }
+        let piece = path.split("piece=").nth(1).and_then(|p| p.parse::<usize>().ok());
+        match piece {
+            Some(0) if path.contains("info_hash=") => (200, served.clone()),
+            _ => (404, vec![]),
+        }
+    })
+    .await;
+
+    let mut torrent_file = tracker_less_torrent().torrent_file;
+    torrent_file.httpseeds = Some(vec![format!("http://{}/seed.php", server)]);
+    let meta = bit_rev::file::TorrentMeta::new(torrent_file).unwrap();
+
+    let config = test_session_config("http_seed_leecher");
+    let download_dir = config.download_dir.clone();
+    let leecher = bit_rev::session::Session::new(config).await.unwrap();
+    let downloading = leecher.add_torrent(meta).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(10), downloading.wait_for_completion())
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(std::fs::read(download_dir.join("hello.txt")).unwrap(), data);
+    downloading.remove(true).await.unwrap();
+}
+
+#[tokio::test]
+async fn stream_a_file_while_it_downloads() {
+    use std::io::SeekFrom;
+    use tokio::io::AsyncSeekExt;
+
+    let data: Vec<u8> = (0..64u8).collect();
+    let requested = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let log = requested.clone();
+    let served = data.clone();
+    let server = web_server(move |_, range| {
+        let (start, end) = range.unwrap_or((0, served.len() - 1));
+        log.lock().unwrap().push(start / 4);
+        (206, served[start..=end].to_vec())
+    })
+    .await;
+
+    let mut torrent_file = tracker_less_torrent().torrent_file;
+    torrent_file.info.name = "stream.bin".to_string();
+    torrent_file.info.pieces = piece_hashes(&data, 4);
+    torrent_file.info.piece_length = 4;
+    torrent_file.info.length = Some(data.len() as i64);
+    torrent_file.url_list = Some(vec![format!("http://{}/stream.bin", server)]);
+    let meta = bit_rev::file::TorrentMeta::new(torrent_file).unwrap();
+
+    let leecher = bit_rev::session::Session::new(test_session_config("stream_leecher")).await.unwrap();
+    let downloading = leecher.add_torrent(meta).await.unwrap();
+    assert!(downloading.open_file(1).is_none());
+    let mut reader = downloading.open_file(0).unwrap();
+    assert_eq!(reader.len(), 64);
+
+    // The pieces after the read position go first.
+    assert_eq!(reader.seek(SeekFrom::Start(40)).await.unwrap(), 40);
+    let mut tail = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), reader.read_to_end(&mut tail))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(tail, data[40..]);
+    assert_eq!(requested.lock().unwrap()[..4], [10, 11, 12, 13]);
+
+    assert_eq!(reader.seek(SeekFrom::End(-60)).await.unwrap(), 4);
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), reader.read_to_end(&mut rest))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(rest, data[4..]);
+    assert!(reader.seek(SeekFrom::Current(-100)).await.is_err());
+
+    downloading.remove(true).await.unwrap();
+}
+
+#[tokio::test]
+async fn rate_limited_web_seed() {
+    let data: Vec<u8> = (0..32u8).collect();
+    let served = data.clone();
+    let server = web_server(move |_, range| {
+        let (start, end) = range.unwrap_or((0, served.len() - 1));
+        (206, served[start..=end].to_vec())
+    })
+    .await;
+
+    let mut torrent_file = tracker_less_torrent().torrent_file;
+    torrent_file.info.name = "limited.bin".to_string();
+    torrent_file.info.pieces = piece_hashes(&data, 32);
+    torrent_file.info.piece_length = 32;
+    torrent_file.info.length = Some(data.len() as i64);
+    torrent_file.url_list = Some(vec![format!("http://{}/limited.bin", server)]);
+    let meta = bit_rev::file::TorrentMeta::new(torrent_file).unwrap();
+
+    let leecher = bit_rev::session::Session::new(test_session_config("limited_web_seed")).await.unwrap();
+    // A quarter of the piece per second, the body waits for tokens as it comes in.
+    leecher.rate_limiter().set_download_limit(Some(8));
+    let start = std::time::Instant::now();
+    let downloading = leecher.add_torrent(meta).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(10), downloading.wait_for_completion())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(start.elapsed() >= Duration::from_secs(2));
+    assert_eq!(downloading.stats().transfer.payload_downloaded, 32);
+
+    downloading.remove(true).await.unwrap();
+}
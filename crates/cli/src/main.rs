@@ -1,65 +1,165 @@
 use indicatif::{ProgressBar, ProgressState, ProgressStyle};
-use std::{
-    fmt::Write,
-    io::SeekFrom,
-    sync::{atomic::AtomicU64, Arc},
-};
-use tokio::{
-    fs::File,
-    io::{AsyncSeekExt, AsyncWriteExt},
-};
-use tracing::trace;
+use std::{fmt::Write, process::ExitCode};
+use tokio::sync::broadcast::error::RecvError;
 
 use bit_rev::{
+    config::ConfigError,
+    events::EventKind,
     file::{self, TorrentMeta},
-    session::Session,
-    torrent::Torrent,
-    tracker_peers::TrackerPeers,
-    utils,
+    network::Network,
+    session::{Session, SessionConfig, SessionError},
+    tracker::{self, TrackerTiers},
 };
 
+/// Wrong arguments.
+const EXIT_USAGE: u8 = 2;
+/// The torrent file or the settings can't be used.
+const EXIT_INVALID_INPUT: u8 = 3;
+/// The session couldn't start, e.g. the port is taken.
+const EXIT_SESSION: u8 = 4;
+/// The download failed, e.g. the disk is full.
+const EXIT_TORRENT_FAILED: u8 = 5;
+/// No tracker answered the scrape.
+const EXIT_SCRAPE_FAILED: u8 = 6;
+
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     #[cfg(not(feature = "tokio-console"))]
     tracing_subscriber::fmt::init();
 
     #[cfg(feature = "tokio-console")]
     console_subscriber::init();
 
-    let filename = std::env::args().nth(1).expect("No torrent path given");
-    let output = std::env::args().nth(2);
+    let mut args = std::env::args().skip(1).peekable();
+    let scrape = args.next_if_eq("scrape").is_some();
+    let Some(filename) = args.next() else {
+        eprintln!(
+            "usage: cli <torrent> [output]\n       cli scrape <torrent>\n\n\
+             The torrent is saved at the output path. Without one, it is saved in\n\
+             `download_dir` from the settings, the current directory by default, under\n\
+             the name it has in the torrent."
+        );
+        return ExitCode::from(EXIT_USAGE);
+    };
+    let output = args.next();
 
-    let torrent_meta = file::from_filename(&filename).unwrap();
+    let torrent_meta = match file::from_filename(&filename) {
+        Ok(torrent_meta) => torrent_meta,
+        Err(e) => {
+            eprintln!("can't read torrent {}: {}", filename, e);
+            return ExitCode::from(EXIT_INVALID_INPUT);
+        }
+    };
 
-    download_file(torrent_meta, output).await
-}
+    if scrape {
+        return scrape_trackers(&torrent_meta).await;
+    }
 
-pub async fn download_file(torrent_meta: TorrentMeta, out_file: Option<String>) {
-    let random_peers = utils::generate_peer_id();
+    match download_file(torrent_meta, output).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
 
-    let torrent = Torrent::new(&torrent_meta.clone());
+#[derive(Debug, thiserror::Error)]
+pub enum CliError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Session(#[from] SessionError),
+}
 
-    let peer_states = Arc::new(bit_rev::peer_state::PeerStates::default());
-    let (have_broadcast, _) = tokio::sync::broadcast::channel(128);
-    let have_broadcast = Arc::new(have_broadcast);
+impl CliError {
+    fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Config(_)
+            | CliError::Session(SessionError::IpFilter { .. } | SessionError::Config(_)) => {
+                EXIT_INVALID_INPUT
+            }
+            CliError::Session(
+                SessionError::TorrentFailed(_)
+                | SessionError::TorrentRemoved
+                | SessionError::Storage(_),
+            ) => EXIT_TORRENT_FAILED,
+            CliError::Session(_) => EXIT_SESSION,
+        }
+    }
+}
 
-    //TODO: move it to a download manager state
-    let tracker_stream = TrackerPeers::new(
-        torrent_meta.clone(),
-        15,
-        random_peers,
-        peer_states,
-        have_broadcast.clone(),
-    );
+/// Prints the swarm counts every tracker of the torrent reports.
+async fn scrape_trackers(torrent_meta: &TorrentMeta) -> ExitCode {
+    // Scrapes go out like announces, from the interface and through the
+    // proxy of the settings.
+    let (network, proxy) = match SessionConfig::load(&*util::paths::SETTINGS) {
+        Ok(config) => (Network::new(config.bind), config.proxy),
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(EXIT_INVALID_INPUT);
+        }
+    };
+    let local_address = match network.http_address() {
+        Ok(local_address) => local_address,
+        Err(e) => {
+            eprintln!("can't bind to the network: {}", e);
+            return ExitCode::from(EXIT_INVALID_INPUT);
+        }
+    };
+    let client = match tracker::http_client(proxy.as_ref(), local_address) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("invalid proxy: {}", e);
+            return ExitCode::from(EXIT_INVALID_INPUT);
+        }
+    };
+    let trackers = TrackerTiers::from_torrent(&torrent_meta.torrent_file);
+    let mut answered = false;
+    for url in trackers.urls() {
+        match tracker::scrape(
+            &client,
+            &network,
+            proxy.as_ref(),
+            &url,
+            &[torrent_meta.info_hash],
+        )
+        .await
+        {
+            Ok(files) => {
+                answered = true;
+                let stats = files
+                    .get(&torrent_meta.info_hash)
+                    .copied()
+                    .unwrap_or_default();
+                println!(
+                    "{}: {} seeders, {} leechers, {} completed",
+                    url, stats.seeders, stats.leechers, stats.completed
+                );
+            }
+            Err(e) => eprintln!("{}: {}", url, e),
+        }
+    }
+    if answered {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_SCRAPE_FAILED)
+    }
+}
 
-    //TODO: I think this is really bad
+pub async fn download_file(
+    torrent_meta: TorrentMeta,
+    out_file: Option<String>,
+) -> Result<(), CliError> {
+    let config = SessionConfig::load(&*util::paths::SETTINGS)?;
 
-    //TODO: return more than just the buffer
-    let downloader =
-        Session::download_torrent(torrent.clone(), tracker_stream.clone(), have_broadcast).await;
+    let session = std::sync::Arc::new(Session::new(config).await?);
+    let torrent = match out_file {
+        Some(path) => session.add_torrent_at(torrent_meta, path).await?,
+        None => session.add_torrent(torrent_meta).await?,
+    };
 
-    let total_size = torrent.length as u64;
-    let pb = ProgressBar::new(total_size);
+    let pb = ProgressBar::new(torrent.total_length());
 
     pb.set_style(
         ProgressStyle::with_template(
@@ -70,43 +170,71 @@ pub async fn download_file(torrent_meta: TorrentMeta, out_file: Option<String>)
         ).progress_chars("#>-")
     );
 
-    let out_filename = match out_file {
-        Some(name) => name,
-        None => torrent_meta.clone().torrent_file.info.name.clone(),
-    };
-    let mut file = File::create(out_filename).await.unwrap();
-
-    // File
-    let total_downloaded = Arc::new(AtomicU64::new(0));
-    let total_downloaded_clone = total_downloaded.clone();
-
-    tokio::spawn(async move {
-        loop {
-            let new = total_downloaded_clone.load(std::sync::atomic::Ordering::Relaxed);
-            pb.set_position(new);
-            pb.set_message("Downloading");
-            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-        }
-    });
-
-    let mut hashset = std::collections::HashSet::new();
-    while hashset.len() < torrent.piece_hashes.len() {
-        let pr = downloader.pr_rx.recv_async().await.unwrap();
-
-        hashset.insert(pr.index);
-        let (start, end) = utils::calculate_bounds_for_piece(&torrent, pr.index as usize);
-        trace!(
-            "index: {}, start: {}, end: {} len {}",
-            pr.index,
-            start,
-            end,
-            pr.length
-        );
-        file.seek(SeekFrom::Start(start as u64)).await.unwrap();
-        file.write_all(pr.buf.as_slice()).await.unwrap();
+    {
+        let torrent = torrent.clone();
+        let pb = pb.clone();
+        tokio::spawn(async move {
+            loop {
+                let stats = torrent.stats();
+                pb.set_position(stats.downloaded);
+                pb.set_message(match stats.blocked_peers {
+                    0 => format!("{} peers", stats.peers.len()),
+                    blocked => format!("{} peers, {} blocked", stats.peers.len(), blocked),
+                });
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+        });
+    }
 
-        total_downloaded.fetch_add(pr.length as u64, std::sync::atomic::Ordering::Relaxed);
+    {
+        let mut events = session.subscribe();
+        let pb = pb.clone();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => match event.kind {
+                        EventKind::TrackerFailed { url, error } => {
+                            pb.println(format!("tracker {} failed: {}", url, error));
+                        }
+                        EventKind::TrackerWarning { url, message } => {
+                            pb.println(format!("tracker {} warns: {}", url, message));
+                        }
+                        EventKind::WebSeedFailed { url, error } => {
+                            pb.println(format!("web seed {} failed: {}", url, error));
+                        }
+                        _ => {}
+                    },
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
     }
 
-    file.sync_all().await.unwrap()
+    // Reads the blocklist again on SIGHUP, like daemons do their settings.
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let session = session.clone();
+        let pb = pb.clone();
+        if let Ok(mut hangup) = signal(SignalKind::hangup()) {
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    match session.reload_ip_filter() {
+                        Ok(()) => pb.println("ip filter reloaded"),
+                        Err(e) => pb.println(format!("{}", e)),
+                    }
+                }
+            });
+        }
+    }
+
+    if let Err(e) = torrent.wait_for_completion().await {
+        pb.abandon_with_message("Failed");
+        return Err(e.into());
+    }
+    pb.set_position(torrent.downloaded());
+    pb.finish_with_message("Done");
+    Ok(())
 }
@@ -0,0 +1,5 @@
+pub mod request;
+pub mod server;
+pub mod swarm;
+
+pub use server::{Tracker, TrackerConfig};
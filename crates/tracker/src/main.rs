@@ -0,0 +1,63 @@
+use std::{collections::HashSet, process::ExitCode};
+
+use tracker::{Tracker, TrackerConfig};
+
+const DEFAULT_ADDR: &str = "0.0.0.0:6969";
+/// The whitelist can't be read.
+const EXIT_INVALID_INPUT: u8 = 3;
+/// The address can't be listened on.
+const EXIT_LISTEN: u8 = 4;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    tracing_subscriber::fmt::init();
+
+    let addr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_ADDR.to_string());
+    let mut config = TrackerConfig::default();
+    if let Some(path) = std::env::args().nth(2) {
+        match read_whitelist(&path) {
+            Ok(whitelist) => config.whitelist = Some(whitelist),
+            Err(e) => {
+                eprintln!("can't read whitelist {}: {}", path, e);
+                return ExitCode::from(EXIT_INVALID_INPUT);
+            }
+        }
+    }
+
+    let tracker = match Tracker::bind(&addr, config).await {
+        Ok(tracker) => tracker,
+        Err(e) => {
+            eprintln!("can't listen on {}: {}", addr, e);
+            return ExitCode::from(EXIT_LISTEN);
+        }
+    };
+    if let Ok(url) = tracker.announce_url() {
+        println!("tracking on {}", url);
+    }
+    tracker.run().await;
+    ExitCode::SUCCESS
+}
+
+/// Hex info hashes, one per line.
+fn read_whitelist(path: &str) -> Result<HashSet<[u8; 20]>, String> {
+    std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| parse_hex_hash(line).ok_or_else(|| format!("invalid info hash {}", line)))
+        .collect()
+}
+
+fn parse_hex_hash(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None;
+    }
+    let mut hash = [0u8; 20];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(hash)
+}
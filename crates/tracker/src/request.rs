@@ -0,0 +1,233 @@
+use std::net::{IpAddr, SocketAddr};
+
+use bit_rev::tracker::AnnounceEvent;
+use thiserror::Error;
+
+/// Why a request was turned down. Sent back as the `failure reason`.
+#[derive(Error, Debug, PartialEq)]
+pub enum RequestError {
+    #[error("missing {0}")]
+    Missing(&'static str),
+    #[error("invalid {0}")]
+    Invalid(&'static str),
+    #[error("torrent not allowed")]
+    NotAllowed,
+}
+
+/// The parameters of an HTTP announce, as sent by a client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: AnnounceEvent,
+    pub compact: bool,
+    pub no_peer_id: bool,
+    pub numwant: Option<usize>,
+    /// The address the client asks us to use instead of the one it connects
+    /// from.
+    pub ip: Option<IpAddr>,
+    /// Where the client also takes IPv6 connections, BEP 7. Sent as an
+    /// address or as `[address]:port`.
+    pub ipv6: Option<SocketAddr>,
+}
+
+/// Decoded `name=value` pairs of a query string, in order. Names can repeat.
+pub fn parse_query(query: &str) -> Result<Vec<(String, Vec<u8>)>, RequestError> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let name = String::from_utf8(percent_decode(name)?)
+                .map_err(|_| RequestError::Invalid("query"))?;
+            Ok((name, percent_decode(value)?))
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> Result<Vec<u8>, RequestError> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut input = s.bytes();
+    while let Some(b) = input.next() {
+        match b {
+            b'%' => {
+                let hex = [input.next(), input.next()];
+                let [Some(high), Some(low)] = hex else {
+                    return Err(RequestError::Invalid("query"));
+                };
+                let digits = std::str::from_utf8(&[high, low])
+                    .ok()
+                    .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+                bytes.push(digits.ok_or(RequestError::Invalid("query"))?);
+            }
+            b'+' => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+    Ok(bytes)
+}
+
+/// The 20 byte values of every `name` parameter.
+pub fn hashes(
+    params: &[(String, Vec<u8>)],
+    name: &'static str,
+) -> Result<Vec<[u8; 20]>, RequestError> {
+    params
+        .iter()
+        .filter(|(n, _)| n == name)
+        .map(|(_, value)| <[u8; 20]>::try_from(&value[..]).map_err(|_| RequestError::Invalid(name)))
+        .collect()
+}
+
+impl AnnounceRequest {
+    pub fn from_query(query: &str) -> Result<AnnounceRequest, RequestError> {
+        let params = parse_query(query)?;
+        let get = |name: &'static str| {
+            params
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, value)| &value[..])
+        };
+        let hash = |name: &'static str| {
+            let value = get(name).ok_or(RequestError::Missing(name))?;
+            <[u8; 20]>::try_from(value).map_err(|_| RequestError::Invalid(name))
+        };
+        let number = |name: &'static str| -> Result<Option<u64>, RequestError> {
+            get(name)
+                .map(|value| {
+                    std::str::from_utf8(value)
+                        .ok()
+                        .and_then(|value| value.parse().ok())
+                        .ok_or(RequestError::Invalid(name))
+                })
+                .transpose()
+        };
+
+        let info_hash = hash("info_hash")?;
+        let peer_id = hash("peer_id")?;
+        let event = match get("event") {
+            None | Some(b"") | Some(b"empty") => AnnounceEvent::None,
+            Some(b"started") => AnnounceEvent::Started,
+            Some(b"completed") => AnnounceEvent::Completed,
+            Some(b"stopped") => AnnounceEvent::Stopped,
+            Some(_) => return Err(RequestError::Invalid("event")),
+        };
+        let port = number("port")?.ok_or(RequestError::Missing("port"))?;
+        let ip = get("ip")
+            .map(|ip| {
+                std::str::from_utf8(ip)
+                    .ok()
+                    .and_then(|ip| ip.parse().ok())
+                    .ok_or(RequestError::Invalid("ip"))
+            })
+            .transpose()?;
+        let port = u16::try_from(port).map_err(|_| RequestError::Invalid("port"))?;
+        let ipv6 = get("ipv6")
+            .map(|ipv6| {
+                let ipv6 = std::str::from_utf8(ipv6).map_err(|_| RequestError::Invalid("ipv6"))?;
+                let addr = match ipv6.parse::<IpAddr>() {
+                    Ok(ip) => SocketAddr::new(ip, port),
+                    Err(_) => ipv6.parse().map_err(|_| RequestError::Invalid("ipv6"))?,
+                };
+                match addr {
+                    SocketAddr::V6(_) => Ok(addr),
+                    SocketAddr::V4(_) => Err(RequestError::Invalid("ipv6")),
+                }
+            })
+            .transpose()?;
+
+        Ok(AnnounceRequest {
+            info_hash,
+            peer_id,
+            port,
+            uploaded: number("uploaded")?.unwrap_or(0),
+            downloaded: number("downloaded")?.unwrap_or(0),
+            left: number("left")?.unwrap_or(0),
+            event,
+            compact: get("compact") != Some(b"0"),
+            no_peer_id: get("no_peer_id").is_some_and(|value| value != b"0"),
+            numwant: number("numwant")?.map(|numwant| numwant as usize),
+            ip,
+            ipv6,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announce_query() {
+        let request = AnnounceRequest::from_query(&format!(
+            "info_hash={}&peer_id=-BR0001-abcdefghijkl&port=6881&uploaded=1&downloaded=2\
+             &left=3&compact=1&event=started&numwant=10&ip=2001%3Adb8%3A%3A1&key=0000dead\
+             &ipv6=%5B2001%3Adb8%3A%3A2%5D%3A6882",
+            "%AB".repeat(20)
+        ))
+        .unwrap();
+
+        assert_eq!(
+            request,
+            AnnounceRequest {
+                info_hash: [0xab; 20],
+                peer_id: *b"-BR0001-abcdefghijkl",
+                port: 6881,
+                uploaded: 1,
+                downloaded: 2,
+                left: 3,
+                event: AnnounceEvent::Started,
+                compact: true,
+                no_peer_id: false,
+                numwant: Some(10),
+                ip: Some("2001:db8::1".parse().unwrap()),
+                ipv6: Some("[2001:db8::2]:6882".parse().unwrap()),
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_announces() {
+        assert_eq!(
+            AnnounceRequest::from_query("peer_id=-BR0001-abcdefghijkl&port=1"),
+            Err(RequestError::Missing("info_hash"))
+        );
+        assert_eq!(
+            AnnounceRequest::from_query("info_hash=short"),
+            Err(RequestError::Invalid("info_hash"))
+        );
+        assert_eq!(
+            AnnounceRequest::from_query(&format!(
+                "info_hash={}&peer_id=-BR0001-abcdefghijkl&port=70000",
+                "a".repeat(20)
+            )),
+            Err(RequestError::Invalid("port"))
+        );
+        assert_eq!(
+            AnnounceRequest::from_query(&format!(
+                "info_hash={}&peer_id=-BR0001-abcdefghijkl&port=1&ipv6=10.0.0.1",
+                "a".repeat(20)
+            )),
+            Err(RequestError::Invalid("ipv6"))
+        );
+        assert_eq!(parse_query("a=%4"), Err(RequestError::Invalid("query")));
+    }
+
+    #[test]
+    fn repeated_hashes() {
+        let params = parse_query(&format!(
+            "info_hash={}&info_hash={}",
+            "%01".repeat(20),
+            "b".repeat(20)
+        ))
+        .unwrap();
+        assert_eq!(
+            hashes(&params, "info_hash").unwrap(),
+            vec![[1; 20], [b'b'; 20]]
+        );
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bit_rev::peer::{BencodeResponse, DictPeer, TrackerPeerList};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+};
+use tracing::{debug, warn};
+
+use crate::{
+    request::{self, AnnounceRequest, RequestError},
+    swarm::Swarms,
+};
+
+/// Longest request head we read.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+/// How long a client has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct TrackerConfig {
+    /// Interval we ask clients to announce at.
+    pub interval: Duration,
+    /// Interval clients must not announce faster than.
+    pub min_interval: Duration,
+    /// Peers that didn't announce for this long are dropped.
+    pub peer_expiry: Duration,
+    /// Peers we send when the client doesn't say.
+    pub default_numwant: usize,
+    /// Most peers we send, whatever the client asks for.
+    pub max_numwant: usize,
+    /// Only these torrents are tracked, when set.
+    pub whitelist: Option<HashSet<[u8; 20]>>,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1800),
+            min_interval: Duration::from_secs(60),
+            peer_expiry: Duration::from_secs(3600),
+            default_numwant: 50,
+            max_numwant: 200,
+            whitelist: None,
+        }
+    }
+}
+
+struct State {
+    config: TrackerConfig,
+    swarms: Mutex<Swarms>,
+}
+
+/// An HTTP tracker answering `/announce` and `/scrape`.
+pub struct Tracker {
+    listener: TcpListener,
+    state: Arc<State>,
+}
+
+#[derive(Serialize)]
+struct ScrapeResponse {
+    files: BTreeMap<ByteBuf, FileStats>,
+}
+
+#[derive(Serialize)]
+struct FileStats {
+    complete: u64,
+    downloaded: u64,
+    incomplete: u64,
+}
+
+#[derive(Serialize)]
+struct FailureResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: String,
+}
+
+impl Tracker {
+    pub async fn bind(addr: impl ToSocketAddrs, config: TrackerConfig) -> io::Result<Tracker> {
+        Ok(Tracker {
+            listener: TcpListener::bind(addr).await?,
+            state: Arc::new(State {
+                config,
+                swarms: Mutex::new(Swarms::default()),
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The URL clients announce to.
+    pub fn announce_url(&self) -> io::Result<String> {
+        Ok(format!("http://{}/announce", self.local_addr()?))
+    }
+
+    /// Answers requests until the task is dropped.
+    pub async fn run(self) {
+        let mut expiry = tokio::time::interval(self.state.config.peer_expiry / 4);
+        loop {
+            tokio::select! {
+                accepted = self.listener.accept() => match accepted {
+                    Ok((socket, addr)) => {
+                        let state = self.state.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = handle_connection(socket, addr, &state).await {
+                                debug!("request from {} failed: {}", addr, e);
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        warn!("accept failed: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                },
+                _ = expiry.tick() => {
+                    let expiry = self.state.config.peer_expiry;
+                    self.state.swarms.lock().unwrap().expire(expiry, Instant::now());
+                }
+            }
+        }
+    }
+}
+
+async fn handle_connection(
+    mut socket: TcpStream,
+    addr: SocketAddr,
+    state: &State,
+) -> io::Result<()> {
+    let head = tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut socket))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out"))??;
+
+    let target = head
+        .strip_prefix("GET ")
+        .and_then(|rest| rest.split_whitespace().next());
+    let (status, body) = match target.map(|target| target.split_once('?').unwrap_or((target, ""))) {
+        Some(("/announce", query)) => ("200 OK", announce(state, query, addr.ip())),
+        Some(("/scrape", query)) => ("200 OK", scrape(state, query)),
+        Some(_) => ("404 Not Found", Vec::new()),
+        None => ("400 Bad Request", Vec::new()),
+    };
+
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(&body);
+    socket.write_all(&response).await?;
+    socket.shutdown().await
+}
+
+// Reads up to the blank line ending the request head.
+async fn read_head(socket: &mut TcpStream) -> io::Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = socket.read(&mut buf).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&buf[..n]);
+        if head.len() > MAX_REQUEST_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too large",
+            ));
+        }
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+fn announce(state: &State, query: &str, source: IpAddr) -> Vec<u8> {
+    let request = match AnnounceRequest::from_query(query) {
+        Ok(request) => request,
+        Err(e) => return failure(e),
+    };
+    if !allowed(state, &request.info_hash) {
+        return failure(RequestError::NotAllowed);
+    }
+
+    let config = &state.config;
+    let source = source.to_canonical();
+    let addr = SocketAddr::new(request.ip.unwrap_or(source), request.port);
+    let numwant = request
+        .numwant
+        .unwrap_or(config.default_numwant)
+        .min(config.max_numwant);
+    let (peers, stats) =
+        state
+            .swarms
+            .lock()
+            .unwrap()
+            .announce(&request, addr, numwant, Instant::now());
+
+    let mut response = BencodeResponse {
+        interval: Some(config.interval.as_secs()),
+        min_interval: Some(config.min_interval.as_secs()),
+        complete: Some(stats.seeders),
+        incomplete: Some(stats.leechers),
+        external_ip: Some(ByteBuf::from(ip_bytes(source))),
+        ..Default::default()
+    };
+    if request.compact {
+        let mut peers4 = Vec::new();
+        let mut peers6 = Vec::new();
+        for (_, addr) in peers {
+            let list = if addr.is_ipv4() {
+                &mut peers4
+            } else {
+                &mut peers6
+            };
+            list.extend_from_slice(&ip_bytes(addr.ip()));
+            list.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        response.peers = TrackerPeerList::Compact(ByteBuf::from(peers4));
+        response.peers6 = ByteBuf::from(peers6);
+    } else {
+        response.peers = TrackerPeerList::Dicts(
+            peers
+                .into_iter()
+                .map(|(peer_id, addr)| DictPeer {
+                    peer_id: (!request.no_peer_id).then(|| ByteBuf::from(peer_id.to_vec())),
+                    ip: addr.ip().to_string(),
+                    port: addr.port(),
+                })
+                .collect(),
+        );
+    }
+    serde_bencode::to_bytes(&response).unwrap_or_default()
+}
+
+fn scrape(state: &State, query: &str) -> Vec<u8> {
+    let info_hashes = match request::parse_query(query)
+        .and_then(|params| request::hashes(&params, "info_hash"))
+    {
+        Ok(info_hashes) => info_hashes,
+        Err(e) => return failure(e),
+    };
+
+    let swarms = state.swarms.lock().unwrap();
+    // No info_hash asks for every torrent.
+    let stats = if info_hashes.is_empty() {
+        swarms.all_stats()
+    } else {
+        info_hashes
+            .into_iter()
+            .filter_map(|info_hash| Some((info_hash, swarms.stats(&info_hash)?)))
+            .collect()
+    };
+    drop(swarms);
+
+    let files = stats
+        .into_iter()
+        .filter(|(info_hash, _)| allowed(state, info_hash))
+        .map(|(info_hash, stats)| {
+            let stats = FileStats {
+                complete: stats.seeders,
+                downloaded: stats.completed,
+                incomplete: stats.leechers,
+            };
+            (ByteBuf::from(info_hash.to_vec()), stats)
+        })
+        .collect();
+    serde_bencode::to_bytes(&ScrapeResponse { files }).unwrap_or_default()
+}
+
+fn ip_bytes(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+fn allowed(state: &State, info_hash: &[u8; 20]) -> bool {
+    state
+        .config
+        .whitelist
+        .as_ref()
+        .is_none_or(|whitelist| whitelist.contains(info_hash))
+}
+
+fn failure(error: RequestError) -> Vec<u8> {
+    serde_bencode::to_bytes(&FailureResponse {
+        failure_reason: error.to_string(),
+    })
+    .unwrap_or_default()
+}
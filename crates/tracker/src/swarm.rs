@@ -0,0 +1,222 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bit_rev::tracker::{AnnounceEvent, ScrapeStats};
+use rand::seq::IteratorRandom;
+
+use crate::request::AnnounceRequest;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Peer {
+    addr: SocketAddr,
+    /// The IPv6 address of a peer announcing over IPv4.
+    addr6: Option<SocketAddr>,
+    seeding: bool,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Swarm {
+    peers: HashMap<[u8; 20], Peer>,
+    /// `completed` announces we got.
+    completed: u64,
+}
+
+impl Swarm {
+    fn stats(&self) -> ScrapeStats {
+        let seeders = self.peers.values().filter(|peer| peer.seeding).count() as u64;
+        ScrapeStats {
+            seeders,
+            leechers: self.peers.len() as u64 - seeders,
+            completed: self.completed,
+        }
+    }
+}
+
+/// The peers of every torrent the tracker heard of.
+#[derive(Debug, Default)]
+pub struct Swarms {
+    torrents: HashMap<[u8; 20], Swarm>,
+}
+
+impl Swarms {
+    /// Records the announce of the peer at `addr` and returns up to `numwant`
+    /// other peers of the torrent with their ids, and the torrent's counts.
+    /// Peers with an IPv4 and an IPv6 address come with both.
+    pub fn announce(
+        &mut self,
+        request: &AnnounceRequest,
+        addr: SocketAddr,
+        numwant: usize,
+        now: Instant,
+    ) -> (Vec<([u8; 20], SocketAddr)>, ScrapeStats) {
+        let swarm = self.torrents.entry(request.info_hash).or_default();
+        match request.event {
+            AnnounceEvent::Stopped => {
+                swarm.peers.remove(&request.peer_id);
+            }
+            event => {
+                let peer = Peer {
+                    addr,
+                    addr6: request.ipv6.filter(|_| addr.is_ipv4()),
+                    seeding: request.left == 0,
+                    last_seen: now,
+                };
+                let previous = swarm.peers.insert(request.peer_id, peer);
+                // Only count peers that actually downloaded, not repeated
+                // `completed` announces.
+                if event == AnnounceEvent::Completed && previous.is_none_or(|p| !p.seeding) {
+                    swarm.completed += 1;
+                }
+            }
+        }
+
+        let peers = swarm
+            .peers
+            .iter()
+            .filter(|(peer_id, _)| **peer_id != request.peer_id)
+            .choose_multiple(&mut rand::thread_rng(), numwant)
+            .into_iter()
+            .flat_map(|(peer_id, peer)| {
+                std::iter::once(peer.addr)
+                    .chain(peer.addr6)
+                    .map(|addr| (*peer_id, addr))
+            })
+            .collect();
+        let stats = swarm.stats();
+        if swarm.peers.is_empty() && swarm.completed == 0 {
+            self.torrents.remove(&request.info_hash);
+        }
+        (peers, stats)
+    }
+
+    /// Counts of `info_hash`, if any peer announced it.
+    pub fn stats(&self, info_hash: &[u8; 20]) -> Option<ScrapeStats> {
+        self.torrents.get(info_hash).map(Swarm::stats)
+    }
+
+    /// Counts of every torrent.
+    pub fn all_stats(&self) -> Vec<([u8; 20], ScrapeStats)> {
+        self.torrents
+            .iter()
+            .map(|(info_hash, swarm)| (*info_hash, swarm.stats()))
+            .collect()
+    }
+
+    /// Forgets peers that didn't announce for `expiry`.
+    pub fn expire(&mut self, expiry: Duration, now: Instant) {
+        for swarm in self.torrents.values_mut() {
+            swarm
+                .peers
+                .retain(|_, peer| now.saturating_duration_since(peer.last_seen) < expiry);
+        }
+        self.torrents
+            .retain(|_, swarm| !swarm.peers.is_empty() || swarm.completed > 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(peer_id: u8, left: u64, event: AnnounceEvent) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: [1; 20],
+            peer_id: [peer_id; 20],
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            event,
+            compact: true,
+            no_peer_id: false,
+            numwant: None,
+            ip: None,
+            ipv6: None,
+        }
+    }
+
+    #[test]
+    fn peers_see_each_other() {
+        let mut swarms = Swarms::default();
+        let now = Instant::now();
+        let seeder: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let leecher: SocketAddr = "[2001:db8::1]:6882".parse().unwrap();
+
+        let (peers, _) = swarms.announce(&request(1, 0, AnnounceEvent::Started), seeder, 50, now);
+        assert!(peers.is_empty());
+        let (peers, stats) =
+            swarms.announce(&request(2, 10, AnnounceEvent::Started), leecher, 50, now);
+        assert_eq!(peers, vec![([1; 20], seeder)]);
+        assert_eq!(
+            stats,
+            ScrapeStats {
+                seeders: 1,
+                leechers: 1,
+                completed: 0
+            }
+        );
+
+        let (_, stats) =
+            swarms.announce(&request(2, 0, AnnounceEvent::Completed), leecher, 50, now);
+        assert_eq!((stats.seeders, stats.leechers, stats.completed), (2, 0, 1));
+        // Saying it again doesn't count twice.
+        swarms.announce(&request(2, 0, AnnounceEvent::Completed), leecher, 50, now);
+        let (peers, stats) =
+            swarms.announce(&request(1, 0, AnnounceEvent::Stopped), seeder, 50, now);
+        assert_eq!(peers, vec![([2; 20], leecher)]);
+        assert_eq!((stats.seeders, stats.leechers, stats.completed), (1, 0, 1));
+    }
+
+    #[test]
+    fn dual_stack_peers() {
+        let mut swarms = Swarms::default();
+        let now = Instant::now();
+        let mut dual = request(1, 10, AnnounceEvent::Started);
+        dual.ipv6 = Some("[2001:db8::1]:6881".parse().unwrap());
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        swarms.announce(&dual, addr, 50, now);
+
+        let other: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let (peers, stats) =
+            swarms.announce(&request(2, 10, AnnounceEvent::Started), other, 50, now);
+        assert_eq!(peers, vec![([1; 20], addr), ([1; 20], dual.ipv6.unwrap())]);
+        assert_eq!(stats.leechers, 2);
+    }
+
+    #[test]
+    fn numwant_limits_the_peers() {
+        let mut swarms = Swarms::default();
+        let now = Instant::now();
+        for id in 0..10 {
+            let addr = SocketAddr::from(([10, 0, 0, id], 6881));
+            swarms.announce(&request(id, 10, AnnounceEvent::Started), addr, 50, now);
+        }
+        let addr = SocketAddr::from(([10, 0, 0, 10], 6881));
+        let (peers, _) = swarms.announce(&request(10, 10, AnnounceEvent::None), addr, 3, now);
+        assert_eq!(peers.len(), 3);
+        assert!(peers.iter().all(|(peer_id, _)| *peer_id != [10; 20]));
+    }
+
+    #[test]
+    fn silent_peers_expire() {
+        let mut swarms = Swarms::default();
+        let start = Instant::now();
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        swarms.announce(&request(1, 10, AnnounceEvent::Started), addr, 50, start);
+        swarms.announce(
+            &request(2, 10, AnnounceEvent::Started),
+            addr,
+            50,
+            start + Duration::from_secs(50),
+        );
+
+        swarms.expire(Duration::from_secs(60), start + Duration::from_secs(70));
+        assert_eq!(swarms.stats(&[1; 20]).unwrap().leechers, 1);
+        swarms.expire(Duration::from_secs(60), start + Duration::from_secs(200));
+        assert_eq!(swarms.stats(&[1; 20]), None);
+    }
+}
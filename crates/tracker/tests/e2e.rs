@@ -0,0 +1,238 @@
+use std::{collections::HashSet, net::SocketAddr, time::Duration};
+
+use bit_rev::{
+    file::{Info, TorrentFile, TorrentMeta},
+    session::{Session, SessionConfig},
+    tracker::{self as client, Announce, AnnounceEvent, ScrapeStats, TrackerError},
+};
+use tracker::{Tracker, TrackerConfig};
+
+const DATA: &[u8] = b"hello tracker";
+
+async fn start_tracker(config: TrackerConfig) -> String {
+    let tracker = Tracker::bind("127.0.0.1:0", config).await.unwrap();
+    let url = tracker.announce_url().unwrap();
+    tokio::spawn(tracker.run());
+    url
+}
+
+fn torrent(announce: &str) -> TorrentMeta {
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(DATA);
+    TorrentMeta::new(TorrentFile {
+        info: Info {
+            name: "hello.txt".to_string(),
+            pieces: serde_bytes::ByteBuf::from(hasher.digest().bytes().to_vec()),
+            piece_length: 16384,
+            md5sum: None,
+            length: Some(DATA.len() as i64),
+            files: None,
+            private: None,
+            path: None,
+            root_hash: None,
+        },
+        announce: Some(announce.to_string()),
+        nodes: None,
+        encoding: None,
+        httpseeds: None,
+        url_list: None,
+        announce_list: None,
+        creation_date: None,
+        comment: None,
+        created_by: None,
+    })
+    .unwrap()
+}
+
+fn session_config(name: &str) -> SessionConfig {
+    SessionConfig {
+        listen_port: 0,
+        download_dir: std::env::temp_dir().join(format!("tracker_{}_{}", name, std::process::id())),
+        enable_dht: false,
+        enable_lsd: false,
+        ..Default::default()
+    }
+}
+
+fn announce(info_hash: [u8; 20], peer_id: u8, port: u16) -> Announce {
+    Announce {
+        info_hash,
+        peer_id: [peer_id; 20],
+        port,
+        uploaded: 0,
+        downloaded: 0,
+        left: 10,
+        event: AnnounceEvent::Started,
+        numwant: None,
+        key: 0,
+        tracker_id: None,
+        ip: None,
+        ipv6: None,
+    }
+}
+
+async fn wait_for_stats(url: &str, info_hash: [u8; 20], expected: ScrapeStats) {
+    let client = reqwest::Client::new();
+    let mut stats = None;
+    let waited = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let files = client::scrape(&client, &Default::default(), None, url, &[info_hash])
+                .await
+                .unwrap();
+            stats = files.get(&info_hash).copied();
+            if stats == Some(expected) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await;
+    assert!(
+        waited.is_ok(),
+        "scrape is {:?}, expected {:?}",
+        stats,
+        expected
+    );
+}
+
+#[tokio::test]
+async fn sessions_find_each_other_through_the_tracker() {
+    let url = start_tracker(TrackerConfig::default()).await;
+    let meta = torrent(&url);
+    let info_hash = meta.info_hash;
+
+    let seeder_config = session_config("seeder");
+    std::fs::create_dir_all(&seeder_config.download_dir).unwrap();
+    std::fs::write(seeder_config.download_dir.join("hello.txt"), DATA).unwrap();
+    let leecher_config = session_config("leecher");
+    let leecher_dir = leecher_config.download_dir.clone();
+
+    let seeder = Session::new(seeder_config).await.unwrap();
+    let seeding = seeder.add_torrent(meta.clone()).await.unwrap();
+    wait_for_stats(
+        &url,
+        info_hash,
+        ScrapeStats {
+            seeders: 1,
+            leechers: 0,
+            completed: 0,
+        },
+    )
+    .await;
+
+    let leecher = Session::new(leecher_config).await.unwrap();
+    let downloading = leecher.add_torrent(meta).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(10), downloading.wait_for_completion())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(std::fs::read(leecher_dir.join("hello.txt")).unwrap(), DATA);
+
+    // The leecher says it completed and is now a seeder too.
+    wait_for_stats(
+        &url,
+        info_hash,
+        ScrapeStats {
+            seeders: 2,
+            leechers: 0,
+            completed: 1,
+        },
+    )
+    .await;
+
+    downloading.remove(true).await.unwrap();
+    seeding.remove(true).await.unwrap();
+}
+
+#[tokio::test]
+async fn ipv6_and_dictionary_peers() {
+    let url = start_tracker(TrackerConfig::default()).await;
+    let http = reqwest::Client::new();
+    let info_hash = [7; 20];
+
+    let mut first = announce(info_hash, 1, 6881);
+    first.ip = Some("2001:db8::1".parse().unwrap());
+    client::announce(&http, &Default::default(), None, &url, &first)
+        .await
+        .unwrap();
+    client::announce(
+        &http,
+        &Default::default(),
+        None,
+        &url,
+        &announce(info_hash, 2, 6882),
+    )
+    .await
+    .unwrap();
+
+    let res = client::announce(
+        &http,
+        &Default::default(),
+        None,
+        &url,
+        &announce(info_hash, 3, 6883),
+    )
+    .await
+    .unwrap();
+    let mut peers = res.get_peers().unwrap();
+    peers.sort();
+    let expected: Vec<SocketAddr> = vec![
+        "127.0.0.1:6882".parse().unwrap(),
+        "[2001:db8::1]:6881".parse().unwrap(),
+    ];
+    assert_eq!(peers, expected);
+    assert_eq!(res.incomplete, Some(3));
+    assert_eq!(res.interval, Some(1800));
+    assert_eq!(res.external_ip(), Some("127.0.0.1".parse().unwrap()));
+
+    // Clients that don't ask for compact peers get dictionaries.
+    let body = http
+        .get(
+            announce(info_hash, 3, 6883)
+                .url(&url)
+                .replace("compact=1", "compact=0"),
+        )
+        .send()
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+    let res: bit_rev::peer::BencodeResponse = serde_bencode::from_bytes(&body).unwrap();
+    assert!(matches!(
+        res.peers,
+        bit_rev::peer::TrackerPeerList::Dicts(_)
+    ));
+    let mut peers = res.get_peers().unwrap();
+    peers.sort();
+    assert_eq!(peers, expected);
+}
+
+#[tokio::test]
+async fn whitelist_rejects_other_torrents() {
+    let url = start_tracker(TrackerConfig {
+        whitelist: Some(HashSet::from([[1; 20]])),
+        ..Default::default()
+    })
+    .await;
+    let http = reqwest::Client::new();
+
+    client::announce(
+        &http,
+        &Default::default(),
+        None,
+        &url,
+        &announce([1; 20], 1, 6881),
+    )
+    .await
+    .unwrap();
+    assert!(matches!(
+        client::announce(&http, &Default::default(), None, &url, &announce([2; 20], 1, 6881)).await,
+        Err(TrackerError::Failure(reason)) if reason == "torrent not allowed"
+    ));
+
+    let files = client::scrape(&http, &Default::default(), None, &url, &[[1; 20], [2; 20]])
+        .await
+        .unwrap();
+    assert_eq!(files.keys().collect::<Vec<_>>(), vec![&[1; 20]]);
+}